@@ -137,10 +137,20 @@ let
                 containers = [{
                   name = pname;
                   image = "registry.fyfaen.as/nix-build-controller:1.0.1";
-                  env = [{
-                    name = "RUST_LOG";
-                    value = "info";
-                  }];
+                  env = [
+                    {
+                      name = "RUST_LOG";
+                      value = "info";
+                    }
+                    # An admin token of nix-serve, to register each job's builder key.
+                    {
+                      name = "NIX_SERVE_ADMIN_TOKEN";
+                      valueFrom.secretKeyRef = {
+                        name = "nix-serve-admin-token";
+                        key = "token";
+                      };
+                    }
+                  ];
                 }];
               };
             };
@@ -162,6 +172,11 @@ let
               resources = [ "jobs" ];
               verbs = [ "create" "delete" "get" "list" "watch" ];
             }
+            {
+              apiGroups = [ "" ];
+              resources = [ "secrets" ];
+              verbs = [ "create" "get" "patch" ];
+            }
             {
               apiGroups = [ "build.fyfaen.as" ];
               resources = [ "nixbuilds" "nixbuilds/status" ];
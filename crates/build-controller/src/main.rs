@@ -1,8 +1,11 @@
+use base64::engine::general_purpose::STANDARD as b64;
+use base64::Engine;
+use ed25519_dalek::SigningKey;
 use futures::StreamExt;
 use k8s_openapi::api::batch::v1::{Job, JobSpec};
 use k8s_openapi::api::core::v1::{
-    Container, EnvVar, EnvVarSource, PodSpec, PodTemplateSpec, ResourceRequirements,
-    SecretKeySelector,
+    Container, EnvVar, EnvVarSource, PodSpec, PodTemplateSpec, ResourceRequirements, Secret,
+    SecretKeySelector, SecretVolumeSource, Volume,
 };
 use k8s_openapi::api::core::v1::{LocalObjectReference, VolumeMount};
 use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
@@ -27,16 +30,109 @@ pub enum Error {
     KubeError(#[from] kube::Error),
     #[error("Build Error: {0}")]
     BuildError(String),
+    #[error("Cache Error: {0}")]
+    CacheError(#[from] reqwest::Error),
 }
 
 struct ContextData {
     client: Client,
+    http: reqwest::Client,
+    /// Admin token for the cache, which builder keys are registered with.
+    cache_token: Option<String>,
+}
+
+fn is_finished(status: &NixBuildStatus) -> bool {
+    status.phase == "Completed" || status.phase == "Failed" || status.phase == "Deployed"
+}
+
+/// A fresh key for one build job, the way `nix key generate-secret` makes them. Returns
+/// the secret and the public key, both as `name:base64`.
+fn generate_builder_key(name: &str) -> Result<(String, String), Error> {
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed)
+        .map_err(|e| Error::BuildError(format!("no randomness for a builder key: {e}")))?;
+    let key = SigningKey::from_bytes(&seed);
+    let public = key.verifying_key();
+    Ok((
+        format!("{name}:{}", b64.encode(key.to_keypair_bytes())),
+        format!("{name}:{}", b64.encode(public.as_bytes())),
+    ))
+}
+
+fn builder_key_secret(job_name: &str) -> String {
+    format!("{job_name}-builder-key")
+}
+
+/// Keeps the job's secret key where its pod mounts it, and has the cache trust the
+/// public half until the job is done.
+async fn set_up_builder_key(
+    ctx: &ContextData,
+    secrets: &Api<Secret>,
+    job_name: &str,
+    owner_reference: OwnerReference,
+) -> Result<(), Error> {
+    let (secret_key, public_key) = generate_builder_key(job_name)?;
+    let secret = Secret {
+        metadata: ObjectMeta {
+            name: Some(builder_key_secret(job_name)),
+            owner_references: Some(vec![owner_reference]),
+            ..ObjectMeta::default()
+        },
+        string_data: Some(BTreeMap::from([("builder.sec".to_owned(), secret_key)])),
+        ..Secret::default()
+    };
+    secrets
+        .patch(
+            &builder_key_secret(job_name),
+            &kube::api::PatchParams::apply("build-controller").force(),
+            &kube::api::Patch::Apply(&secret),
+        )
+        .await?;
+
+    let mut request = ctx
+        .http
+        .put(format!("http://{CACHE_HOST}:3000/admin/builder-keys/{job_name}"))
+        .body(public_key);
+    if let Some(token) = &ctx.cache_token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(Error::BuildError(format!(
+            "cache refused builder key for {job_name}: {}",
+            response.status()
+        )));
+    }
+    tracing::info!("Registered builder key for {}", job_name);
+    Ok(())
+}
+
+/// Stops the cache trusting a finished job's key. It expires on its own if this fails.
+async fn revoke_builder_key(ctx: &ContextData, job_name: &str) {
+    let mut request = ctx
+        .http
+        .delete(format!("http://{CACHE_HOST}:3000/admin/builder-keys/{job_name}"));
+    if let Some(token) = &ctx.cache_token {
+        request = request.bearer_auth(token);
+    }
+    match request.send().await {
+        Ok(response) if response.status().is_success() => {
+            tracing::info!("Revoked builder key for {}", job_name)
+        }
+        Ok(response) => tracing::warn!(
+            "Cache refused to revoke builder key for {}: {}",
+            job_name,
+            response.status()
+        ),
+        Err(e) => tracing::warn!("Failed to revoke builder key for {}: {}", job_name, e),
+    }
 }
 
 async fn reconcile(build: Arc<NixBuild>, ctx: Arc<ContextData>) -> Result<Action, Error> {
     let ns = build.namespace().unwrap_or_else(|| "default".into());
     let builds: Api<NixBuild> = Api::namespaced(ctx.client.clone(), &ns);
     let jobs: Api<Job> = Api::namespaced(ctx.client.clone(), &ns);
+    let secrets: Api<Secret> = Api::namespaced(ctx.client.clone(), &ns);
 
     let current_status = build.status.clone().unwrap_or_default();
     let mut new_status = current_status.clone();
@@ -67,9 +163,12 @@ async fn reconcile(build: Arc<NixBuild>, ctx: Arc<ContextData>) -> Result<Action
     if new_status.observed_generation == build.metadata.generation {
         tracing::info!("We've seen this guy before, generations match");
         // Nothing has changed in the spec, just check job status if it exists
-        if let Some(job_name) = &new_status.job_name {
-            if let Ok(job) = jobs.get(job_name).await {
+        if let Some(job_name) = new_status.job_name.clone() {
+            if let Ok(job) = jobs.get(&job_name).await {
                 update_status_from_job(&mut new_status, &job);
+                if is_finished(&new_status) && !is_finished(&current_status) {
+                    revoke_builder_key(&ctx, &job_name).await;
+                }
 
                 if current_status.needs_update(&new_status) {
                     update_build_status(&builds, &build, new_status).await?;
@@ -99,6 +198,9 @@ async fn reconcile(build: Arc<NixBuild>, ctx: Arc<ContextData>) -> Result<Action
             tracing::info!("Job exists! {}", &job_name);
             if let Some(status) = &j.status {
                 update_status_from_job(&mut new_status, &j);
+                if is_finished(&new_status) && !is_finished(&current_status) {
+                    revoke_builder_key(&ctx, &job_name).await;
+                }
 
                 if current_status.needs_update(&new_status) {
                     update_build_status(&builds, &build, new_status).await?;
@@ -132,6 +234,7 @@ async fn reconcile(build: Arc<NixBuild>, ctx: Arc<ContextData>) -> Result<Action
                 &job_name,
                 &owner_reference
             );
+            set_up_builder_key(&ctx, &secrets, &job_name, owner_reference.clone()).await?;
             let job = create_build_job(&build, job_name, owner_reference)?;
             jobs.create(&Default::default(), &job).await?;
             update_build_status(&builds, &build, new_status).await?;
@@ -202,15 +305,20 @@ async fn update_build_status(
     Ok(())
 }
 
+/// Where build jobs substitute from and push to. The netrc written for the job only
+/// covers this host.
+const CACHE_HOST: &str = "nix-serve.nixbuilder.svc.cluster.local";
+
 fn create_build_job(
     build: &NixBuild,
     name: String,
     owner_reference: OwnerReference,
 ) -> Result<Job, Error> {
+    let key_secret = builder_key_secret(&name);
     let resources = ResourceRequirements {
         ..ResourceRequirements::default()
     };
-    let image = "registry.fyfaen.as/nix-builder:1.0.12";
+    let image = "registry.fyfaen.as/nix-builder:1.0.13";
     let builder = Container {
         name: "builder".to_owned(),
         image: Some(image.to_owned()),
@@ -244,6 +352,40 @@ fn create_build_job(
                 }),
                 ..Default::default()
             },
+            EnvVar {
+                name: "NIX_SERVE_PUSH_URL".to_owned(),
+                value: Some(format!("http://{CACHE_HOST}:3000")),
+                ..Default::default()
+            },
+            EnvVar {
+                name: "NIX_SERVE_PUSH_SECRET_KEY".to_owned(),
+                value: Some("/etc/nix-serve-push/builder.sec".to_owned()),
+                ..Default::default()
+            },
+            EnvVar {
+                name: "NIX_SERVE_PUBLIC_KEY".to_owned(),
+                value_from: Some(EnvVarSource {
+                    secret_key_ref: Some(SecretKeySelector {
+                        name: "nix-serve-keys".to_owned(),
+                        key: "cache.pub".to_owned(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            EnvVar {
+                name: "NIX_SERVE_TOKEN".to_owned(),
+                value_from: Some(EnvVarSource {
+                    secret_key_ref: Some(SecretKeySelector {
+                        name: "nix-serve-push-token".to_owned(),
+                        key: "token".to_owned(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
         ]),
         command: Some(vec![
             "/bin/bash".to_owned(),
@@ -264,9 +406,38 @@ fn create_build_job(
 
                 export PATH="/home/nixuser/.nix-profile/bin:/nix/var/nix/profiles/default/bin:$PATH"
                 export NIX_PATH="/home/nixuser/.nix-defexpr/channels:/nix/var/nix/profiles/per-user/root/channels"
+                # The hook only queues the paths, nix-serve-push uploads them in the background.
                 echo '#!/usr/bin/env bash' >> /home/nixuser/push-to-cache.sh
-                echo '/home/nixuser/.nix-profile/bin/nix --extra-experimental-features nix-command --extra-experimental-features flakes copy --to http://{} $OUT_PATHS' >> /home/nixuser/push-to-cache.sh
+                echo 'exec nix-serve-push push $OUT_PATHS' >> /home/nixuser/push-to-cache.sh
                 chmod +x /home/nixuser/push-to-cache.sh
+                NIX_SERVE_PUSH_TOKEN="$NIX_SERVE_TOKEN" nix-serve-push daemon &
+
+                # Drain only once: the daemon exits after answering.
+                DRAINED=0
+                drain_uploads() {{
+                    [ "$DRAINED" = 1 ] && return 0
+                    DRAINED=1
+                    echo "[builder] waiting for uploads to the cache"
+                    if ! nix-serve-push drain; then
+                        publish_status "Failed" "uploading to the cache failed"
+                        echo "[builder] uploading to the cache failed"
+                        return 1
+                    fi
+                }}
+                # What built before a failure is still uploaded, whichever way the job ends.
+                on_exit() {{
+                    local status=$?
+                    if ! drain_uploads && [ "$status" -eq 0 ]; then
+                        status=1
+                    fi
+                    exit "$status"
+                }}
+                trap on_exit EXIT
+                mkdir -p /home/nixuser/.config/nix
+                echo "machine {CACHE_HOST} login $BUILD_NAME password $NIX_SERVE_TOKEN" > /home/nixuser/.netrc
+                chmod 600 /home/nixuser/.netrc
+                echo "netrc-file = /home/nixuser/.netrc" >> /home/nixuser/.config/nix/nix.conf
+                unset NIX_SERVE_TOKEN
                 set -euo pipefail
                 which nix
 
@@ -274,10 +445,10 @@ fn create_build_job(
                 publish_status "Building" "Populating cache"
                 echo "[builder] starting"
                 nix --extra-experimental-features nix-command --extra-experimental-features flakes \
-                    --option require-sigs false \
+                    --option extra-trusted-public-keys "$NIX_SERVE_PUBLIC_KEY" \
                     --option substitute true \
-                    --option extra-substituters http://{} \
-                    build .#{} \
+                    --option extra-substituters http://{CACHE_HOST}:3000 \
+                    build .#{attr} \
                     --post-build-hook /home/nixuser/push-to-cache.sh
                 echo "[builder]"
 
@@ -320,11 +491,13 @@ fn create_build_job(
 
                 echo "[builder] building manifest"
                 nix --extra-experimental-features nix-command --extra-experimental-features flakes \
-                    --option require-sigs false \
+                    --option extra-trusted-public-keys "$NIX_SERVE_PUBLIC_KEY" \
                     --option substitute true \
-                    --option extra-substituters http://nix-serve-nixbuilder.svc.cluster.local:3000 \
+                    --option extra-substituters http://{CACHE_HOST}:3000 \
                     build .#manifests --out-link manifests \
-                    --post-build-hook /home/nixuser/push-to-cache.sh ## TODO: Replace this with a fifo and like 4 workers instead of blocking on curl
+                    --post-build-hook /home/nixuser/push-to-cache.sh
+
+                drain_uploads || exit 1
 
                 echo "[builder] publishing deploy message"
                 MANIFEST_CONTENT=$(cat manifests | base64 -w0)
@@ -336,15 +509,19 @@ fn create_build_job(
 
                 publish_status "Deploying" "Build proccess completed successfully "
                 "#,
-                "nix-serve.nixbuilder.svc.cluster.local:3000",
-                "nix-serve.nixbuilder.svc.cluster.local:3000",
-                build
+                attr = build
                     .spec
                     .nix_attr
                     .as_ref()
                     .unwrap_or(&"default".to_string())
             ),
         ]),
+        volume_mounts: Some(vec![VolumeMount {
+            name: "nix-serve-push-key".to_owned(),
+            mount_path: "/etc/nix-serve-push".to_owned(),
+            read_only: Some(true),
+            ..VolumeMount::default()
+        }]),
         resources: Some(resources),
         ..Container::default()
     };
@@ -364,6 +541,14 @@ fn create_build_job(
                         name: "nix-serve-regcred".to_string(),
                     }]),
                     restart_policy: Some("Never".to_string()),
+                    volumes: Some(vec![Volume {
+                        name: "nix-serve-push-key".to_owned(),
+                        secret: Some(SecretVolumeSource {
+                            secret_name: Some(key_secret),
+                            ..SecretVolumeSource::default()
+                        }),
+                        ..Volume::default()
+                    }]),
                     ..PodSpec::default()
                 }),
                 ..PodTemplateSpec::default()
@@ -408,6 +593,10 @@ async fn main() -> Result<(), Error> {
 
     let context = Arc::new(ContextData {
         client: client.clone(),
+        http: reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?,
+        cache_token: env::var("NIX_SERVE_ADMIN_TOKEN").ok(),
     });
 
     let builds: Api<NixBuild> = Api::<NixBuild>::namespaced(client, "nixbuilder"); // Api::all(client); <- for clusterwide resources.
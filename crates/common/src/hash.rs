@@ -0,0 +1,157 @@
+use base64::engine::general_purpose::STANDARD as b64;
+use base64::Engine;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::nixbase32;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum HashError {
+    #[error("unknown hash algorithm in {0:?}")]
+    UnknownAlgo(String),
+    #[error("invalid hash digest {0:?}")]
+    InvalidDigest(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgo {
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashAlgo::Md5 => 16,
+            HashAlgo::Sha1 => 20,
+            HashAlgo::Sha256 => 32,
+            HashAlgo::Sha512 => 64,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Md5 => "md5",
+            HashAlgo::Sha1 => "sha1",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512 => "sha512",
+        }
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = HashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md5" => Ok(HashAlgo::Md5),
+            "sha1" => Ok(HashAlgo::Sha1),
+            "sha256" => Ok(HashAlgo::Sha256),
+            "sha512" => Ok(HashAlgo::Sha512),
+            _ => Err(HashError::UnknownAlgo(s.to_owned())),
+        }
+    }
+}
+
+/// A hash as it appears in narinfo `NarHash:`/`FileHash:` fields. Parses the
+/// `<algo>:<nix32|hex|base64>` and SRI `<algo>-<base64>` forms and always prints
+/// `<algo>:<nix32>`, which is what goes into signature fingerprints.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Hash {
+    algo: HashAlgo,
+    digest: Vec<u8>,
+}
+
+impl Hash {
+    pub fn new(algo: HashAlgo, digest: Vec<u8>) -> Result<Self, HashError> {
+        if digest.len() != algo.digest_len() {
+            return Err(HashError::InvalidDigest(format!("{} bytes", digest.len())));
+        }
+        Ok(Hash { algo, digest })
+    }
+
+    pub fn algo(&self) -> HashAlgo {
+        self.algo
+    }
+
+    pub fn digest(&self) -> &[u8] {
+        &self.digest
+    }
+
+    pub fn to_nix32(&self) -> String {
+        nixbase32::encode(&self.digest)
+    }
+
+    fn decode_digest(algo: HashAlgo, s: &str) -> Option<Vec<u8>> {
+        let len = algo.digest_len();
+        if s.len() == nixbase32::encoded_len(len) {
+            return nixbase32::decode(s, len);
+        }
+        if s.len() == len * 2 {
+            return (0..len)
+                .map(|i| u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok())
+                .collect();
+        }
+        b64.decode(s).ok().filter(|digest| digest.len() == len)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = HashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algo, digest) = s
+            .split_once(':')
+            .or_else(|| s.split_once('-'))
+            .ok_or_else(|| HashError::UnknownAlgo(s.to_owned()))?;
+        let algo: HashAlgo = algo.parse()?;
+        let digest = Self::decode_digest(algo, digest)
+            .ok_or_else(|| HashError::InvalidDigest(s.to_owned()))?;
+        Ok(Hash { algo, digest })
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algo.name(), self.to_nix32())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EMPTY_NIX32: &str = "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+
+    #[test]
+    fn parses_all_encodings() {
+        let nix32: Hash = EMPTY_NIX32.parse().unwrap();
+        let hex: Hash = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+            .parse()
+            .unwrap();
+        let sri: Hash = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+            .parse()
+            .unwrap();
+        assert_eq!(nix32, hex);
+        assert_eq!(nix32, sri);
+        assert_eq!(sri.to_string(), EMPTY_NIX32);
+    }
+
+    #[test]
+    fn rejects_bad_hashes() {
+        assert!(matches!(
+            "sha3:abc".parse::<Hash>(),
+            Err(HashError::UnknownAlgo(_))
+        ));
+        assert!(matches!(
+            "sha256:tooshort".parse::<Hash>(),
+            Err(HashError::InvalidDigest(_))
+        ));
+        assert!("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+            .parse::<Hash>()
+            .is_err());
+    }
+}
@@ -1,3 +1,7 @@
+pub mod hash;
+pub mod narinfo;
+pub mod nixbase32;
+
 use std::{borrow::Cow, future::Future};
 use tokio::sync::mpsc;
 
@@ -0,0 +1,466 @@
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::hash::{Hash, HashError};
+use crate::nixbase32;
+
+pub const STORE_DIR: &str = "/nix/store";
+
+/// Length of the hash part of a store path.
+pub const HASH_PART_LEN: usize = 32;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NarInfoError {
+    #[error("invalid store path {0:?}")]
+    InvalidStorePath(String),
+    #[error("invalid line {0:?}")]
+    InvalidLine(String),
+    #[error("missing field {0}")]
+    MissingField(&'static str),
+    #[error("duplicate field {0}")]
+    DuplicateField(String),
+    #[error("invalid {0}: {1:?}")]
+    InvalidValue(&'static str, String),
+    #[error("invalid hash: {0}")]
+    InvalidHash(#[from] HashError),
+}
+
+/// True for strings that can be the hash part of a store path: 32 characters of the
+/// nix base32 alphabet. Anything else, `..` and `/` included, is rejected.
+pub fn is_valid_hash_part(s: &str) -> bool {
+    s.len() == HASH_PART_LEN && s.bytes().all(nixbase32::is_valid_char)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 211
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"+-._?=".contains(&c))
+}
+
+/// A store path without its store directory, `<hash>-<name>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StorePath {
+    hash: String,
+    name: String,
+}
+
+impl StorePath {
+    /// Parses a base name such as `7h1y...-hello-2.12`.
+    pub fn from_base_name(s: &str) -> Result<Self, NarInfoError> {
+        let invalid = || NarInfoError::InvalidStorePath(s.to_owned());
+        let (hash, name) = s.split_once('-').ok_or_else(invalid)?;
+        if !is_valid_hash_part(hash) || !is_valid_name(name) {
+            return Err(invalid());
+        }
+        Ok(StorePath {
+            hash: hash.to_owned(),
+            name: name.to_owned(),
+        })
+    }
+
+    /// Parses a full path below [`STORE_DIR`].
+    pub fn from_absolute(s: &str) -> Result<Self, NarInfoError> {
+        s.strip_prefix(STORE_DIR)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or_else(|| NarInfoError::InvalidStorePath(s.to_owned()))
+            .and_then(Self::from_base_name)
+    }
+
+    pub fn hash_part(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn base_name(&self) -> String {
+        format!("{}-{}", self.hash, self.name)
+    }
+
+    pub fn to_absolute(&self) -> String {
+        format!("{STORE_DIR}/{}-{}", self.hash, self.name)
+    }
+}
+
+impl fmt::Display for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.hash, self.name)
+    }
+}
+
+/// The `Compression:` of a NAR. Nix treats a missing field as bzip2.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Compression {
+    None,
+    Xz,
+    #[default]
+    Bzip2,
+    Zstd,
+    Brotli,
+    Gzip,
+    Other(String),
+}
+
+impl Compression {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Compression::None => "none",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+            Compression::Zstd => "zstd",
+            Compression::Brotli => "br",
+            Compression::Gzip => "gzip",
+            Compression::Other(other) => other,
+        }
+    }
+
+    /// The extension Nix gives NAR files with this compression, e.g. `.xz`.
+    pub fn extension(&self) -> &str {
+        match self {
+            Compression::None => "",
+            Compression::Xz => ".xz",
+            Compression::Bzip2 => ".bz2",
+            Compression::Zstd => ".zst",
+            Compression::Brotli => ".br",
+            Compression::Gzip => ".gz",
+            Compression::Other(_) => "",
+        }
+    }
+
+    /// Guesses the compression of a NAR file from its extension.
+    pub fn from_file_name(name: &str) -> Self {
+        [
+            Compression::Xz,
+            Compression::Bzip2,
+            Compression::Zstd,
+            Compression::Brotli,
+            Compression::Gzip,
+        ]
+        .into_iter()
+        .find(|compression| name.ends_with(compression.extension()))
+        .unwrap_or(Compression::None)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "none" => Compression::None,
+            "xz" => Compression::Xz,
+            "bzip2" => Compression::Bzip2,
+            "zstd" => Compression::Zstd,
+            "br" => Compression::Brotli,
+            "gzip" => Compression::Gzip,
+            other => Compression::Other(other.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// True for names that can sit in a binary cache's `nar/` directory: no path
+/// separators, no leading dot, nothing outside `[A-Za-z0-9._-]`.
+pub fn is_valid_nar_file(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"._-".contains(&c))
+}
+
+/// The contents of a `.narinfo` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarInfo {
+    pub store_path: StorePath,
+    /// Relative to the cache root, usually `nar/<filehash>.nar<ext>`.
+    pub url: String,
+    pub compression: Compression,
+    pub file_hash: Option<Hash>,
+    pub file_size: Option<u64>,
+    pub nar_hash: Hash,
+    pub nar_size: u64,
+    pub references: Vec<StorePath>,
+    pub deriver: Option<StorePath>,
+    pub sigs: Vec<String>,
+    pub ca: Option<String>,
+    /// Fields this model doesn't know about (`System:`, `Builder-Sig:`, ...), kept in
+    /// order so they survive a round trip.
+    pub extra: Vec<(String, String)>,
+}
+
+impl NarInfo {
+    /// The string that `Sig:` signatures are made over.
+    pub fn fingerprint(&self) -> String {
+        let references: Vec<String> = self.references.iter().map(|r| r.to_absolute()).collect();
+        format!(
+            "1;{};{};{};{}",
+            self.store_path.to_absolute(),
+            self.nar_hash,
+            self.nar_size,
+            references.join(",")
+        )
+    }
+
+    /// Values of an unknown field, e.g. `extra_values("System")`.
+    pub fn extra_values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.extra
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The NAR's file name inside `nar/`, if `URL:` points there.
+    pub fn nar_file(&self) -> Option<&str> {
+        self.url
+            .strip_prefix("nar/")
+            .filter(|file| is_valid_nar_file(file))
+    }
+}
+
+fn set_once<T>(slot: &mut Option<T>, key: &str, value: T) -> Result<(), NarInfoError> {
+    if slot.is_some() {
+        return Err(NarInfoError::DuplicateField(key.to_owned()));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+fn parse_size(field: &'static str, value: &str) -> Result<u64, NarInfoError> {
+    value
+        .parse()
+        .map_err(|_| NarInfoError::InvalidValue(field, value.to_owned()))
+}
+
+impl FromStr for NarInfo {
+    type Err = NarInfoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut store_path = None;
+        let mut url = None;
+        let mut compression = None;
+        let mut file_hash = None;
+        let mut file_size = None;
+        let mut nar_hash = None;
+        let mut nar_size = None;
+        let mut references = None;
+        let mut deriver = None;
+        let mut sigs = Vec::new();
+        let mut ca = None;
+        let mut extra = Vec::new();
+
+        for line in s.lines() {
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| NarInfoError::InvalidLine(line.to_owned()))?;
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match key {
+                "StorePath" => set_once(&mut store_path, key, StorePath::from_absolute(value)?)?,
+                "URL" => {
+                    if value.is_empty() || value.contains("..") || value.starts_with('/') {
+                        return Err(NarInfoError::InvalidValue("URL", value.to_owned()));
+                    }
+                    set_once(&mut url, key, value.to_owned())?
+                }
+                "Compression" => set_once(&mut compression, key, value.parse().unwrap())?,
+                "FileHash" => set_once(&mut file_hash, key, value.parse()?)?,
+                "FileSize" => set_once(&mut file_size, key, parse_size("FileSize", value)?)?,
+                "NarHash" => set_once(&mut nar_hash, key, value.parse()?)?,
+                "NarSize" => set_once(&mut nar_size, key, parse_size("NarSize", value)?)?,
+                "References" => {
+                    let refs = value
+                        .split_whitespace()
+                        .map(StorePath::from_base_name)
+                        .collect::<Result<Vec<_>, _>>()?;
+                    set_once(&mut references, key, refs)?
+                }
+                "Deriver" => {
+                    if value != "unknown-deriver" {
+                        set_once(&mut deriver, key, StorePath::from_base_name(value)?)?
+                    }
+                }
+                "Sig" => sigs.push(value.to_owned()),
+                "CA" => set_once(&mut ca, key, value.to_owned())?,
+                _ => extra.push((key.to_owned(), value.to_owned())),
+            }
+        }
+
+        Ok(NarInfo {
+            store_path: store_path.ok_or(NarInfoError::MissingField("StorePath"))?,
+            url: url.ok_or(NarInfoError::MissingField("URL"))?,
+            compression: compression.unwrap_or_default(),
+            file_hash,
+            file_size,
+            nar_hash: nar_hash.ok_or(NarInfoError::MissingField("NarHash"))?,
+            nar_size: nar_size.ok_or(NarInfoError::MissingField("NarSize"))?,
+            references: references.unwrap_or_default(),
+            deriver,
+            sigs,
+            ca,
+            extra,
+        })
+    }
+}
+
+impl fmt::Display for NarInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "StorePath: {}", self.store_path.to_absolute())?;
+        writeln!(f, "URL: {}", self.url)?;
+        writeln!(f, "Compression: {}", self.compression)?;
+        if let Some(file_hash) = &self.file_hash {
+            writeln!(f, "FileHash: {file_hash}")?;
+        }
+        if let Some(file_size) = self.file_size {
+            writeln!(f, "FileSize: {file_size}")?;
+        }
+        writeln!(f, "NarHash: {}", self.nar_hash)?;
+        writeln!(f, "NarSize: {}", self.nar_size)?;
+        let references: Vec<String> = self.references.iter().map(|r| r.base_name()).collect();
+        writeln!(f, "References: {}", references.join(" "))?;
+        if let Some(deriver) = &self.deriver {
+            writeln!(f, "Deriver: {deriver}")?;
+        }
+        for (key, value) in &self.extra {
+            writeln!(f, "{key}: {value}")?;
+        }
+        for sig in &self.sigs {
+            writeln!(f, "Sig: {sig}")?;
+        }
+        if let Some(ca) = &self.ca {
+            writeln!(f, "CA: {ca}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HELLO: &str = "StorePath: /nix/store/7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello-2.12.1
+URL: nar/1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3.nar.xz
+Compression: xz
+FileHash: sha256:1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3
+FileSize: 50088
+NarHash: sha256:0yzhigwjl6bws649vcs2asa4lbs8hg93hyix187gc7s7a74w5h80
+NarSize: 226488
+References: 7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello-2.12.1 yaz7pyf0ah88g2v505l38n0f3wg2vzdj-glibc-2.37-8
+Deriver: 5fg7wvyi0bq3hpsmd5n3d7qgng4xi0ad-hello-2.12.1.drv
+System: x86_64-linux
+Sig: cache.nixos.org-1:Mqfw5SP2hPLKzkhkBwgJDcWBqfhZ1WDKjQydOwdvUf/ZK6SS6WdL9Ob7Pz8DtsxMNW/Bx4a5C5E/9aL7k0rNCw==
+";
+
+    #[test]
+    fn roundtrip() {
+        let info: NarInfo = HELLO.parse().unwrap();
+        assert_eq!(
+            info.store_path.hash_part(),
+            "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1"
+        );
+        assert_eq!(info.store_path.name(), "hello-2.12.1");
+        assert_eq!(info.compression, Compression::Xz);
+        assert_eq!(info.file_size, Some(50088));
+        assert_eq!(info.nar_size, 226488);
+        assert_eq!(info.references.len(), 2);
+        assert_eq!(
+            info.extra_values("System").collect::<Vec<_>>(),
+            ["x86_64-linux"]
+        );
+        assert_eq!(
+            info.nar_file(),
+            Some("1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3.nar.xz")
+        );
+        assert_eq!(info.to_string(), HELLO);
+    }
+
+    #[test]
+    fn fingerprint() {
+        let info: NarInfo = HELLO.parse().unwrap();
+        assert_eq!(
+            info.fingerprint(),
+            "1;/nix/store/7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello-2.12.1;\
+             sha256:0yzhigwjl6bws649vcs2asa4lbs8hg93hyix187gc7s7a74w5h80;226488;\
+             /nix/store/7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello-2.12.1,\
+             /nix/store/yaz7pyf0ah88g2v505l38n0f3wg2vzdj-glibc-2.37-8"
+        );
+    }
+
+    #[test]
+    fn defaults() {
+        let info: NarInfo = "StorePath: /nix/store/7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello
+URL: nar/x.nar.bz2
+NarHash: sha256:0yzhigwjl6bws649vcs2asa4lbs8hg93hyix187gc7s7a74w5h80
+NarSize: 1
+References:
+Deriver: unknown-deriver
+"
+        .parse()
+        .unwrap();
+        assert_eq!(info.compression, Compression::Bzip2);
+        assert!(info.references.is_empty());
+        assert!(info.deriver.is_none());
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let without = |field: &str| -> String {
+            HELLO
+                .lines()
+                .filter(|l| !l.starts_with(field))
+                .map(|l| format!("{l}\n"))
+                .collect()
+        };
+        assert_eq!(
+            without("NarHash").parse::<NarInfo>(),
+            Err(NarInfoError::MissingField("NarHash"))
+        );
+        assert!(HELLO
+            .replace("/nix/store/7h1y", "/nix/store/../y")
+            .parse::<NarInfo>()
+            .is_err());
+        assert!(HELLO
+            .replace("NarSize: 226488", "NarSize: lots")
+            .parse::<NarInfo>()
+            .is_err());
+        assert!(HELLO
+            .replace("URL: nar/", "URL: ../../etc/")
+            .parse::<NarInfo>()
+            .is_err());
+        assert!(format!("{HELLO}NarSize: 1\n").parse::<NarInfo>().is_err());
+        assert!(format!("{HELLO}garbage\n").parse::<NarInfo>().is_err());
+    }
+
+    #[test]
+    fn hash_parts() {
+        assert!(is_valid_hash_part("7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1"));
+        assert!(!is_valid_hash_part("../../../../../../../etc/passwd"));
+        assert!(!is_valid_hash_part("7h1ydl0sxmdc8aihhwbdbx0ybvxqmfde"));
+        assert!(!is_valid_hash_part("7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd"));
+        assert!(is_valid_nar_file("abc.nar.xz"));
+        assert!(!is_valid_nar_file("../abc.nar"));
+        assert!(!is_valid_nar_file(".hidden"));
+    }
+
+    #[test]
+    fn compression_from_file_name() {
+        assert_eq!(Compression::from_file_name("abc.nar.xz"), Compression::Xz);
+        assert_eq!(
+            Compression::from_file_name("abc.nar.zst"),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::from_file_name("abc.nar"), Compression::None);
+    }
+}
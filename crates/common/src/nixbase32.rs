@@ -0,0 +1,92 @@
+//! Nix's base32 encoding, as used in store paths and `sha256:` hashes. It uses its own
+//! alphabet (no `e`, `o`, `u`, `t`) and encodes bytes starting from the end.
+
+const ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Length of the encoding of `len` bytes.
+pub fn encoded_len(len: usize) -> usize {
+    (len * 8).div_ceil(5)
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    let len = encoded_len(bytes.len());
+    let mut out = String::with_capacity(len);
+    for n in (0..len).rev() {
+        let b = n * 5;
+        let i = b / 8;
+        let j = b % 8;
+        let low = bytes[i] >> j;
+        let high = if i + 1 < bytes.len() {
+            bytes[i + 1].checked_shl(8 - j as u32).unwrap_or(0)
+        } else {
+            0
+        };
+        out.push(ALPHABET[((low | high) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+pub fn is_valid_char(c: u8) -> bool {
+    ALPHABET.contains(&c)
+}
+
+/// Decodes `s` into `len` bytes. Fails on characters outside the alphabet, the wrong
+/// length, or bits that don't fit.
+pub fn decode(s: &str, len: usize) -> Option<Vec<u8>> {
+    if s.len() != encoded_len(len) {
+        return None;
+    }
+    let mut out = vec![0u8; len];
+    for (n, c) in s.bytes().rev().enumerate() {
+        let digit = ALPHABET.iter().position(|&a| a == c)? as u16;
+        let b = n * 5;
+        let i = b / 8;
+        let j = b % 8;
+        let shifted = digit << j;
+        out[i] |= shifted as u8;
+        let carry = (shifted >> 8) as u8;
+        if i + 1 < len {
+            out[i + 1] |= carry;
+        } else if carry != 0 {
+            return None;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for len in [0, 1, 5, 20, 32, 64] {
+            let bytes: Vec<u8> = (0..len as u8).map(|b| b.wrapping_mul(37)).collect();
+            let encoded = encode(&bytes);
+            assert_eq!(encoded.len(), encoded_len(len));
+            assert_eq!(decode(&encoded, len).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn known_values() {
+        // sha256 of the empty string, as printed by `nix hash file --base32 /dev/null`.
+        let digest = [
+            0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+            0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+            0x78, 0x52, 0xb8, 0x55,
+        ];
+        assert_eq!(
+            encode(&digest),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c7e", 32).is_none());
+        assert!(decode("0mdqa9w1p6cmli", 32).is_none());
+        // The top bits of the first character don't fit into 32 bytes.
+        assert!(decode("zmdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73", 32).is_none());
+    }
+}
@@ -39,13 +39,38 @@ let
             image: registry.fyfaen.as/nix-serve-service:1.0.1
             ports:
             - containerPort: 3000
+            env:
+            - name: NIX_SERVE_SIGNING_KEY
+              value: /etc/nix-serve/cache.sec
+            - name: NIX_SERVE_TRUSTED_BUILDER_KEYS
+              valueFrom:
+                secretKeyRef:
+                  name: nix-serve-keys
+                  key: trusted-builder-keys
+            - name: NIX_SERVE_COMPRESSION
+              value: zstd
+            - name: NIX_SERVE_TOKENS
+              value: /etc/nix-serve/tokens
             volumeMounts:
             - name: nix-serve-data
               mountPath: /app/nar
+            - name: nix-serve-keys
+              mountPath: /etc/nix-serve
+              readOnly: true
           volumes:
           - name: nix-serve-data
             persistentVolumeClaim:
               claimName: nix-serve-data-pvc
+          # Also holds cache.pub, the public half of cache.sec, which build jobs
+          # trust substitutes from this cache with.
+          - name: nix-serve-keys
+            secret:
+              secretName: nix-serve-keys
+              items:
+              - key: cache.sec
+                path: cache.sec
+              - key: tokens
+                path: tokens
     ---
     apiVersion: v1
     kind: Service
@@ -60,4 +85,212 @@ let
           port: 3000
           targetPort: 3000
   '';
-in { inherit manifests; }
+
+  replicas = 3;
+  # The replicas' stable names, which the paths are placed by.
+  peers = builtins.concatStringsSep " " (builtins.genList
+    (i: "http://nix-serve-${toString i}.nix-serve-peers.nixbuilder.svc.cluster.local:3000")
+    replicas);
+  # Splits the cache between replicas instead, each path kept on two of them. To move
+  # over, delete the nix-serve Deployment and apply this instead of `manifests`;
+  # nix-serve-migrate copies the cache on nix-serve-data-pvc into the replicas. Delete
+  # the claim and the job once that has succeeded.
+  #
+  # Opt in knowing what replicas can't do. They don't collect garbage, neither on
+  # schedule nor to stay under max_size, since a path's references live on other
+  # replicas, so their volumes only grow. Quotas are counted by each replica on its
+  # own, so a token can upload up to its quota on every one of them.
+  # Copies an owner missed and NARs left on replicas that don't own them are cleaned
+  # up by the replicas' hourly repair.
+  clusterManifests = pkgs.writeText "k8s-cluster.yaml" ''
+    # The single replica's cache, until nix-serve-migrate has copied it.
+    apiVersion: v1
+    kind: PersistentVolumeClaim
+    metadata:
+      name: nix-serve-data-pvc
+      namespace: nixbuilder
+    spec:
+      accessModes:
+        - ReadWriteOnce
+      resources:
+        requests:
+          storage: 20Gi
+    ---
+    apiVersion: apps/v1
+    kind: StatefulSet
+    metadata:
+      name: nix-serve
+      namespace: nixbuilder
+    spec:
+      replicas: ${toString replicas}
+      serviceName: nix-serve-peers
+      selector:
+        matchLabels:
+          app: nix-serve
+      template:
+        metadata:
+          labels:
+            app: nix-serve
+        spec:
+          securityContext:
+            runAsUser: 1069
+            runAsGroup: 1069
+            fsGroup: 1069
+          imagePullSecrets:
+            - name: "nix-serve-regcred"
+          containers:
+          - name: nix-serve
+            image: registry.fyfaen.as/nix-serve-service:1.0.1
+            ports:
+            - containerPort: 3000
+            env:
+            - name: POD_NAME
+              valueFrom:
+                fieldRef:
+                  fieldPath: metadata.name
+            - name: NIX_SERVE_CLUSTER_SELF_URL
+              value: http://$(POD_NAME).nix-serve-peers.nixbuilder.svc.cluster.local:3000
+            - name: NIX_SERVE_CLUSTER_PEERS
+              value: ${peers}
+            # An admin token from `tokens`, for replicas to repair each other's copies.
+            - name: NIX_SERVE_CLUSTER_TOKEN
+              valueFrom:
+                secretKeyRef:
+                  name: nix-serve-keys
+                  key: cluster-token
+            - name: NIX_SERVE_SIGNING_KEY
+              value: /etc/nix-serve/cache.sec
+            - name: NIX_SERVE_TRUSTED_BUILDER_KEYS
+              valueFrom:
+                secretKeyRef:
+                  name: nix-serve-keys
+                  key: trusted-builder-keys
+            - name: NIX_SERVE_COMPRESSION
+              value: zstd
+            - name: NIX_SERVE_TOKENS
+              value: /etc/nix-serve/tokens
+            volumeMounts:
+            - name: nix-serve-data
+              mountPath: /app/nar
+            - name: nix-serve-keys
+              mountPath: /etc/nix-serve
+              readOnly: true
+          volumes:
+          # Also holds cache.pub, the public half of cache.sec, which build jobs
+          # trust substitutes from this cache with.
+          - name: nix-serve-keys
+            secret:
+              secretName: nix-serve-keys
+              items:
+              - key: cache.sec
+                path: cache.sec
+              - key: tokens
+                path: tokens
+      volumeClaimTemplates:
+      - metadata:
+          name: nix-serve-data
+        spec:
+          accessModes:
+            - ReadWriteOnce
+          resources:
+            requests:
+              storage: 20Gi
+    ---
+    # Gives the replicas their stable names, resolvable whether they are ready or not.
+    apiVersion: v1
+    kind: Service
+    metadata:
+      name: nix-serve-peers
+      namespace: nixbuilder
+    spec:
+      clusterIP: None
+      publishNotReadyAddresses: true
+      selector:
+        app: nix-serve
+      ports:
+        - protocol: TCP
+          port: 3000
+          targetPort: 3000
+    ---
+    apiVersion: v1
+    kind: Service
+    metadata:
+      name: nix-serve
+      namespace: nixbuilder
+    spec:
+      selector:
+        app: nix-serve
+      ports:
+        - protocol: TCP
+          port: 3000
+          targetPort: 3000
+    ---
+    # Uploads the old cache through the service, so each path lands on its owners.
+    # Its narinfo and realisations are signed with the cache key, which the service
+    # accepts uploads signed by. Uploads are idempotent, so it is safe to run again.
+    # Pins, registered builds and images are not carried over.
+    apiVersion: batch/v1
+    kind: Job
+    metadata:
+      name: nix-serve-migrate
+      namespace: nixbuilder
+    spec:
+      backoffLimit: 10
+      template:
+        spec:
+          restartPolicy: OnFailure
+          securityContext:
+            runAsUser: 1069
+            runAsGroup: 1069
+            fsGroup: 1069
+          imagePullSecrets:
+            - name: "nix-serve-regcred"
+          initContainers:
+          - name: export
+            image: registry.fyfaen.as/nix-serve-service:1.0.1
+            command: ["./nix-serve-service", "export", "--all", "/export/cache"]
+            volumeMounts:
+            - name: old-data
+              mountPath: /app/nar
+            - name: export
+              mountPath: /export
+          containers:
+          - name: upload
+            image: curlimages/curl:8.10.1
+            env:
+            - name: CACHE
+              value: http://nix-serve.nixbuilder.svc.cluster.local:3000
+            # A write token from `tokens`.
+            - name: TOKEN
+              valueFrom:
+                secretKeyRef:
+                  name: nix-serve-keys
+                  key: migrate-token
+            command:
+            - /bin/sh
+            - -euc
+            - |
+              put() { curl -fsS -o /dev/null -H "Authorization: Bearer $TOKEN" "$@"; }
+              cd /export/cache
+              for narinfo in *.narinfo; do
+                [ -e "$narinfo" ] || continue
+                nar=$(sed -n 's/^URL: //p' "$narinfo")
+                put -T "$nar" "$CACHE/$nar"
+                put -T "$narinfo" "$CACHE/$narinfo"
+              done
+              for file in realisations/* log/*; do
+                [ -e "$file" ] || continue
+                put -T "$file" "$CACHE/$file"
+              done
+            volumeMounts:
+            - name: export
+              mountPath: /export
+              readOnly: true
+          volumes:
+          - name: old-data
+            persistentVolumeClaim:
+              claimName: nix-serve-data-pvc
+          - name: export
+            emptyDir: {}
+  '';
+in { inherit manifests clusterManifests; }
@@ -0,0 +1,399 @@
+//! Management endpoints below `/admin`.
+
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::auth::{self, Token};
+use crate::gc::{self, Deletion, GcError, GcReport, Pins, StoredPath};
+use crate::keyring::{KeyList, KeyringError};
+use crate::scrub::{ScrubReport, ScrubStatus};
+use crate::storage::CacheStats;
+use crate::usage::UsageReport;
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct GcQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Runs garbage collection now and returns what was (or, for a dry run, would be) deleted.
+pub async fn run_gc(
+    State(state): State<AppState>,
+    token: Option<Extension<Arc<Token>>>,
+    Query(query): Query<GcQuery>,
+) -> Result<Json<GcReport>, StatusCode> {
+    info!(dry_run = query.dry_run, "Garbage collection requested");
+    info!(target: "audit", token = %auth::uploader(&token), dry_run = query.dry_run, "Ran GC");
+    match state.gc.run(query.dry_run).await {
+        Ok(report) => Ok(Json(report)),
+        Err(GcError::Clustered) => {
+            warn!("Refusing garbage collection in a cluster");
+            Err(StatusCode::CONFLICT)
+        }
+        Err(e) => {
+            error!(error = %e, "Garbage collection failed");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// The last scrub and the paths in quarantine.
+pub async fn get_scrub(State(state): State<AppState>) -> Result<Json<ScrubStatus>, StatusCode> {
+    state.scrub.status().await.map(Json).map_err(|e| {
+        error!(error = %e, "Failed to load scrub state");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Re-hashes every stored NAR now, quarantining damaged paths.
+pub async fn run_scrub(
+    State(state): State<AppState>,
+    token: Option<Extension<Arc<Token>>>,
+) -> Result<Json<ScrubReport>, StatusCode> {
+    info!("Scrub requested");
+    info!(target: "audit", token = %auth::uploader(&token), "Ran scrub");
+    match state.scrub.run().await {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => {
+            error!(error = %e, "Scrub failed");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BuildRoots {
+    pub name: String,
+    /// Store paths or hash parts of the build's outputs.
+    pub paths: Vec<String>,
+}
+
+/// Registers the outputs of a build, keeping their closures alive while the build is
+/// among the last `gc.keep_builds`.
+pub async fn register_build(
+    State(state): State<AppState>,
+    token: Option<Extension<Arc<Token>>>,
+    Json(build): Json<BuildRoots>,
+) -> StatusCode {
+    if let Some(path) = build.paths.iter().find(|p| gc::hash_part(p).is_none()) {
+        warn!(build = %build.name, path = %path, "Rejecting invalid build root");
+        return StatusCode::BAD_REQUEST;
+    }
+    info!(target: "audit", token = %auth::uploader(&token), build = %build.name, "Registered build");
+    match state.gc.register_build(build.name, build.paths).await {
+        Ok(()) => StatusCode::OK,
+        Err(e) => {
+            error!(error = %e, "Failed to register build");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Bytes and paths stored, overall and by uploading token, next to the quotas.
+pub async fn get_usage(State(state): State<AppState>) -> Json<UsageReport> {
+    Json(state.usage.report())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PathsQuery {
+    /// Only paths whose store path contains this.
+    #[serde(default)]
+    pub query: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PathSummary {
+    #[serde(flatten)]
+    pub path: StoredPath,
+    /// The token that uploaded it; unknown for paths from before accounting or upstream.
+    pub uploader: Option<String>,
+}
+
+fn summary(state: &AppState, path: StoredPath) -> PathSummary {
+    PathSummary {
+        uploader: state.usage.upload(&path.hash).map(|upload| upload.token),
+        path,
+    }
+}
+
+fn path_hash(path: &str) -> Result<&str, StatusCode> {
+    gc::hash_part(path).ok_or_else(|| {
+        warn!(path = %path, "Rejecting invalid store path");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+fn gc_error(e: GcError) -> StatusCode {
+    error!(error = %e, "Admin request failed");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Lists the paths in the cache with their size, upload time, uploader and last access.
+pub async fn list_paths(
+    State(state): State<AppState>,
+    Query(query): Query<PathsQuery>,
+) -> Result<Json<Vec<PathSummary>>, StatusCode> {
+    let paths = state.gc.paths().await.map_err(gc_error)?;
+    let paths = paths
+        .into_iter()
+        .filter(|path| match (&query.query, &path.store_path) {
+            (Some(query), Some(store_path)) => store_path.contains(query.as_str()),
+            (Some(_), None) => false,
+            (None, _) => true,
+        })
+        .map(|path| summary(&state, path))
+        .collect();
+    Ok(Json(paths))
+}
+
+#[derive(Debug, Serialize)]
+pub struct PathDetails {
+    #[serde(flatten)]
+    pub path: PathSummary,
+    /// Everything it references, transitively, that is in the cache.
+    pub closure: Vec<PathSummary>,
+    pub closure_size: u64,
+    /// Paths in the cache that reference it.
+    pub referrers: Vec<String>,
+}
+
+/// A path with its closure. Takes a store path, base name or hash part.
+pub async fn get_path(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+) -> Result<Json<PathDetails>, StatusCode> {
+    let hash = path_hash(&path)?;
+    let (mut closure, referrers) = state
+        .gc
+        .closure(hash)
+        .await
+        .map_err(gc_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let closure_size = closure.iter().map(|path| path.size).sum();
+    let path = summary(&state, closure.remove(0));
+    Ok(Json(PathDetails {
+        path,
+        closure: closure.into_iter().map(|p| summary(&state, p)).collect(),
+        closure_size,
+        referrers,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuery {
+    /// Delete even if other paths refer to it.
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Deletes a path. 409 with the referring paths if others still need it.
+pub async fn delete_path(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<(StatusCode, Json<Deletion>), StatusCode> {
+    let hash = path_hash(&path)?;
+    let deletion = state.gc.delete(hash, query.force).await.map_err(gc_error)?;
+    let status = match &deletion {
+        Deletion::Deleted { .. } => {
+            info!(target: "audit", token = %auth::uploader(&token), hash = %hash, "Deleted path");
+            StatusCode::OK
+        }
+        Deletion::Referenced { .. } => StatusCode::CONFLICT,
+        Deletion::NotFound => StatusCode::NOT_FOUND,
+    };
+    Ok((status, Json(deletion)))
+}
+
+pub async fn list_pins(State(state): State<AppState>) -> Result<Json<Pins>, StatusCode> {
+    state.gc.pins().await.map(Json).map_err(gc_error)
+}
+
+/// Pins a path as a GC root. 201 if it wasn't pinned yet.
+pub async fn pin(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+) -> Result<StatusCode, StatusCode> {
+    path_hash(&path)?;
+    let added = state.gc.pin(&path).await.map_err(gc_error)?;
+    info!(target: "audit", token = %auth::uploader(&token), path = %path, "Pinned path");
+    Ok(if added {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    })
+}
+
+/// Unpins a path pinned through the API. 404 if it wasn't.
+pub async fn unpin(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+) -> Result<StatusCode, StatusCode> {
+    path_hash(&path)?;
+    if !state.gc.unpin(&path).await.map_err(gc_error)? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    info!(target: "audit", token = %auth::uploader(&token), path = %path, "Unpinned path");
+    Ok(StatusCode::OK)
+}
+
+pub async fn list_builder_keys(State(state): State<AppState>) -> Json<KeyList> {
+    Json(state.builder_keys.list())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterQuery {
+    /// Trust the key for at most this long instead of `builder_key_ttl_secs`.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+/// Trusts a build job's public key, sent as the body, to sign its uploads until it
+/// expires or is revoked. 201 if it wasn't registered yet.
+pub async fn register_builder_key(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+    Query(query): Query<RegisterQuery>,
+    key: String,
+) -> StatusCode {
+    let key = key.trim();
+    if key.split_once(':').map(|(key_name, _)| key_name) != Some(name.as_str()) {
+        warn!(name = %name, "Rejecting builder key registered under another name");
+        return StatusCode::BAD_REQUEST;
+    }
+    let ttl = query.ttl_secs.map(Duration::from_secs);
+    match state
+        .builder_keys
+        .register(key, ttl, auth::uploader(&token))
+        .await
+    {
+        Ok(added) => {
+            info!(target: "audit", token = %auth::uploader(&token), key = %name, "Registered builder key");
+            if added {
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            }
+        }
+        Err(e @ (KeyringError::Key(_) | KeyringError::Configured(_))) => {
+            warn!(name = %name, error = %e, "Rejecting builder key");
+            StatusCode::BAD_REQUEST
+        }
+        Err(e) => {
+            error!(name = %name, error = %e, "Failed to register builder key");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Stops trusting a registered builder key. 404 if there is none by that name.
+pub async fn revoke_builder_key(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+) -> StatusCode {
+    match state.builder_keys.revoke(&name).await {
+        Ok(true) => {
+            info!(target: "audit", token = %auth::uploader(&token), key = %name, "Revoked builder key");
+            StatusCode::OK
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!(name = %name, error = %e, "Failed to revoke builder key");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub usage: UsageReport,
+    pub narinfo_cache: Option<CacheStats>,
+    pub pins: usize,
+    pub builds: usize,
+    pub upstreams: bool,
+}
+
+/// Totals for the whole cache.
+pub async fn get_stats(State(state): State<AppState>) -> Result<Json<Stats>, StatusCode> {
+    let pins = state.gc.pins().await.map_err(gc_error)?;
+    let builds = state.gc.builds().await.map_err(gc_error)?;
+    Ok(Json(Stats {
+        usage: state.usage.report(),
+        narinfo_cache: state.narinfo_cache.as_ref().map(|cache| cache.stats()),
+        pins: pins.config.len() + pins.api.len(),
+        builds: builds.len(),
+        upstreams: state.proxy.is_some(),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::DiskStorage;
+    use crate::test::{
+        admin_request, request, test_state, upload_hello, with_admin, HELLO, HELLO_NARINFO,
+    };
+    use axum::http::{Method, StatusCode};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn reports_on_uploaded_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let app = crate::router(with_admin(test_state(storage)));
+        upload_hello(&app).await;
+        let (status, _) = request(&app, Method::GET, HELLO_NARINFO, vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) =
+            admin_request(&app, Method::POST, "/admin/gc?dry_run=true", vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["paths_total"], 1);
+        assert_eq!(report["bytes_total"], 100_000);
+        assert_eq!(report["collected"].as_array().unwrap().len(), 0);
+
+        let (status, body) = admin_request(&app, Method::POST, "/admin/scrub", vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["paths_checked"], 1);
+        assert_eq!(report["findings"], serde_json::json!([]));
+        let (status, body) = request(&app, Method::GET, "/metrics", vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(String::from_utf8(body)
+            .unwrap()
+            .contains("nix_serve_scrub_findings{problem=\"corrupt\"} 0"));
+
+        let (status, body) =
+            admin_request(&app, Method::GET, "/admin/paths?query=hello", vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        let paths: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(paths[0]["hash"], HELLO);
+        assert_eq!(paths[0]["size"], 100_000);
+        assert_eq!(paths[0]["uploader"], "admin");
+        assert!(paths[0]["last_access"].is_string());
+        let (status, body) =
+            admin_request(&app, Method::GET, &format!("/admin/paths/{HELLO}"), vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        let details: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(details["closure_size"], 100_000);
+        let (status, _) = admin_request(&app, Method::GET, "/admin/paths/nope", vec![]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = admin_request(&app, Method::GET, "/admin/stats", vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["pins"], 0);
+    }
+}
@@ -0,0 +1,314 @@
+//! Token authentication. Clients send `Authorization: Bearer <token>`, or the token as
+//! the password of HTTP basic auth so a plain netrc file works for Nix.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::engine::general_purpose::STANDARD as b64;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::config::AuthConfig;
+use crate::AppState;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("failed to read tokens from {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("{0}:{1}: expected <name> <scopes> <token>")]
+    Format(String, usize),
+    #[error("{0}:{1}: unknown scope {2:?}, expected read, write or admin")]
+    Scope(String, usize, String),
+    #[error("token {0:?} is defined twice")]
+    Duplicate(String),
+    #[error("unknown or malformed credentials")]
+    Invalid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Read,
+    /// Uploads, and registering builds as GC roots. Includes read.
+    Write,
+    /// The rest of `/admin`. Includes write.
+    Admin,
+}
+
+impl Scope {
+    /// What a request needs: reads only need read, anything that changes the cache
+    /// needs write, and managing it needs admin.
+    fn for_request(method: &Method, path: &str) -> Scope {
+        if path == "/admin/gc/builds" {
+            Scope::Write
+        } else if path.starts_with("/admin/") {
+            Scope::Admin
+        } else if matches!(*method, Method::GET | Method::HEAD) {
+            Scope::Read
+        } else {
+            Scope::Write
+        }
+    }
+}
+
+/// Who a request was authenticated as. Handlers that record uploads take it from the
+/// request extensions; it is absent when authentication is off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub name: String,
+    pub scope: Scope,
+}
+
+/// The configured tokens, keyed by the sha256 of the secret so lookups don't compare
+/// secrets byte by byte. Shared by all caches.
+pub struct Auth {
+    tokens: HashMap<[u8; 32], Arc<Token>>,
+}
+
+/// Who may use one cache, on top of holding a token with the right scope.
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    /// Reads without a token are allowed.
+    pub anonymous_read: bool,
+    /// Only these tokens, and admin tokens, may write. Empty allows every write token.
+    pub writers: Vec<String>,
+}
+
+impl Access {
+    fn may_write(&self, token: &Token) -> bool {
+        token.scope == Scope::Admin || self.writers.is_empty() || self.writers.contains(&token.name)
+    }
+}
+
+fn secret_hash(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+impl Auth {
+    /// Loads the tokens file named in the config, or every file in it if it is a
+    /// directory, which is how a Kubernetes secret is mounted. `None` when no tokens
+    /// are configured.
+    pub fn load(config: &AuthConfig) -> Result<Option<Self>, AuthError> {
+        let Some(path) = &config.tokens else {
+            return Ok(None);
+        };
+        let read = |path: &Path| {
+            std::fs::read_to_string(path)
+                .map_err(|e| AuthError::Read(path.display().to_string(), e))
+        };
+        let path = Path::new(path);
+        let mut files = Vec::new();
+        if path.is_dir() {
+            let entries = std::fs::read_dir(path)
+                .map_err(|e| AuthError::Read(path.display().to_string(), e))?;
+            for entry in entries {
+                let entry = entry.map_err(|e| AuthError::Read(path.display().to_string(), e))?;
+                let name = entry.file_name();
+                // Secret mounts keep their real files in hidden `..data` directories.
+                if name.to_string_lossy().starts_with('.') || !entry.path().is_file() {
+                    continue;
+                }
+                files.push((entry.path().display().to_string(), read(&entry.path())?));
+            }
+        } else {
+            files.push((path.display().to_string(), read(path)?));
+        }
+        files.sort();
+
+        let mut auth = Auth {
+            tokens: HashMap::new(),
+        };
+        for (file, content) in files {
+            auth.add_tokens(&file, &content)?;
+        }
+        Ok(Some(auth))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_tokens(content: &str) -> Self {
+        let mut auth = Auth {
+            tokens: HashMap::new(),
+        };
+        auth.add_tokens("tokens", content).unwrap();
+        auth
+    }
+
+    /// Parses lines of `<name> <scope> <token>`, e.g. `ci write 5f1c...`. Empty lines
+    /// and `#` comments are skipped.
+    fn add_tokens(&mut self, file: &str, content: &str) -> Result<(), AuthError> {
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [name, scope, secret] = fields[..] else {
+                return Err(AuthError::Format(file.to_owned(), number + 1));
+            };
+            let scope = match scope {
+                "read" => Scope::Read,
+                "write" => Scope::Write,
+                "admin" => Scope::Admin,
+                other => {
+                    return Err(AuthError::Scope(
+                        file.to_owned(),
+                        number + 1,
+                        other.to_owned(),
+                    ))
+                }
+            };
+            let token = Arc::new(Token {
+                name: name.to_owned(),
+                scope,
+            });
+            if self.tokens.insert(secret_hash(secret), token).is_some() {
+                return Err(AuthError::Duplicate(name.to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The token a request presents. `Err` if it presents one that isn't known.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Arc<Token>>, AuthError> {
+        let Some(value) = headers.get(header::AUTHORIZATION) else {
+            return Ok(None);
+        };
+        let value = value.to_str().map_err(|_| AuthError::Invalid)?;
+        let secret = if let Some(token) = value.strip_prefix("Bearer ") {
+            token.trim().to_owned()
+        } else if let Some(basic) = value.strip_prefix("Basic ") {
+            let decoded = b64.decode(basic.trim()).map_err(|_| AuthError::Invalid)?;
+            let decoded = String::from_utf8(decoded).map_err(|_| AuthError::Invalid)?;
+            let (_, password) = decoded.split_once(':').ok_or(AuthError::Invalid)?;
+            password.to_owned()
+        } else {
+            return Err(AuthError::Invalid);
+        };
+        self.tokens
+            .get(&secret_hash(&secret))
+            .cloned()
+            .map(Some)
+            .ok_or(AuthError::Invalid)
+    }
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"nix-serve\"")],
+    )
+        .into_response()
+}
+
+/// Rejects requests whose token lacks the scope they need, and hands the token on to
+/// the handlers. Without tokens configured everything but administration is open;
+/// that stays closed, since it includes trusting builder keys.
+pub async fn require_token(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let access = &state.access;
+    let needed = Scope::for_request(request.method(), request.uri().path());
+    let Some(auth) = &state.auth else {
+        if needed == Scope::Admin {
+            warn!(method = %request.method(), path = %request.uri().path(), "Refusing administration without tokens configured");
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        return next.run(request).await;
+    };
+    let token = match auth.authenticate(request.headers()) {
+        Ok(token) => token,
+        Err(e) => {
+            warn!(error = %e, method = %request.method(), path = %request.uri().path(), "Rejecting unknown token");
+            return unauthorized();
+        }
+    };
+    match &token {
+        Some(token) if token.scope < needed => {
+            warn!(
+                token = %token.name,
+                method = %request.method(),
+                path = %request.uri().path(),
+                "Token lacks the scope for this request"
+            );
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        Some(token) if needed >= Scope::Write && !access.may_write(token) => {
+            warn!(token = %token.name, path = %request.uri().path(), "Token may not write to this cache");
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        Some(token) => {
+            request.extensions_mut().insert(token.clone());
+        }
+        None if needed == Scope::Read && access.anonymous_read => {}
+        None => return unauthorized(),
+    }
+    next.run(request).await
+}
+
+/// The name uploads are attributed to in the audit log.
+pub fn uploader(token: &Option<axum::Extension<Arc<Token>>>) -> &str {
+    token
+        .as_ref()
+        .map_or("anonymous", |axum::Extension(token)| token.name.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn auth() -> Auth {
+        Auth::from_tokens("# builders\nci write s3cret\n\nalice read hunter2\n")
+    }
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn bearer_and_basic() {
+        let auth = auth();
+        let ci = auth
+            .authenticate(&headers("Bearer s3cret"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ci.name, "ci");
+        assert_eq!(ci.scope, Scope::Write);
+
+        let basic = format!("Basic {}", b64.encode("anything:hunter2"));
+        let alice = auth.authenticate(&headers(&basic)).unwrap().unwrap();
+        assert_eq!(alice.name, "alice");
+        assert_eq!(alice.scope, Scope::Read);
+
+        assert!(auth.authenticate(&headers("Bearer nope")).is_err());
+        assert!(auth.authenticate(&HeaderMap::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_token_files() {
+        let mut auth = auth();
+        assert!(matches!(
+            auth.add_tokens("more", "bob root pw"),
+            Err(AuthError::Scope(_, 1, _))
+        ));
+        assert!(matches!(
+            auth.add_tokens("more", "bob pw"),
+            Err(AuthError::Format(_, 1))
+        ));
+        assert!(matches!(
+            auth.add_tokens("more", "bob read s3cret"),
+            Err(AuthError::Duplicate(_))
+        ));
+    }
+}
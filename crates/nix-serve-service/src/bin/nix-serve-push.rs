@@ -0,0 +1,199 @@
+//! Uploads build outputs to the cache in the background.
+//!
+//! `nix-serve-push daemon` runs for the whole build job and uploads whatever it is
+//! handed. `nix-serve-push push <paths>...` hands it paths; it is what the post-build
+//! hook runs, and returns as soon as they are queued. `nix-serve-push drain` waits for
+//! every queued upload, stops the daemon and fails if any path could not be uploaded.
+
+use nix_serve_service::{
+    config::PushConfig,
+    push::{NixStore, Pusher, Summary},
+    signing::SecretKey,
+};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+use tracing::{error, info, warn, Level};
+
+const USAGE: &str = "usage: nix-serve-push daemon | push <path>... | drain";
+
+/// How long `push` and `drain` wait for a daemon that is still starting.
+const CONNECT_ATTEMPTS: u32 = 50;
+const CONNECT_DELAY: Duration = Duration::from_millis(200);
+
+async fn daemon() -> ExitCode {
+    let config = PushConfig::from_env().expect("failed to load config");
+    let key = SecretKey::from_file(&config.secret_key).expect("failed to load secret key");
+    let pusher = Pusher::new(&config, key, Arc::new(NixStore)).expect("failed to set up uploads");
+
+    // A socket left behind by an earlier daemon would make the bind fail.
+    let _ = std::fs::remove_file(&config.socket);
+    let listener = UnixListener::bind(&config.socket).expect("failed to bind socket");
+    info!(socket = %config.socket, "Waiting for paths to upload");
+
+    let (drained, mut done) = watch::channel(false);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!(error = %e, "Failed to accept connection");
+                    continue;
+                }
+            },
+            _ = done.changed() => break,
+        };
+        let pusher = pusher.clone();
+        let drained = drained.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, &pusher, &drained).await {
+                warn!(error = %e, "Failed to talk to client");
+            }
+        });
+    }
+    let _ = std::fs::remove_file(&config.socket);
+    ExitCode::SUCCESS
+}
+
+/// Handles one client: `push <paths>` lines are queued, `drain` is answered with the
+/// summary as JSON once everything is uploaded.
+async fn serve(
+    stream: UnixStream,
+    pusher: &Pusher,
+    drained: &watch::Sender<bool>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("push") => {
+                let paths: Vec<String> = words.map(str::to_owned).collect();
+                info!(paths = ?paths, "Queued paths");
+                if !paths.is_empty() {
+                    pusher.push(paths);
+                }
+                writer.write_all(b"queued\n").await?;
+            }
+            Some("drain") => {
+                info!("Waiting for uploads to finish");
+                let summary = pusher.drain().await;
+                info!(
+                    uploaded = summary.uploaded,
+                    present = summary.present,
+                    failed = summary.failed.len(),
+                    "Uploads finished"
+                );
+                let mut json = serde_json::to_vec(&summary).expect("serialising can't fail");
+                json.push(b'\n');
+                writer.write_all(&json).await?;
+                let _ = drained.send(true);
+                return Ok(());
+            }
+            _ => writer.write_all(b"error unknown command\n").await?,
+        }
+    }
+    Ok(())
+}
+
+async fn connect(socket: &str) -> std::io::Result<UnixStream> {
+    let mut attempts = 0;
+    loop {
+        match UnixStream::connect(socket).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempts < CONNECT_ATTEMPTS => {
+                attempts += 1;
+                if attempts == 1 {
+                    warn!(socket = %socket, error = %e, "Daemon not reachable yet, retrying");
+                }
+                tokio::time::sleep(CONNECT_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sends one command to the daemon and gives back its answer.
+async fn send(command: &str) -> std::io::Result<String> {
+    let socket = PushConfig::socket_from_env();
+    let stream = connect(&socket).await?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(format!("{command}\n").as_bytes()).await?;
+    let mut answer = String::new();
+    BufReader::new(reader).read_line(&mut answer).await?;
+    Ok(answer)
+}
+
+async fn push(paths: &[String]) -> ExitCode {
+    if paths.is_empty() {
+        return ExitCode::SUCCESS;
+    }
+    match send(&format!("push {}", paths.join(" "))).await {
+        Ok(answer) if answer.trim() == "queued" => ExitCode::SUCCESS,
+        Ok(answer) => {
+            error!(answer = %answer.trim(), "Daemon refused paths");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            error!(error = %e, "Failed to hand paths to the daemon");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn drain() -> ExitCode {
+    let answer = match send("drain").await {
+        Ok(answer) => answer,
+        Err(e) => {
+            error!(error = %e, "Failed to reach the daemon");
+            return ExitCode::FAILURE;
+        }
+    };
+    let summary: Summary = match serde_json::from_str(&answer) {
+        Ok(summary) => summary,
+        Err(_) => {
+            error!(answer = %answer.trim(), "Daemon gave no summary");
+            return ExitCode::FAILURE;
+        }
+    };
+    info!(
+        uploaded = summary.uploaded,
+        present = summary.present,
+        "Uploads finished"
+    );
+    for (path, reason) in &summary.failed {
+        error!(store_path = %path, error = %reason, "Path was not uploaded");
+    }
+    if summary.failed_batches > 0 {
+        error!(
+            batches = summary.failed_batches,
+            "Some paths could not be queried"
+        );
+    }
+    if summary.is_success() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::INFO)
+        .with_writer(std::io::stderr)
+        .init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("daemon") => daemon().await,
+        Some("push") => push(&args[1..]).await,
+        Some("drain") => drain().await,
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,1514 @@
+//! Several replicas serving one cache. Each store path belongs to `replicas` of them,
+//! picked by consistent hashing of its hash part over the peer list, so a replica
+//! joining or leaving only moves the paths next to it on the ring. Peers should be
+//! named by something that survives restarts, like the DNS names of StatefulSet pods;
+//! a pod IP changes on every restart, and so do the paths it owns. Whichever replica a
+//! request arrives at passes it on to the owners; uploads go to all of them. A read
+//! none of the owners can serve is tried on the other replicas too, where paths stay
+//! until they have been moved to their new owners.
+//!
+//! A path's narinfo, NAR and listing live together on its owners, so compression works
+//! on each replica as on a single one. A path's references and the log of its
+//! derivation usually live on other replicas, though, so replicas refuse to collect
+//! garbage and scrubbing doesn't report missing references. NARs are addressed by
+//! file hash, so narinfo are served pointing at `nar/<hash part>/<file>` for the NAR to
+//! be found again. Clients upload a NAR before its narinfo, to whichever replica they
+//! reach; an owner that gets the narinfo without the NAR fetches it from its peers.
+//! Images are published on every replica, which read the paths in them from their
+//! owners both to publish them and to serve their layers.
+//!
+//! Uploads succeed once one owner has them, so an owner that was away or refused may
+//! miss a copy, and after the peers change paths sit on replicas that no longer own
+//! them. Each replica repairs that by itself: now and then, and whenever the peers
+//! change, it copies what it holds to owners missing it, then drops what it doesn't
+//! own once every owner has a copy. NARs left behind by uploads that reached a
+//! replica other than the owners go too, once no narinfo has used them for a while.
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use bytes::Bytes;
+use futures::{future::join_all, stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Notify;
+use tower::ServiceExt;
+use tracing::{info, warn};
+
+use common::narinfo::{self, NarInfo, StorePath};
+
+use crate::config::ClusterConfig;
+use crate::gc::hash_part;
+use crate::oci::{OciError, PathSource};
+use crate::realisation::Realisation;
+use crate::storage::{ByteStream, NixCacheStorage, StorageError};
+
+/// Marks requests one replica passes to another, which serves them itself.
+const FORWARDED: HeaderName = HeaderName::from_static("x-nix-serve-forwarded");
+
+/// Points each peer gets on the ring; more spread the paths more evenly.
+const VNODES: usize = 64;
+
+/// Largest narinfo, realisation or log passed on to several owners.
+const MAX_REPLICATED_BODY: usize = 64 * 1024 * 1024;
+
+/// Narinfos read at once when repairing.
+const REPAIR_CONCURRENCY: usize = 32;
+
+/// How long a NAR no narinfo here uses is kept, for the narinfo to arrive.
+const STRAY_NAR_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Error)]
+pub enum ClusterError {
+    #[error("failed to resolve peers {0}: {1}")]
+    Resolve(String, std::io::Error),
+    #[error("HTTP client error: {0}")]
+    Client(#[from] reqwest::Error),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+}
+
+/// What a repair pass over one cache did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Repair {
+    /// Paths, logs and realisations copied to owners missing them.
+    pub copied: usize,
+    /// Those dropped here, since every owner has them.
+    pub dropped: usize,
+    /// NARs dropped here, since no narinfo here used them.
+    pub stray_nars: usize,
+}
+
+fn point(s: &str) -> u64 {
+    let digest = Sha256::digest(s.as_bytes());
+    u64::from_be_bytes(
+        digest[..8]
+            .try_into()
+            .expect("sha256 is longer than 8 bytes"),
+    )
+}
+
+/// The peers, each at `VNODES` points of a hash ring.
+#[derive(Debug, Default)]
+pub struct Ring {
+    peers: Vec<String>,
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    pub fn new(mut peers: Vec<String>) -> Self {
+        peers.sort();
+        peers.dedup();
+        let mut points: Vec<(u64, usize)> = peers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, peer)| (0..VNODES).map(move |v| (point(&format!("{peer}#{v}")), i)))
+            .collect();
+        points.sort_unstable();
+        Ring { peers, points }
+    }
+
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    /// The first `n` distinct peers at or after `key` on the ring.
+    pub fn owners(&self, key: &str, n: usize) -> Vec<&str> {
+        let start = self.points.partition_point(|&(p, _)| p < point(key));
+        let mut owners = Vec::new();
+        for &(_, peer) in self.points[start..].iter().chain(&self.points[..start]) {
+            let peer = self.peers[peer].as_str();
+            if !owners.contains(&peer) {
+                owners.push(peer);
+                if owners.len() == n {
+                    break;
+                }
+            }
+        }
+        owners
+    }
+}
+
+/// Where a request is served.
+#[derive(Debug, PartialEq, Eq)]
+enum Target {
+    /// By the owners of a store path: its narinfo, listing and admin view.
+    Path { hash: String, narinfo: bool },
+    /// A NAR by the owners of the path it belongs to. `local` is where the replica
+    /// serving it finds it, `<prefix>/nar/<file>`.
+    Nar { hash: String, local: String },
+    /// By the owners of a build log.
+    Log { drv: String },
+    /// A realisation, stored with the path it realises. Looked up by id alone, so
+    /// reads may have to ask around.
+    Realisation,
+    /// A NAR by file name alone: the upload that precedes its narinfo, or a narinfo
+    /// fetched before the cluster was set up.
+    UnknownNar,
+    /// GC roots and builder keys, which every replica needs to know about.
+    Everywhere,
+    /// The image registry of the cache at `prefix`. Images are published everywhere
+    /// and read from whichever replica gets the request.
+    Image { prefix: String },
+    /// By whichever replica gets it.
+    Local,
+}
+
+/// Works out where `path` is served. The first segment is the cache name if it isn't
+/// one of the top-level routes, which named caches can't be called.
+fn target(method: &Method, path: &str) -> Target {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let (prefix, rest) = match segments.as_slice() {
+        [cache, rest @ ..]
+            if !rest.is_empty() && !crate::config::RESERVED_CACHE_NAMES.contains(cache) =>
+        {
+            (format!("/{cache}"), rest)
+        }
+        rest => (String::new(), rest),
+    };
+    match rest {
+        [file] => match file
+            .strip_suffix(".narinfo")
+            .map(|hash| (hash, true))
+            .or_else(|| file.strip_suffix(".ls").map(|hash| (hash, false)))
+        {
+            Some((hash, narinfo)) if narinfo::is_valid_hash_part(hash) => Target::Path {
+                hash: hash.to_owned(),
+                narinfo,
+            },
+            _ => Target::Local,
+        },
+        ["nar", hash, file] if narinfo::is_valid_hash_part(hash) => Target::Nar {
+            hash: (*hash).to_owned(),
+            local: format!("{prefix}/nar/{file}"),
+        },
+        ["nar", _] => Target::UnknownNar,
+        ["log", drv] => Target::Log {
+            drv: (*drv).to_owned(),
+        },
+        ["realisations", _] => Target::Realisation,
+        ["images", ..] | ["v2", ..] => Target::Image { prefix },
+        ["admin", "gc", "builds"] if method == Method::POST => Target::Everywhere,
+        ["admin", "pins", _] if method != Method::GET => Target::Everywhere,
+        ["admin", "builder-keys", _] => Target::Everywhere,
+        ["admin", "paths", path] => match hash_part(path) {
+            Some(hash) => Target::Path {
+                hash: hash.to_owned(),
+                narinfo: false,
+            },
+            None => Target::Local,
+        },
+        _ => Target::Local,
+    }
+}
+
+/// What is kept of a request to send it on, possibly several times.
+struct Incoming {
+    method: Method,
+    path: String,
+    query: Option<String>,
+    headers: HeaderMap,
+    /// Where the registry reads image paths from, for image requests.
+    paths: Option<Arc<dyn PathSource>>,
+}
+
+impl Incoming {
+    fn uri(&self, path: &str) -> String {
+        match &self.query {
+            Some(query) => format!("{path}?{query}"),
+            None => path.to_owned(),
+        }
+    }
+
+    /// The directory of the cache the request is for, `` or `/<name>`.
+    fn prefix(&self) -> &str {
+        self.path.rsplit_once('/').map_or("", |(prefix, _)| prefix)
+    }
+}
+
+pub struct Cluster {
+    config: ClusterConfig,
+    /// This replica's URL, as it appears in the ring.
+    me: String,
+    ring: RwLock<Arc<Ring>>,
+    /// Wakes up repairs when the peers change.
+    changed: Notify,
+    client: reqwest::Client,
+}
+
+impl Cluster {
+    pub async fn new(config: ClusterConfig) -> Result<Self, ClusterError> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(2))
+            .build()?;
+        let cluster = Cluster {
+            me: config.self_url.trim_end_matches('/').to_owned(),
+            config,
+            ring: Default::default(),
+            changed: Notify::new(),
+            client,
+        };
+        cluster.refresh().await?;
+        Ok(cluster)
+    }
+
+    /// Rebuilds the ring from the configured peers and what DNS says now. This replica
+    /// is always on it, even before DNS lists it.
+    pub async fn refresh(&self) -> Result<(), ClusterError> {
+        let mut peers: Vec<String> = self
+            .config
+            .peers
+            .iter()
+            .map(|peer| peer.trim_end_matches('/').to_owned())
+            .chain([self.me.clone()])
+            .collect();
+        if let Some(name) = &self.config.peers_dns {
+            let addrs = tokio::net::lookup_host(name.as_str())
+                .await
+                .map_err(|e| ClusterError::Resolve(name.clone(), e))?;
+            peers.extend(addrs.map(|addr| format!("http://{addr}")));
+        }
+        let ring = Ring::new(peers);
+        let old = self.ring();
+        if ring.peers() != old.peers() {
+            info!(peers = ?ring.peers(), "Cluster membership changed");
+            *self.ring.write().expect("ring lock poisoned") = Arc::new(ring);
+            if !old.peers().is_empty() {
+                self.changed.notify_one();
+            }
+        }
+        Ok(())
+    }
+
+    /// Refreshes the peer list every `refresh_secs`.
+    pub async fn background(self: Arc<Self>) {
+        if self.config.peers_dns.is_none() {
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.refresh_secs));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.refresh().await {
+                warn!(error = %e, "Failed to refresh cluster peers");
+            }
+        }
+    }
+
+    fn ring(&self) -> Arc<Ring> {
+        self.ring.read().expect("ring lock poisoned").clone()
+    }
+
+    /// The owners of `key`, this replica first if it is one so it is asked first.
+    fn owners(&self, key: &str) -> Vec<String> {
+        let ring = self.ring();
+        let mut owners: Vec<String> = ring
+            .owners(key, self.config.replicas.max(1))
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        if let Some(i) = owners.iter().position(|owner| *owner == self.me) {
+            owners[..=i].rotate_right(1);
+        }
+        owners
+    }
+
+    /// Every peer but this replica.
+    fn others(&self) -> Vec<String> {
+        let ring = self.ring();
+        ring.peers()
+            .iter()
+            .filter(|peer| **peer != self.me)
+            .cloned()
+            .collect()
+    }
+
+    async fn handle(self: &Arc<Self>, app: Router, request: Request) -> Response {
+        let (parts, body) = request.into_parts();
+        let mut incoming = Incoming {
+            method: parts.method,
+            path: parts.uri.path().to_owned(),
+            query: parts.uri.query().map(str::to_owned),
+            headers: parts.headers,
+            paths: None,
+        };
+        let target = target(&incoming.method, &incoming.path);
+        if let Target::Image { prefix } = &target {
+            incoming.paths = Some(Arc::new(ClusterPaths {
+                cluster: self.clone(),
+                app: app.clone(),
+                prefix: prefix.clone(),
+                headers: authorization(&incoming.headers),
+            }));
+        }
+        let read = matches!(incoming.method, Method::GET | Method::HEAD);
+
+        if incoming.headers.contains_key(FORWARDED) {
+            return match target {
+                Target::Path { narinfo: true, .. } if incoming.method == Method::PUT => {
+                    self.put_narinfo(&app, incoming, body).await
+                }
+                Target::Nar { local, .. } => serve(&app, &incoming, &local, body).await,
+                _ => serve(&app, &incoming, &incoming.path, body).await,
+            };
+        }
+
+        match target {
+            Target::Path { hash, narinfo } if read => {
+                let response = self.read(&app, &incoming, &hash, &incoming.path).await;
+                match narinfo && incoming.method == Method::GET {
+                    true => point_at_owners(response, &hash).await,
+                    false => response,
+                }
+            }
+            Target::Nar { hash, local } if read => self.read(&app, &incoming, &hash, &local).await,
+            Target::Log { drv } if read => self.read(&app, &incoming, &drv, &incoming.path).await,
+            Target::Path { hash: key, .. } | Target::Log { drv: key } => {
+                self.write(&app, incoming, body, |_| Some(key)).await
+            }
+            Target::Realisation if read => self.read_anywhere(&app, &incoming).await,
+            Target::Realisation => {
+                self.write(&app, incoming, body, |body| {
+                    let content = std::str::from_utf8(body).ok()?;
+                    let out_path = Realisation::parse(content).ok()?.out_path().ok()?;
+                    Some(out_path.hash_part().to_owned())
+                })
+                .await
+            }
+            Target::UnknownNar if read => self.read_anywhere(&app, &incoming).await,
+            Target::Image { .. } if read => serve(&app, &incoming, &incoming.path, body).await,
+            Target::Everywhere | Target::Image { .. } => self.broadcast(&app, incoming, body).await,
+            Target::Nar { local, .. } => serve(&app, &incoming, &local, body).await,
+            Target::UnknownNar | Target::Local => {
+                serve(&app, &incoming, &incoming.path, body).await
+            }
+        }
+    }
+
+    /// Passes a request on to `peer`, marked so it is served there.
+    async fn forward(
+        &self,
+        peer: &str,
+        incoming: &Incoming,
+        path: &str,
+        body: reqwest::Body,
+    ) -> Result<Response, reqwest::Error> {
+        let mut headers = incoming.headers.clone();
+        for name in [
+            header::HOST,
+            header::CONNECTION,
+            header::TRANSFER_ENCODING,
+            header::CONTENT_LENGTH,
+        ] {
+            headers.remove(name);
+        }
+        headers.insert(FORWARDED, HeaderValue::from_static("1"));
+        let response = self
+            .client
+            .request(
+                incoming.method.clone(),
+                format!("{peer}{}", incoming.uri(path)),
+            )
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+
+        let mut builder = Response::builder().status(response.status());
+        for (name, value) in response.headers() {
+            if name != header::TRANSFER_ENCODING && name != header::CONNECTION {
+                builder = builder.header(name, value);
+            }
+        }
+        Ok(builder
+            .body(Body::from_stream(response.bytes_stream()))
+            .expect("headers were valid on the way in"))
+    }
+
+    /// Serves a read from the first owner of `key` that has what it asks for. Owners that
+    /// are down or don't have it are skipped, which covers paths uploaded while an owner
+    /// was away. If no owner has it, the other replicas are asked as well, for paths
+    /// whose owners changed since they were uploaded.
+    async fn read(&self, app: &Router, incoming: &Incoming, key: &str, path: &str) -> Response {
+        let owners = self.owners(key);
+        let ring = self.ring();
+        let rest = ring.peers().iter().filter(|peer| !owners.contains(peer));
+        let mut missing = None;
+        for peer in owners.iter().chain(rest).cloned() {
+            let response = if peer == self.me {
+                serve(app, incoming, path, Body::empty()).await
+            } else {
+                match self
+                    .forward(&peer, incoming, path, reqwest::Body::default())
+                    .await
+                {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!(peer = %peer, path = %path, error = %e, "Peer unreachable");
+                        continue;
+                    }
+                }
+            };
+            let status = response.status();
+            if status == StatusCode::NOT_FOUND {
+                missing = Some(response);
+            } else if status.is_server_error() {
+                warn!(peer = %peer, path = %path, status = %status, "Peer failed to serve");
+            } else {
+                return response;
+            }
+        }
+        missing.unwrap_or_else(|| StatusCode::BAD_GATEWAY.into_response())
+    }
+
+    /// Serves a read locally, or from any peer that has it.
+    async fn read_anywhere(&self, app: &Router, incoming: &Incoming) -> Response {
+        let response = serve(app, incoming, &incoming.path, Body::empty()).await;
+        if response.status() != StatusCode::NOT_FOUND {
+            return response;
+        }
+        for peer in self.others() {
+            let body = reqwest::Body::default();
+            match self.forward(&peer, incoming, &incoming.path, body).await {
+                Ok(found) if found.status().is_success() => return found,
+                Ok(_) => {}
+                Err(e) => warn!(peer = %peer, error = %e, "Peer unreachable"),
+            }
+        }
+        response
+    }
+
+    /// Sends an upload to every owner of the key `key_of` finds in its body. It succeeds
+    /// if one owner takes it; the others are only logged, reads find the copy that made
+    /// it and repairs copy it to the rest. Uploads without a key are left to this replica to refuse.
+    async fn write(
+        &self,
+        app: &Router,
+        incoming: Incoming,
+        body: Body,
+        key_of: impl FnOnce(&[u8]) -> Option<String>,
+    ) -> Response {
+        let body = match axum::body::to_bytes(body, MAX_REPLICATED_BODY).await {
+            Ok(body) => body,
+            Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        };
+        let Some(key) = key_of(&body) else {
+            return serve(app, &incoming, &incoming.path, body.into()).await;
+        };
+        let owners = self.owners(&key);
+        let results = join_all(owners.iter().map(|owner| {
+            let body = body.clone();
+            let incoming = &incoming;
+            async move {
+                if *owner == self.me {
+                    Ok(self.put_local(app, incoming, body).await)
+                } else {
+                    self.forward(owner, incoming, &incoming.path, body.into())
+                        .await
+                }
+            }
+        }))
+        .await;
+
+        let mut accepted = None;
+        let mut refused = None;
+        for (owner, result) in owners.iter().zip(results) {
+            match result {
+                Ok(response) if response.status().is_success() => {
+                    accepted.get_or_insert(response);
+                }
+                Ok(response) => {
+                    warn!(peer = %owner, path = %incoming.path, status = %response.status(), "Replica refused upload");
+                    refused.get_or_insert(response);
+                }
+                Err(e) => {
+                    warn!(peer = %owner, path = %incoming.path, error = %e, "Peer unreachable")
+                }
+            }
+        }
+        accepted
+            .or(refused)
+            .unwrap_or_else(|| StatusCode::BAD_GATEWAY.into_response())
+    }
+
+    /// Stores an upload on this replica, fetching the NAR of a narinfo first if needed.
+    async fn put_local(&self, app: &Router, incoming: &Incoming, body: Bytes) -> Response {
+        match target(&incoming.method, &incoming.path) {
+            Target::Path { narinfo: true, .. } => self.put_narinfo_bytes(app, incoming, body).await,
+            _ => serve(app, incoming, &incoming.path, body.into()).await,
+        }
+    }
+
+    async fn put_narinfo(&self, app: &Router, incoming: Incoming, body: Body) -> Response {
+        match axum::body::to_bytes(body, MAX_REPLICATED_BODY).await {
+            Ok(body) => self.put_narinfo_bytes(app, &incoming, body).await,
+            Err(_) => StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        }
+    }
+
+    async fn put_narinfo_bytes(&self, app: &Router, incoming: &Incoming, body: Bytes) -> Response {
+        if let Some(info) = std::str::from_utf8(&body)
+            .ok()
+            .and_then(|content| content.parse::<NarInfo>().ok())
+        {
+            if let Some(file) = info.nar_file() {
+                self.pull_nar(app, incoming, file).await;
+            }
+        }
+        serve(app, incoming, &incoming.path, body.into()).await
+    }
+
+    /// Fetches the NAR `file` from whichever peer has it unless this replica has it
+    /// already. It is stored through the usual upload route, so it is hashed and counted
+    /// like any other upload. If no peer has it, the narinfo is refused as usual. The
+    /// peer's copy stays behind until a repair finds no narinfo there uses it.
+    async fn pull_nar(&self, app: &Router, incoming: &Incoming, file: &str) {
+        let path = format!("{}/nar/{file}", incoming.prefix());
+        let head = Incoming {
+            method: Method::HEAD,
+            path: path.clone(),
+            query: None,
+            headers: authorization(&incoming.headers),
+            paths: None,
+        };
+        if serve(app, &head, &path, Body::empty()).await.status() != StatusCode::NOT_FOUND {
+            return;
+        }
+
+        let get = Incoming {
+            method: Method::GET,
+            ..head
+        };
+        for peer in self.others() {
+            let response = match self
+                .forward(&peer, &get, &path, reqwest::Body::default())
+                .await
+            {
+                Ok(response) if response.status().is_success() => response,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!(peer = %peer, error = %e, "Peer unreachable");
+                    continue;
+                }
+            };
+            let mut put = Incoming {
+                method: Method::PUT,
+                path: path.clone(),
+                query: None,
+                headers: get.headers.clone(),
+                paths: None,
+            };
+            if let Some(length) = response.headers().get(header::CONTENT_LENGTH) {
+                put.headers.insert(header::CONTENT_LENGTH, length.clone());
+            }
+            let stored = serve(app, &put, &path, response.into_body()).await;
+            if stored.status().is_success() {
+                info!(file = %file, peer = %peer, "Fetched NAR from peer");
+                return;
+            }
+            warn!(file = %file, peer = %peer, status = %stored.status(), "Failed to store NAR from peer");
+        }
+    }
+
+    /// Applies a change here and, if it worked, on every other replica too.
+    async fn broadcast(&self, app: &Router, incoming: Incoming, body: Body) -> Response {
+        let body = match axum::body::to_bytes(body, MAX_REPLICATED_BODY).await {
+            Ok(body) => body,
+            Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        };
+        let response = serve(app, &incoming, &incoming.path, body.clone().into()).await;
+        if !response.status().is_success() {
+            return response;
+        }
+        for peer in self.others() {
+            match self
+                .forward(&peer, &incoming, &incoming.path, body.clone().into())
+                .await
+            {
+                Ok(sent) if sent.status().is_success() => {}
+                Ok(sent) => {
+                    warn!(peer = %peer, path = %incoming.path, status = %sent.status(), "Peer refused change")
+                }
+                Err(e) => warn!(peer = %peer, error = %e, "Peer unreachable"),
+            }
+        }
+        response
+    }
+}
+
+impl Cluster {
+    /// Repairs `caches`, the storage of each by the prefix it is served at, every
+    /// `repair_secs` and whenever the peers change.
+    pub async fn repairing(self: Arc<Self>, caches: Vec<(String, Arc<dyn NixCacheStorage>)>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.repair_secs));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                () = self.changed.notified() => {}
+            }
+            for (prefix, storage) in &caches {
+                match self.repair(prefix, storage.as_ref()).await {
+                    Ok(repair) => info!(
+                        cache = %prefix,
+                        copied = repair.copied,
+                        dropped = repair.dropped,
+                        stray_nars = repair.stray_nars,
+                        "Repaired cluster copies"
+                    ),
+                    Err(e) => warn!(cache = %prefix, error = %e, "Failed to repair cluster copies"),
+                }
+            }
+        }
+    }
+
+    /// Copies the paths, logs and realisations of the cache served at `prefix` to their
+    /// owners that don't have them, and drops those this replica doesn't own once all
+    /// owners do. Owners that can't be reached keep copies from being dropped.
+    pub async fn repair(
+        &self,
+        prefix: &str,
+        storage: &dyn NixCacheStorage,
+    ) -> Result<Repair, ClusterError> {
+        let mut repair = Repair::default();
+        self.repair_paths(prefix, storage, &mut repair).await?;
+        self.repair_realisations(prefix, storage, &mut repair)
+            .await?;
+        self.repair_logs(prefix, storage, &mut repair).await?;
+        Ok(repair)
+    }
+
+    async fn repair_paths(
+        &self,
+        prefix: &str,
+        storage: &dyn NixCacheStorage,
+        repair: &mut Repair,
+    ) -> Result<(), ClusterError> {
+        let narinfos: Vec<(String, String)> = stream::iter(storage.list_narinfos().await?)
+            .map(|object| async move {
+                let content = storage.get_narinfo(&object.name).await;
+                (object.name, content)
+            })
+            .buffer_unordered(REPAIR_CONCURRENCY)
+            .filter_map(|(hash, content)| async move {
+                match content {
+                    Ok(content) => Some(Ok((hash, content))),
+                    Err(StorageError::NotFound) => None,
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .try_collect()
+            .await?;
+        // How many narinfo here use each NAR, so one is only dropped with the last.
+        let mut users: HashMap<String, usize> = HashMap::new();
+        let narinfos: Vec<(String, String, Option<String>)> = narinfos
+            .into_iter()
+            .map(|(hash, content)| {
+                let file = content
+                    .parse::<NarInfo>()
+                    .ok()
+                    .and_then(|info| info.nar_file().map(str::to_owned));
+                if let Some(file) = &file {
+                    *users.entry(file.clone()).or_default() += 1;
+                }
+                (hash, content, file)
+            })
+            .collect();
+
+        for (hash, content, file) in narinfos {
+            let owners = self.owners(&hash);
+            let path = format!("{prefix}/{hash}.narinfo");
+            let copy = |peer: String| {
+                self.copy_path(peer, prefix, storage, &hash, &content, file.as_deref())
+            };
+            if !self.replicate(&owners, &path, repair, copy).await {
+                continue;
+            }
+            storage.delete_narinfo(&hash).await?;
+            ignore_missing(storage.delete_listing(&hash).await)?;
+            if let Some(file) = &file {
+                let left = users.get_mut(file).expect("counted above");
+                *left -= 1;
+                if *left == 0 {
+                    ignore_missing(storage.delete_nar(file).await)?;
+                }
+            }
+            info!(hash = %hash, "Dropped path this replica doesn't own");
+            repair.dropped += 1;
+        }
+
+        let cutoff = chrono::Utc::now() - STRAY_NAR_AGE;
+        for object in storage.list_nars().await? {
+            if users.contains_key(&object.name) || object.modified > cutoff {
+                continue;
+            }
+            ignore_missing(storage.delete_nar(&object.name).await)?;
+            info!(file = %object.name, "Dropped NAR no narinfo uses");
+            repair.stray_nars += 1;
+        }
+        Ok(())
+    }
+
+    async fn repair_realisations(
+        &self,
+        prefix: &str,
+        storage: &dyn NixCacheStorage,
+        repair: &mut Repair,
+    ) -> Result<(), ClusterError> {
+        for object in storage.list_realisations().await? {
+            let id = object.name;
+            let content = match storage.get_realisation(&id).await {
+                Ok(content) => content,
+                Err(StorageError::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let Some(key) = Realisation::parse(&content)
+                .ok()
+                .and_then(|realisation| realisation.out_path().ok())
+                .map(|out_path| out_path.hash_part().to_owned())
+            else {
+                continue;
+            };
+            let owners = self.owners(&key);
+            let path = format!("{prefix}/realisations/{id}.doi");
+            let copy = |peer: String| {
+                let request = self
+                    .peer_request(Method::PUT, format!("{peer}{path}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(content.clone());
+                async move {
+                    request.send().await?.error_for_status()?;
+                    Ok(())
+                }
+            };
+            if self.replicate(&owners, &path, repair, copy).await {
+                ignore_missing(storage.delete_realisation(&id).await)?;
+                repair.dropped += 1;
+            }
+        }
+        Ok(())
+    }
+
+    async fn repair_logs(
+        &self,
+        prefix: &str,
+        storage: &dyn NixCacheStorage,
+        repair: &mut Repair,
+    ) -> Result<(), ClusterError> {
+        for object in storage.list_logs().await? {
+            let drv = object.name;
+            let owners = self.owners(&drv);
+            let path = format!("{prefix}/log/{drv}");
+            let (drv, path) = (&drv, &path);
+            let copy = |peer: String| async move {
+                let log = storage.get_log(drv).await?;
+                self.peer_request(Method::PUT, format!("{peer}{path}"))
+                    .header(header::CONTENT_ENCODING, "br")
+                    .body(reqwest::Body::wrap_stream(log))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(())
+            };
+            if self.replicate(&owners, path, repair, copy).await {
+                ignore_missing(storage.delete_log(drv).await)?;
+                repair.dropped += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure the owners other than this replica hold `path`, copying it with
+    /// `copy` to those that don't. Whether they all have it now and this replica
+    /// isn't one of them, so its copy can go.
+    async fn replicate<F, Fut>(
+        &self,
+        owners: &[String],
+        path: &str,
+        repair: &mut Repair,
+        copy: F,
+    ) -> bool
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<(), ClusterError>>,
+    {
+        let mut everywhere = true;
+        for owner in owners.iter().filter(|owner| **owner != self.me) {
+            let copied = match self.peer_has(owner, path).await {
+                Ok(true) => Ok(()),
+                Ok(false) => copy(owner.clone()).await.map(|()| {
+                    info!(peer = %owner, path = %path, "Copied to owner missing it");
+                    repair.copied += 1;
+                }),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = copied {
+                warn!(peer = %owner, path = %path, error = %e, "Failed to repair copy on peer");
+                everywhere = false;
+            }
+        }
+        everywhere && !owners.contains(&self.me)
+    }
+
+    /// Copies a path to `peer`: its NAR unless the peer has that already, then its
+    /// narinfo, which the peer accepts since the cache signed it.
+    async fn copy_path(
+        &self,
+        peer: String,
+        prefix: &str,
+        storage: &dyn NixCacheStorage,
+        hash: &str,
+        narinfo: &str,
+        file: Option<&str>,
+    ) -> Result<(), ClusterError> {
+        if let Some(file) = file {
+            let path = format!("{prefix}/nar/{file}");
+            if !self.peer_has(&peer, &path).await? {
+                let nar = storage.get_nar(file, None).await?;
+                self.peer_request(Method::PUT, format!("{peer}{path}"))
+                    .body(reqwest::Body::wrap_stream(nar))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        let path = format!("{prefix}/{hash}.narinfo");
+        self.peer_request(Method::PUT, format!("{peer}{path}"))
+            .body(narinfo.to_owned())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Whether `peer` itself holds `path`.
+    async fn peer_has(&self, peer: &str, path: &str) -> Result<bool, reqwest::Error> {
+        let response = self
+            .peer_request(Method::HEAD, format!("{peer}{path}"))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    /// A request of the repair to `url`, served by the peer itself, with the cluster
+    /// token if there is one.
+    fn peer_request(&self, method: Method, url: String) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, url)
+            .header(FORWARDED, HeaderValue::from_static("1"));
+        match &self.config.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+/// Treats something already gone as deleted.
+fn ignore_missing(result: Result<(), StorageError>) -> Result<(), StorageError> {
+    match result {
+        Err(StorageError::NotFound) => Ok(()),
+        result => result,
+    }
+}
+
+/// Serves a request on this replica, at `path` instead of the path it came in at.
+async fn serve(app: &Router, incoming: &Incoming, path: &str, body: Body) -> Response {
+    let mut request = Request::new(body);
+    *request.method_mut() = incoming.method.clone();
+    *request.headers_mut() = incoming.headers.clone();
+    if let Some(paths) = &incoming.paths {
+        request.extensions_mut().insert(paths.clone());
+    }
+    match incoming.uri(path).parse() {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    }
+    match app.clone().oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+/// Just the credentials of a request, to make requests of its own with.
+fn authorization(headers: &HeaderMap) -> HeaderMap {
+    let mut kept = HeaderMap::new();
+    if let Some(authorization) = headers.get(header::AUTHORIZATION) {
+        kept.insert(header::AUTHORIZATION, authorization.clone());
+    }
+    kept
+}
+
+/// Reads the paths of images from their owners, with the credentials of the request
+/// that needs them.
+struct ClusterPaths {
+    cluster: Arc<Cluster>,
+    app: Router,
+    prefix: String,
+    headers: HeaderMap,
+}
+
+impl ClusterPaths {
+    async fn get(&self, path: &StorePath, local: String) -> Result<Response, OciError> {
+        let incoming = Incoming {
+            method: Method::GET,
+            path: local,
+            query: None,
+            headers: self.headers.clone(),
+            paths: None,
+        };
+        let response = self
+            .cluster
+            .read(&self.app, &incoming, path.hash_part(), &incoming.path)
+            .await;
+        match response.status() {
+            StatusCode::OK => Ok(response),
+            StatusCode::NOT_FOUND => Err(OciError::MissingPath(path.to_absolute())),
+            status => Err(OciError::Fetch(path.to_absolute(), status.to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl PathSource for ClusterPaths {
+    async fn narinfo(&self, path: &StorePath) -> Result<NarInfo, OciError> {
+        let local = format!("{}/{}.narinfo", self.prefix, path.hash_part());
+        let response = self.get(path, local).await?;
+        let content = axum::body::to_bytes(response.into_body(), MAX_REPLICATED_BODY)
+            .await
+            .map_err(|e| OciError::Fetch(path.to_absolute(), e.to_string()))?;
+        String::from_utf8_lossy(&content)
+            .parse()
+            .map_err(|e| OciError::NarInfo(path.to_absolute(), e))
+    }
+
+    async fn nar(&self, path: &StorePath, info: &NarInfo) -> Result<ByteStream, OciError> {
+        let file = info
+            .nar_file()
+            .ok_or_else(|| OciError::MissingPath(path.to_absolute()))?;
+        let response = self
+            .get(path, format!("{}/nar/{file}", self.prefix))
+            .await?;
+        Ok(response
+            .into_body()
+            .into_data_stream()
+            .map_err(std::io::Error::other)
+            .boxed())
+    }
+}
+
+/// Points the URL of a narinfo at `nar/<hash>/<file>`, so whichever replica the client
+/// fetches the NAR from knows whom to ask.
+async fn point_at_owners(response: Response, hash: &str) -> Response {
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let content = match axum::body::to_bytes(body, MAX_REPLICATED_BODY).await {
+        Ok(content) => content,
+        Err(e) => {
+            warn!(hash = %hash, error = %e, "Failed to read narinfo");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    let Ok(content) = std::str::from_utf8(&content) else {
+        return Response::from_parts(parts, content.into());
+    };
+    let content: String = content
+        .split_inclusive('\n')
+        .map(|line| match line.strip_prefix("URL: nar/") {
+            Some(file) if !file.trim_end().contains('/') => format!("URL: nar/{hash}/{file}"),
+            _ => line.to_owned(),
+        })
+        .collect();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, content.into())
+}
+
+/// Puts the cluster in front of `app`.
+pub fn layer(app: Router, cluster: Arc<Cluster>) -> Router {
+    Router::new()
+        .fallback(move |request: Request| async move { cluster.handle(app, request).await })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gc::Gc;
+    use crate::integrity::sha256;
+    use crate::scrub::{ScrubReport, Scrubber};
+    use crate::signing::test_key;
+    use crate::storage::{DiskStorage, NixCacheStorage};
+    use common::nixbase32;
+
+    #[test]
+    fn owners_move_only_with_their_peer() {
+        let peers: Vec<String> = (0..4).map(|i| format!("http://10.0.0.{i}:3000")).collect();
+        let ring = Ring::new(peers.clone());
+        let smaller = Ring::new(peers[1..].to_vec());
+        let mut moved = 0;
+        for i in 0..1000 {
+            let key = format!("key-{i}");
+            let owners = ring.owners(&key, 2);
+            assert_eq!(owners.len(), 2);
+            assert_ne!(owners[0], owners[1]);
+            if !owners.contains(&peers[0].as_str()) {
+                assert_eq!(smaller.owners(&key, 2), owners);
+            } else {
+                moved += 1;
+            }
+        }
+        // Each peer owns about half the keys with two owners out of four.
+        assert!((350..650).contains(&moved), "{moved}");
+        assert_eq!(ring.owners("key", 10).len(), 4);
+    }
+
+    #[test]
+    fn routes_by_path() {
+        const A: &str = "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1";
+        let path = |hash: &str, narinfo| Target::Path {
+            hash: hash.to_owned(),
+            narinfo,
+        };
+        assert_eq!(
+            target(&Method::GET, &format!("/{A}.narinfo")),
+            path(A, true)
+        );
+        assert_eq!(
+            target(&Method::GET, &format!("/team/{A}.ls")),
+            path(A, false)
+        );
+        assert_eq!(
+            target(&Method::GET, &format!("/team/nar/{A}/x.nar.zst")),
+            Target::Nar {
+                hash: A.to_owned(),
+                local: "/team/nar/x.nar.zst".to_owned()
+            }
+        );
+        assert_eq!(target(&Method::PUT, "/nar/x.nar"), Target::UnknownNar);
+        assert_eq!(target(&Method::GET, "/team/nar/x.nar"), Target::UnknownNar);
+        assert_eq!(
+            target(&Method::POST, "/admin/gc/builds"),
+            Target::Everywhere
+        );
+        assert_eq!(target(&Method::GET, "/admin/pins"), Target::Local);
+        assert_eq!(target(&Method::GET, "/nix-cache-info"), Target::Local);
+        assert_eq!(
+            target(&Method::GET, "/team/v2/nar/blobs/x"),
+            Target::Image {
+                prefix: "/team".to_owned()
+            }
+        );
+    }
+
+    /// A store path with a NAR of its own, referring to the base names `references`.
+    fn path(i: u32, references: &str) -> (String, Vec<u8>, String) {
+        let hash = nixbase32::encode(sha256(&i.to_le_bytes()).digest())[..32].to_owned();
+        let nar: Vec<u8> = (0..10_000u32).map(|j| ((i + j) % 251) as u8).collect();
+        let nar_hash = sha256(&nar);
+        let narinfo = format!(
+            "StorePath: /nix/store/{hash}-path-{i}\n\
+             URL: nar/{}.nar\n\
+             Compression: none\n\
+             NarHash: {nar_hash}\n\
+             NarSize: {}\n\
+             References: {references}\n",
+            nar_hash.to_nix32(),
+            nar.len()
+        );
+        let fingerprint = narinfo.parse::<NarInfo>().unwrap().fingerprint();
+        let sig = test_key("builder-1", 2).sign(&fingerprint);
+        (hash, nar, format!("{narinfo}Builder-Sig: {sig}\n"))
+    }
+
+    /// Sends the admin token of [`crate::test::with_admin`], which the replicas use.
+    fn admin_client() -> reqwest::Client {
+        let mut headers = HeaderMap::new();
+        let token = format!("Bearer {}", crate::test::ADMIN_TOKEN);
+        headers.insert(header::AUTHORIZATION, token.parse().unwrap());
+        reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .unwrap()
+    }
+
+    /// Replicas started as `main` would, each with storage of its own, plus the peers
+    /// `down` that never answer.
+    struct Replicas {
+        peers: Vec<String>,
+        storages: Vec<Arc<dyn NixCacheStorage>>,
+        clusters: Vec<Arc<Cluster>>,
+        dirs: Vec<tempfile::TempDir>,
+    }
+
+    async fn start(live: usize, down: &[&str], replicas: usize) -> Replicas {
+        let mut listeners = Vec::new();
+        let mut peers = Vec::new();
+        for _ in 0..live {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(format!("http://{}", listener.local_addr().unwrap()));
+            listeners.push(listener);
+        }
+        peers.extend(down.iter().map(|peer| peer.to_string()));
+
+        let mut dirs = Vec::new();
+        let mut storages: Vec<Arc<dyn NixCacheStorage>> = Vec::new();
+        let mut clusters = Vec::new();
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let dir = tempfile::tempdir().unwrap();
+            let storage: Arc<dyn NixCacheStorage> =
+                Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+            let config = ClusterConfig {
+                self_url: peers[i].clone(),
+                peers: peers.clone(),
+                peers_dns: None,
+                replicas,
+                refresh_secs: 30,
+                repair_secs: 3600,
+                token: Some(crate::test::ADMIN_TOKEN.to_owned()),
+            };
+            let cluster = Arc::new(Cluster::new(config).await.unwrap());
+            let mut state = crate::test::with_admin(crate::test::test_state(storage.clone()));
+            state.gc = Arc::new(Gc::new(storage.clone(), Default::default()).in_cluster());
+            state.scrub = Arc::new(Scrubber::new(storage.clone(), Default::default()).in_cluster());
+            let app = crate::app(state, Vec::new());
+            clusters.push(cluster.clone());
+            tokio::spawn(async move {
+                axum::serve(listener, layer(app, cluster)).await.unwrap();
+            });
+            dirs.push(dir);
+            storages.push(storage);
+        }
+        Replicas {
+            peers,
+            storages,
+            clusters,
+            dirs,
+        }
+    }
+
+    #[tokio::test]
+    async fn shards_and_replicates_paths() {
+        // The last replica is down for the whole test.
+        let Replicas {
+            peers, storages, ..
+        } = &start(3, &["http://127.0.0.1:1"], 2).await;
+
+        let client = admin_client();
+        let paths: Vec<_> = (0..8).map(|i| path(i, "")).collect();
+        for (i, (hash, nar, narinfo)) in paths.iter().enumerate() {
+            // NAR and narinfo arrive at different replicas, neither necessarily an owner.
+            let info: NarInfo = narinfo.parse().unwrap();
+            let response = client
+                .put(format!("{}/{}", peers[i % 3], info.url))
+                .body(nar.clone())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let response = client
+                .put(format!("{}/{hash}.narinfo", peers[(i + 1) % 3]))
+                .body(narinfo.clone())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", hash);
+        }
+
+        let ring = Ring::new(peers.clone());
+        for (hash, nar, _) in &paths {
+            let mut holders = Vec::new();
+            for (i, storage) in storages.iter().enumerate() {
+                if storage.get_narinfo(hash).await.is_ok() {
+                    holders.push(peers[i].as_str());
+                }
+            }
+            let live_owners: Vec<&str> = ring
+                .owners(hash, 2)
+                .into_iter()
+                .filter(|owner| !owner.ends_with(":1"))
+                .collect();
+            holders.sort();
+            let mut expected = live_owners.clone();
+            expected.sort();
+            assert_eq!(holders, expected, "{hash}");
+
+            for peer in &peers[..3] {
+                let narinfo = client
+                    .get(format!("{peer}/{hash}.narinfo"))
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(narinfo.status(), StatusCode::OK);
+                let info: NarInfo = narinfo.text().await.unwrap().parse().unwrap();
+                assert!(info.url.starts_with(&format!("nar/{hash}/")));
+                let fetched = client
+                    .get(format!("{peer}/{}", info.url))
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(fetched.status(), StatusCode::OK);
+                assert_eq!(fetched.bytes().await.unwrap(), nar[..]);
+            }
+        }
+
+        let missing = client
+            .get(format!("{}/{}.narinfo", peers[0], "0".repeat(32)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn finds_paths_left_on_former_owners() {
+        let Replicas {
+            peers, storages, ..
+        } = &start(2, &[], 1).await;
+        let ring = Ring::new(peers.clone());
+        let (hash, nar, narinfo) = path(0, "");
+        let owner = ring.owners(&hash, 1)[0];
+        let former = peers.iter().position(|peer| peer != owner).unwrap();
+        let info: NarInfo = narinfo.parse().unwrap();
+        let body = futures::stream::once(async move { Ok(Bytes::from(nar)) }).boxed();
+        storages[former]
+            .put_nar(info.nar_file().unwrap(), body)
+            .await
+            .unwrap();
+        storages[former]
+            .put_narinfo(&hash, narinfo.clone())
+            .await
+            .unwrap();
+
+        let client = admin_client();
+        for peer in peers {
+            let response = client
+                .get(format!("{peer}/{hash}.narinfo"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let info: NarInfo = response.text().await.unwrap().parse().unwrap();
+            let response = client
+                .get(format!("{peer}/{}", info.url))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn repairs_copies_on_owners() {
+        let Replicas {
+            peers,
+            storages,
+            clusters,
+            ..
+        } = &start(2, &[], 1).await;
+        let ring = Ring::new(peers.clone());
+        let (hash, nar, narinfo) = path(0, "");
+        let drv = format!("{hash}-path-0.drv");
+        let former = peers
+            .iter()
+            .position(|peer| peer != ring.owners(&hash, 1)[0])
+            .unwrap();
+        let owner = 1 - former;
+        let log_former = peers
+            .iter()
+            .position(|peer| peer != ring.owners(&drv, 1)[0])
+            .unwrap();
+
+        // Stored as the cache keeps it, signed by the cache rather than a builder.
+        let narinfo = narinfo.split("Builder-Sig").next().unwrap().to_owned();
+        let info: NarInfo = narinfo.parse().unwrap();
+        let sig = test_key("cache-1", 1).sign(&info.fingerprint());
+        let narinfo = format!("{narinfo}Sig: {sig}\n");
+        let file = info.nar_file().unwrap();
+        let body = futures::stream::once(async move { Ok(Bytes::from(nar)) }).boxed();
+        storages[former].put_nar(file, body).await.unwrap();
+        storages[former]
+            .put_narinfo(&hash, narinfo.clone())
+            .await
+            .unwrap();
+        let log = futures::stream::once(async { Ok(Bytes::from_static(b"log")) }).boxed();
+        storages[log_former].put_log(&drv, log).await.unwrap();
+
+        let mut repaired = Repair::default();
+        for i in [former, log_former] {
+            let repair = clusters[i].repair("", storages[i].as_ref()).await.unwrap();
+            repaired.copied += repair.copied;
+            repaired.dropped += repair.dropped;
+        }
+        assert_eq!(
+            repaired,
+            Repair {
+                copied: 2,
+                dropped: 2,
+                stray_nars: 0
+            }
+        );
+        assert_eq!(storages[owner].get_narinfo(&hash).await.unwrap(), narinfo);
+        assert!(storages[owner].nar_size(file).await.is_ok());
+        assert!(storages[1 - log_former].get_log(&drv).await.is_ok());
+        assert!(storages[former].get_narinfo(&hash).await.is_err());
+        assert!(storages[former].nar_size(file).await.is_err());
+        assert!(storages[log_former].get_log(&drv).await.is_err());
+
+        // Nothing left to do once every copy is where it belongs.
+        for i in 0..2 {
+            let repair = clusters[i].repair("", storages[i].as_ref()).await.unwrap();
+            assert_eq!(repair, Repair::default());
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_nars_no_narinfo_uses() {
+        let Replicas {
+            storages,
+            clusters,
+            dirs,
+            ..
+        } = &start(1, &[], 1).await;
+        for file in ["old.nar", "new.nar"] {
+            let body = futures::stream::once(async { Ok(Bytes::from_static(b"nar")) }).boxed();
+            storages[0].put_nar(file, body).await.unwrap();
+        }
+        let old = std::time::SystemTime::now() - 2 * STRAY_NAR_AGE;
+        std::fs::File::options()
+            .write(true)
+            .open(dirs[0].path().join("nar/old.nar"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let repair = clusters[0].repair("", storages[0].as_ref()).await.unwrap();
+        assert_eq!(repair.stray_nars, 1);
+        assert!(storages[0].nar_size("old.nar").await.is_err());
+        // Its narinfo may still be on the way.
+        assert!(storages[0].nar_size("new.nar").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn leaves_references_on_other_replicas_alone() {
+        let Replicas {
+            peers, storages, ..
+        } = &start(2, &[], 1).await;
+        let ring = Ring::new(peers.clone());
+        let owner = |hash: &str| {
+            let owner = ring.owners(hash, 1)[0];
+            peers.iter().position(|peer| peer == owner).unwrap()
+        };
+
+        // A dependency and a path referring to it, owned by different replicas.
+        let dependency = path(0, "");
+        let base_name = format!("{}-path-0", dependency.0);
+        let referrer = (1..)
+            .map(|i| path(i, &base_name))
+            .find(|(hash, _, _)| owner(hash) != owner(&dependency.0))
+            .unwrap();
+        let client = admin_client();
+        for (hash, nar, narinfo) in [&dependency, &referrer] {
+            let info: NarInfo = narinfo.parse().unwrap();
+            for (url, body) in [
+                (info.url.clone(), nar.clone()),
+                (format!("{hash}.narinfo"), narinfo.clone().into_bytes()),
+            ] {
+                let response = client
+                    .put(format!("{}/{url}", peers[0]))
+                    .body(body)
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+            }
+        }
+
+        // Neither replica sees the whole graph, so neither collects.
+        for peer in peers {
+            let response = client
+                .post(format!("{peer}/admin/gc"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CONFLICT);
+        }
+        let storage = &storages[owner(&dependency.0)];
+        assert!(storage.get_narinfo(&dependency.0).await.is_ok());
+
+        // The referrer's owner doesn't hold the dependency, which isn't a problem.
+        let response = client
+            .post(format!("{}/admin/scrub", peers[owner(&referrer.0)]))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let report: ScrubReport = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(report.paths_checked, 1);
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+    }
+
+    #[tokio::test]
+    async fn publishes_images_from_paths_on_other_replicas() {
+        use crate::oci::test::{add_path, spec, APP, LIB};
+
+        let Replicas {
+            peers, storages, ..
+        } = &start(2, &[], 1).await;
+        let ring = Ring::new(peers.clone());
+        for (path, references) in [(LIB, &[][..]), (APP, &[LIB][..])] {
+            let hash = hash_part(path).unwrap();
+            let owner = ring.owners(hash, 1)[0];
+            let owner = peers.iter().position(|peer| peer == owner).unwrap();
+            add_path(storages[owner].as_ref(), path, references).await;
+        }
+
+        // Each replica publishes it, reading the paths it doesn't own from the other.
+        let client = admin_client();
+        let response = client
+            .put(format!("{}/images/app:1", peers[0]))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&spec(&[APP])).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for peer in peers {
+            let manifest = client
+                .get(format!("{peer}/v2/app/manifests/1"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(manifest.status(), StatusCode::OK);
+            let manifest: serde_json::Value =
+                serde_json::from_slice(&manifest.bytes().await.unwrap()).unwrap();
+            let layers = manifest["layers"].as_array().unwrap();
+            assert_eq!(layers.len(), 2);
+            for layer in layers {
+                let digest = layer["digest"].as_str().unwrap();
+                let blob = client
+                    .get(format!("{peer}/v2/app/blobs/{digest}"))
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(blob.status(), StatusCode::OK);
+                let blob = blob.bytes().await.unwrap();
+                assert_eq!(blob.len() as u64, layer["size"].as_u64().unwrap());
+                assert_eq!(format!("sha256:{:x}", Sha256::digest(&blob)), digest);
+            }
+        }
+    }
+}
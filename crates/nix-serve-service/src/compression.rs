@@ -0,0 +1,425 @@
+//! Server-side NAR compression: uncompressed uploads can be stored compressed, and
+//! clients that only understand xz can be served xz transcoded on the fly.
+
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, BzDecoder, BzEncoder, GzipDecoder, GzipEncoder, XzDecoder,
+    XzEncoder, ZstdDecoder, ZstdEncoder,
+};
+use async_compression::tokio::write;
+use async_compression::Level;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::info;
+use uuid::Uuid;
+
+use common::narinfo::{Compression, NarInfo};
+
+use crate::config::CompressionConfig;
+use crate::integrity;
+use crate::storage::{ByteStream, NixCacheStorage, StorageError};
+
+/// Buffer for streaming transcoded NARs.
+const READ_BUFFER: usize = 256 * 1024;
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("unsupported compression {0}")]
+    Unsupported(String),
+    #[error("narinfo URL {0:?} does not point into nar/")]
+    BadUrl(String),
+    #[error("failed to compress NAR: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+type Reader<'a> = Box<dyn AsyncRead + Unpin + Send + 'a>;
+
+pub fn decoder<'a>(
+    compression: &Compression,
+    compressed: impl AsyncBufRead + Unpin + Send + 'a,
+) -> Result<Reader<'a>, CompressionError> {
+    Ok(match compression {
+        Compression::None => Box::new(compressed),
+        Compression::Xz => Box::new(XzDecoder::new(compressed)),
+        Compression::Bzip2 => Box::new(BzDecoder::new(compressed)),
+        Compression::Zstd => Box::new(ZstdDecoder::new(compressed)),
+        Compression::Brotli => Box::new(BrotliDecoder::new(compressed)),
+        Compression::Gzip => Box::new(GzipDecoder::new(compressed)),
+        Compression::Other(other) => return Err(CompressionError::Unsupported(other.clone())),
+    })
+}
+
+pub fn encoder<'a>(
+    compression: &Compression,
+    level: Level,
+    plain: impl AsyncBufRead + Unpin + Send + 'a,
+) -> Result<Reader<'a>, CompressionError> {
+    Ok(match compression {
+        Compression::None => Box::new(plain),
+        Compression::Xz => Box::new(XzEncoder::with_quality(plain, level)),
+        Compression::Bzip2 => Box::new(BzEncoder::with_quality(plain, level)),
+        Compression::Zstd => Box::new(ZstdEncoder::with_quality(plain, level)),
+        Compression::Brotli => Box::new(BrotliEncoder::with_quality(plain, level)),
+        Compression::Gzip => Box::new(GzipEncoder::with_quality(plain, level)),
+        Compression::Other(other) => return Err(CompressionError::Unsupported(other.clone())),
+    })
+}
+
+/// Collects what an encoder writes, for [`encode_steadily`] to pass on.
+#[derive(Clone, Default)]
+struct Collector(Arc<Mutex<Vec<u8>>>);
+
+impl Collector {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl AsyncWrite for Collector {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Compresses `content` into the same bytes however it arrives. The [`encoder`] readers
+/// flush whenever their input isn't ready, which makes the output depend on timing;
+/// that's fine for streaming to a client, but not for a file named after its hash.
+fn encode_steadily(
+    compression: &Compression,
+    level: Level,
+    content: ByteStream,
+) -> Result<ByteStream, CompressionError> {
+    let out = Collector::default();
+    let encoder: Box<dyn AsyncWrite + Unpin + Send> = match compression {
+        Compression::None => Box::new(out.clone()),
+        Compression::Xz => Box::new(write::XzEncoder::with_quality(out.clone(), level)),
+        Compression::Bzip2 => Box::new(write::BzEncoder::with_quality(out.clone(), level)),
+        Compression::Zstd => Box::new(write::ZstdEncoder::with_quality(out.clone(), level)),
+        Compression::Brotli => Box::new(write::BrotliEncoder::with_quality(out.clone(), level)),
+        Compression::Gzip => Box::new(write::GzipEncoder::with_quality(out.clone(), level)),
+        Compression::Other(other) => return Err(CompressionError::Unsupported(other.clone())),
+    };
+    let compressed = futures::stream::try_unfold(Some((content, encoder)), move |state| {
+        let out = out.clone();
+        async move {
+            let Some((mut content, mut encoder)) = state else {
+                return Ok(None);
+            };
+            loop {
+                let finished = match content.next().await {
+                    Some(chunk) => {
+                        encoder.write_all(&chunk?).await?;
+                        false
+                    }
+                    None => {
+                        encoder.shutdown().await?;
+                        true
+                    }
+                };
+                let bytes = out.take();
+                if finished {
+                    return Ok((!bytes.is_empty()).then(|| (Bytes::from(bytes), None)));
+                }
+                if !bytes.is_empty() {
+                    return Ok(Some((Bytes::from(bytes), Some((content, encoder)))));
+                }
+            }
+        }
+    });
+    Ok(compressed.boxed())
+}
+
+/// The parsed `compression` settings.
+#[derive(Debug, Clone)]
+pub struct CompressionPolicy {
+    /// What uncompressed uploads are stored as.
+    pub store: Compression,
+    level: Level,
+    xz_user_agents: Vec<String>,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        CompressionPolicy {
+            store: Compression::None,
+            level: Level::Default,
+            xz_user_agents: Vec::new(),
+        }
+    }
+}
+
+impl CompressionPolicy {
+    pub fn from_config(config: &CompressionConfig) -> Result<Self, CompressionError> {
+        let store: Compression = config.store.parse().unwrap_or_else(|e| match e {});
+        if let Compression::Other(other) = &store {
+            return Err(CompressionError::Unsupported(other.clone()));
+        }
+        Ok(CompressionPolicy {
+            store,
+            level: config.level.map_or(Level::Default, Level::Precise),
+            xz_user_agents: config.xz_user_agents.clone(),
+        })
+    }
+
+    /// Whether xz transcoding is on at all.
+    pub fn transcodes(&self) -> bool {
+        !self.xz_user_agents.is_empty()
+    }
+
+    /// Whether a client with this `User-Agent:` should be served xz.
+    pub fn wants_xz(&self, user_agent: Option<&str>) -> bool {
+        user_agent.is_some_and(|agent| {
+            self.xz_user_agents
+                .iter()
+                .any(|pattern| agent.contains(pattern.as_str()))
+        })
+    }
+}
+
+fn reader(content: ByteStream) -> BufReader<StreamReader<ByteStream, bytes::Bytes>> {
+    BufReader::with_capacity(READ_BUFFER, StreamReader::new(content))
+}
+
+/// Stores the uncompressed NAR of `info` again, compressed as `policy.store` says,
+/// and returns the narinfo pointing at the compressed copy. Signatures stay valid since
+/// they don't cover the file. The uncompressed NAR is left for GC to collect as an
+/// orphan, so clients that already fetched the old narinfo can still download it.
+pub async fn compress_stored(
+    storage: &dyn NixCacheStorage,
+    policy: &CompressionPolicy,
+    info: &NarInfo,
+) -> Result<NarInfo, CompressionError> {
+    let file = info
+        .nar_file()
+        .ok_or_else(|| CompressionError::BadUrl(info.url.clone()))?;
+    if info.compression != Compression::None || policy.store == Compression::None {
+        return Ok(info.clone());
+    }
+
+    // The compressed file is named after its hash, which is only known once it has been
+    // compressed: compress into a temporary NAR while hashing, then rename that. One
+    // left behind by a crash is an orphan to GC.
+    let compressed = encode_steadily(
+        &policy.store,
+        policy.level,
+        storage.get_nar(file, None).await?,
+    )?;
+    let (stream, digest) = integrity::digest_stream(compressed);
+    let temp_file = format!(
+        "{}.compressing.nar{}",
+        Uuid::new_v4(),
+        policy.store.extension()
+    );
+    storage.put_nar(&temp_file, stream).await?;
+    let digest = digest.finish();
+    let compressed_file = format!("{}.nar{}", digest.hash.to_nix32(), policy.store.extension());
+
+    let stored = match storage.nar_size(&compressed_file).await {
+        Ok(size) if size == digest.size => storage.delete_nar(&temp_file).await,
+        Ok(_) | Err(StorageError::NotFound) => {
+            storage.rename_nar(&temp_file, &compressed_file).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = stored {
+        let _ = storage.delete_nar(&temp_file).await;
+        return Err(e.into());
+    }
+    info!(
+        file = %file,
+        compressed = %compressed_file,
+        nar_size = info.nar_size,
+        file_size = digest.size,
+        "Stored NAR compressed"
+    );
+
+    let mut compressed_info = info.clone();
+    compressed_info.url = format!("nar/{compressed_file}");
+    compressed_info.compression = policy.store.clone();
+    compressed_info.file_hash = Some(digest.hash);
+    compressed_info.file_size = Some(digest.size);
+    Ok(compressed_info)
+}
+
+/// Rewrites a narinfo to point at an xz transcoding of its NAR, `nar/<file>.xz`. The
+/// file hash and size of that aren't known up front, so they are left out.
+pub fn xz_narinfo(content: &str) -> Option<String> {
+    let mut info: NarInfo = content.parse().ok()?;
+    if info.compression == Compression::Xz {
+        return None;
+    }
+    info.url.push_str(".xz");
+    info.compression = Compression::Xz;
+    info.file_hash = None;
+    info.file_size = None;
+    Some(info.to_string())
+}
+
+/// Streams the stored NAR `source` recompressed as xz.
+pub async fn transcode_to_xz(
+    storage: &dyn NixCacheStorage,
+    source: &str,
+) -> Result<ByteStream, CompressionError> {
+    let content = storage.get_nar(source, None).await?;
+    let plain = decoder(&Compression::from_file_name(source), reader(content))?;
+    let xz = XzEncoder::new(BufReader::with_capacity(READ_BUFFER, plain));
+    Ok(ReaderStream::with_capacity(xz, READ_BUFFER).boxed())
+}
+
+/// How build logs are stored. Brotli because browsers can take it as is.
+pub const LOG_COMPRESSION: Compression = Compression::Brotli;
+
+/// The compression a `Content-Encoding:` names; `None` for one we don't know.
+pub fn from_content_encoding(encoding: &str) -> Option<Compression> {
+    match encoding.trim() {
+        "" | "identity" => Some(Compression::None),
+        "br" => Some(Compression::Brotli),
+        "gzip" | "x-gzip" => Some(Compression::Gzip),
+        "zstd" => Some(Compression::Zstd),
+        "xz" => Some(Compression::Xz),
+        "bzip2" => Some(Compression::Bzip2),
+        _ => None,
+    }
+}
+
+/// Recompresses a log uploaded with `encoding` the way logs are stored.
+pub fn compress_log(
+    content: ByteStream,
+    encoding: &Compression,
+) -> Result<ByteStream, CompressionError> {
+    if *encoding == LOG_COMPRESSION {
+        return Ok(content);
+    }
+    let plain = decoder(encoding, reader(content))?;
+    let compressed = encoder(
+        &LOG_COMPRESSION,
+        Level::Default,
+        BufReader::with_capacity(READ_BUFFER, plain),
+    )?;
+    Ok(ReaderStream::with_capacity(compressed, READ_BUFFER).boxed())
+}
+
+/// Decompresses a stored log for clients that can't take it compressed.
+pub fn decompress_log(content: ByteStream) -> Result<ByteStream, CompressionError> {
+    let plain = decoder(&LOG_COMPRESSION, reader(content))?;
+    Ok(ReaderStream::with_capacity(plain, READ_BUFFER).boxed())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::integrity::sha256;
+    use crate::storage::DiskStorage;
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn compresses_and_transcodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let nar: Vec<u8> = (0..100_000u32).map(|i| (i % 17) as u8).collect();
+        let file = format!("{}.nar", sha256(&nar).to_nix32());
+        let body = Bytes::from(nar.clone());
+        storage
+            .put_nar(&file, futures::stream::once(async { Ok(body) }).boxed())
+            .await
+            .unwrap();
+        let info: NarInfo = format!(
+            "StorePath: /nix/store/7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello\n\
+             URL: nar/{file}\n\
+             Compression: none\n\
+             NarHash: {}\n\
+             NarSize: {}\n\
+             References: \n",
+            sha256(&nar),
+            nar.len()
+        )
+        .parse()
+        .unwrap();
+
+        let policy = CompressionPolicy::from_config(&CompressionConfig {
+            store: "zstd".to_owned(),
+            level: None,
+            xz_user_agents: vec!["Nix/2.3".to_owned()],
+        })
+        .unwrap();
+        let compressed = compress_stored(&storage, &policy, &info).await.unwrap();
+        assert_eq!(compressed.compression, Compression::Zstd);
+        assert!(compressed.url.ends_with(".nar.zst"));
+        assert!(compressed.file_size.unwrap() < nar.len() as u64);
+        assert_eq!(compressed.fingerprint(), info.fingerprint());
+        integrity::verify_narinfo(&storage, &compressed)
+            .await
+            .unwrap();
+        // Compressing again finds the compressed NAR there and leaves no temporary one.
+        assert_eq!(
+            compress_stored(&storage, &policy, &info).await.unwrap(),
+            compressed
+        );
+        let mut nars: Vec<String> = storage
+            .list_nars()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|nar| nar.name)
+            .collect();
+        nars.sort();
+        let mut expected = vec![file.clone(), compressed.nar_file().unwrap().to_owned()];
+        expected.sort();
+        assert_eq!(nars, expected);
+
+        assert!(policy.wants_xz(Some("curl/8.7.1 Nix/2.3.16")));
+        assert!(!policy.wants_xz(Some("curl/8.7.1 Nix/2.24.9")));
+        let xz_info: NarInfo = xz_narinfo(&compressed.to_string())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(xz_info.url, format!("{}.xz", compressed.url));
+        assert!(xz_info.file_hash.is_none());
+
+        let xz: Vec<Bytes> = transcode_to_xz(&storage, compressed.nar_file().unwrap())
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let mut plain = Vec::new();
+        XzDecoder::new(&xz.concat()[..])
+            .read_to_end(&mut plain)
+            .await
+            .unwrap();
+        assert_eq!(plain, nar);
+    }
+
+    #[test]
+    fn rejects_unknown_compression() {
+        let config = CompressionConfig {
+            store: "lz4".to_owned(),
+            level: None,
+            xz_user_agents: Vec::new(),
+        };
+        assert!(CompressionPolicy::from_config(&config).is_err());
+    }
+}
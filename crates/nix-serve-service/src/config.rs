@@ -0,0 +1,912 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("failed to parse config file {0}: {1}")]
+    Parse(String, serde_yaml::Error),
+    #[error("unknown storage backend {0:?}, expected \"disk\" or \"s3\"")]
+    UnknownBackend(String),
+    #[error("missing required setting {0}")]
+    Missing(&'static str),
+    #[error("invalid value {1:?} for {0}")]
+    Invalid(&'static str, String),
+}
+
+/// Service configuration. Read from the YAML file named by `NIX_SERVE_CONFIG` if it
+/// is set, otherwise assembled from `NIX_SERVE_*` environment variables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: String,
+    /// Path to the cache's secret key file (`name:base64`), as made by `nix key generate-secret`.
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    /// Public keys (`name:base64`) of the builders allowed to upload narinfo.
+    #[serde(default)]
+    pub trusted_builder_keys: Vec<String>,
+    /// How long a builder key registered through `/admin/builder-keys` is trusted,
+    /// unless the registration asks for less.
+    #[serde(default = "default_builder_key_ttl_secs")]
+    pub builder_key_ttl_secs: u64,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub scrub: ScrubConfig,
+    #[serde(default)]
+    pub narinfo_cache: NarInfoCacheConfig,
+    /// Caches to fetch from on a miss, in order. Empty means no pull-through.
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamConfig>,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Advertised in `nix-cache-info`; substituters with lower values are tried first.
+    #[serde(default = "default_priority")]
+    pub priority: u32,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    /// Backpressure on NAR and log uploads, across all caches.
+    #[serde(default)]
+    pub uploads: UploadLimitsConfig,
+    /// Store NARs deduplicated in content-defined chunks. Off when unset.
+    #[serde(default)]
+    pub dedup: Option<DedupConfig>,
+    /// NATS server to publish `cache.path.added` and `cache.path.deleted` to. No
+    /// events are sent when unset.
+    #[serde(default)]
+    pub nats_url: Option<String>,
+    /// Index separate debug info in uploaded NARs by build id under `debuginfo/`, like
+    /// Nix's `index-debug-info`.
+    #[serde(default)]
+    pub index_debug_info: bool,
+    /// Further caches, each served below `/<name>/` next to the default one at `/`.
+    #[serde(default)]
+    pub caches: Vec<NamedCacheConfig>,
+    /// Share the caches with other replicas. Off when unset.
+    #[serde(default)]
+    pub cluster: Option<ClusterConfig>,
+}
+
+/// A cache of its own below `/<name>/`. Everything not set here (builder keys, GC,
+/// compression, upstreams, ...) is shared with the default cache.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NamedCacheConfig {
+    pub name: String,
+    /// Defaults to `<name>` below the default cache's directory or bucket prefix.
+    #[serde(default)]
+    pub storage: Option<StorageConfig>,
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    #[serde(default = "default_priority")]
+    pub priority: u32,
+    /// Public caches can be read without a token; private ones need a read token.
+    #[serde(default)]
+    pub public: bool,
+    /// Names of the tokens that may write to this cache. Empty allows every write token;
+    /// admin tokens may always write.
+    #[serde(default)]
+    pub write_tokens: Vec<String>,
+    /// Not inherited; a named cache without one is unlimited.
+    #[serde(default)]
+    pub quota: QuotaConfig,
+}
+
+/// Limits on what a cache may hold. Uploads over a limit are refused with 507, or 413
+/// if a single NAR is larger than `max_upload_bytes`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QuotaConfig {
+    /// Bytes of stored NARs.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_paths: Option<u64>,
+    #[serde(default)]
+    pub max_upload_bytes: Option<u64>,
+    /// Bytes of store paths each token may have in the cache, by token name.
+    #[serde(default)]
+    pub tokens: BTreeMap<String, u64>,
+}
+
+/// Limits on uploads in flight. Uploads over a concurrency limit are refused with 429
+/// and a `Retry-After:`; the bandwidth caps only slow uploads down. A client is the
+/// token it uploads with, or its address without one. Unset limits don't apply.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadLimitsConfig {
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    #[serde(default)]
+    pub max_concurrent_per_client: Option<usize>,
+    /// Bytes per second of all uploads together.
+    #[serde(default)]
+    pub max_bytes_per_sec: Option<u64>,
+    #[serde(default)]
+    pub max_bytes_per_sec_per_client: Option<u64>,
+    /// Sent in `Retry-After:` with a 429.
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+impl Default for UploadLimitsConfig {
+    fn default() -> Self {
+        UploadLimitsConfig {
+            max_concurrent: None,
+            max_concurrent_per_client: None,
+            max_bytes_per_sec: None,
+            max_bytes_per_sec_per_client: None,
+            retry_after_secs: default_retry_after_secs(),
+        }
+    }
+}
+
+/// Chunk sizes for deduplicated storage. Chunks are cut where the content says, so
+/// NARs that differ in a few files share the chunks of everything else. That only works
+/// on uncompressed NARs: upload them uncompressed and keep `compression.store` at
+/// `none`; each chunk is stored zstd-compressed on its own.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DedupConfig {
+    #[serde(default = "default_min_chunk_size")]
+    pub min_chunk_size: u32,
+    #[serde(default = "default_avg_chunk_size")]
+    pub avg_chunk_size: u32,
+    #[serde(default = "default_max_chunk_size")]
+    pub max_chunk_size: u32,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            min_chunk_size: default_min_chunk_size(),
+            avg_chunk_size: default_avg_chunk_size(),
+            max_chunk_size: default_max_chunk_size(),
+        }
+    }
+}
+
+/// Names that are routes of the default cache or directories in its storage.
+pub(crate) const RESERVED_CACHE_NAMES: &[&str] = &[
+    "nar",
+    "log",
+    "realisations",
+    "debuginfo",
+    "chunks",
+    "meta",
+    "admin",
+    "metrics",
+    "nix-cache-info",
+    "images",
+    "v2",
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Disk {
+        #[serde(default = "default_cache_dir")]
+        cache_dir: String,
+    },
+    S3(S3Config),
+}
+
+impl StorageConfig {
+    /// Storage for the named cache `name`, nested in this one.
+    pub fn for_cache(&self, name: &str) -> StorageConfig {
+        match self {
+            StorageConfig::Disk { cache_dir } => StorageConfig::Disk {
+                cache_dir: format!("{}/{name}", cache_dir.trim_end_matches('/')),
+            },
+            StorageConfig::S3(s3) => StorageConfig::S3(S3Config {
+                prefix: match s3.prefix.trim_matches('/') {
+                    "" => name.to_owned(),
+                    prefix => format!("{prefix}/{name}"),
+                },
+                hot_tier: s3.hot_tier.as_ref().map(|hot| HotTierConfig {
+                    cache_dir: format!("{}/{name}", hot.cache_dir.trim_end_matches('/')),
+                    max_bytes: hot.max_bytes,
+                }),
+                ..s3.clone()
+            }),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Disk {
+            cache_dir: default_cache_dir(),
+        }
+    }
+}
+
+/// Any S3-compatible bucket, e.g. Hetzner object storage.
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Config {
+    pub bucket: String,
+    pub endpoint: String,
+    pub region: String,
+    /// Key prefix inside the bucket, so several caches can share one bucket.
+    #[serde(default)]
+    pub prefix: String,
+    /// Falls back to `AWS_ACCESS_KEY_ID` so the secret can come from a k8s secret.
+    #[serde(default)]
+    pub access_key: Option<String>,
+    /// Falls back to `AWS_SECRET_ACCESS_KEY`.
+    #[serde(default)]
+    pub secret_key: Option<String>,
+    /// Most non-AWS providers want path style requests.
+    #[serde(default = "default_true")]
+    pub path_style: bool,
+    /// Keep recently used NARs on local disk as well.
+    #[serde(default)]
+    pub hot_tier: Option<HotTierConfig>,
+}
+
+/// A local disk in front of the bucket, holding the most recently used NARs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HotTierConfig {
+    pub cache_dir: String,
+    /// Least recently used NARs are dropped from disk beyond this.
+    pub max_bytes: u64,
+}
+
+/// A binary cache to pull through from, e.g. `https://cache.nixos.org`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamConfig {
+    pub url: String,
+    /// Only narinfo signed by one of these keys are taken from the upstream.
+    #[serde(default)]
+    pub public_keys: Vec<String>,
+}
+
+/// Who may read and write. Without a tokens file the cache is open to everyone.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthConfig {
+    /// File of `<name> <read|write|admin> <token>` lines, or a directory of such files such
+    /// as a mounted Kubernetes secret.
+    #[serde(default)]
+    pub tokens: Option<String>,
+    /// Let requests without a token read the default cache; writes still need one.
+    /// Named caches set this with `public`.
+    #[serde(default)]
+    pub anonymous_read: bool,
+}
+
+/// How NARs are compressed at rest and for old clients.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionConfig {
+    /// Uncompressed uploads are stored compressed with this (`none`, `zstd`, `xz`, ...).
+    /// Already compressed uploads are kept as they are.
+    #[serde(default = "default_compression")]
+    pub store: String,
+    /// Compression level; the algorithm's default if unset.
+    #[serde(default)]
+    pub level: Option<i32>,
+    /// Clients whose `User-Agent:` contains one of these are served narinfo pointing at
+    /// an xz transcoding of the NAR, e.g. `Nix/2.3` for Nix versions without zstd.
+    #[serde(default)]
+    pub xz_user_agents: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            store: default_compression(),
+            level: None,
+            xz_user_agents: Vec::new(),
+        }
+    }
+}
+
+/// The in-memory narinfo cache. A capacity of 0 turns it off.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NarInfoCacheConfig {
+    #[serde(default = "default_narinfo_cache_capacity")]
+    pub capacity: usize,
+    /// How long a lookup for a missing narinfo is remembered. On disk, changes are seen
+    /// right away; this bounds how stale a miss can be for writes from elsewhere.
+    #[serde(default = "default_negative_ttl_secs")]
+    pub negative_ttl_secs: u64,
+}
+
+impl Default for NarInfoCacheConfig {
+    fn default() -> Self {
+        NarInfoCacheConfig {
+            capacity: default_narinfo_cache_capacity(),
+            negative_ttl_secs: default_negative_ttl_secs(),
+        }
+    }
+}
+
+/// What garbage collection keeps. Everything reachable through narinfo `References:`
+/// from a root survives; the roots are the pinned paths, the outputs of the last
+/// `keep_builds` registered builds, recently fetched paths and fresh uploads.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GcConfig {
+    /// Store paths (or their hash parts) that are never collected.
+    #[serde(default)]
+    pub pinned: Vec<String>,
+    #[serde(default = "default_keep_builds")]
+    pub keep_builds: usize,
+    /// Paths whose narinfo was fetched within this many seconds are roots.
+    #[serde(default = "default_keep_accessed_secs")]
+    pub keep_accessed_secs: u64,
+    /// Nothing uploaded within this many seconds is collected, so a NAR whose narinfo
+    /// hasn't arrived yet survives.
+    #[serde(default = "default_min_age_secs")]
+    pub min_age_secs: u64,
+    /// When set, unreachable paths are only collected, least recently used first,
+    /// until the cache fits in this many bytes.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Run GC on its own every this many seconds. Otherwise only the admin endpoint runs it.
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    /// Build logs are kept while a kept path was built by their derivation, or for
+    /// this many seconds after upload.
+    #[serde(default = "default_keep_logs_secs")]
+    pub keep_logs_secs: u64,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            pinned: Vec::new(),
+            keep_builds: default_keep_builds(),
+            keep_accessed_secs: default_keep_accessed_secs(),
+            min_age_secs: default_min_age_secs(),
+            max_size: None,
+            interval_secs: None,
+            keep_logs_secs: default_keep_logs_secs(),
+        }
+    }
+}
+
+/// Background re-hashing of stored NARs against their narinfo.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScrubConfig {
+    /// Scrub every this many seconds. `None` leaves it to the admin endpoint.
+    #[serde(default = "default_scrub_interval_secs")]
+    pub interval_secs: Option<u64>,
+    /// Take paths whose NAR is missing or damaged out of the cache. Otherwise they are
+    /// only reported.
+    #[serde(default = "default_true")]
+    pub quarantine: bool,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        ScrubConfig {
+            interval_secs: default_scrub_interval_secs(),
+            quarantine: true,
+        }
+    }
+}
+
+/// Replicas that split the store paths between them, each keeping its own storage.
+/// The peers are `peers`, the addresses `peers_dns` resolves to (e.g. a headless
+/// service) and this replica. Replicas don't collect garbage, but they do move paths
+/// to their owners and drop what they hold without owning it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterConfig {
+    /// How the other replicas reach this one, e.g.
+    /// `http://nix-serve-0.nix-serve-peers:3000`. Must match how it appears in `peers`
+    /// or DNS.
+    pub self_url: String,
+    /// Base URLs of the replicas. Paths are placed by these, so they should stay the
+    /// same across restarts, like the DNS names of StatefulSet pods.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// `<host>:<port>` resolving to the replicas, reached at `http://<address>`. Pod
+    /// addresses change when pods restart, moving the paths they own, so this suits
+    /// replicas that come and go more than ones with storage of their own.
+    #[serde(default)]
+    pub peers_dns: Option<String>,
+    /// Replicas holding each store path.
+    #[serde(default = "default_replicas")]
+    pub replicas: usize,
+    /// How often `peers_dns` is resolved again.
+    #[serde(default = "default_refresh_secs")]
+    pub refresh_secs: u64,
+    /// How often each replica copies what it holds to owners missing it and drops what
+    /// it doesn't own, also done whenever the peers change.
+    #[serde(default = "default_repair_secs")]
+    pub repair_secs: u64,
+    /// Presented to the other replicas when repairing. Needs to be able to write to
+    /// every cache, so an admin token if some caches restrict their writers.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Settings of `nix-serve-push`, the uploader run on builders. Always read from
+/// `NIX_SERVE_PUSH_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct PushConfig {
+    /// Root URL of the cache, e.g. `http://nix-serve:3000/team-a`.
+    pub cache_url: String,
+    /// Sent as a bearer token, if set.
+    pub token: Option<String>,
+    /// Path to the secret key uploads are signed with in `Builder-Sig:`.
+    pub secret_key: String,
+    /// Unix socket the post-build hook hands paths over on.
+    pub socket: String,
+    /// Paths uploaded at the same time.
+    pub workers: usize,
+    /// Attempts after the first one when the cache can't be reached or fails.
+    pub retries: u32,
+}
+
+impl PushConfig {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Ok(PushConfig {
+            cache_url: env::var("NIX_SERVE_PUSH_URL")
+                .map_err(|_| ConfigError::Missing("NIX_SERVE_PUSH_URL"))?,
+            token: env::var("NIX_SERVE_PUSH_TOKEN").ok(),
+            secret_key: env::var("NIX_SERVE_PUSH_SECRET_KEY")
+                .map_err(|_| ConfigError::Missing("NIX_SERVE_PUSH_SECRET_KEY"))?,
+            socket: Self::socket_from_env(),
+            workers: env_parse("NIX_SERVE_PUSH_WORKERS")?.unwrap_or(4),
+            retries: env_parse("NIX_SERVE_PUSH_RETRIES")?.unwrap_or(5),
+        })
+    }
+
+    /// The socket alone, which is all the hook side needs.
+    pub fn socket_from_env() -> String {
+        env::var("NIX_SERVE_PUSH_SOCKET").unwrap_or_else(|_| "/tmp/nix-serve-push.sock".to_owned())
+    }
+}
+
+fn default_listen_addr() -> String {
+    "0.0.0.0:3000".to_owned()
+}
+
+fn default_cache_dir() -> String {
+    "nar".to_owned()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_priority() -> u32 {
+    20
+}
+
+fn default_compression() -> String {
+    "none".to_owned()
+}
+
+fn default_keep_builds() -> usize {
+    20
+}
+
+fn default_keep_accessed_secs() -> u64 {
+    14 * 24 * 60 * 60
+}
+
+fn default_keep_logs_secs() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_min_age_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_scrub_interval_secs() -> Option<u64> {
+    Some(7 * 24 * 60 * 60)
+}
+
+fn default_min_chunk_size() -> u32 {
+    16 * 1024
+}
+
+fn default_avg_chunk_size() -> u32 {
+    64 * 1024
+}
+
+fn default_max_chunk_size() -> u32 {
+    256 * 1024
+}
+
+fn default_replicas() -> usize {
+    2
+}
+
+fn default_refresh_secs() -> u64 {
+    30
+}
+
+fn default_repair_secs() -> u64 {
+    60 * 60
+}
+
+fn default_builder_key_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_retry_after_secs() -> u64 {
+    10
+}
+
+fn default_narinfo_cache_capacity() -> usize {
+    64 * 1024
+}
+
+fn default_negative_ttl_secs() -> u64 {
+    30
+}
+
+fn env_parse<T: std::str::FromStr>(name: &'static str) -> Result<Option<T>, ConfigError> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Invalid(name, value)),
+        Err(_) => Ok(None),
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var("NIX_SERVE_CONFIG") {
+            Ok(path) => Self::from_file(&path)?,
+            Err(_) => Self::from_env()?,
+        };
+        config.check_cache_names()?;
+
+        config.fill_s3_credentials(
+            env::var("AWS_ACCESS_KEY_ID").ok(),
+            env::var("AWS_SECRET_ACCESS_KEY").ok(),
+        );
+
+        Ok(config)
+    }
+
+    /// Gives every S3 storage, the default cache's and those of named caches, the
+    /// credentials it doesn't set itself.
+    fn fill_s3_credentials(&mut self, access_key: Option<String>, secret_key: Option<String>) {
+        let storages = std::iter::once(&mut self.storage).chain(
+            self.caches
+                .iter_mut()
+                .filter_map(|cache| cache.storage.as_mut()),
+        );
+        for storage in storages {
+            if let StorageConfig::S3(s3) = storage {
+                if s3.access_key.is_none() {
+                    s3.access_key = access_key.clone();
+                }
+                if s3.secret_key.is_none() {
+                    s3.secret_key = secret_key.clone();
+                }
+            }
+        }
+    }
+
+    fn check_cache_names(&self) -> Result<(), ConfigError> {
+        let mut seen = std::collections::HashSet::new();
+        for cache in &self.caches {
+            let name = &cache.name;
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                && !name.ends_with(".narinfo")
+                && !RESERVED_CACHE_NAMES.contains(&name.as_str());
+            if !valid || !seen.insert(name) {
+                return Err(ConfigError::Invalid("caches.name", name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        serde_yaml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let storage = match env::var("NIX_SERVE_STORAGE").as_deref() {
+            Err(_) | Ok("disk") => StorageConfig::Disk {
+                cache_dir: env::var("NIX_SERVE_CACHE_DIR").unwrap_or_else(|_| default_cache_dir()),
+            },
+            Ok("s3") => StorageConfig::S3(S3Config {
+                bucket: env::var("NIX_SERVE_S3_BUCKET")
+                    .map_err(|_| ConfigError::Missing("NIX_SERVE_S3_BUCKET"))?,
+                endpoint: env::var("NIX_SERVE_S3_ENDPOINT")
+                    .map_err(|_| ConfigError::Missing("NIX_SERVE_S3_ENDPOINT"))?,
+                region: env::var("NIX_SERVE_S3_REGION")
+                    .map_err(|_| ConfigError::Missing("NIX_SERVE_S3_REGION"))?,
+                prefix: env::var("NIX_SERVE_S3_PREFIX").unwrap_or_default(),
+                access_key: None,
+                secret_key: None,
+                path_style: env::var("NIX_SERVE_S3_PATH_STYLE")
+                    .map(|v| v != "false")
+                    .unwrap_or(true),
+                hot_tier: match env::var("NIX_SERVE_S3_HOT_DIR") {
+                    Ok(cache_dir) => Some(HotTierConfig {
+                        cache_dir,
+                        max_bytes: env_parse("NIX_SERVE_S3_HOT_MAX_BYTES")?
+                            .ok_or(ConfigError::Missing("NIX_SERVE_S3_HOT_MAX_BYTES"))?,
+                    }),
+                    Err(_) => None,
+                },
+            }),
+            Ok(other) => return Err(ConfigError::UnknownBackend(other.to_owned())),
+        };
+
+        let defaults = GcConfig::default();
+        let gc = GcConfig {
+            pinned: env::var("NIX_SERVE_GC_PINNED")
+                .map(|paths| paths.split_whitespace().map(str::to_owned).collect())
+                .unwrap_or_default(),
+            keep_builds: env_parse("NIX_SERVE_GC_KEEP_BUILDS")?.unwrap_or(defaults.keep_builds),
+            keep_accessed_secs: env_parse("NIX_SERVE_GC_KEEP_ACCESSED_SECS")?
+                .unwrap_or(defaults.keep_accessed_secs),
+            min_age_secs: env_parse("NIX_SERVE_GC_MIN_AGE_SECS")?.unwrap_or(defaults.min_age_secs),
+            max_size: env_parse("NIX_SERVE_GC_MAX_SIZE")?,
+            interval_secs: env_parse("NIX_SERVE_GC_INTERVAL_SECS")?,
+            keep_logs_secs: env_parse("NIX_SERVE_GC_KEEP_LOGS_SECS")?
+                .unwrap_or(defaults.keep_logs_secs),
+        };
+
+        let scrub = ScrubConfig {
+            // 0 turns scheduled scrubbing off.
+            interval_secs: match env_parse("NIX_SERVE_SCRUB_INTERVAL_SECS")? {
+                Some(0) => None,
+                Some(secs) => Some(secs),
+                None => default_scrub_interval_secs(),
+            },
+            quarantine: env_parse("NIX_SERVE_SCRUB_QUARANTINE")?.unwrap_or(true),
+        };
+
+        let narinfo_cache = NarInfoCacheConfig {
+            capacity: env_parse("NIX_SERVE_NARINFO_CACHE_CAPACITY")?
+                .unwrap_or_else(default_narinfo_cache_capacity),
+            negative_ttl_secs: env_parse("NIX_SERVE_NARINFO_CACHE_NEGATIVE_TTL_SECS")?
+                .unwrap_or_else(default_negative_ttl_secs),
+        };
+
+        let upstream_keys: Vec<String> = env::var("NIX_SERVE_UPSTREAM_KEYS")
+            .map(|keys| keys.split_whitespace().map(str::to_owned).collect())
+            .unwrap_or_default();
+        let upstreams = env::var("NIX_SERVE_UPSTREAMS")
+            .map(|urls| {
+                urls.split_whitespace()
+                    .map(|url| UpstreamConfig {
+                        url: url.to_owned(),
+                        public_keys: upstream_keys.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let compression = CompressionConfig {
+            store: env::var("NIX_SERVE_COMPRESSION").unwrap_or_else(|_| default_compression()),
+            level: env_parse("NIX_SERVE_COMPRESSION_LEVEL")?,
+            xz_user_agents: env::var("NIX_SERVE_XZ_USER_AGENTS")
+                .map(|agents| agents.split_whitespace().map(str::to_owned).collect())
+                .unwrap_or_default(),
+        };
+
+        let auth = AuthConfig {
+            tokens: env::var("NIX_SERVE_TOKENS").ok(),
+            anonymous_read: env_parse("NIX_SERVE_ANONYMOUS_READ")?.unwrap_or(false),
+        };
+
+        let dedup = match env_parse("NIX_SERVE_DEDUP")?.unwrap_or(false) {
+            true => {
+                let defaults = DedupConfig::default();
+                Some(DedupConfig {
+                    min_chunk_size: env_parse("NIX_SERVE_DEDUP_MIN_CHUNK_SIZE")?
+                        .unwrap_or(defaults.min_chunk_size),
+                    avg_chunk_size: env_parse("NIX_SERVE_DEDUP_AVG_CHUNK_SIZE")?
+                        .unwrap_or(defaults.avg_chunk_size),
+                    max_chunk_size: env_parse("NIX_SERVE_DEDUP_MAX_CHUNK_SIZE")?
+                        .unwrap_or(defaults.max_chunk_size),
+                })
+            }
+            false => None,
+        };
+
+        let cluster = match env::var("NIX_SERVE_CLUSTER_SELF_URL") {
+            Ok(self_url) => Some(ClusterConfig {
+                self_url,
+                peers: env::var("NIX_SERVE_CLUSTER_PEERS")
+                    .map(|peers| peers.split_whitespace().map(str::to_owned).collect())
+                    .unwrap_or_default(),
+                peers_dns: env::var("NIX_SERVE_CLUSTER_PEERS_DNS").ok(),
+                replicas: env_parse("NIX_SERVE_CLUSTER_REPLICAS")?.unwrap_or_else(default_replicas),
+                refresh_secs: env_parse("NIX_SERVE_CLUSTER_REFRESH_SECS")?
+                    .unwrap_or_else(default_refresh_secs),
+                repair_secs: env_parse("NIX_SERVE_CLUSTER_REPAIR_SECS")?
+                    .unwrap_or_else(default_repair_secs),
+                token: env::var("NIX_SERVE_CLUSTER_TOKEN").ok(),
+            }),
+            Err(_) => None,
+        };
+
+        Ok(Config {
+            listen_addr: env::var("NIX_SERVE_LISTEN").unwrap_or_else(|_| default_listen_addr()),
+            signing_key: env::var("NIX_SERVE_SIGNING_KEY").ok(),
+            trusted_builder_keys: env::var("NIX_SERVE_TRUSTED_BUILDER_KEYS")
+                .map(|keys| keys.split_whitespace().map(str::to_owned).collect())
+                .unwrap_or_default(),
+            builder_key_ttl_secs: env_parse("NIX_SERVE_BUILDER_KEY_TTL_SECS")?
+                .unwrap_or_else(default_builder_key_ttl_secs),
+            storage,
+            gc,
+            scrub,
+            narinfo_cache,
+            upstreams,
+            compression,
+            auth,
+            priority: env_parse("NIX_SERVE_PRIORITY")?.unwrap_or_else(default_priority),
+            quota: QuotaConfig {
+                max_bytes: env_parse("NIX_SERVE_QUOTA_MAX_BYTES")?,
+                max_paths: env_parse("NIX_SERVE_QUOTA_MAX_PATHS")?,
+                max_upload_bytes: env_parse("NIX_SERVE_QUOTA_MAX_UPLOAD_BYTES")?,
+                tokens: BTreeMap::new(),
+            },
+            uploads: UploadLimitsConfig {
+                max_concurrent: env_parse("NIX_SERVE_UPLOADS_MAX_CONCURRENT")?,
+                max_concurrent_per_client: env_parse(
+                    "NIX_SERVE_UPLOADS_MAX_CONCURRENT_PER_CLIENT",
+                )?,
+                max_bytes_per_sec: env_parse("NIX_SERVE_UPLOADS_MAX_BYTES_PER_SEC")?,
+                max_bytes_per_sec_per_client: env_parse(
+                    "NIX_SERVE_UPLOADS_MAX_BYTES_PER_SEC_PER_CLIENT",
+                )?,
+                retry_after_secs: env_parse("NIX_SERVE_UPLOADS_RETRY_AFTER_SECS")?
+                    .unwrap_or_else(default_retry_after_secs),
+            },
+            dedup,
+            nats_url: env::var("NIX_SERVE_NATS_URL").ok(),
+            index_debug_info: env_parse("NIX_SERVE_INDEX_DEBUG_INFO")?.unwrap_or(false),
+            caches: Vec::new(),
+            cluster,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_s3_config() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+signing_key: /etc/nix-serve/cache.sec
+storage:
+  type: s3
+  bucket: nix-cache
+  endpoint: https://fsn1.your-objectstorage.com
+  region: fsn1
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen_addr, "0.0.0.0:3000");
+        assert_eq!(config.gc.keep_builds, 20);
+        assert!(config.gc.max_size.is_none());
+        assert!(config.upstreams.is_empty());
+        match config.storage {
+            StorageConfig::S3(s3) => {
+                assert_eq!(s3.bucket, "nix-cache");
+                assert_eq!(s3.region, "fsn1");
+                assert!(s3.path_style);
+                assert!(s3.access_key.is_none());
+            }
+            other => panic!("expected s3 storage, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn fills_s3_credentials_of_named_caches() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+caches:
+  - name: team-a
+    storage:
+      type: s3
+      bucket: team-a
+      endpoint: https://fsn1.your-objectstorage.com
+      region: fsn1
+  - name: team-b
+    storage:
+      type: s3
+      bucket: team-b
+      endpoint: https://fsn1.your-objectstorage.com
+      region: fsn1
+      access_key: own
+"#,
+        )
+        .unwrap();
+        config.fill_s3_credentials(Some("env".into()), Some("secret".into()));
+
+        let credentials: Vec<_> = config
+            .caches
+            .iter()
+            .map(|cache| match &cache.storage {
+                Some(StorageConfig::S3(s3)) => (s3.access_key.clone(), s3.secret_key.clone()),
+                other => panic!("expected s3 storage, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(
+            credentials,
+            [
+                (Some("env".into()), Some("secret".into())),
+                (Some("own".into()), Some("secret".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_named_caches() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+storage:
+  type: s3
+  bucket: nix-cache
+  endpoint: https://fsn1.your-objectstorage.com
+  region: fsn1
+  prefix: main
+caches:
+  - name: team-a
+    public: true
+    priority: 30
+  - name: staging
+    write_tokens: [ci-staging]
+"#,
+        )
+        .unwrap();
+        config.check_cache_names().unwrap();
+        assert_eq!(config.priority, 20);
+        assert_eq!(config.caches[0].priority, 30);
+        assert!(config.caches[0].public);
+        assert_eq!(config.caches[1].write_tokens, ["ci-staging"]);
+        match config.storage.for_cache("team-a") {
+            StorageConfig::S3(s3) => assert_eq!(s3.prefix, "main/team-a"),
+            other => panic!("expected s3 storage, got {other:?}"),
+        }
+
+        let reserved: Config = serde_yaml::from_str("caches: [{name: nar}]").unwrap();
+        assert!(reserved.check_cache_names().is_err());
+        let twice: Config = serde_yaml::from_str("caches: [{name: a}, {name: a}]").unwrap();
+        assert!(twice.check_cache_names().is_err());
+    }
+
+    #[test]
+    fn parses_cluster_config() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+cluster:
+  self_url: http://10.0.3.7:3000
+  peers_dns: nix-serve-peers:3000
+"#,
+        )
+        .unwrap();
+        let cluster = config.cluster.unwrap();
+        assert_eq!(cluster.replicas, 2);
+        assert!(cluster.peers.is_empty());
+        assert_eq!(cluster.peers_dns.as_deref(), Some("nix-serve-peers:3000"));
+        assert_eq!(cluster.repair_secs, 3600);
+        assert!(cluster.token.is_none());
+    }
+
+    #[test]
+    fn defaults_to_disk() {
+        let config: Config = serde_yaml::from_str("listen_addr: 127.0.0.1:8080").unwrap();
+        assert!(matches!(
+            config.storage,
+            StorageConfig::Disk { ref cache_dir } if cache_dir == "nar"
+        ));
+    }
+}
@@ -0,0 +1,211 @@
+//! Notifications on NATS when paths enter or leave the cache, so the build controller
+//! and dashboards can react to new outputs without polling.
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tracing::{debug, error, warn};
+
+use common::narinfo::{NarInfo, StorePath};
+
+pub const PATH_ADDED: &str = "cache.path.added";
+pub const PATH_DELETED: &str = "cache.path.deleted";
+
+/// Events held while NATS is slow or unreachable. Past that they are dropped rather
+/// than holding up uploads.
+const QUEUE_LEN: usize = 1024;
+
+/// How long [`Events::flush`] waits for NATS.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum EventsError {
+    #[error("failed to connect to NATS: {0}")]
+    Connect(#[from] async_nats::ConnectError),
+}
+
+/// Published on [`PATH_ADDED`] once a narinfo is committed.
+#[derive(Debug, Clone, Serialize)]
+pub struct PathAdded {
+    /// The named cache, or `None` for the default one.
+    pub cache: Option<String>,
+    pub store_path: String,
+    pub url: String,
+    pub nar_hash: String,
+    pub nar_size: u64,
+    pub references: Vec<String>,
+    pub uploader: String,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionReason {
+    Gc,
+    /// Deleted through the admin API.
+    Admin,
+    /// Taken out by the scrubber for a missing or damaged NAR.
+    Quarantine,
+}
+
+/// Published on [`PATH_DELETED`] once a narinfo is gone.
+#[derive(Debug, Clone, Serialize)]
+pub struct PathDeleted {
+    pub cache: Option<String>,
+    pub store_path: String,
+    pub reason: DeletionReason,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+struct Event {
+    subject: &'static str,
+    payload: Bytes,
+}
+
+enum Message {
+    Publish(Event),
+    /// Answered once everything queued before has gone out.
+    Flush(oneshot::Sender<()>),
+}
+
+/// The connection to NATS, shared by all caches.
+#[derive(Clone)]
+pub struct Publisher {
+    sender: mpsc::Sender<Message>,
+}
+
+impl Publisher {
+    /// Connects in the background, so the cache comes up even while NATS is down.
+    pub async fn connect(url: &str) -> Result<Self, EventsError> {
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect(url)
+            .await?;
+        let (sender, mut receiver) = mpsc::channel::<Message>(QUEUE_LEN);
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                match message {
+                    Message::Publish(event) => {
+                        if let Err(e) = client.publish(event.subject, event.payload).await {
+                            warn!(subject = event.subject, error = %e, "Failed to publish event");
+                        }
+                    }
+                    Message::Flush(done) => {
+                        if let Err(e) = client.flush().await {
+                            warn!(error = %e, "Failed to flush events");
+                        }
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Ok(Publisher { sender })
+    }
+
+    /// Events of the cache `name`, or of the default cache.
+    pub fn for_cache(&self, name: Option<&str>) -> Events {
+        Events {
+            sender: Some(self.sender.clone()),
+            cache: name.map(str::to_owned),
+        }
+    }
+}
+
+/// Where one cache reports the paths added and deleted. The default sends nothing.
+#[derive(Debug, Default)]
+pub struct Events {
+    sender: Option<mpsc::Sender<Message>>,
+    cache: Option<String>,
+}
+
+impl Events {
+    fn send(&self, subject: &'static str, payload: &impl Serialize) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let payload = serde_json::to_vec(payload).expect("serialising an event can't fail");
+        match sender.try_send(Message::Publish(Event {
+            subject,
+            payload: payload.into(),
+        })) {
+            Ok(()) => debug!(subject, "Queued event"),
+            Err(TrySendError::Full(_)) => warn!(subject, "Event queue is full, dropping event"),
+            Err(TrySendError::Closed(_)) => error!(subject, "Event publisher is gone"),
+        }
+    }
+
+    pub fn path_added(&self, info: &NarInfo, uploader: &str) {
+        self.send(
+            PATH_ADDED,
+            &PathAdded {
+                cache: self.cache.clone(),
+                store_path: info.store_path.to_absolute(),
+                url: info.url.clone(),
+                nar_hash: info.nar_hash.to_string(),
+                nar_size: info.nar_size,
+                references: info.references.iter().map(StorePath::to_absolute).collect(),
+                uploader: uploader.to_owned(),
+                time: Utc::now(),
+            },
+        );
+    }
+
+    /// Waits until the events sent so far are out, for commands that exit right after.
+    /// Gives up after [`FLUSH_TIMEOUT`].
+    pub async fn flush(&self) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let (done, flushed) = oneshot::channel();
+        let flushing = async {
+            sender.send(Message::Flush(done)).await.ok()?;
+            flushed.await.ok()
+        };
+        match tokio::time::timeout(FLUSH_TIMEOUT, flushing).await {
+            Ok(Some(())) => {}
+            Ok(None) => error!("Event publisher is gone"),
+            Err(_) => warn!("Timed out publishing events"),
+        }
+    }
+
+    pub fn path_deleted(&self, store_path: &str, reason: DeletionReason) {
+        self.send(
+            PATH_DELETED,
+            &PathDeleted {
+                cache: self.cache.clone(),
+                store_path: store_path.to_owned(),
+                reason,
+                time: Utc::now(),
+            },
+        );
+    }
+}
+
+/// Events that end up in a channel instead of NATS, with what was sent so far.
+#[cfg(test)]
+pub(crate) fn test_events(
+    cache: Option<&str>,
+) -> (Events, impl FnMut() -> Vec<(String, serde_json::Value)>) {
+    let (sender, mut receiver) = mpsc::channel(QUEUE_LEN);
+    let events = Events {
+        sender: Some(sender),
+        cache: cache.map(str::to_owned),
+    };
+    let received = move || {
+        let mut received = Vec::new();
+        while let Ok(Message::Publish(event)) = receiver.try_recv() {
+            let Event { subject, payload } = event;
+            received.push((
+                subject.to_owned(),
+                serde_json::from_slice(&payload).unwrap(),
+            ));
+        }
+        received
+    };
+    (events, received)
+}
@@ -0,0 +1,1079 @@
+//! Closure-aware garbage collection. The reference graph comes from the stored narinfo
+//! `References:`; a path is only deleted when nothing that is kept refers to it.
+
+use chrono::{DateTime, Duration, Utc};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use common::narinfo::{self, NarInfo, StorePath};
+
+use crate::config::GcConfig;
+use crate::events::{DeletionReason, Events};
+use crate::nar::DebugInfoLink;
+use crate::oci::{self, OciError};
+use crate::realisation::Realisation;
+use crate::storage::{NixCacheStorage, ObjectInfo, StorageError};
+
+/// Name of the GC bookkeeping document in the storage backend's meta area.
+const STATE_FILE: &str = "gc.json";
+
+/// Narinfos fetched at once when loading the cache. Goes through the narinfo cache when
+/// one is configured, so this mostly matters for S3.
+const FETCH_CONCURRENCY: usize = 32;
+
+/// How often recorded narinfo accesses are written back to storage.
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+#[derive(Debug, Error)]
+pub enum GcError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("corrupt GC state: {0}")]
+    State(#[from] serde_json::Error),
+    #[error("{0} is not a store path")]
+    InvalidPath(String),
+    #[error("failed to load images: {0}")]
+    Images(#[from] OciError),
+    #[error("a cluster replica only holds part of the reference graph, so it can't collect")]
+    Clustered,
+}
+
+/// The outputs of one build, registered so they stay around for a while.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Build {
+    pub name: String,
+    pub time: DateTime<Utc>,
+    /// Store paths or hash parts.
+    pub paths: Vec<String>,
+}
+
+/// What GC remembers between runs and restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct GcState {
+    /// Oldest first.
+    #[serde(default)]
+    builds: Vec<Build>,
+    /// Last narinfo fetch per hash part.
+    #[serde(default)]
+    accessed: HashMap<String, DateTime<Utc>>,
+    /// Hash parts pinned through the admin API, on top of the configured ones.
+    #[serde(default)]
+    pinned: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CollectedPath {
+    pub store_path: String,
+    pub url: String,
+    pub size: u64,
+    pub last_used: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub paths_total: usize,
+    /// Paths reachable from a root, or kept to fill `max_size`.
+    pub paths_kept: usize,
+    pub bytes_total: u64,
+    pub bytes_freed: u64,
+    pub collected: Vec<CollectedPath>,
+    /// NARs no narinfo points at.
+    pub orphaned_nars: Vec<String>,
+    /// Build logs of derivations no kept path came from, past `keep_logs_secs`.
+    pub collected_logs: Vec<String>,
+    /// Realisations whose output path was collected or is gone.
+    pub collected_realisations: Vec<String>,
+    /// Build ids whose debug info NAR was collected or is gone.
+    pub collected_debuginfo: Vec<String>,
+    /// Deletions that failed; the objects are still there.
+    pub errors: usize,
+}
+
+/// A path in the cache, as the admin API lists it.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredPath {
+    pub hash: String,
+    /// `None` if the narinfo doesn't parse.
+    pub store_path: Option<String>,
+    pub url: Option<String>,
+    /// Size of the NAR file as stored.
+    pub size: u64,
+    pub nar_size: Option<u64>,
+    pub references: Vec<String>,
+    pub uploaded: DateTime<Utc>,
+    pub last_access: Option<DateTime<Utc>>,
+    pub pinned: bool,
+}
+
+/// What pins a path: the config file, or the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct Pins {
+    pub config: Vec<String>,
+    pub api: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum Deletion {
+    Deleted {
+        /// The NAR too, unless another narinfo still points at it.
+        nar: Option<String>,
+    },
+    /// Other paths refer to it, so deleting it would break their closures.
+    Referenced {
+        referrers: Vec<String>,
+    },
+    NotFound,
+}
+
+/// The hash part of `/nix/store/<hash>-<name>`, `<hash>-<name>` or a bare `<hash>`.
+pub fn hash_part(path: &str) -> Option<&str> {
+    let base = path
+        .strip_prefix(narinfo::STORE_DIR)
+        .map_or(path, |rest| rest.trim_start_matches('/'));
+    let hash = base.get(..narinfo::HASH_PART_LEN)?;
+    let rest = &base[narinfo::HASH_PART_LEN..];
+    (narinfo::is_valid_hash_part(hash) && (rest.is_empty() || rest.starts_with('-')))
+        .then_some(hash)
+}
+
+struct Entry {
+    info: Option<NarInfo>,
+    uploaded: DateTime<Utc>,
+    size: u64,
+}
+
+impl Entry {
+    fn references(&self) -> impl Iterator<Item = &str> {
+        self.info
+            .iter()
+            .flat_map(|info| info.references.iter().map(StorePath::hash_part))
+    }
+}
+
+/// The store paths whose narinfo refers to `hash`, other than itself.
+fn referrers(entries: &HashMap<String, Entry>, hash: &str) -> Vec<String> {
+    let mut referrers: Vec<String> = entries
+        .iter()
+        .filter(|(other, entry)| *other != hash && entry.references().any(|r| r == hash))
+        .filter_map(|(_, entry)| Some(entry.info.as_ref()?.store_path.to_absolute()))
+        .collect();
+    referrers.sort();
+    referrers
+}
+
+/// Adds the closure of `hash` to `kept`, returning the hashes that were new.
+fn add_closure(
+    entries: &HashMap<String, Entry>,
+    kept: &mut HashSet<String>,
+    hash: &str,
+) -> Vec<String> {
+    let mut added = Vec::new();
+    let mut queue = vec![hash.to_owned()];
+    while let Some(hash) = queue.pop() {
+        let Some(entry) = entries.get(&hash) else {
+            continue;
+        };
+        if !kept.insert(hash.clone()) {
+            continue;
+        }
+        queue.extend(entry.references().map(str::to_owned));
+        added.push(hash);
+    }
+    added
+}
+
+/// The part of the closure of `hash` that is not in `kept`, without copying `kept`.
+fn closure_outside(
+    entries: &HashMap<String, Entry>,
+    kept: &HashSet<String>,
+    hash: &str,
+) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut queue = vec![hash];
+    while let Some(hash) = queue.pop() {
+        let Some((hash, entry)) = entries.get_key_value(hash) else {
+            continue;
+        };
+        if kept.contains(hash) || !seen.insert(hash) {
+            continue;
+        }
+        queue.extend(entry.references());
+    }
+    seen.into_iter().cloned().collect()
+}
+
+pub struct Gc {
+    storage: Arc<dyn NixCacheStorage>,
+    config: GcConfig,
+    /// Accesses since the state was last written.
+    accessed: Mutex<HashMap<String, DateTime<Utc>>>,
+    /// Serialises runs and state updates.
+    lock: tokio::sync::Mutex<()>,
+    events: Arc<Events>,
+    /// Set on cluster replicas, which refuse to collect.
+    clustered: bool,
+}
+
+impl Gc {
+    pub fn new(storage: Arc<dyn NixCacheStorage>, config: GcConfig) -> Self {
+        for path in &config.pinned {
+            if hash_part(path).is_none() {
+                warn!(path = %path, "Ignoring invalid pinned store path");
+            }
+        }
+        Gc {
+            storage,
+            config,
+            accessed: Mutex::new(HashMap::new()),
+            lock: tokio::sync::Mutex::new(()),
+            events: Arc::default(),
+            clustered: false,
+        }
+    }
+
+    /// Reports deleted paths to `events`.
+    pub fn with_events(mut self, events: Arc<Events>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Refuses to collect. A cluster replica only has the paths it owns, while their
+    /// references, and the logs of their derivations, may live on other replicas.
+    pub fn in_cluster(mut self) -> Self {
+        self.clustered = true;
+        self
+    }
+
+    /// Records that a narinfo was fetched. Cheap; persisted on the next flush or run.
+    pub fn touch(&self, hash: &str) {
+        self.accessed
+            .lock()
+            .unwrap()
+            .insert(hash.to_owned(), Utc::now());
+    }
+
+    async fn load_state(&self) -> Result<GcState, GcError> {
+        let mut state: GcState = match self.storage.get_meta(STATE_FILE).await {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(StorageError::NotFound) => GcState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let accessed = self.accessed.lock().unwrap().clone();
+        for (hash, time) in accessed {
+            let last = state.accessed.entry(hash).or_insert(time);
+            *last = (*last).max(time);
+        }
+        Ok(state)
+    }
+
+    async fn save_state(&self, state: &mut GcState) -> Result<(), GcError> {
+        let cutoff = Utc::now() - Duration::seconds(self.config.keep_accessed_secs as i64);
+        state.accessed.retain(|_, time| *time > cutoff);
+        let skip = state.builds.len().saturating_sub(self.config.keep_builds);
+        state.builds.drain(..skip);
+        let content = serde_json::to_vec(state)?;
+        self.storage.put_meta(STATE_FILE, content).await?;
+        self.accessed
+            .lock()
+            .unwrap()
+            .retain(|hash, time| state.accessed.get(hash).is_none_or(|saved| *time > *saved));
+        Ok(())
+    }
+
+    /// Every stored NAR by file name, and every narinfo by hash part.
+    async fn load_entries(
+        &self,
+    ) -> Result<(HashMap<String, ObjectInfo>, HashMap<String, Entry>), GcError> {
+        let nars: HashMap<String, ObjectInfo> = self
+            .storage
+            .list_nars()
+            .await?
+            .into_iter()
+            .map(|nar| (nar.name.clone(), nar))
+            .collect();
+
+        let storage = &self.storage;
+        let mut fetched = stream::iter(self.storage.list_narinfos().await?)
+            .map(|object| async move {
+                let content = storage.get_narinfo(&object.name).await;
+                (object, content)
+            })
+            .buffer_unordered(FETCH_CONCURRENCY);
+
+        let mut entries = HashMap::new();
+        while let Some((object, content)) = fetched.next().await {
+            let info = match content {
+                Ok(content) => match content.parse::<NarInfo>() {
+                    Ok(info) => Some(info),
+                    Err(e) => {
+                        warn!(hash = %object.name, error = %e, "Keeping unparsable narinfo");
+                        None
+                    }
+                },
+                Err(StorageError::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let size = info
+                .as_ref()
+                .and_then(|info| {
+                    let file = info.nar_file()?;
+                    nars.get(file).map(|nar| nar.size).or(info.file_size)
+                })
+                .unwrap_or(0);
+            entries.insert(
+                object.name,
+                Entry {
+                    info,
+                    uploaded: object.modified,
+                    size,
+                },
+            );
+        }
+        Ok((nars, entries))
+    }
+
+    /// Listings go with their narinfo. Paths whose NAR didn't parse, or that predate
+    /// listings, have none.
+    async fn delete_listing(&self, hash: &str) {
+        match self.storage.delete_listing(hash).await {
+            Ok(()) | Err(StorageError::NotFound) => {}
+            Err(e) => warn!(hash = %hash, error = %e, "Failed to delete NAR listing"),
+        }
+    }
+
+    fn is_pinned(&self, state: &GcState, hash: &str) -> bool {
+        state.pinned.contains(hash)
+            || self
+                .config
+                .pinned
+                .iter()
+                .any(|path| hash_part(path) == Some(hash))
+    }
+
+    fn stored_path(&self, state: &GcState, hash: &str, entry: &Entry) -> StoredPath {
+        let info = entry.info.as_ref();
+        StoredPath {
+            hash: hash.to_owned(),
+            store_path: info.map(|info| info.store_path.to_absolute()),
+            url: info.map(|info| info.url.clone()),
+            size: entry.size,
+            nar_size: info.map(|info| info.nar_size),
+            references: info
+                .map(|info| info.references.iter().map(StorePath::to_absolute).collect())
+                .unwrap_or_default(),
+            uploaded: entry.uploaded,
+            last_access: state.accessed.get(hash).copied(),
+            pinned: self.is_pinned(state, hash),
+        }
+    }
+
+    /// Every path in the cache, ordered by store path.
+    pub async fn paths(&self) -> Result<Vec<StoredPath>, GcError> {
+        let _guard = self.lock.lock().await;
+        let state = self.load_state().await?;
+        let (_, entries) = self.load_entries().await?;
+        let mut paths: Vec<StoredPath> = entries
+            .iter()
+            .map(|(hash, entry)| self.stored_path(&state, hash, entry))
+            .collect();
+        paths.sort_by(|a, b| a.store_path.cmp(&b.store_path).then(a.hash.cmp(&b.hash)));
+        Ok(paths)
+    }
+
+    /// The closure of `hash` as far as it is in the cache, starting with `hash` itself,
+    /// and the paths that refer to it. `None` if it isn't in the cache.
+    pub async fn closure(
+        &self,
+        hash: &str,
+    ) -> Result<Option<(Vec<StoredPath>, Vec<String>)>, GcError> {
+        let _guard = self.lock.lock().await;
+        let state = self.load_state().await?;
+        let (_, entries) = self.load_entries().await?;
+        if !entries.contains_key(hash) {
+            return Ok(None);
+        }
+        let closure = add_closure(&entries, &mut HashSet::new(), hash)
+            .iter()
+            .map(|hash| self.stored_path(&state, hash, &entries[hash]))
+            .collect();
+        Ok(Some((closure, referrers(&entries, hash))))
+    }
+
+    /// Deletes a path, and its NAR unless another narinfo shares it. Refuses if other
+    /// paths refer to it, unless `force`.
+    pub async fn delete(&self, hash: &str, force: bool) -> Result<Deletion, GcError> {
+        let _guard = self.lock.lock().await;
+        let mut state = self.load_state().await?;
+        let (_, entries) = self.load_entries().await?;
+        let Some(entry) = entries.get(hash) else {
+            return Ok(Deletion::NotFound);
+        };
+        let referrers = referrers(&entries, hash);
+        if !referrers.is_empty() && !force {
+            return Ok(Deletion::Referenced { referrers });
+        }
+
+        let file = entry.info.as_ref().and_then(NarInfo::nar_file);
+        let shared = entries.iter().any(|(other, entry)| {
+            other != hash && entry.info.as_ref().and_then(NarInfo::nar_file) == file
+        });
+        self.storage.delete_narinfo(hash).await?;
+        self.delete_listing(hash).await;
+        let nar = match file.filter(|_| !shared) {
+            Some(file) => {
+                self.storage.delete_nar(file).await?;
+                Some(file.to_owned())
+            }
+            None => None,
+        };
+        info!(hash = %hash, nar = ?nar, forced = !referrers.is_empty(), "Deleted path");
+        if let Some(info) = &entry.info {
+            self.events
+                .path_deleted(&info.store_path.to_absolute(), DeletionReason::Admin);
+        }
+        state.accessed.remove(hash);
+        state.pinned.remove(hash);
+        self.save_state(&mut state).await?;
+        Ok(Deletion::Deleted { nar })
+    }
+
+    pub async fn pins(&self) -> Result<Pins, GcError> {
+        let _guard = self.lock.lock().await;
+        let state = self.load_state().await?;
+        Ok(Pins {
+            config: self.config.pinned.clone(),
+            api: state.pinned.into_iter().collect(),
+        })
+    }
+
+    /// Pins a store path, base name or hash part. Returns whether it was newly pinned.
+    pub async fn pin(&self, path: &str) -> Result<bool, GcError> {
+        let hash = hash_part(path).ok_or_else(|| GcError::InvalidPath(path.to_owned()))?;
+        let _guard = self.lock.lock().await;
+        let mut state = self.load_state().await?;
+        let added = state.pinned.insert(hash.to_owned());
+        self.save_state(&mut state).await?;
+        Ok(added)
+    }
+
+    /// Unpins a path pinned through [`Gc::pin`]. Pins from the config stay.
+    pub async fn unpin(&self, path: &str) -> Result<bool, GcError> {
+        let hash = hash_part(path).ok_or_else(|| GcError::InvalidPath(path.to_owned()))?;
+        let _guard = self.lock.lock().await;
+        let mut state = self.load_state().await?;
+        let removed = state.pinned.remove(hash);
+        self.save_state(&mut state).await?;
+        Ok(removed)
+    }
+
+    /// Builds registered as roots, newest last.
+    pub async fn builds(&self) -> Result<Vec<Build>, GcError> {
+        let _guard = self.lock.lock().await;
+        Ok(self.load_state().await?.builds)
+    }
+
+    /// Writes recorded accesses back to storage.
+    pub async fn flush(&self) -> Result<(), GcError> {
+        let _guard = self.lock.lock().await;
+        let mut state = self.load_state().await?;
+        self.save_state(&mut state).await
+    }
+
+    /// Registers the outputs of a build as a GC root.
+    pub async fn register_build(&self, name: String, paths: Vec<String>) -> Result<(), GcError> {
+        let _guard = self.lock.lock().await;
+        let mut state = self.load_state().await?;
+        info!(build = %name, paths = paths.len(), "Registering build as GC root");
+        state.builds.push(Build {
+            name,
+            time: Utc::now(),
+            paths,
+        });
+        self.save_state(&mut state).await
+    }
+
+    /// Collects everything not kept alive by a root. With `dry_run` only reports what
+    /// would go.
+    pub async fn run(&self, dry_run: bool) -> Result<GcReport, GcError> {
+        if self.clustered {
+            return Err(GcError::Clustered);
+        }
+        let _guard = self.lock.lock().await;
+        let mut state = self.load_state().await?;
+        let now = Utc::now();
+        let young = now - Duration::seconds(self.config.min_age_secs as i64);
+        let accessed_cutoff = now - Duration::seconds(self.config.keep_accessed_secs as i64);
+
+        let (nars, entries) = self.load_entries().await?;
+
+        let last_used = |hash: &str, entry: &Entry| {
+            state
+                .accessed
+                .get(hash)
+                .map_or(entry.uploaded, |&t| t.max(entry.uploaded))
+        };
+
+        let images = oci::image_paths(self.storage.as_ref()).await?;
+        let builds = state.builds.iter().rev().take(self.config.keep_builds);
+        let mut roots: BTreeSet<&str> = self
+            .config
+            .pinned
+            .iter()
+            .chain(&state.pinned)
+            .chain(builds.flat_map(|build| &build.paths))
+            .chain(&images)
+            .filter_map(|path| hash_part(path))
+            .collect();
+        for (hash, entry) in &entries {
+            let recent = state
+                .accessed
+                .get(hash)
+                .is_some_and(|&t| t > accessed_cutoff);
+            if recent || entry.uploaded > young || entry.info.is_none() {
+                roots.insert(hash);
+            }
+        }
+
+        let mut kept = HashSet::new();
+        for root in roots {
+            add_closure(&entries, &mut kept, root);
+        }
+
+        if let Some(max_size) = self.config.max_size {
+            let mut kept_size: u64 = kept.iter().map(|hash| entries[hash].size).sum();
+            let mut candidates: Vec<(&String, DateTime<Utc>)> = entries
+                .iter()
+                .filter(|(hash, _)| !kept.contains(*hash))
+                .map(|(hash, entry)| (hash, last_used(hash, entry)))
+                .collect();
+            candidates.sort_by_key(|(_, last_used)| std::cmp::Reverse(*last_used));
+            for (hash, _) in candidates {
+                if kept.contains(hash) {
+                    continue;
+                }
+                let added = closure_outside(&entries, &kept, hash);
+                let added_size: u64 = added.iter().map(|hash| entries[hash].size).sum();
+                if kept_size + added_size <= max_size {
+                    kept.extend(added);
+                    kept_size += added_size;
+                }
+            }
+        }
+
+        let referenced: HashSet<&str> = entries
+            .values()
+            .filter_map(|entry| entry.info.as_ref()?.nar_file())
+            .collect();
+        let kept_nars: HashSet<&str> = kept
+            .iter()
+            .filter_map(|hash| entries[hash].info.as_ref()?.nar_file())
+            .collect();
+
+        let mut report = GcReport {
+            dry_run,
+            paths_total: entries.len(),
+            paths_kept: kept.len(),
+            bytes_total: nars.values().map(|nar| nar.size).sum(),
+            bytes_freed: 0,
+            collected: Vec::new(),
+            orphaned_nars: Vec::new(),
+            collected_logs: Vec::new(),
+            collected_realisations: Vec::new(),
+            collected_debuginfo: Vec::new(),
+            errors: 0,
+        };
+
+        let mut nars_to_delete = BTreeSet::new();
+        let mut collected: Vec<_> = entries
+            .iter()
+            .filter(|(hash, _)| !kept.contains(*hash))
+            .collect();
+        collected.sort_by_key(|(hash, entry)| last_used(hash, entry));
+        for (hash, entry) in collected {
+            let Some(info) = &entry.info else { continue };
+            if let Some(file) = info.nar_file().filter(|file| !kept_nars.contains(file)) {
+                nars_to_delete.insert(file.to_owned());
+            }
+            report.collected.push(CollectedPath {
+                store_path: info.store_path.to_absolute(),
+                url: info.url.clone(),
+                size: entry.size,
+                last_used: last_used(hash, entry),
+            });
+        }
+        for (file, nar) in &nars {
+            if !referenced.contains(file.as_str()) && nar.modified <= young {
+                report.orphaned_nars.push(file.clone());
+                nars_to_delete.insert(file.clone());
+            }
+        }
+        report.bytes_freed = nars_to_delete
+            .iter()
+            .filter_map(|file| nars.get(file))
+            .map(|nar| nar.size)
+            .sum();
+
+        let logs_cutoff = now - Duration::seconds(self.config.keep_logs_secs as i64);
+        let kept_derivers: HashSet<String> = kept
+            .iter()
+            .filter_map(|hash| Some(entries[hash].info.as_ref()?.deriver.as_ref()?.to_string()))
+            .collect();
+        for log in self.storage.list_logs().await? {
+            if !kept_derivers.contains(&log.name) && log.modified <= logs_cutoff {
+                report.collected_logs.push(log.name);
+            }
+        }
+        report.collected_logs.sort();
+
+        for object in self.storage.list_realisations().await? {
+            if object.modified > young {
+                continue;
+            }
+            let out_path = match self.storage.get_realisation(&object.name).await {
+                Ok(content) => match Realisation::parse(&content) {
+                    Ok(realisation) => realisation.out_path.clone(),
+                    Err(e) => {
+                        warn!(id = %object.name, error = %e, "Keeping unparsable realisation");
+                        continue;
+                    }
+                },
+                Err(StorageError::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if hash_part(&out_path).is_none_or(|hash| !kept.contains(hash)) {
+                report.collected_realisations.push(object.name);
+            }
+        }
+        report.collected_realisations.sort();
+
+        for object in self.storage.list_debuginfo().await? {
+            if object.modified > young {
+                continue;
+            }
+            let link = match self.storage.get_debuginfo(&object.name).await {
+                Ok(content) => match serde_json::from_str::<DebugInfoLink>(&content) {
+                    Ok(link) => link,
+                    Err(e) => {
+                        warn!(build_id = %object.name, error = %e, "Keeping unparsable debug info link");
+                        continue;
+                    }
+                },
+                Err(StorageError::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let gone = link
+                .nar_file()
+                .is_none_or(|file| !nars.contains_key(file) || nars_to_delete.contains(file));
+            if gone {
+                report.collected_debuginfo.push(object.name);
+            }
+        }
+        report.collected_debuginfo.sort();
+
+        if !dry_run {
+            // narinfo first, so nothing is ever served that points at a deleted NAR.
+            for path in &report.collected {
+                let hash = hash_part(&path.store_path).unwrap_or_default();
+                if let Err(e) = self.storage.delete_narinfo(hash).await {
+                    error!(hash = %hash, error = %e, "Failed to delete narinfo");
+                    report.errors += 1;
+                    continue;
+                }
+                state.accessed.remove(hash);
+                self.delete_listing(hash).await;
+                self.events
+                    .path_deleted(&path.store_path, DeletionReason::Gc);
+            }
+            for file in &nars_to_delete {
+                if let Err(e) = self.storage.delete_nar(file).await {
+                    error!(file = %file, error = %e, "Failed to delete NAR");
+                    report.errors += 1;
+                }
+            }
+            for build_id in &report.collected_debuginfo {
+                if let Err(e) = self.storage.delete_debuginfo(build_id).await {
+                    error!(build_id = %build_id, error = %e, "Failed to delete debug info link");
+                    report.errors += 1;
+                }
+            }
+            for id in &report.collected_realisations {
+                if let Err(e) = self.storage.delete_realisation(id).await {
+                    error!(id = %id, error = %e, "Failed to delete realisation");
+                    report.errors += 1;
+                }
+            }
+            for drv in &report.collected_logs {
+                if let Err(e) = self.storage.delete_log(drv).await {
+                    error!(drv = %drv, error = %e, "Failed to delete build log");
+                    report.errors += 1;
+                }
+            }
+        }
+        self.save_state(&mut state).await?;
+
+        info!(
+            dry_run,
+            paths_total = report.paths_total,
+            paths_kept = report.paths_kept,
+            collected = report.collected.len(),
+            orphaned_nars = report.orphaned_nars.len(),
+            collected_logs = report.collected_logs.len(),
+            collected_realisations = report.collected_realisations.len(),
+            collected_debuginfo = report.collected_debuginfo.len(),
+            bytes_freed = report.bytes_freed,
+            errors = report.errors,
+            "Garbage collection finished"
+        );
+        Ok(report)
+    }
+
+    /// Flushes accesses regularly and, if configured, runs GC on its interval.
+    pub async fn background(self: Arc<Self>) {
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        if self.clustered && self.config.interval_secs.is_some() {
+            warn!("Not collecting garbage on a schedule in a cluster");
+        }
+        let mut collect = self
+            .config
+            .interval_secs
+            .filter(|_| !self.clustered)
+            .map(|secs| tokio::time::interval(std::time::Duration::from_secs(secs)));
+        // Both fire immediately; skip that so startup doesn't kick off a collection.
+        flush.tick().await;
+        if let Some(collect) = &mut collect {
+            collect.tick().await;
+        }
+        loop {
+            let run = async {
+                match &mut collect {
+                    Some(collect) => collect.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = flush.tick() => {
+                    if let Err(e) = self.flush().await {
+                        error!(error = %e, "Failed to write GC state");
+                    }
+                }
+                _ = run => {
+                    if let Err(e) = self.run(false).await {
+                        error!(error = %e, "Scheduled garbage collection failed");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::DiskStorage;
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    const A: &str = "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1";
+    const B: &str = "0mdqa9w1p6cmli6976v4wi0sw9r4p5pr";
+    const C: &str = "kj7lzfd1877wk11c9c730yzhigwjl6bw";
+    const D: &str = "s649vcs2asa4lbs8hg93hyix187gc7s7";
+
+    /// Stores a path `hash` of `size` bytes referring to `references`.
+    async fn add_path(storage: &dyn NixCacheStorage, hash: &str, size: usize, references: &[&str]) {
+        let file = format!("{hash}.nar");
+        let content = Bytes::from(vec![0u8; size]);
+        storage
+            .put_nar(&file, futures::stream::once(async { Ok(content) }).boxed())
+            .await
+            .unwrap();
+        let references: Vec<String> = references.iter().map(|r| format!("{r}-dep")).collect();
+        let narinfo = format!(
+            "StorePath: /nix/store/{hash}-path\n\
+             URL: nar/{file}\n\
+             Compression: none\n\
+             NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73\n\
+             NarSize: {size}\n\
+             References: {}\n\
+             Deriver: {hash}-path.drv\n",
+            references.join(" ")
+        );
+        storage.put_narinfo(hash, narinfo).await.unwrap();
+    }
+
+    async fn setup() -> (tempfile::TempDir, Arc<dyn NixCacheStorage>) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn NixCacheStorage> =
+            Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        // A -> B -> C, D on its own.
+        add_path(storage.as_ref(), A, 100, &[B]).await;
+        add_path(storage.as_ref(), B, 200, &[C]).await;
+        add_path(storage.as_ref(), C, 300, &[]).await;
+        add_path(storage.as_ref(), D, 400, &[]).await;
+        let orphan = futures::stream::once(async { Ok(Bytes::from_static(b"x")) }).boxed();
+        storage.put_nar("orphan.nar", orphan).await.unwrap();
+        (dir, storage)
+    }
+
+    fn config() -> GcConfig {
+        GcConfig {
+            min_age_secs: 0,
+            ..GcConfig::default()
+        }
+    }
+
+    fn collected(report: &GcReport) -> BTreeSet<&str> {
+        report
+            .collected
+            .iter()
+            .map(|path| hash_part(&path.store_path).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn hash_parts() {
+        assert_eq!(hash_part(&format!("/nix/store/{A}-hello")), Some(A));
+        assert_eq!(hash_part(&format!("{A}-hello")), Some(A));
+        assert_eq!(hash_part(A), Some(A));
+        assert_eq!(hash_part(&format!("{A}hello")), None);
+        assert_eq!(hash_part("/nix/store/short-hello"), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_closure_of_pinned_paths() {
+        let (_dir, storage) = setup().await;
+        let gc = Gc::new(
+            storage.clone(),
+            GcConfig {
+                pinned: vec![format!("/nix/store/{B}-dep")],
+                ..config()
+            },
+        );
+
+        let report = gc.run(true).await.unwrap();
+        assert_eq!(collected(&report), BTreeSet::from([A, D]));
+        assert_eq!(report.orphaned_nars, vec!["orphan.nar"]);
+        assert_eq!(report.bytes_freed, 501);
+        assert!(storage.get_narinfo(A).await.is_ok());
+
+        gc.run(false).await.unwrap();
+        assert!(matches!(
+            storage.get_narinfo(A).await,
+            Err(StorageError::NotFound)
+        ));
+        assert!(matches!(
+            storage.nar_size(&format!("{D}.nar")).await,
+            Err(StorageError::NotFound)
+        ));
+        assert!(storage.get_narinfo(C).await.is_ok());
+        assert!(storage.nar_size(&format!("{C}.nar")).await.is_ok());
+        assert!(storage.nar_size("orphan.nar").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closures_deletes_and_pins() {
+        let (_dir, storage) = setup().await;
+        let (events, mut sent_events) = crate::events::test_events(Some("team"));
+        let gc = Gc::new(storage.clone(), config()).with_events(Arc::new(events));
+
+        let (closure, referrers) = gc.closure(A).await.unwrap().unwrap();
+        let hashes: Vec<&str> = closure.iter().map(|path| path.hash.as_str()).collect();
+        assert_eq!(hashes, [A, B, C]);
+        assert!(referrers.is_empty());
+        assert!(gc.closure(&"0".repeat(32)).await.unwrap().is_none());
+
+        match gc.delete(B, false).await.unwrap() {
+            Deletion::Referenced { referrers } => {
+                assert_eq!(referrers, [format!("/nix/store/{A}-path")])
+            }
+            other => panic!("expected B to be referenced, got {other:?}"),
+        }
+        assert!(matches!(
+            gc.delete(A, false).await.unwrap(),
+            Deletion::Deleted { nar: Some(_) }
+        ));
+        assert!(storage.nar_size(&format!("{A}.nar")).await.is_err());
+        let sent = sent_events();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, crate::events::PATH_DELETED);
+        assert_eq!(sent[0].1["store_path"], format!("/nix/store/{A}-path"));
+        assert_eq!(sent[0].1["reason"], "admin");
+        assert_eq!(sent[0].1["cache"], "team");
+
+        assert!(gc.pin(&format!("/nix/store/{D}-path")).await.unwrap());
+        assert!(!gc.pin(D).await.unwrap());
+        assert!(gc.pin("nope").await.is_err());
+        let report = gc.run(false).await.unwrap();
+        assert_eq!(collected(&report), BTreeSet::from([B, C]));
+        let reasons: Vec<_> = sent_events()
+            .into_iter()
+            .map(|(_, e)| e["reason"].clone())
+            .collect();
+        assert_eq!(reasons, ["gc", "gc"]);
+        let paths = gc.paths().await.unwrap();
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].pinned);
+
+        assert!(gc.unpin(D).await.unwrap());
+        assert!(!gc.unpin(D).await.unwrap());
+        assert!(gc.pins().await.unwrap().api.is_empty());
+    }
+
+    #[tokio::test]
+    async fn builds_and_accesses_are_roots() {
+        let (_dir, storage) = setup().await;
+        let gc = Gc::new(
+            storage.clone(),
+            GcConfig {
+                keep_builds: 1,
+                ..config()
+            },
+        );
+        gc.register_build("old".to_owned(), vec![D.to_owned()])
+            .await
+            .unwrap();
+        gc.register_build("new".to_owned(), vec![format!("/nix/store/{C}-dep")])
+            .await
+            .unwrap();
+        gc.touch(A);
+
+        let report = gc.run(true).await.unwrap();
+        assert_eq!(collected(&report), BTreeSet::from([D]));
+
+        // Accesses survive a restart.
+        let gc = Gc::new(
+            storage,
+            GcConfig {
+                keep_builds: 1,
+                ..config()
+            },
+        );
+        let report = gc.run(true).await.unwrap();
+        assert_eq!(collected(&report), BTreeSet::from([D]));
+    }
+
+    #[tokio::test]
+    async fn size_policy_keeps_whole_closures() {
+        let (_dir, storage) = setup().await;
+        // Only C fits on its own. A alone would fit too, but not together with B and C.
+        let gc = Gc::new(
+            storage.clone(),
+            GcConfig {
+                max_size: Some(350),
+                ..config()
+            },
+        );
+        let report = gc.run(true).await.unwrap();
+        assert_eq!(collected(&report), BTreeSet::from([A, B, D]));
+
+        let gc = Gc::new(
+            storage,
+            GcConfig {
+                max_size: Some(1000),
+                ..config()
+            },
+        );
+        let report = gc.run(true).await.unwrap();
+        assert!(report.collected.is_empty());
+    }
+
+    #[tokio::test]
+    async fn logs_follow_their_outputs() {
+        let (_dir, storage) = setup().await;
+        for drv in [format!("{A}-path.drv"), format!("{B}-path.drv")] {
+            let log = futures::stream::once(async { Ok(Bytes::from_static(b"log")) }).boxed();
+            storage.put_log(&drv, log).await.unwrap();
+        }
+        let gc = Gc::new(
+            storage.clone(),
+            GcConfig {
+                pinned: vec![B.to_owned()],
+                ..config()
+            },
+        );
+        // Fresh logs survive even without outputs.
+        let report = gc.run(true).await.unwrap();
+        assert!(report.collected_logs.is_empty());
+
+        let gc = Gc::new(
+            storage.clone(),
+            GcConfig {
+                pinned: vec![B.to_owned()],
+                keep_logs_secs: 0,
+                ..config()
+            },
+        );
+        let report = gc.run(false).await.unwrap();
+        assert_eq!(report.collected_logs, [format!("{A}-path.drv")]);
+        assert!(storage.get_log(&format!("{A}-path.drv")).await.is_err());
+        assert!(storage.get_log(&format!("{B}-path.drv")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn realisations_follow_their_outputs() {
+        let (_dir, storage) = setup().await;
+        let ids = ["sha256:aa!out", "sha256:bb!out"];
+        for (id, hash) in ids.iter().zip([A, B]) {
+            let realisation = Realisation {
+                id: id.to_string(),
+                out_path: format!("{hash}-path"),
+                signatures: Vec::new(),
+                dependent_realisations: Default::default(),
+            };
+            storage
+                .put_realisation(id, realisation.to_json())
+                .await
+                .unwrap();
+        }
+        let gc = Gc::new(
+            storage.clone(),
+            GcConfig {
+                pinned: vec![B.to_owned()],
+                ..config()
+            },
+        );
+        let report = gc.run(false).await.unwrap();
+        assert_eq!(report.collected_realisations, [ids[0]]);
+        assert!(storage.get_realisation(ids[0]).await.is_err());
+        assert!(storage.get_realisation(ids[1]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn debuginfo_follows_its_nar() {
+        let (_dir, storage) = setup().await;
+        for (build_id, hash) in [("aa01", C), ("bb02", D)] {
+            let link = DebugInfoLink::new(
+                &format!("nar/{hash}.nar"),
+                "lib/debug/.build-id/aa/01.debug".to_owned(),
+            );
+            storage
+                .put_debuginfo(build_id, serde_json::to_string(&link).unwrap())
+                .await
+                .unwrap();
+        }
+        let gc = Gc::new(
+            storage.clone(),
+            GcConfig {
+                pinned: vec![C.to_owned()],
+                ..config()
+            },
+        );
+        let report = gc.run(false).await.unwrap();
+        assert_eq!(report.collected_debuginfo, ["bb02"]);
+        assert!(storage.get_debuginfo("aa01").await.is_ok());
+        assert!(storage.get_debuginfo("bb02").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn young_paths_survive() {
+        let (_dir, storage) = setup().await;
+        let gc = Gc::new(storage, GcConfig::default());
+        let report = gc.run(true).await.unwrap();
+        assert!(report.collected.is_empty());
+        assert!(report.orphaned_nars.is_empty());
+    }
+}
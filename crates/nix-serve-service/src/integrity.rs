@@ -0,0 +1,530 @@
+//! Checks that what ends up in the cache is what the narinfo claims it is, so a NAR
+//! cut short by a dying uploader is never served.
+
+use futures::{ready, Stream, StreamExt};
+use sha2::{Digest as _, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncRead, BufReader, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::io::StreamReader;
+use tracing::debug;
+
+use common::hash::{Hash, HashAlgo};
+use common::narinfo::{Compression, NarInfo};
+use common::nixbase32;
+
+use crate::compression;
+use crate::nar::{self, Listing, NarError};
+use crate::storage::{ByteStream, NixCacheStorage, StorageError};
+
+/// How long the digests of an uploaded NAR are kept for its narinfo to be checked against.
+const KEEP_UPLOADED: Duration = Duration::from_secs(15 * 60);
+
+/// Most uploads whose digests are kept at once.
+const MAX_UPLOADED: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum IntegrityError {
+    #[error("{field} mismatch: expected {expected}, got {actual}")]
+    Mismatch {
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+    #[error("URL {0:?} does not point into nar/")]
+    BadUrl(String),
+    #[error("NAR {0} has not been uploaded")]
+    MissingNar(String),
+    #[error("unsupported compression {0}")]
+    UnsupportedCompression(String),
+    #[error("unsupported hash algorithm {0}")]
+    UnsupportedHash(&'static str),
+    #[error("could not read NAR: {0}")]
+    Read(io::Error),
+    #[error(transparent)]
+    Storage(StorageError),
+}
+
+impl IntegrityError {
+    /// Finds an integrity failure that a verifying stream smuggled through the storage
+    /// backend as an IO error.
+    pub fn from_storage(e: &StorageError) -> Option<&IntegrityError> {
+        match e {
+            StorageError::Io(e) => e.get_ref()?.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// sha256 and length of some bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    pub hash: Hash,
+    pub size: u64,
+}
+
+#[derive(Default)]
+struct Hasher {
+    sha256: Sha256,
+    size: u64,
+}
+
+impl Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.sha256.update(bytes);
+        self.size += bytes.len() as u64;
+    }
+
+    fn finish(&mut self) -> Digest {
+        let digest = self.sha256.finalize_reset().to_vec();
+        Digest {
+            hash: Hash::new(HashAlgo::Sha256, digest).expect("sha256 digest has the right length"),
+            size: std::mem::take(&mut self.size),
+        }
+    }
+}
+
+fn check<T: PartialEq + ToString>(
+    field: &'static str,
+    expected: &T,
+    actual: &T,
+) -> Result<(), IntegrityError> {
+    if expected != actual {
+        return Err(IntegrityError::Mismatch {
+            field,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// The file hash a NAR is named after: Nix uploads `nar/<nix32 sha256>.nar<ext>`.
+pub fn file_hash_from_name(file: &str) -> Option<Hash> {
+    let stem = file.split('.').next()?;
+    let digest = nixbase32::decode(stem, HashAlgo::Sha256.digest_len())?;
+    Hash::new(HashAlgo::Sha256, digest).ok()
+}
+
+/// Passes an upload through while hashing it. If the result doesn't match `expected`,
+/// the stream ends in an error instead of finishing, which makes the storage backends
+/// throw the upload away rather than commit it.
+pub fn verify_upload(content: ByteStream, expected: Hash) -> ByteStream {
+    VerifyingStream {
+        inner: content,
+        hasher: Hasher::default(),
+        expected,
+        finished: false,
+    }
+    .boxed()
+}
+
+struct VerifyingStream {
+    inner: ByteStream,
+    hasher: Hasher,
+    expected: Hash,
+    finished: bool,
+}
+
+impl Stream for VerifyingStream {
+    type Item = io::Result<bytes::Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        match ready!(self.inner.poll_next_unpin(cx)) {
+            Some(Ok(chunk)) => {
+                self.hasher.update(&chunk);
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Some(Err(e)) => Poll::Ready(Some(Err(e))),
+            None => {
+                self.finished = true;
+                let actual = self.hasher.finish().hash;
+                Poll::Ready(
+                    check("FileHash", &self.expected, &actual)
+                        .err()
+                        .map(|e| Err(io::Error::new(io::ErrorKind::InvalidData, e))),
+                )
+            }
+        }
+    }
+}
+
+/// Hashes whatever is read through it, and remembers whether reading failed.
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Hasher,
+    failed: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        if let Err(e) = ready!(Pin::new(&mut this.inner).poll_read(cx, buf)) {
+            this.failed = true;
+            return Poll::Ready(Err(e));
+        }
+        this.hasher.update(&buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Passes `content` through while hashing it. Once the stream has ended,
+/// [`StreamDigest::finish`] gives the digest of what went through.
+pub fn digest_stream(content: ByteStream) -> (ByteStream, StreamDigest) {
+    let hasher = Arc::new(Mutex::new(Hasher::default()));
+    let digest = StreamDigest(hasher.clone());
+    let content = content
+        .inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                hasher.lock().unwrap().update(chunk);
+            }
+        })
+        .boxed();
+    (content, digest)
+}
+
+pub struct StreamDigest(Arc<Mutex<Hasher>>);
+
+impl StreamDigest {
+    pub fn finish(&self) -> Digest {
+        self.0.lock().unwrap().finish()
+    }
+}
+
+/// Hashes a (possibly compressed) NAR, returning the digests of the file as stored and
+/// of the NAR inside it, and its listing unless it doesn't parse as a NAR.
+pub async fn hash_nar(
+    content: ByteStream,
+    compression: &Compression,
+) -> Result<(Digest, Digest, Option<Listing>), IntegrityError> {
+    let mut file_hasher = Hasher::default();
+    let mut nar_hasher = Hasher::default();
+    let listing = {
+        let file = HashingReader {
+            inner: StreamReader::new(content),
+            hasher: &mut file_hasher,
+            failed: false,
+        };
+        let nar = compression::decoder(compression, BufReader::new(file))
+            .map_err(|_| IntegrityError::UnsupportedCompression(compression.to_string()))?;
+        let mut nar = HashingReader {
+            inner: nar,
+            hasher: &mut nar_hasher,
+            failed: false,
+        };
+        let listing = match nar::list(&mut nar).await {
+            Ok(listing) => Some(listing),
+            Err(NarError::Io(e)) if nar.failed => return Err(IntegrityError::Read(e)),
+            Err(e) => {
+                debug!(error = %e, "Not listing NAR");
+                None
+            }
+        };
+        // Hash whatever follows the root node too, so trailing garbage is caught.
+        tokio::io::copy(&mut nar, &mut tokio::io::sink())
+            .await
+            .map_err(IntegrityError::Read)?;
+        listing
+    };
+    Ok((file_hasher.finish(), nar_hasher.finish(), listing))
+}
+
+/// Hashes a NAR as it is uploaded, without holding the upload up. Returns the stream to
+/// store and a task yielding what it found once the stream has been consumed and dropped.
+pub fn hash_upload(
+    content: ByteStream,
+    compression: Compression,
+) -> (ByteStream, JoinHandle<Result<Uploaded, IntegrityError>>) {
+    let (sender, receiver) = mpsc::channel::<io::Result<bytes::Bytes>>(16);
+    let copies = futures::stream::unfold(receiver, |mut receiver| async {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+    let hashing = tokio::spawn(async move {
+        let (file, nar, listing) = hash_nar(copies.boxed(), &compression).await?;
+        Ok(Uploaded {
+            compression,
+            file,
+            nar,
+            listing,
+        })
+    });
+    let content = content
+        .then(move |chunk| {
+            let sender = sender.clone();
+            async move {
+                if let Ok(bytes) = &chunk {
+                    // Hashing stops early on what isn't a NAR; the upload goes on.
+                    let _ = sender.send(Ok(bytes.clone())).await;
+                }
+                chunk
+            }
+        })
+        .boxed();
+    (content, hashing)
+}
+
+/// What hashing an upload found.
+#[derive(Debug, Clone)]
+pub struct Uploaded {
+    pub compression: Compression,
+    pub file: Digest,
+    pub nar: Digest,
+    pub listing: Option<Listing>,
+}
+
+/// The digests of recently uploaded NARs, by file name, so their narinfo can be checked
+/// without reading them back from storage. Only NARs named after their file hash are
+/// recorded: the name then pins down the content, whoever uploads it again.
+#[derive(Default)]
+pub struct UploadedNars {
+    nars: Mutex<HashMap<String, (Instant, Arc<Uploaded>)>>,
+}
+
+impl UploadedNars {
+    pub fn record(&self, file: &str, uploaded: Uploaded) {
+        if file_hash_from_name(file) != Some(uploaded.file.hash.clone()) {
+            return;
+        }
+        let mut nars = self.nars.lock().unwrap();
+        nars.retain(|_, (time, _)| time.elapsed() < KEEP_UPLOADED);
+        if nars.len() >= MAX_UPLOADED {
+            let oldest = nars
+                .iter()
+                .min_by_key(|(_, (time, _))| *time)
+                .map(|(file, _)| file.clone());
+            if let Some(oldest) = oldest {
+                nars.remove(&oldest);
+            }
+        }
+        nars.insert(file.to_owned(), (Instant::now(), Arc::new(uploaded)));
+    }
+
+    fn get(&self, file: &str) -> Option<Arc<Uploaded>> {
+        let nars = self.nars.lock().unwrap();
+        let (time, uploaded) = nars.get(file)?;
+        (time.elapsed() < KEEP_UPLOADED).then(|| uploaded.clone())
+    }
+
+    /// Like [`verify_narinfo`], but against the digests taken when the NAR was uploaded
+    /// if there are any. Then only the NAR's size is looked up, to be sure it is still
+    /// there.
+    pub async fn verify_narinfo(
+        &self,
+        storage: &dyn NixCacheStorage,
+        info: &NarInfo,
+    ) -> Result<Option<Listing>, IntegrityError> {
+        let file = info
+            .nar_file()
+            .ok_or_else(|| IntegrityError::BadUrl(info.url.clone()))?;
+        let Some(uploaded) = self
+            .get(file)
+            .filter(|uploaded| uploaded.compression == info.compression)
+        else {
+            return verify_narinfo(storage, info).await;
+        };
+        match storage.nar_size(file).await {
+            Ok(size) => check("FileSize", &uploaded.file.size, &size)?,
+            Err(StorageError::NotFound) => return Err(IntegrityError::MissingNar(file.to_owned())),
+            Err(e) => return Err(IntegrityError::Storage(e)),
+        }
+        compare(info, &uploaded.file, &uploaded.nar)?;
+        Ok(uploaded.listing.clone())
+    }
+}
+
+fn compare(info: &NarInfo, file: &Digest, nar: &Digest) -> Result<(), IntegrityError> {
+    if let Some(file_hash) = &info.file_hash {
+        check("FileHash", file_hash, &file.hash)?;
+    }
+    if let Some(file_size) = &info.file_size {
+        check("FileSize", file_size, &file.size)?;
+    }
+    check("NarHash", &info.nar_hash, &nar.hash)?;
+    check("NarSize", &info.nar_size, &nar.size)
+}
+
+/// Re-hashes the stored NAR a narinfo points at and checks `FileHash`, `FileSize`,
+/// `NarHash` and `NarSize` against it. Returns the listing of the NAR, read on the way.
+pub async fn verify_narinfo(
+    storage: &dyn NixCacheStorage,
+    info: &NarInfo,
+) -> Result<Option<Listing>, IntegrityError> {
+    let file = info
+        .nar_file()
+        .ok_or_else(|| IntegrityError::BadUrl(info.url.clone()))?;
+    for hash in info.file_hash.iter().chain([&info.nar_hash]) {
+        if hash.algo() != HashAlgo::Sha256 {
+            return Err(IntegrityError::UnsupportedHash(hash.algo().name()));
+        }
+    }
+
+    let content = match storage.get_nar(file, None).await {
+        Ok(content) => content,
+        Err(StorageError::NotFound) => return Err(IntegrityError::MissingNar(file.to_owned())),
+        Err(e) => return Err(IntegrityError::Storage(e)),
+    };
+    let (file_digest, nar_digest, listing) = hash_nar(content, &info.compression).await?;
+    compare(info, &file_digest, &nar_digest)?;
+    Ok(listing)
+}
+
+#[cfg(test)]
+pub(crate) fn sha256(bytes: &[u8]) -> Hash {
+    Hash::new(HashAlgo::Sha256, Sha256::digest(bytes).to_vec()).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_compression::tokio::bufread::XzEncoder;
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use tokio::io::AsyncReadExt;
+
+    fn stream_of(bytes: Vec<u8>) -> ByteStream {
+        futures::stream::iter(
+            bytes
+                .chunks(1000)
+                .map(|c| Ok(Bytes::copy_from_slice(c)))
+                .collect::<Vec<_>>(),
+        )
+        .boxed()
+    }
+
+    #[tokio::test]
+    async fn hashes_compressed_nar() {
+        let nar: Vec<u8> = (0..50_000u32).map(|i| (i % 7) as u8).collect();
+        let mut xz = Vec::new();
+        XzEncoder::new(&nar[..]).read_to_end(&mut xz).await.unwrap();
+
+        let (file, inner, listing) = hash_nar(stream_of(xz.clone()), &Compression::Xz)
+            .await
+            .unwrap();
+        assert_eq!(
+            file,
+            Digest {
+                hash: sha256(&xz),
+                size: xz.len() as u64
+            }
+        );
+        assert_eq!(
+            inner,
+            Digest {
+                hash: sha256(&nar),
+                size: nar.len() as u64
+            }
+        );
+        assert!(listing.is_none());
+
+        let truncated = xz[..xz.len() / 2].to_vec();
+        assert!(hash_nar(stream_of(truncated), &Compression::Xz)
+            .await
+            .is_err());
+
+        let nar = crate::nar::test::sample_nar();
+        let (_, inner, listing) = hash_nar(stream_of(nar.clone()), &Compression::None)
+            .await
+            .unwrap();
+        assert_eq!(inner.hash, sha256(&nar));
+        assert_eq!(listing.unwrap().debug_info().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_mismatched_upload() {
+        let nar = b"hello".to_vec();
+        let ok: Vec<Bytes> = verify_upload(stream_of(nar.clone()), sha256(&nar))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(ok.concat(), nar);
+
+        let err = verify_upload(stream_of(b"hell".to_vec()), sha256(&nar))
+            .try_collect::<Vec<Bytes>>()
+            .await
+            .unwrap_err();
+        let err = StorageError::Io(err);
+        assert!(matches!(
+            IntegrityError::from_storage(&err),
+            Some(IntegrityError::Mismatch {
+                field: "FileHash",
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn checks_narinfo_against_upload_digests() {
+        use crate::storage::DiskStorage;
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let nar = crate::nar::test::sample_nar();
+        let file = format!("{}.nar", sha256(&nar).to_nix32());
+        let (stream, hashing) = hash_upload(stream_of(nar.clone()), Compression::None);
+        storage.put_nar(&file, stream).await.unwrap();
+        let uploaded = UploadedNars::default();
+        uploaded.record(&file, hashing.await.unwrap().unwrap());
+
+        let narinfo = |nar_hash: &Hash| -> NarInfo {
+            format!(
+                "StorePath: /nix/store/7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello\n\
+                 URL: nar/{file}\n\
+                 Compression: none\n\
+                 NarHash: {nar_hash}\n\
+                 NarSize: {}\n\
+                 References: \n",
+                nar.len()
+            )
+            .parse()
+            .unwrap()
+        };
+        // Same size, different content: only a re-read would notice.
+        let garbage = vec![0u8; nar.len()];
+        storage.put_nar(&file, stream_of(garbage)).await.unwrap();
+        let listing = uploaded
+            .verify_narinfo(&storage, &narinfo(&sha256(&nar)))
+            .await
+            .unwrap();
+        assert_eq!(listing.unwrap().debug_info().len(), 1);
+        assert!(matches!(
+            uploaded
+                .verify_narinfo(&storage, &narinfo(&sha256(b"other")))
+                .await,
+            Err(IntegrityError::Mismatch {
+                field: "NarHash",
+                ..
+            })
+        ));
+
+        storage.delete_nar(&file).await.unwrap();
+        assert!(matches!(
+            uploaded
+                .verify_narinfo(&storage, &narinfo(&sha256(&nar)))
+                .await,
+            Err(IntegrityError::MissingNar(_))
+        ));
+    }
+
+    #[test]
+    fn file_hash_from_nar_name() {
+        let hash = sha256(b"");
+        let name = format!("{}.nar.xz", hash.to_nix32());
+        assert_eq!(file_hash_from_name(&name), Some(hash));
+        assert_eq!(file_hash_from_name("abc.nar"), None);
+    }
+}
@@ -0,0 +1,215 @@
+//! The builder keys uploads are checked against: the configured ones, plus short-lived
+//! keys registered through `/admin/builder-keys` for a single build job. A registered
+//! key stops being trusted when it expires or is revoked, so a leaked job can't sign
+//! uploads for long.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::signing::{KeyError, PublicKey};
+use crate::storage::{NixCacheStorage, StorageError};
+
+/// Name of the document keeping registered keys across restarts.
+const KEYS_FILE: &str = "builder-keys.json";
+
+#[derive(Debug, Error)]
+pub enum KeyringError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("corrupt builder key registrations: {0}")]
+    State(#[from] serde_json::Error),
+    #[error("invalid key: {0}")]
+    Key(#[from] KeyError),
+    #[error("a configured builder key is already called {0}")]
+    Configured(String),
+}
+
+/// A key registered for a build job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Registration {
+    /// `name:base64`, as `nix key convert-secret-to-public` prints it.
+    pub key: String,
+    pub expires: DateTime<Utc>,
+    /// The token that registered it.
+    pub token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyList {
+    pub configured: Vec<String>,
+    pub registered: BTreeMap<String, Registration>,
+}
+
+pub struct Keyring {
+    configured: Vec<PublicKey>,
+    /// The longest a registered key is trusted for.
+    ttl: Duration,
+    /// Registered keys by name, with what they parsed to.
+    registered: Mutex<BTreeMap<String, (Registration, PublicKey)>>,
+    /// Where registrations are kept, once attached.
+    storage: OnceLock<Arc<dyn NixCacheStorage>>,
+}
+
+impl Keyring {
+    pub fn new(configured: Vec<PublicKey>, ttl: Duration) -> Self {
+        Keyring {
+            configured,
+            ttl,
+            registered: Mutex::new(BTreeMap::new()),
+            storage: OnceLock::new(),
+        }
+    }
+
+    /// Keeps registrations in `storage` from now on, and trusts the unexpired ones
+    /// kept there before.
+    pub async fn attach(&self, storage: Arc<dyn NixCacheStorage>) -> Result<(), KeyringError> {
+        let saved: BTreeMap<String, Registration> = match storage.get_meta(KEYS_FILE).await {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(StorageError::NotFound) => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let now = Utc::now();
+        let mut registered = self.registered.lock().unwrap();
+        for (name, registration) in saved {
+            if registration.expires > now {
+                let key = PublicKey::parse(&registration.key)?;
+                registered.entry(name).or_insert((registration, key));
+            }
+        }
+        drop(registered);
+        let _ = self.storage.set(storage);
+        Ok(())
+    }
+
+    /// Every key currently trusted to sign uploads.
+    pub fn keys(&self) -> Vec<PublicKey> {
+        let now = Utc::now();
+        let registered = self.registered.lock().unwrap();
+        let live = registered
+            .values()
+            .filter(|(registration, _)| registration.expires > now)
+            .map(|(_, key)| key.clone());
+        self.configured.iter().cloned().chain(live).collect()
+    }
+
+    pub fn list(&self) -> KeyList {
+        let now = Utc::now();
+        KeyList {
+            configured: self.configured.iter().map(ToString::to_string).collect(),
+            registered: self
+                .registered
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, (registration, _))| registration.expires > now)
+                .map(|(name, (registration, _))| (name.clone(), registration.clone()))
+                .collect(),
+        }
+    }
+
+    /// Trusts `key` for `ttl`, or the configured maximum, replacing a registered key of
+    /// the same name. Returns whether it is new.
+    pub async fn register(
+        &self,
+        key: &str,
+        ttl: Option<Duration>,
+        token: &str,
+    ) -> Result<bool, KeyringError> {
+        let ttl = ttl.map_or(self.ttl, |ttl| ttl.min(self.ttl));
+        let parsed = PublicKey::parse(key)?;
+        let name = parsed.name().to_owned();
+        if self.configured.iter().any(|key| key.name() == name) {
+            return Err(KeyringError::Configured(name));
+        }
+        let registration = Registration {
+            key: parsed.to_string(),
+            expires: Utc::now() + ttl,
+            token: token.to_owned(),
+        };
+        let added = {
+            let mut registered = self.registered.lock().unwrap();
+            let now = Utc::now();
+            registered.retain(|_, (registration, _)| registration.expires > now);
+            registered.insert(name, (registration, parsed)).is_none()
+        };
+        self.save().await?;
+        Ok(added)
+    }
+
+    /// Stops trusting a registered key. Returns whether there was one.
+    pub async fn revoke(&self, name: &str) -> Result<bool, KeyringError> {
+        let removed = self.registered.lock().unwrap().remove(name).is_some();
+        if removed {
+            self.save().await?;
+        }
+        Ok(removed)
+    }
+
+    async fn save(&self) -> Result<(), KeyringError> {
+        let Some(storage) = self.storage.get() else {
+            return Ok(());
+        };
+        let content = {
+            let registered = self.registered.lock().unwrap();
+            let saved: BTreeMap<&String, &Registration> = registered
+                .iter()
+                .map(|(name, (registration, _))| (name, registration))
+                .collect();
+            serde_json::to_vec(&saved)?
+        };
+        storage.put_meta(KEYS_FILE, content).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signing::test_key;
+    use crate::storage::DiskStorage;
+
+    #[tokio::test]
+    async fn registered_keys_expire_and_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn NixCacheStorage> =
+            Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let configured = test_key("builder-1", 2).public_key();
+        let day = Duration::from_secs(24 * 3600);
+        let keyring = Keyring::new(vec![configured.clone()], day);
+        keyring.attach(storage.clone()).await.unwrap();
+
+        let job = test_key("job-a", 3).public_key().to_string();
+        assert!(keyring.register(&job, None, "controller").await.unwrap());
+        assert!(!keyring.register(&job, None, "controller").await.unwrap());
+        let short_lived = test_key("job-b", 4).public_key().to_string();
+        keyring
+            .register(&short_lived, Some(Duration::ZERO), "controller")
+            .await
+            .unwrap();
+        assert!(matches!(
+            keyring
+                .register(&configured.to_string(), None, "controller")
+                .await,
+            Err(KeyringError::Configured(_))
+        ));
+        let names = |keyring: &Keyring| -> Vec<String> {
+            keyring
+                .keys()
+                .iter()
+                .map(|key| key.name().to_owned())
+                .collect()
+        };
+        assert_eq!(names(&keyring), ["builder-1", "job-a"]);
+
+        let restarted = Keyring::new(vec![configured], day);
+        restarted.attach(storage).await.unwrap();
+        assert_eq!(names(&restarted), ["builder-1", "job-a"]);
+        assert!(restarted.revoke("job-a").await.unwrap());
+        assert!(!restarted.revoke("job-a").await.unwrap());
+        assert_eq!(names(&restarted), ["builder-1"]);
+    }
+}
@@ -1,289 +1,501 @@
-use axum::{
-    body::{BodyDataStream, Bytes},
-    extract::Path,
-    http::StatusCode,
-    routing::{get, put},
-    Router,
+use nix_serve_service::{
+    app,
+    auth::{Access, Auth},
+    cluster::{self, Cluster},
+    compression::CompressionPolicy,
+    config::{Config, QuotaConfig, StorageConfig},
+    events::{Events, Publisher},
+    gc::Gc,
+    keyring::Keyring,
+    oci::Registry,
+    routes::cache_info,
+    scrub::Scrubber,
+    signing::{PublicKey, SecretKey},
+    storage::{self, CachedStorage, ChunkedStorage, DiskStorage, NarInfoCache, NixCacheStorage},
+    throttle::Throttle,
+    transfer::{self, Selection, TransferError, TransferReport, Trust},
+    upstream::Proxy,
+    usage::{AccountedStorage, Usage},
+    AppState,
 };
-
-use futures::StreamExt;
-use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::{error, info, warn, Level};
-use uuid::{self, Uuid};
-
-struct Config {
-    signing_key: String,
-    cache_dir: String,
+use uuid::Uuid;
+
+/// What all caches have in common.
+struct Shared {
+    builder_keys: Arc<Keyring>,
+    compression: Arc<CompressionPolicy>,
+    auth: Option<Arc<Auth>>,
+    events: Option<Publisher>,
+    uploads: Arc<Throttle>,
 }
 
-// TODO: What is a sensible priority value? why does the cache hav it?
-async fn get_cache_info() -> &'static str {
-    info!("Serving nix-cache-info");
-    "StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 20"
+/// What sets one cache apart from the others.
+struct CacheSettings<'a> {
+    /// `None` for the default cache.
+    name: Option<&'a str>,
+    storage: StorageConfig,
+    signing_key: Option<&'a str>,
+    access: Access,
+    priority: u32,
+    quota: QuotaConfig,
 }
 
-async fn disk_get_narinfo(Path(hash): Path<String>) -> Result<String, StatusCode> {
-    info!(hash = %hash, "Fetching narinfo");
-    match fs::read_to_string(format!("nar/{}", hash)).await {
-        Ok(content) => Ok(content),
-        Err(_) => {
-            info!(hash = %hash, "narinfo not found");
-            Err(StatusCode::NOT_FOUND)
-        }
+/// The settings of the default cache, or of the named cache `name`.
+fn cache_settings<'a>(config: &'a Config, name: Option<&'a str>) -> CacheSettings<'a> {
+    let Some(name) = name else {
+        return CacheSettings {
+            name: None,
+            storage: config.storage.clone(),
+            signing_key: config.signing_key.as_deref(),
+            access: Access {
+                anonymous_read: config.auth.anonymous_read,
+                writers: Vec::new(),
+            },
+            priority: config.priority,
+            quota: config.quota.clone(),
+        };
+    };
+    let cache = config
+        .caches
+        .iter()
+        .find(|cache| cache.name == name)
+        .unwrap_or_else(|| panic!("no cache named {name} configured"));
+    CacheSettings {
+        name: Some(&cache.name),
+        storage: cache
+            .storage
+            .clone()
+            .unwrap_or_else(|| config.storage.for_cache(&cache.name)),
+        signing_key: cache.signing_key.as_deref(),
+        access: Access {
+            anonymous_read: cache.public,
+            writers: cache.write_tokens.clone(),
+        },
+        priority: cache.priority,
+        quota: cache.quota.clone(),
     }
 }
 
-// TODO: this should be streaming
-async fn disk_get_nar(
-    Path(hash): Path<String>,
-) -> Result<impl axum::response::IntoResponse, StatusCode> {
-    info!(hash = %hash, "Fetching NAR");
-    match fs::read(format!("nar/{}", hash)).await {
-        Ok(bytes) => {
-            info!(hash = %hash, size = bytes.len(), "Successfully read NAR");
-            Ok(Bytes::from(bytes))
-        }
-        Err(_) => {
-            info!(hash = %hash, "NAR not found");
-            Err(StatusCode::NOT_FOUND)
-        }
-    }
-}
-// TODO: The NARINFO stuff should have a few things going for it
-// 1. It should verify the signature from the builders ephemeral keys - Builder-Sig: <signature>
-// 2. If that verification checks out, the cache (this service!) should sign the narinfo with the cache key - Sig: <signature>
-async fn disk_put_narinfo(Path(hash): Path<String>, body: String) -> StatusCode {
-    info!(hash = %hash, size = body.len(), "Uploading narinfo");
-    match fs::write(format!("nar/{}", hash), body).await {
-        Ok(_) => {
-            info!(hash = %hash, "Successfully wrote narinfo");
-            StatusCode::OK
-        }
-        Err(e) => {
-            error!(hash = %hash, error = %e, "Failed to write narinfo");
-            StatusCode::INTERNAL_SERVER_ERROR
-        }
+/// Opens a storage backend, deduplicated if so configured.
+async fn open_storage(config: &Config, storage_config: &StorageConfig) -> Arc<dyn NixCacheStorage> {
+    let storage = storage::from_config(storage_config)
+        .await
+        .expect("failed to set up storage backend");
+    match &config.dedup {
+        Some(dedup) => Arc::new(
+            ChunkedStorage::new(storage, dedup)
+                .await
+                .expect("failed to set up deduplicated storage"),
+        ),
+        None => storage,
     }
 }
 
-async fn disk_put_nar(
-    Path(hash): Path<String>,
-    body: axum::body::Body,
-) -> Result<StatusCode, StatusCode> {
-    warn!(hash = %hash, "Starting NAR upload");
-
-    let cache_dir = "nar"; // this is config
-    let temp_path = format!("{}/{}.{}.temp", cache_dir, hash, Uuid::new_v4());
-    let final_path = format!("{}/{}", cache_dir, hash);
-
-    // We create a temporary file and if everything goes well we yeet that into the
-    // cache.
-    let mut file = match tokio::fs::File::create(&temp_path).await {
-        Ok(file) => file,
-        Err(e) => {
-            error!(hash = %hash, error = %e, "Failed to create temp file");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+/// Sets up the storage, key, pull-through, GC and scrubbing of one cache, without
+/// starting anything in the background.
+async fn cache_state(config: &Config, shared: &Shared, cache: CacheSettings<'_>) -> AppState {
+    let storage_config = &cache.storage;
+    let storage = open_storage(config, storage_config).await;
+
+    let narinfo_cache = (config.narinfo_cache.capacity > 0)
+        .then(|| Arc::new(NarInfoCache::new(&config.narinfo_cache)));
+    let storage: Arc<dyn NixCacheStorage> = match &narinfo_cache {
+        Some(cache) => {
+            let cached = CachedStorage::new(storage, cache.clone());
+            let cached = match storage_config {
+                StorageConfig::Disk { cache_dir } => {
+                    // Only written to on the first upload, but watched from the start.
+                    std::fs::create_dir_all(cache_dir)
+                        .expect("failed to create the cache directory");
+                    cached
+                        .watch(cache_dir.as_ref())
+                        .expect("failed to watch the cache directory")
+                }
+                StorageConfig::S3(_) => cached,
+            };
+            Arc::new(cached)
         }
+        None => storage,
     };
+    let usage = Arc::new(Usage::new(storage.clone(), cache.quota));
+    let storage: Arc<dyn NixCacheStorage> = Arc::new(AccountedStorage::new(storage, usage.clone()));
+
+    let cache_key = cache.signing_key.map(|path| {
+        let key = SecretKey::from_file(path).expect("failed to load signing key");
+        info!(public_key = %key.public_key(), "Signing narinfo with cache key");
+        Arc::new(key)
+    });
+    if cache_key.is_none() {
+        warn!("No signing key configured, narinfo will be served unsigned");
+    }
 
-    let mut stream = body.into_data_stream();
-    while let Some(chunk_result) = stream.next().await {
-        let chunk: Bytes = match chunk_result {
-            Ok(chunk) => chunk,
-            Err(e) => {
-                error!(hash = %hash, error = %e, "Failed to read chunk");
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
-            }
-        };
-
-        if let Err(e) = file.write_all(&chunk).await {
-            error!(hash = %hash, error = %e, "Failed to write chunk");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    let proxy = (!config.upstreams.is_empty()).then(|| {
+        for upstream in &config.upstreams {
+            info!(upstream = %upstream.url, "Pulling misses through from upstream");
         }
+        Arc::new(
+            Proxy::new(&config.upstreams, storage.clone(), cache_key.clone())
+                .expect("failed to set up upstream caches"),
+        )
+    });
+
+    let events = Arc::new(
+        shared
+            .events
+            .as_ref()
+            .map_or_else(Events::default, |publisher| publisher.for_cache(cache.name)),
+    );
+    let mut gc = Gc::new(storage.clone(), config.gc.clone()).with_events(events.clone());
+    let mut scrub =
+        Scrubber::new(storage.clone(), config.scrub.clone()).with_events(events.clone());
+    if config.cluster.is_some() {
+        gc = gc.in_cluster();
+        scrub = scrub.in_cluster();
     }
 
-    if let Err(e) = tokio::fs::rename(temp_path, final_path).await {
-        error!(hash = %hash, error = %e, "Failed to rename temp file");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    AppState {
+        gc: Arc::new(gc),
+        scrub: Arc::new(scrub),
+        oci: Arc::new(Registry::new(storage.clone())),
+        storage,
+        cache_key,
+        builder_keys: shared.builder_keys.clone(),
+        narinfo_cache,
+        proxy,
+        compression: shared.compression.clone(),
+        auth: shared.auth.clone(),
+        access: Arc::new(cache.access),
+        priority: cache.priority,
+        usage,
+        index_debug_info: config.index_debug_info,
+        events,
+        uploads: shared.uploads.clone(),
+        uploaded: Default::default(),
     }
-
-    info!(hash = %hash, "Successfully wrote NAR");
-    Ok(StatusCode::OK)
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
-    let cache_dir = "nar";
-    tokio::fs::create_dir_all(cache_dir)
-        .await
-        .expect("failed to create cache dir");
-    info!("Starting Nix cache server");
-
-    let app = Router::new()
-        .route("/nix-cache-info", get(get_cache_info))
-        .route("/:hash.narinfo", get(disk_get_narinfo))
-        .route("/:hash.narinfo", put(disk_put_narinfo))
-        .route("/nar/:hash.nar", get(disk_get_nar))
-        .route("/nar/:hash.nar", put(disk_put_nar));
-
-    let addr = "0.0.0.0:3000";
-    info!("Listening on {}", addr);
+/// Sets up a cache to be served: clears what interrupted writes left behind, then
+/// starts its usage counting, GC and scrubbing.
+async fn start_cache(config: &Config, shared: &Shared, cache: CacheSettings<'_>) -> AppState {
+    match storage::remove_temp_files(&cache.storage).await {
+        Ok(0) => {}
+        Ok(removed) => info!(removed, "Removed temporary files of interrupted writes"),
+        Err(e) => warn!(error = %e, "Failed to remove temporary files of interrupted writes"),
+    }
+    let state = cache_state(config, shared, cache).await;
+    tokio::spawn(state.usage.clone().background());
+    tokio::spawn(state.gc.clone().background());
+    tokio::spawn(state.scrub.clone().background());
+    state
+}
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+async fn connect_events(config: &Config) -> Option<Publisher> {
+    let url = config.nats_url.as_ref()?;
+    info!(nats_url = %url, "Publishing cache events to NATS");
+    Some(
+        Publisher::connect(url)
+            .await
+            .expect("failed to set up NATS connection"),
+    )
 }
 
-impl NixCacheStorage for DiskStorage {
-    async fn get_narinfo(&self, hash: &str) -> Result<String, StatusCode> {
-        disk_get_narinfo("foo"").await
-    }
+async fn serve(config: Config) {
+    info!("Starting Nix cache server");
 
-    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StatusCode> {
-        disk_put_narinfo().await
+    let builder_keys: Vec<PublicKey> = config
+        .trusted_builder_keys
+        .iter()
+        .map(|key| PublicKey::parse(key).expect("failed to parse trusted builder key"))
+        .collect();
+    if builder_keys.is_empty() {
+        warn!("No trusted builder keys configured, narinfo uploads will be rejected until one is registered");
     }
 
-    async fn get_nar(&self, hash: &str) -> Result<Bytes, StatusCode> {
-        disk_get_nar().await
+    let compression = Arc::new(
+        CompressionPolicy::from_config(&config.compression)
+            .expect("failed to parse compression settings"),
+    );
+    info!(store = %compression.store, "NAR compression");
+
+    let auth = Auth::load(&config.auth)
+        .expect("failed to load tokens")
+        .map(Arc::new);
+    if auth.is_none() {
+        warn!("No tokens configured, anyone can upload to the cache");
     }
 
-    async fn put_nar(&self, hash: &str, content: axum::body::Body) -> Result<(), StatusCode> {
-        disk_put_nar().await
+    let events = connect_events(&config).await;
+
+    let shared = Shared {
+        builder_keys: Arc::new(Keyring::new(
+            builder_keys,
+            Duration::from_secs(config.builder_key_ttl_secs),
+        )),
+        compression,
+        auth,
+        events,
+        uploads: Arc::new(Throttle::new(config.uploads.clone())),
+    };
+
+    let root = start_cache(&config, &shared, cache_settings(&config, None)).await;
+    shared
+        .builder_keys
+        .attach(root.storage.clone())
+        .await
+        .expect("failed to load registered builder keys");
+    let mut caches = Vec::new();
+    for cache in &config.caches {
+        info!(cache = %cache.name, public = cache.public, "Serving named cache");
+        let settings = cache_settings(&config, Some(&cache.name));
+        let state = start_cache(&config, &shared, settings).await;
+        caches.push((cache.name.clone(), state));
     }
-}
+    let stores = std::iter::once((String::new(), root.storage.clone()))
+        .chain(
+            caches
+                .iter()
+                .map(|(name, state)| (format!("/{name}"), state.storage.clone())),
+        )
+        .collect();
+    let app = app(root, caches);
+    let app = match config.cluster.clone() {
+        Some(cluster_config) => {
+            let cluster = Arc::new(
+                Cluster::new(cluster_config)
+                    .await
+                    .expect("failed to set up cluster"),
+            );
+            tokio::spawn(cluster.clone().background());
+            tokio::spawn(cluster.clone().repairing(stores));
+            cluster::layer(app, cluster)
+        }
+        None => app,
+    };
 
-struct DiskStorage {
-    base_dir: String
-}
+    info!("Listening on {}", config.listen_addr);
 
-trait NixCacheStorage {
-    async fn get_narinfo(&self, hash: &str) -> Result<String, StatusCode>;
-    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StatusCode>;
-    async fn get_nar(&self, hash: &str) -> Result<Bytes, StatusCode>;
-    async fn put_nar(&self, hash: &str, content: axum::body::Body) -> Result<(), StatusCode>;
+    let listener = tokio::net::TcpListener::bind(&config.listen_addr)
+        .await
+        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
-// For hetzner buckets
-struct S3Storage {
-    bucket: String,
-    base_dir: String,
-    credential: (String, String),
+const USAGE: &str = "\
+usage: nix-serve-service [serve]
+       nix-serve-service export [--cache <name>] [--closure | --all] <dir | file.tar> [<path>...]
+       nix-serve-service import [--cache <name>] [--trusted-key <key>]... [--no-check-sigs] <dir | file.tar>";
+
+/// Arguments of `export` and `import`.
+#[derive(Default)]
+struct TransferArgs {
+    cache: Option<String>,
+    closure: bool,
+    all: bool,
+    trusted_keys: Vec<String>,
+    no_check_sigs: bool,
+    /// The binary cache, then for `export` the paths to export.
+    positional: Vec<String>,
 }
 
-use axum::body::Body;
-use s3::{creds::Credentials, request::ResponseData, Bucket, Region};
-
-impl NixCacheStorage for S3Storage {
-    async fn get_narinfo(&self, hash: &str) -> Result<String, StatusCode> {
-        let region = Region::Custom {
-            region: "eu-central-1".to_owned(), // Adjust for Hetzner, wtf is it called
-            endpoint: "https://s3.eu-central-1.amazonaws.com".to_owned(), // Is config
+impl TransferArgs {
+    fn parse(command: &str, args: &[String]) -> Option<Self> {
+        let mut parsed = TransferArgs::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match (command, arg.as_str()) {
+                (_, "--cache") => parsed.cache = Some(args.next()?.clone()),
+                ("export", "--closure") => parsed.closure = true,
+                ("export", "--all") => parsed.all = true,
+                ("import", "--trusted-key") => parsed.trusted_keys.push(args.next()?.clone()),
+                ("import", "--no-check-sigs") => parsed.no_check_sigs = true,
+                (_, flag) if flag.starts_with("--") => return None,
+                _ => parsed.positional.push(arg.clone()),
+            }
+        }
+        let valid = match command {
+            // `--all` takes no paths, anything else at least one.
+            "export" if parsed.all => !parsed.closure && parsed.positional.len() == 1,
+            "export" => parsed.positional.len() > 1,
+            _ => parsed.positional.len() == 1,
         };
-
-        let credentials = Credentials::new(
-            Some(&self.credential.0),
-            Some(&self.credential.1),
-            None,
-            None,
-            None,
-        )
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        let bucket = Bucket::new(&self.bucket, region, credentials)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        let path = format!("{}/{}.narinfo", self.base_dir, hash);
-
-        let (data, _) = bucket
-            .get_object(&path)
-            .await
-            .map_err(|_| StatusCode::NOT_FOUND)?;
-
-        String::from_utf8(data).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+        valid.then_some(parsed)
     }
+}
 
-    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StatusCode> {
-        let region = Region::Custom {
-            region: "eu-central-1".to_owned(),
-            endpoint: "https://s3.eu-central-1.amazonaws.com".to_owned(),
-        };
-
-        let credentials = Credentials::new(
-            Some(&self.credential.0),
-            Some(&self.credential.1),
-            None,
-            None,
-            None,
-        )
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        let bucket = Bucket::new(&self.bucket, region, credentials)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        let path = format!("{}/{}.narinfo", self.base_dir, hash);
+fn is_tarball(path: &str) -> bool {
+    path.ends_with(".tar")
+}
 
-        bucket
-            .put_object(&path, content.as_bytes())
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// A directory next to `tarball` to unpack into or pack from.
+fn staging_dir(tarball: &str) -> PathBuf {
+    PathBuf::from(format!("{tarball}.{}.temp", Uuid::new_v4()))
+}
 
-        Ok(())
+fn finish(report: &TransferReport) -> ExitCode {
+    info!(
+        copied = report.copied,
+        present = report.present,
+        failed = report.failed.len(),
+        "Transfer finished"
+    );
+    println!(
+        "{}",
+        serde_json::to_string(report).expect("serialising can't fail")
+    );
+    if report.is_success() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
     }
+}
 
-    async fn get_nar(&self, hash: &str) -> Result<Bytes, StatusCode> {
-        let region = Region::Custom {
-            region: "eu-central-1".to_owned(),
-            endpoint: "https://s3.eu-central-1.amazonaws.com".to_owned(),
-        };
-
-        let credentials = Credentials::new(
-            Some(&self.credential.0),
-            Some(&self.credential.1),
-            None,
-            None,
-            None,
-        )
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        let bucket = Bucket::new(&self.bucket, region, credentials)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        let path = format!("{}/nar/{}.nar", self.base_dir, hash);
-
-        let ResponseData(data, _) = bucket
-            .get_object(&path)
-            .await
-            .map_err(|_| StatusCode::NOT_FOUND)?;
+async fn export(config: &Config, args: TransferArgs) -> ExitCode {
+    let cache = cache_settings(config, args.cache.as_deref());
+    let priority = cache.priority;
+    let storage = open_storage(config, &cache.storage).await;
+    let (target, paths) = args.positional.split_first().expect("checked by parse");
+    let selection = if args.all {
+        Selection::Everything
+    } else if args.closure {
+        Selection::Closures(paths.to_vec())
+    } else {
+        Selection::Paths(paths.to_vec())
+    };
+    let dir = if is_tarball(target) {
+        staging_dir(target)
+    } else {
+        PathBuf::from(target)
+    };
 
-        Ok(Bytes::from(data))
+    let exported = async {
+        let disk = DiskStorage::new(&dir).await?;
+        tokio::fs::write(dir.join("nix-cache-info"), cache_info(priority)).await?;
+        let report = transfer::export(storage.as_ref(), &disk, &selection).await?;
+        if is_tarball(target) {
+            transfer::pack(dir.clone(), target.into()).await?;
+        }
+        Ok::<_, TransferError>(report)
     }
+    .await;
+    if is_tarball(target) {
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+    match exported {
+        Ok(report) => finish(&report),
+        Err(e) => {
+            error!(target = %target, error = %e, "Export failed");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    async fn put_nar(&self, hash: &str, content: Body) -> Result<(), StatusCode> {
-        let region = Region::Custom {
-            region: "eu-central-1".to_owned(),
-            endpoint: "https://s3.eu-central-1.amazonaws.com".to_owned(),
-        };
-
-        let credentials = Credentials::new(
-            Some(&self.credential.0),
-            Some(&self.credential.1),
-            None,
-            None,
-            None,
-        )
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        let bucket = Bucket::new(&self.bucket, region, credentials)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+async fn import(config: &Config, args: TransferArgs) -> ExitCode {
+    let cache = cache_settings(config, args.cache.as_deref());
+    let cache_key = cache
+        .signing_key
+        .map(|path| SecretKey::from_file(path).expect("failed to load signing key"));
+    let keys = args
+        .trusted_keys
+        .iter()
+        .chain(&config.trusted_builder_keys)
+        .map(|key| PublicKey::parse(key).expect("failed to parse trusted key"))
+        .chain(cache_key.as_ref().map(SecretKey::public_key))
+        .collect();
+    let trust = Trust {
+        keys,
+        check_signatures: !args.no_check_sigs,
+        cache_key,
+    };
+    let source = &args.positional[0];
+    let dir = if is_tarball(source) {
+        staging_dir(source)
+    } else {
+        PathBuf::from(source)
+    };
 
-        let path = format!("{}/{}.narinfo", self.base_dir, hash);
+    // Stored like uploads to the running cache would be, except that nothing runs in
+    // the background and temporary files are left to the server.
+    let shared = Shared {
+        builder_keys: Arc::new(Keyring::new(Vec::new(), Duration::ZERO)),
+        compression: Arc::new(
+            CompressionPolicy::from_config(&config.compression)
+                .expect("failed to parse compression settings"),
+        ),
+        auth: None,
+        events: connect_events(config).await,
+        uploads: Default::default(),
+    };
+    let state = cache_state(config, &shared, cache).await;
+    if let Err(e) = state.usage.refresh().await {
+        warn!(error = %e, "Failed to load upload records, imported paths won't be attributed");
+    }
 
-        bucket
-            .put_object(&path, content.as_bytes())
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let imported = async {
+        if is_tarball(source) {
+            transfer::unpack(source.into(), dir.clone()).await?;
+        } else if !tokio::fs::try_exists(dir.join("nix-cache-info")).await? {
+            warn!(source = %source, "No nix-cache-info, this may not be a binary cache");
+        }
+        let disk = DiskStorage::new(&dir).await?;
+        transfer::import(&disk, &state, &trust).await
+    }
+    .await;
+    if let Err(e) = state.usage.save().await {
+        error!(error = %e, "Failed to write upload records");
+    }
+    state.events.flush().await;
+    if is_tarball(source) {
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+    match imported {
+        Ok(report) => finish(&report),
+        Err(e) => {
+            error!(source = %source, error = %e, "Import failed");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-        Ok(())
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::INFO)
+        .with_writer(std::io::stderr)
+        .init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = args.first().map_or("serve", String::as_str);
+    let transfer_args = match command {
+        "serve" if args.len() <= 1 => None,
+        "export" | "import" => match TransferArgs::parse(command, &args[1..]) {
+            Some(parsed) => Some(parsed),
+            None => {
+                eprintln!("{USAGE}");
+                return ExitCode::from(2);
+            }
+        },
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let config = Config::load().expect("failed to load config");
+    match transfer_args {
+        None => {
+            serve(config).await;
+            ExitCode::SUCCESS
+        }
+        Some(args) if command == "export" => export(&config, args).await,
+        Some(args) => import(&config, args).await,
     }
 }
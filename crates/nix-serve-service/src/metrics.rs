@@ -0,0 +1,207 @@
+//! `/metrics` in the Prometheus text format.
+
+use axum::{extract::State, http::header, response::IntoResponse};
+use std::fmt::Write;
+
+use crate::scrub::Problem;
+use crate::AppState;
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// Escapes a label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut out = String::new();
+    if let Some(cache) = &state.narinfo_cache {
+        let stats = cache.stats();
+        metric(
+            &mut out,
+            "nix_serve_narinfo_cache_hits_total",
+            "counter",
+            "narinfo lookups served from memory.",
+            stats.hits,
+        );
+        metric(
+            &mut out,
+            "nix_serve_narinfo_cache_negative_hits_total",
+            "counter",
+            "narinfo lookups answered by a remembered miss.",
+            stats.negative_hits,
+        );
+        metric(
+            &mut out,
+            "nix_serve_narinfo_cache_misses_total",
+            "counter",
+            "narinfo lookups that went to the storage backend.",
+            stats.misses,
+        );
+        metric(
+            &mut out,
+            "nix_serve_narinfo_cache_invalidations_total",
+            "counter",
+            "narinfo cache entries dropped because the narinfo changed.",
+            stats.invalidations,
+        );
+        metric(
+            &mut out,
+            "nix_serve_narinfo_cache_entries",
+            "gauge",
+            "narinfo and misses currently cached.",
+            stats.entries,
+        );
+    }
+
+    let totals = state.usage.totals();
+    metric(
+        &mut out,
+        "nix_serve_stored_bytes",
+        "gauge",
+        "Bytes of NARs in the cache.",
+        totals.bytes,
+    );
+    metric(
+        &mut out,
+        "nix_serve_stored_paths",
+        "gauge",
+        "Store paths in the cache.",
+        totals.paths,
+    );
+    if let Some(max_bytes) = state.usage.quota().max_bytes {
+        metric(
+            &mut out,
+            "nix_serve_quota_bytes",
+            "gauge",
+            "Bytes of NARs the cache may hold.",
+            max_bytes,
+        );
+    }
+    if let Some(max_paths) = state.usage.quota().max_paths {
+        metric(
+            &mut out,
+            "nix_serve_quota_paths",
+            "gauge",
+            "Store paths the cache may hold.",
+            max_paths,
+        );
+    }
+    metric(
+        &mut out,
+        "nix_serve_quota_rejections_total",
+        "counter",
+        "Uploads refused for going over a quota.",
+        state.usage.rejections(),
+    );
+    let tokens = state.usage.token_totals();
+    if !tokens.is_empty() {
+        let _ = writeln!(
+            out,
+            "# HELP nix_serve_token_stored_bytes Bytes of NARs in the cache by uploading token."
+        );
+        let _ = writeln!(out, "# TYPE nix_serve_token_stored_bytes gauge");
+        for (token, totals) in &tokens {
+            let _ = writeln!(
+                out,
+                "nix_serve_token_stored_bytes{{token=\"{}\"}} {}",
+                label(token),
+                totals.bytes
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP nix_serve_token_stored_paths Store paths in the cache by uploading token."
+        );
+        let _ = writeln!(out, "# TYPE nix_serve_token_stored_paths gauge");
+        for (token, totals) in &tokens {
+            let _ = writeln!(
+                out,
+                "nix_serve_token_stored_paths{{token=\"{}\"}} {}",
+                label(token),
+                totals.paths
+            );
+        }
+    }
+
+    metric(
+        &mut out,
+        "nix_serve_uploads_in_flight",
+        "gauge",
+        "Uploads being received, across all caches.",
+        state.uploads.in_flight(),
+    );
+    metric(
+        &mut out,
+        "nix_serve_uploads_throttled_total",
+        "counter",
+        "Uploads turned away with 429 for going over a concurrency limit.",
+        state.uploads.rejections(),
+    );
+
+    let scrub = state.scrub.stats();
+    metric(
+        &mut out,
+        "nix_serve_scrub_runs_total",
+        "counter",
+        "Scrubs run since startup.",
+        scrub.runs,
+    );
+    metric(
+        &mut out,
+        "nix_serve_scrub_quarantined_total",
+        "counter",
+        "Paths quarantined by the scrubber since startup.",
+        scrub.quarantined_total,
+    );
+    metric(
+        &mut out,
+        "nix_serve_scrub_quarantined_paths",
+        "gauge",
+        "Paths currently in quarantine.",
+        scrub.quarantined,
+    );
+    if let Some(finished) = scrub.last_finished {
+        metric(
+            &mut out,
+            "nix_serve_scrub_last_run_timestamp_seconds",
+            "gauge",
+            "When the last scrub finished.",
+            finished.timestamp().max(0) as u64,
+        );
+        metric(
+            &mut out,
+            "nix_serve_scrub_paths_checked",
+            "gauge",
+            "Paths checked by the last scrub.",
+            scrub.paths_checked,
+        );
+        metric(
+            &mut out,
+            "nix_serve_scrub_bytes_checked",
+            "gauge",
+            "Bytes of NARs read by the last scrub.",
+            scrub.bytes_checked,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nix_serve_scrub_findings Problems found by the last scrub, by kind."
+        );
+        let _ = writeln!(out, "# TYPE nix_serve_scrub_findings gauge");
+        for kind in Problem::KINDS {
+            let count = scrub.findings.get(kind).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "nix_serve_scrub_findings{{problem=\"{kind}\"}} {count}"
+            );
+        }
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
@@ -0,0 +1,368 @@
+//! Reads the NAR format to list what a NAR contains, in the `.ls` JSON format Nix
+//! writes next to narinfo files.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+/// Longest file name or symlink target accepted.
+const MAX_STRING: u64 = 64 * 1024;
+
+/// Deepest directory nesting accepted.
+pub(crate) const MAX_DEPTH: usize = 512;
+
+/// Where Nix puts separate debug info, as `<2 hex digits>/<rest of build id>.debug`.
+const BUILD_ID_DIR: [&str; 3] = ["lib", "debug", ".build-id"];
+
+#[derive(Debug, Error)]
+pub enum NarError {
+    #[error("could not read NAR: {0}")]
+    Io(#[from] io::Error),
+    #[error("not a NAR: {0}")]
+    Invalid(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Node {
+    Regular {
+        size: u64,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        executable: bool,
+        /// Where the contents start in the uncompressed NAR.
+        #[serde(rename = "narOffset")]
+        nar_offset: u64,
+    },
+    Symlink {
+        target: String,
+    },
+    Directory {
+        entries: BTreeMap<String, Node>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Listing {
+    pub version: u32,
+    pub root: Node,
+}
+
+/// A separate debug info file found in a NAR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub build_id: String,
+    /// Path of the file inside the NAR, without a leading `/`.
+    pub member: String,
+}
+
+/// What `debuginfo/<build-id>` holds, as Nix writes it: the NAR, relative to
+/// `debuginfo/`, and the file in it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebugInfoLink {
+    pub archive: String,
+    pub member: String,
+}
+
+impl DebugInfoLink {
+    pub fn new(url: &str, member: String) -> Self {
+        DebugInfoLink {
+            archive: format!("../{url}"),
+            member,
+        }
+    }
+
+    /// The NAR file name, as the storage backend knows it.
+    pub fn nar_file(&self) -> Option<&str> {
+        self.archive.strip_prefix("../nar/")
+    }
+}
+
+impl Listing {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("serialising a listing can't fail")
+    }
+
+    /// The files in `lib/debug/.build-id`, as Nix indexes them for `debuginfo/`.
+    pub fn debug_info(&self) -> Vec<DebugInfo> {
+        let mut node = &self.root;
+        for name in BUILD_ID_DIR {
+            match node {
+                Node::Directory { entries } => match entries.get(name) {
+                    Some(entry) => node = entry,
+                    None => return Vec::new(),
+                },
+                _ => return Vec::new(),
+            }
+        }
+        let Node::Directory { entries } = node else {
+            return Vec::new();
+        };
+
+        let is_hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+        let mut found = Vec::new();
+        for (prefix, dir) in entries {
+            let Node::Directory { entries } = dir else {
+                continue;
+            };
+            if prefix.len() != 2 || !is_hex(prefix) {
+                continue;
+            }
+            for (file, node) in entries {
+                let Some(rest) = file.strip_suffix(".debug") else {
+                    continue;
+                };
+                if !matches!(node, Node::Regular { .. }) || rest.is_empty() || !is_hex(rest) {
+                    continue;
+                }
+                found.push(DebugInfo {
+                    build_id: format!("{prefix}{rest}"),
+                    member: format!("{}/{prefix}/{file}", BUILD_ID_DIR.join("/")),
+                });
+            }
+        }
+        found
+    }
+}
+
+/// Whether `id` looks like an ELF build id.
+pub fn is_valid_build_id(id: &str) -> bool {
+    (3..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+pub(crate) struct Reader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: AsyncRead + Unpin + Send> Reader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Reader { inner, offset: 0 }
+    }
+
+    pub(crate) async fn u64(&mut self) -> Result<u64, NarError> {
+        let n = self.inner.read_u64_le().await?;
+        self.offset += 8;
+        Ok(n)
+    }
+
+    /// Copies the next `n` bytes to `out`, e.g. the contents of a file.
+    pub(crate) async fn copy(
+        &mut self,
+        n: u64,
+        out: &mut (impl AsyncWrite + Unpin + ?Sized),
+    ) -> Result<(), NarError> {
+        let copied = tokio::io::copy(&mut (&mut self.inner).take(n), out).await?;
+        self.offset += copied;
+        if copied != n {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    async fn skip(&mut self, n: u64) -> Result<(), NarError> {
+        let skipped =
+            tokio::io::copy(&mut (&mut self.inner).take(n), &mut tokio::io::sink()).await?;
+        self.offset += skipped;
+        if skipped != n {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn skip_padding(&mut self, len: u64) -> Result<(), NarError> {
+        self.skip((8 - len % 8) % 8).await
+    }
+
+    pub(crate) async fn bytes(&mut self) -> Result<Vec<u8>, NarError> {
+        let len = self.u64().await?;
+        if len > MAX_STRING {
+            return Err(NarError::Invalid("string too long"));
+        }
+        let mut bytes = vec![0; len as usize];
+        self.inner.read_exact(&mut bytes).await?;
+        self.offset += len;
+        self.skip_padding(len).await?;
+        Ok(bytes)
+    }
+
+    pub(crate) async fn string(&mut self) -> Result<String, NarError> {
+        String::from_utf8(self.bytes().await?).map_err(|_| NarError::Invalid("name is not UTF-8"))
+    }
+
+    pub(crate) async fn expect(&mut self, tag: &'static str) -> Result<(), NarError> {
+        if self.bytes().await? != tag.as_bytes() {
+            return Err(NarError::Invalid(tag));
+        }
+        Ok(())
+    }
+
+    fn node(
+        &mut self,
+        depth: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Node, NarError>> + Send + '_>> {
+        Box::pin(async move {
+            if depth > MAX_DEPTH {
+                return Err(NarError::Invalid("nested too deeply"));
+            }
+            self.expect("(").await?;
+            self.expect("type").await?;
+            let node = match &self.bytes().await?[..] {
+                b"regular" => {
+                    let mut tag = self.bytes().await?;
+                    let executable = tag == b"executable";
+                    if executable {
+                        self.expect("").await?;
+                        tag = self.bytes().await?;
+                    }
+                    if tag != b"contents" {
+                        return Err(NarError::Invalid("contents"));
+                    }
+                    let size = self.u64().await?;
+                    let nar_offset = self.offset;
+                    self.skip(size).await?;
+                    self.skip_padding(size).await?;
+                    self.expect(")").await?;
+                    Node::Regular {
+                        size,
+                        executable,
+                        nar_offset,
+                    }
+                }
+                b"symlink" => {
+                    self.expect("target").await?;
+                    let target = self.string().await?;
+                    self.expect(")").await?;
+                    Node::Symlink { target }
+                }
+                b"directory" => {
+                    let mut entries = BTreeMap::new();
+                    loop {
+                        match &self.bytes().await?[..] {
+                            b")" => break,
+                            b"entry" => {}
+                            _ => return Err(NarError::Invalid("entry")),
+                        }
+                        self.expect("(").await?;
+                        self.expect("name").await?;
+                        let name = self.string().await?;
+                        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                            return Err(NarError::Invalid("bad file name"));
+                        }
+                        self.expect("node").await?;
+                        let node = self.node(depth + 1).await?;
+                        self.expect(")").await?;
+                        if entries.insert(name, node).is_some() {
+                            return Err(NarError::Invalid("duplicate file name"));
+                        }
+                    }
+                    Node::Directory { entries }
+                }
+                _ => return Err(NarError::Invalid("type")),
+            };
+            Ok(node)
+        })
+    }
+}
+
+/// Reads a NAR from `reader` up to the end of its root node.
+pub async fn list(reader: impl AsyncRead + Unpin + Send) -> Result<Listing, NarError> {
+    let mut reader = Reader::new(reader);
+    reader.expect("nix-archive-1").await?;
+    let root = reader.node(0).await?;
+    Ok(Listing { version: 1, root })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    fn string(nar: &mut Vec<u8>, s: &[u8]) {
+        nar.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        nar.extend_from_slice(s);
+        nar.resize(nar.len().next_multiple_of(8), 0);
+    }
+
+    fn strings(nar: &mut Vec<u8>, strings: &[&str]) {
+        for s in strings {
+            string(nar, s.as_bytes());
+        }
+    }
+
+    /// A NAR with an executable, a symlink and a debug info file.
+    pub(crate) fn sample_nar() -> Vec<u8> {
+        let mut nar = Vec::new();
+        strings(&mut nar, &["nix-archive-1", "(", "type", "directory"]);
+        strings(&mut nar, &["entry", "(", "name", "bin", "node"]);
+        strings(&mut nar, &["(", "type", "directory"]);
+        strings(&mut nar, &["entry", "(", "name", "hello", "node"]);
+        strings(
+            &mut nar,
+            &["(", "type", "regular", "executable", "", "contents"],
+        );
+        string(&mut nar, b"#!/bin/sh\necho hello\n");
+        strings(&mut nar, &[")", ")", ")", ")"]);
+        strings(&mut nar, &["entry", "(", "name", "lib", "node"]);
+        strings(&mut nar, &["(", "type", "directory"]);
+        strings(&mut nar, &["entry", "(", "name", "debug", "node"]);
+        strings(&mut nar, &["(", "type", "directory"]);
+        strings(&mut nar, &["entry", "(", "name", ".build-id", "node"]);
+        strings(&mut nar, &["(", "type", "directory"]);
+        strings(&mut nar, &["entry", "(", "name", "ab", "node"]);
+        strings(&mut nar, &["(", "type", "directory"]);
+        strings(&mut nar, &["entry", "(", "name", "cdef01.debug", "node"]);
+        strings(&mut nar, &["(", "type", "regular", "contents"]);
+        string(&mut nar, b"\x7fELF");
+        strings(
+            &mut nar,
+            &[")", ")", ")", ")", ")", ")", ")", ")", ")", ")"],
+        );
+        strings(&mut nar, &["entry", "(", "name", "sh", "node"]);
+        strings(
+            &mut nar,
+            &["(", "type", "symlink", "target", "bin/hello", ")", ")"],
+        );
+        strings(&mut nar, &[")"]);
+        nar
+    }
+
+    #[tokio::test]
+    async fn lists_nar() {
+        let nar = sample_nar();
+        let listing = list(&nar[..]).await.unwrap();
+        let json: serde_json::Value = serde_json::from_str(&listing.to_json()).unwrap();
+        let hello = &json["root"]["entries"]["bin"]["entries"]["hello"];
+        assert_eq!(hello["type"], "regular");
+        assert_eq!(hello["executable"], true);
+        assert_eq!(hello["size"], 21);
+        let offset = hello["narOffset"].as_u64().unwrap() as usize;
+        assert_eq!(&nar[offset..offset + 9], b"#!/bin/sh");
+        assert_eq!(json["root"]["entries"]["sh"]["target"], "bin/hello");
+        assert!(
+            json["root"]["entries"]["lib"]["entries"]["debug"]["entries"][".build-id"]["entries"]
+                ["ab"]["entries"]["cdef01.debug"]
+                .get("executable")
+                .is_none()
+        );
+
+        assert_eq!(
+            listing.debug_info(),
+            [DebugInfo {
+                build_id: "abcdef01".to_owned(),
+                member: "lib/debug/.build-id/ab/cdef01.debug".to_owned(),
+            }]
+        );
+
+        assert!(matches!(
+            list(&nar[..nar.len() - 20]).await,
+            Err(NarError::Io(_))
+        ));
+        assert!(matches!(
+            list(&b"not a nar at all"[..]).await,
+            Err(NarError::Invalid(_) | NarError::Io(_))
+        ));
+    }
+}
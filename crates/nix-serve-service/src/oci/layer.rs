@@ -0,0 +1,345 @@
+//! Image layers made from NARs: uncompressed tarballs that unpack to
+//! `/nix/store/<hash>-<name>`, identical every time they are made so their digest
+//! can be computed once and the layer rebuilt on every pull.
+
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::nar::{NarError, Reader, MAX_DEPTH};
+
+const BLOCK: usize = 512;
+
+/// Every entry gets the mtime Nix gives store paths.
+const MTIME: u64 = 1;
+
+/// Largest size the ustar header holds; bigger files need a PAX record.
+const MAX_USTAR_SIZE: u64 = 0o777_7777_7777;
+
+const REGULAR: u8 = b'0';
+const SYMLINK: u8 = b'2';
+const DIRECTORY: u8 = b'5';
+const PAX: u8 = b'x';
+
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{value:0width$o}");
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+fn header(name: &str, mode: u32, size: u64, kind: u8, link: &str) -> [u8; BLOCK] {
+    let mut header = [0; BLOCK];
+    let copy = |field: &mut [u8], value: &str| {
+        let len = value.len().min(field.len());
+        field[..len].copy_from_slice(&value.as_bytes()[..len]);
+    };
+    copy(&mut header[0..100], name);
+    octal(&mut header[100..108], mode.into());
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size.min(MAX_USTAR_SIZE));
+    octal(&mut header[136..148], MTIME);
+    header[148..156].fill(b' ');
+    header[156] = kind;
+    copy(&mut header[157..257], link);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    copy(&mut header[265..297], "root");
+    copy(&mut header[297..329], "root");
+    octal(&mut header[329..337], 0);
+    octal(&mut header[337..345], 0);
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    header
+}
+
+/// A PAX record, `<length> <key>=<value>\n`, where the length counts itself.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    format!("{len} {key}={value}\n")
+}
+
+fn padding(len: u64) -> usize {
+    (BLOCK - (len % BLOCK as u64) as usize) % BLOCK
+}
+
+struct TarWriter<'a, W: ?Sized> {
+    out: &'a mut W,
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> TarWriter<'_, W> {
+    async fn entry(
+        &mut self,
+        name: &str,
+        mode: u32,
+        size: u64,
+        kind: u8,
+        link: &str,
+    ) -> io::Result<()> {
+        let mut records = String::new();
+        if name.len() > 100 {
+            records.push_str(&pax_record("path", name));
+        }
+        if link.len() > 100 {
+            records.push_str(&pax_record("linkpath", link));
+        }
+        if size > MAX_USTAR_SIZE {
+            records.push_str(&pax_record("size", &size.to_string()));
+        }
+        if !records.is_empty() {
+            let len = records.len() as u64;
+            self.out
+                .write_all(&header("././@PaxHeader", 0o644, len, PAX, ""))
+                .await?;
+            self.out.write_all(records.as_bytes()).await?;
+            self.pad(len).await?;
+        }
+        self.out
+            .write_all(&header(name, mode, size, kind, link))
+            .await
+    }
+
+    async fn pad(&mut self, len: u64) -> io::Result<()> {
+        self.out.write_all(&[0; BLOCK][..padding(len)]).await
+    }
+
+    async fn directory(&mut self, name: &str) -> io::Result<()> {
+        self.entry(&format!("{name}/"), 0o555, 0, DIRECTORY, "")
+            .await
+    }
+
+    /// Writes the tree of one NAR below `name`.
+    fn node<'a, R: AsyncRead + Unpin + Send>(
+        &'a mut self,
+        nar: &'a mut Reader<R>,
+        name: String,
+        depth: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<(), NarError>> + Send + 'a>>
+    where
+        W: Send,
+    {
+        Box::pin(async move {
+            if depth > MAX_DEPTH {
+                return Err(NarError::Invalid("nested too deeply"));
+            }
+            nar.expect("(").await?;
+            nar.expect("type").await?;
+            match &nar.bytes().await?[..] {
+                b"regular" => {
+                    let mut tag = nar.bytes().await?;
+                    let executable = tag == b"executable";
+                    if executable {
+                        nar.expect("").await?;
+                        tag = nar.bytes().await?;
+                    }
+                    if tag != b"contents" {
+                        return Err(NarError::Invalid("contents"));
+                    }
+                    let size = nar.u64().await?;
+                    let mode = if executable { 0o555 } else { 0o444 };
+                    self.entry(&name, mode, size, REGULAR, "").await?;
+                    nar.copy(size, self.out).await?;
+                    self.pad(size).await?;
+                    nar.skip_padding(size).await?;
+                }
+                b"symlink" => {
+                    nar.expect("target").await?;
+                    let target = nar.string().await?;
+                    self.entry(&name, 0o777, 0, SYMLINK, &target).await?;
+                }
+                b"directory" => {
+                    self.directory(&name).await?;
+                    loop {
+                        match &nar.bytes().await?[..] {
+                            b")" => return Ok(()),
+                            b"entry" => {}
+                            _ => return Err(NarError::Invalid("entry")),
+                        }
+                        nar.expect("(").await?;
+                        nar.expect("name").await?;
+                        let entry = nar.string().await?;
+                        if entry.is_empty() || entry == "." || entry == ".." || entry.contains('/')
+                        {
+                            return Err(NarError::Invalid("bad file name"));
+                        }
+                        nar.expect("node").await?;
+                        self.node(nar, format!("{name}/{entry}"), depth + 1).await?;
+                        nar.expect(")").await?;
+                    }
+                }
+                _ => return Err(NarError::Invalid("type")),
+            }
+            nar.expect(")").await
+        })
+    }
+}
+
+/// Writes the start of a layer: the `nix/store` directories every layer carries so it
+/// unpacks on its own.
+pub async fn write_start(out: &mut (impl AsyncWrite + Unpin + Send + ?Sized)) -> io::Result<()> {
+    let mut tar = TarWriter { out };
+    tar.directory("nix").await?;
+    tar.directory("nix/store").await
+}
+
+/// Unpacks the NAR read from `nar` into the layer as `nix/store/<base_name>`.
+pub async fn write_nar(
+    out: &mut (impl AsyncWrite + Unpin + Send + ?Sized),
+    base_name: &str,
+    nar: impl AsyncRead + Unpin + Send,
+) -> Result<(), NarError> {
+    let mut nar = Reader::new(nar);
+    nar.expect("nix-archive-1").await?;
+    let mut tar = TarWriter { out };
+    tar.node(&mut nar, format!("nix/store/{base_name}"), 0)
+        .await
+}
+
+/// Writes the two empty blocks that end a tarball.
+pub async fn write_end(out: &mut (impl AsyncWrite + Unpin + Send + ?Sized)) -> io::Result<()> {
+    out.write_all(&[0; 2 * BLOCK]).await?;
+    out.flush().await
+}
+
+/// Hashes and counts what is written to it, to learn a layer's digest without keeping it.
+#[derive(Default)]
+pub struct DigestWriter {
+    sha256: Sha256,
+    size: u64,
+}
+
+impl DigestWriter {
+    /// The `sha256:<hex>` digest and size of everything written.
+    pub fn finish(self) -> (String, u64) {
+        (format!("sha256:{:x}", self.sha256.finalize()), self.size)
+    }
+}
+
+impl AsyncWrite for DigestWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.sha256.update(buf);
+        this.size += buf.len() as u64;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nar::test::sample_nar;
+
+    #[test]
+    fn pax_records_count_themselves() {
+        assert_eq!(pax_record("path", "abc"), "12 path=abc\n");
+        let long = "x".repeat(90);
+        let record = pax_record("path", &long);
+        assert_eq!(record.len().to_string(), record.split(' ').next().unwrap());
+    }
+
+    /// Reads back the entries of a tarball as (name, type, mode, link, contents).
+    fn entries(tar: &[u8]) -> Vec<(String, u8, u32, String, Vec<u8>)> {
+        let field = |block: &[u8]| {
+            let end = block.iter().position(|&b| b == 0).unwrap_or(block.len());
+            String::from_utf8(block[..end].to_vec()).unwrap()
+        };
+        let number = |block: &[u8]| u64::from_str_radix(field(block).trim(), 8).unwrap();
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        let mut long_name = None;
+        while tar[offset..offset + BLOCK].iter().any(|&b| b != 0) {
+            let header = &tar[offset..offset + BLOCK];
+            let checksum: u64 = header[..148]
+                .iter()
+                .chain(&[b' '; 8])
+                .chain(&header[156..])
+                .map(|&b| u64::from(b))
+                .sum();
+            assert_eq!(number(&header[148..156]), checksum);
+            let size = number(&header[124..136]) as usize;
+            let contents = tar[offset + BLOCK..offset + BLOCK + size].to_vec();
+            offset += BLOCK + size + padding(size as u64);
+            if header[156] == PAX {
+                let records = String::from_utf8(contents).unwrap();
+                long_name = records
+                    .split_once("path=")
+                    .map(|(_, path)| path.trim_end().to_owned());
+                continue;
+            }
+            let name = long_name.take().unwrap_or_else(|| field(&header[..100]));
+            entries.push((
+                name,
+                header[156],
+                number(&header[100..108]) as u32,
+                field(&header[157..257]),
+                contents,
+            ));
+        }
+        assert_eq!(tar.len(), offset + 2 * BLOCK);
+        entries
+    }
+
+    #[tokio::test]
+    async fn unpacks_nars_into_the_store() {
+        let base = format!("7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-{}", "hello".repeat(20));
+        let mut tar = Vec::new();
+        write_start(&mut tar).await.unwrap();
+        write_nar(&mut tar, &base, &sample_nar()[..]).await.unwrap();
+        write_end(&mut tar).await.unwrap();
+        assert_eq!(tar.len() % BLOCK, 0);
+
+        let entries = entries(&tar);
+        let names: Vec<&str> = entries.iter().map(|e| e.0.as_str()).collect();
+        assert_eq!(
+            names[..3],
+            ["nix/", "nix/store/", &format!("nix/store/{base}/")]
+        );
+        let hello = entries
+            .iter()
+            .find(|e| e.0 == format!("nix/store/{base}/bin/hello"))
+            .unwrap();
+        assert_eq!((hello.1, hello.2), (REGULAR, 0o555));
+        assert_eq!(hello.4, b"#!/bin/sh\necho hello\n");
+        let sh = entries
+            .iter()
+            .find(|e| e.0 == format!("nix/store/{base}/sh"))
+            .unwrap();
+        assert_eq!((sh.1, sh.3.as_str()), (SYMLINK, "bin/hello"));
+
+        let mut again = DigestWriter::default();
+        write_start(&mut again).await.unwrap();
+        write_nar(&mut again, &base, &sample_nar()[..])
+            .await
+            .unwrap();
+        write_end(&mut again).await.unwrap();
+        let (digest, size) = again.finish();
+        assert_eq!(size, tar.len() as u64);
+        assert_eq!(digest, format!("sha256:{:x}", Sha256::digest(&tar)));
+
+        let mut broken = Vec::new();
+        let nar = sample_nar();
+        assert!(write_nar(&mut broken, &base, &nar[..nar.len() - 30])
+            .await
+            .is_err());
+    }
+}
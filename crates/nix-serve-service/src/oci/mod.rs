@@ -0,0 +1,657 @@
+//! An OCI registry over the cache. A build publishes a small image spec naming store
+//! paths and the container config; the cache turns the closure of those paths into
+//! one layer per store path, and serves manifests, configs and layers through the
+//! OCI distribution API, making the layers from the stored NARs on every pull. The
+//! narinfo and NARs come from a [`PathSource`]: the cache's storage, or in a cluster,
+//! whichever replica holds them.
+
+pub mod layer;
+pub mod routes;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncWrite, BufReader};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{error, info};
+
+use common::narinfo::{NarInfo, NarInfoError, StorePath};
+
+use crate::compression::{self, CompressionError};
+use crate::nar::NarError;
+use crate::storage::{ByteStream, NixCacheStorage, StorageError};
+
+use layer::DigestWriter;
+
+/// Name of the image and layer index in the storage backend's meta area.
+const STATE_FILE: &str = "oci.json";
+
+/// Most layers an image gets. Past that, the least shared paths go into one layer.
+const MAX_LAYERS: usize = 100;
+
+pub const MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+
+#[derive(Debug, Error)]
+pub enum OciError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("corrupt image index: {0}")]
+    State(#[from] serde_json::Error),
+    #[error("invalid image reference {0:?}")]
+    InvalidReference(String),
+    #[error("{0} is not a store path")]
+    InvalidPath(String),
+    #[error("an image needs at least one store path")]
+    Empty,
+    #[error("{0} is not in the cache")]
+    MissingPath(String),
+    #[error("failed to fetch {0}: {1}")]
+    Fetch(String, String),
+    #[error("unreadable narinfo for {0}: {1}")]
+    NarInfo(String, NarInfoError),
+    #[error("cannot unpack {0}: {1}")]
+    Nar(String, NarError),
+    #[error(transparent)]
+    Compression(#[from] CompressionError),
+    #[error("failed to write layer: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// What a build publishes: the store paths to put in the image, and how to run it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageSpec {
+    /// Their closure ends up in the image.
+    pub store_paths: Vec<String>,
+    #[serde(default)]
+    pub config: ImageConfig,
+    #[serde(default = "default_architecture")]
+    pub architecture: String,
+    #[serde(default = "default_os")]
+    pub os: String,
+}
+
+fn default_architecture() -> String {
+    "amd64".to_owned()
+}
+
+fn default_os() -> String {
+    "linux".to_owned()
+}
+
+/// The `config` of an OCI image config, with its field names.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exposed_ports: Option<BTreeMap<String, serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<BTreeMap<String, serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<BTreeMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<String>,
+}
+
+/// A published image, with the manifest and config served for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Image {
+    pub spec: ImageSpec,
+    pub manifest_digest: String,
+    pub manifest: String,
+    pub config_digest: String,
+    pub config: String,
+    /// Layer digests, bottom first.
+    pub layers: Vec<String>,
+    pub published: DateTime<Utc>,
+}
+
+/// The store paths one layer unpacks, and the size of its tarball.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Layer {
+    pub store_paths: Vec<String>,
+    pub size: u64,
+}
+
+/// Every published image and the layers they use.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OciState {
+    /// Tags by repository name.
+    #[serde(default)]
+    images: BTreeMap<String, BTreeMap<String, Image>>,
+    /// By digest.
+    #[serde(default)]
+    layers: BTreeMap<String, Layer>,
+}
+
+/// A blob of a repository.
+#[derive(Debug)]
+pub enum Blob {
+    Config(String),
+    Layer(Layer),
+}
+
+fn is_valid_component(s: &str) -> bool {
+    let alnum = |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit();
+    !s.is_empty()
+        && s.bytes().all(|b| alnum(b) || b"._-".contains(&b))
+        && s.bytes().next().is_some_and(alnum)
+        && s.bytes().last().is_some_and(alnum)
+}
+
+/// Whether `name` is a repository name as the distribution spec allows them, e.g.
+/// `team/app`.
+pub fn is_valid_name(name: &str) -> bool {
+    name.len() <= 255 && name.split('/').all(is_valid_component)
+}
+
+pub fn is_valid_tag(tag: &str) -> bool {
+    (1..=128).contains(&tag.len())
+        && !tag.starts_with(['.', '-'])
+        && tag
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+}
+
+/// Splits `team/app:1.0` into name and tag.
+pub fn parse_reference(reference: &str) -> Result<(&str, &str), OciError> {
+    reference
+        .rsplit_once(':')
+        .filter(|(name, tag)| is_valid_name(name) && is_valid_tag(tag))
+        .ok_or_else(|| OciError::InvalidReference(reference.to_owned()))
+}
+
+fn sha256_digest(content: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(content))
+}
+
+/// Where the narinfo and NARs of the paths in images are read from.
+#[async_trait]
+pub trait PathSource: Send + Sync {
+    /// The narinfo of `path`; [`OciError::MissingPath`] if there is none.
+    async fn narinfo(&self, path: &StorePath) -> Result<NarInfo, OciError>;
+    /// The NAR `info` points at, as stored.
+    async fn nar(&self, path: &StorePath, info: &NarInfo) -> Result<ByteStream, OciError>;
+}
+
+/// The paths in the cache's own storage.
+pub struct StoredPaths(pub Arc<dyn NixCacheStorage>);
+
+#[async_trait]
+impl PathSource for StoredPaths {
+    async fn narinfo(&self, path: &StorePath) -> Result<NarInfo, OciError> {
+        let content = match self.0.get_narinfo(path.hash_part()).await {
+            Ok(content) => content,
+            Err(StorageError::NotFound) => return Err(OciError::MissingPath(path.to_absolute())),
+            Err(e) => return Err(e.into()),
+        };
+        content
+            .parse()
+            .map_err(|e| OciError::NarInfo(path.to_absolute(), e))
+    }
+
+    async fn nar(&self, path: &StorePath, info: &NarInfo) -> Result<ByteStream, OciError> {
+        let file = info
+            .nar_file()
+            .ok_or_else(|| OciError::MissingPath(path.to_absolute()))?;
+        match self.0.get_nar(file, None).await {
+            Ok(nar) => Ok(nar),
+            Err(StorageError::NotFound) => Err(OciError::MissingPath(path.to_absolute())),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Writes the layer unpacking `store_paths` from their NARs in `paths`.
+async fn write_layer(
+    paths: &dyn PathSource,
+    store_paths: &[String],
+    out: &mut (dyn AsyncWrite + Unpin + Send),
+) -> Result<(), OciError> {
+    layer::write_start(out).await?;
+    for path in store_paths {
+        let store_path =
+            StorePath::from_absolute(path).map_err(|_| OciError::InvalidPath(path.clone()))?;
+        let info = paths.narinfo(&store_path).await?;
+        let nar = paths.nar(&store_path, &info).await?;
+        let nar = compression::decoder(&info.compression, BufReader::new(StreamReader::new(nar)))?;
+        layer::write_nar(out, &store_path.base_name(), nar)
+            .await
+            .map_err(|e| OciError::Nar(path.clone(), e))?;
+    }
+    Ok(layer::write_end(out).await?)
+}
+
+/// Store paths of all published images, which GC keeps with their closures.
+pub async fn image_paths(storage: &dyn NixCacheStorage) -> Result<Vec<String>, OciError> {
+    let state = load_state(storage).await?;
+    Ok(state
+        .images
+        .values()
+        .flat_map(|tags| tags.values())
+        .flat_map(|image| image.spec.store_paths.iter().cloned())
+        .collect())
+}
+
+async fn load_state(storage: &dyn NixCacheStorage) -> Result<OciState, OciError> {
+    match storage.get_meta(STATE_FILE).await {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(StorageError::NotFound) => Ok(OciState::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub struct Registry {
+    storage: Arc<dyn NixCacheStorage>,
+    /// Serialises changes to the index.
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl Registry {
+    pub fn new(storage: Arc<dyn NixCacheStorage>) -> Self {
+        Registry {
+            storage,
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// The paths in this cache's storage, where images find them outside a cluster.
+    pub fn stored_paths(&self) -> Arc<dyn PathSource> {
+        Arc::new(StoredPaths(self.storage.clone()))
+    }
+
+    async fn save_state(&self, state: &mut OciState) -> Result<(), OciError> {
+        let used: std::collections::HashSet<&String> = state
+            .images
+            .values()
+            .flat_map(|tags| tags.values())
+            .flat_map(|image| &image.layers)
+            .collect();
+        let unused: Vec<String> = state
+            .layers
+            .keys()
+            .filter(|digest| !used.contains(digest))
+            .cloned()
+            .collect();
+        for digest in unused {
+            state.layers.remove(&digest);
+        }
+        let content = serde_json::to_vec(state)?;
+        self.storage.put_meta(STATE_FILE, content).await?;
+        Ok(())
+    }
+
+    /// The closure of `store_paths`, split into layers: the paths most others depend
+    /// on first, each in its own layer, so images built on the same dependencies share
+    /// them.
+    async fn layer_paths(
+        &self,
+        paths: &dyn PathSource,
+        store_paths: &[String],
+    ) -> Result<Vec<Vec<String>>, OciError> {
+        let mut closure: HashMap<StorePath, NarInfo> = HashMap::new();
+        let mut todo: VecDeque<StorePath> = store_paths
+            .iter()
+            .map(|path| {
+                StorePath::from_absolute(path).map_err(|_| OciError::InvalidPath(path.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        while let Some(path) = todo.pop_front() {
+            if closure.contains_key(&path) {
+                continue;
+            }
+            let info = paths.narinfo(&path).await?;
+            todo.extend(info.references.iter().cloned());
+            closure.insert(path, info);
+        }
+
+        let mut referrers: HashMap<&StorePath, usize> = HashMap::new();
+        for (path, info) in &closure {
+            for reference in info.references.iter().filter(|r| *r != path) {
+                *referrers.entry(reference).or_default() += 1;
+            }
+        }
+        let mut ordered: Vec<&StorePath> = closure.keys().collect();
+        ordered.sort_by_key(|path| (Reverse(referrers.get(path).copied().unwrap_or(0)), *path));
+
+        let mut layers: Vec<Vec<String>> = ordered
+            .iter()
+            .map(|path| vec![path.to_absolute()])
+            .collect();
+        if layers.len() > MAX_LAYERS {
+            let mut rest: Vec<String> = layers.drain(MAX_LAYERS - 1..).flatten().collect();
+            rest.sort();
+            layers.push(rest);
+        }
+        Ok(layers)
+    }
+
+    /// Publishes `spec` as `name:tag`, replacing what the tag pointed at before. Its
+    /// closure is read from `paths`.
+    pub async fn put_image(
+        &self,
+        name: &str,
+        tag: &str,
+        spec: ImageSpec,
+        paths: &dyn PathSource,
+    ) -> Result<Image, OciError> {
+        if !is_valid_name(name) || !is_valid_tag(tag) {
+            return Err(OciError::InvalidReference(format!("{name}:{tag}")));
+        }
+        if spec.store_paths.is_empty() {
+            return Err(OciError::Empty);
+        }
+        let layer_paths = self.layer_paths(paths, &spec.store_paths).await?;
+
+        let _guard = self.lock.lock().await;
+        let mut state = load_state(self.storage.as_ref()).await?;
+        let known: HashMap<Vec<String>, String> = state
+            .layers
+            .iter()
+            .map(|(digest, layer)| (layer.store_paths.clone(), digest.clone()))
+            .collect();
+        let mut layers = Vec::new();
+        for store_paths in layer_paths {
+            if let Some(digest) = known.get(&store_paths) {
+                layers.push(digest.clone());
+                continue;
+            }
+            let mut writer = DigestWriter::default();
+            write_layer(paths, &store_paths, &mut writer).await?;
+            let (digest, size) = writer.finish();
+            info!(digest = %digest, size, paths = store_paths.len(), "Made image layer");
+            state
+                .layers
+                .insert(digest.clone(), Layer { store_paths, size });
+            layers.push(digest);
+        }
+
+        // Layers are uncompressed, so their digests are also the diff ids.
+        let config = serde_json::to_string(&serde_json::json!({
+            "architecture": spec.architecture,
+            "os": spec.os,
+            "created": "1970-01-01T00:00:01Z",
+            "config": spec.config,
+            "rootfs": { "type": "layers", "diff_ids": layers },
+        }))?;
+        let config_digest = sha256_digest(config.as_bytes());
+        let manifest = serde_json::to_string(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_TYPE,
+            "config": {
+                "mediaType": CONFIG_TYPE,
+                "digest": config_digest,
+                "size": config.len(),
+            },
+            "layers": layers.iter().map(|digest| serde_json::json!({
+                "mediaType": LAYER_TYPE,
+                "digest": digest,
+                "size": state.layers[digest].size,
+            })).collect::<Vec<_>>(),
+        }))?;
+        let image = Image {
+            spec,
+            manifest_digest: sha256_digest(manifest.as_bytes()),
+            manifest,
+            config_digest,
+            config,
+            layers,
+            published: Utc::now(),
+        };
+        state
+            .images
+            .entry(name.to_owned())
+            .or_default()
+            .insert(tag.to_owned(), image.clone());
+        self.save_state(&mut state).await?;
+        info!(image = %name, tag = %tag, digest = %image.manifest_digest, layers = image.layers.len(), "Published image");
+        Ok(image)
+    }
+
+    /// Removes a tag. Returns whether it existed.
+    pub async fn delete_image(&self, name: &str, tag: &str) -> Result<bool, OciError> {
+        let _guard = self.lock.lock().await;
+        let mut state = load_state(self.storage.as_ref()).await?;
+        let Some(tags) = state.images.get_mut(name) else {
+            return Ok(false);
+        };
+        if tags.remove(tag).is_none() {
+            return Ok(false);
+        }
+        if tags.is_empty() {
+            state.images.remove(name);
+        }
+        self.save_state(&mut state).await?;
+        Ok(true)
+    }
+
+    /// The tags of a repository, `None` if it has none.
+    pub async fn tags(&self, name: &str) -> Result<Option<Vec<String>>, OciError> {
+        let state = load_state(self.storage.as_ref()).await?;
+        Ok(state
+            .images
+            .get(name)
+            .map(|tags| tags.keys().cloned().collect()))
+    }
+
+    /// The image `reference` names in a repository, by tag or manifest digest.
+    pub async fn image(&self, name: &str, reference: &str) -> Result<Option<Image>, OciError> {
+        let mut state = load_state(self.storage.as_ref()).await?;
+        let Some(mut tags) = state.images.remove(name) else {
+            return Ok(None);
+        };
+        if reference.starts_with("sha256:") {
+            return Ok(tags
+                .into_values()
+                .find(|image| image.manifest_digest == reference));
+        }
+        Ok(tags.remove(reference))
+    }
+
+    /// A config or layer of a repository, by digest.
+    pub async fn blob(&self, name: &str, digest: &str) -> Result<Option<Blob>, OciError> {
+        let mut state = load_state(self.storage.as_ref()).await?;
+        let Some(tags) = state.images.remove(name) else {
+            return Ok(None);
+        };
+        for image in tags.into_values() {
+            if image.config_digest == digest {
+                return Ok(Some(Blob::Config(image.config)));
+            }
+            if image.layers.iter().any(|layer| layer == digest) {
+                return Ok(state.layers.remove(digest).map(Blob::Layer));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Makes a layer from the NARs in `paths` as it is being sent. A failure cuts the
+    /// stream short, which the client notices by the size and digest.
+    pub fn layer_stream(&self, layer: Layer, paths: Arc<dyn PathSource>) -> ByteStream {
+        let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            if let Err(e) = write_layer(paths.as_ref(), &layer.store_paths, &mut writer).await {
+                error!(paths = ?layer.store_paths, error = %e, "Failed to make image layer");
+            }
+        });
+        ReaderStream::new(reader).boxed()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::integrity::sha256;
+    use crate::storage::DiskStorage;
+    use bytes::Bytes;
+    use common::narinfo::Compression;
+    use futures::TryStreamExt;
+
+    pub(crate) const APP: &str = "/nix/store/7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-app";
+    pub(crate) const LIB: &str = "/nix/store/yaz7pyf0ah88g2v505l38n0f3wg2vzdj-lib";
+
+    /// Stores `path` with the sample NAR as its contents.
+    pub(crate) async fn add_path(storage: &dyn NixCacheStorage, path: &str, references: &[&str]) {
+        let nar = crate::nar::test::sample_nar();
+        let nar_hash = sha256(&nar);
+        let file = format!("{}.nar", nar_hash.to_nix32());
+        let info = NarInfo {
+            store_path: StorePath::from_absolute(path).unwrap(),
+            url: format!("nar/{file}"),
+            compression: Compression::None,
+            file_hash: None,
+            file_size: None,
+            nar_hash,
+            nar_size: nar.len() as u64,
+            references: references
+                .iter()
+                .map(|r| StorePath::from_absolute(r).unwrap())
+                .collect(),
+            deriver: None,
+            sigs: Vec::new(),
+            ca: None,
+            extra: Vec::new(),
+        };
+        let body = futures::stream::once(async { Ok(Bytes::from(nar)) }).boxed();
+        storage.put_nar(&file, body).await.unwrap();
+        storage
+            .put_narinfo(info.store_path.hash_part(), info.to_string())
+            .await
+            .unwrap();
+    }
+
+    pub(crate) fn spec(paths: &[&str]) -> ImageSpec {
+        serde_json::from_value(serde_json::json!({
+            "store_paths": paths,
+            "config": { "Entrypoint": [format!("{APP}/bin/hello")] },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn validates_references() {
+        assert_eq!(
+            parse_reference("team/app:1.0").unwrap(),
+            ("team/app", "1.0")
+        );
+        assert!(parse_reference("team/app").is_err());
+        assert!(parse_reference("Team/app:1").is_err());
+        assert!(parse_reference("team//app:1").is_err());
+        assert!(parse_reference("team/app-:1").is_err());
+        assert!(parse_reference("team/app:-1").is_err());
+    }
+
+    #[tokio::test]
+    async fn publishes_images_from_closures() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn NixCacheStorage> =
+            Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        add_path(storage.as_ref(), LIB, &[LIB]).await;
+        add_path(storage.as_ref(), APP, &[LIB]).await;
+        let registry = Registry::new(storage.clone());
+        let paths = registry.stored_paths();
+        let paths = paths.as_ref();
+
+        assert!(matches!(
+            registry.put_image("app", "1", spec(&[]), paths).await,
+            Err(OciError::Empty)
+        ));
+        let missing = "/nix/store/0yzhigwjl6bws649vcs2asa4lbs8hg93-gone";
+        assert!(matches!(
+            registry.put_image("app", "1", spec(&[missing]), paths).await,
+            Err(OciError::MissingPath(path)) if path == missing
+        ));
+
+        let image = registry
+            .put_image("team/app", "1", spec(&[APP]), paths)
+            .await
+            .unwrap();
+        assert_eq!(image.layers.len(), 2);
+        let manifest: serde_json::Value = serde_json::from_str(&image.manifest).unwrap();
+        assert_eq!(manifest["config"]["digest"], image.config_digest);
+        let config: serde_json::Value = serde_json::from_str(&image.config).unwrap();
+        assert_eq!(config["rootfs"]["diff_ids"][0], image.layers[0]);
+        assert_eq!(
+            config["config"]["Entrypoint"][0],
+            format!("{APP}/bin/hello")
+        );
+
+        // The shared dependency comes first.
+        let Some(Blob::Layer(lib)) = registry.blob("team/app", &image.layers[0]).await.unwrap()
+        else {
+            panic!("expected the first layer");
+        };
+        assert_eq!(lib.store_paths, [LIB]);
+        let tar: Vec<Bytes> = registry
+            .layer_stream(lib.clone(), registry.stored_paths())
+            .try_collect()
+            .await
+            .unwrap();
+        let tar = tar.concat();
+        assert_eq!(tar.len() as u64, lib.size);
+        assert_eq!(sha256_digest(&tar), image.layers[0]);
+
+        assert!(matches!(
+            registry.blob("team/app", &image.config_digest).await.unwrap(),
+            Some(Blob::Config(config)) if config == image.config
+        ));
+        assert!(registry
+            .blob("other", &image.config_digest)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Same paths, same layers and manifest.
+        let again = registry
+            .put_image("team/app", "latest", spec(&[APP]), paths)
+            .await
+            .unwrap();
+        assert_eq!(again.manifest_digest, image.manifest_digest);
+        assert_eq!(
+            registry.tags("team/app").await.unwrap().unwrap(),
+            ["1", "latest"]
+        );
+        let by_digest = registry
+            .image("team/app", &image.manifest_digest)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_digest.manifest, image.manifest);
+        assert_eq!(image_paths(storage.as_ref()).await.unwrap(), [APP, APP]);
+
+        let lib_only = registry
+            .put_image("lib", "1", spec(&[LIB]), paths)
+            .await
+            .unwrap();
+        assert_eq!(lib_only.layers, image.layers[..1]);
+
+        assert!(registry.delete_image("team/app", "1").await.unwrap());
+        assert!(registry.delete_image("team/app", "latest").await.unwrap());
+        assert!(!registry.delete_image("team/app", "latest").await.unwrap());
+        assert!(registry.tags("team/app").await.unwrap().is_none());
+        assert!(registry
+            .blob("lib", &image.layers[0])
+            .await
+            .unwrap()
+            .is_some());
+        let state = load_state(storage.as_ref()).await.unwrap();
+        assert_eq!(state.layers.len(), 1);
+    }
+}
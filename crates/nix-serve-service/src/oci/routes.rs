@@ -0,0 +1,365 @@
+//! Publishing image specs below `/images`, and the read side of the OCI distribution
+//! API below `/v2`.
+
+use axum::{
+    body::Body,
+    extract::{Extension, Path, State},
+    http::{header, HeaderName, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::auth::{self, Token};
+use crate::AppState;
+
+use super::{Blob, Image, ImageSpec, OciError, PathSource, MANIFEST_TYPE};
+
+const API_VERSION: HeaderName = HeaderName::from_static("docker-distribution-api-version");
+const CONTENT_DIGEST: HeaderName = HeaderName::from_static("docker-content-digest");
+
+/// What publishing an image reports back.
+#[derive(Debug, Serialize)]
+pub struct Published {
+    pub image: String,
+    pub digest: String,
+    pub layers: usize,
+}
+
+fn spec_status(e: &OciError) -> StatusCode {
+    match e {
+        OciError::InvalidReference(_)
+        | OciError::InvalidPath(_)
+        | OciError::Empty
+        | OciError::MissingPath(_) => StatusCode::BAD_REQUEST,
+        OciError::Fetch(..) => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Where image paths are read from: what a cluster put on the request, or this cache.
+fn paths(state: &AppState, paths: Option<Extension<Arc<dyn PathSource>>>) -> Arc<dyn PathSource> {
+    paths.map_or_else(|| state.oci.stored_paths(), |Extension(paths)| paths)
+}
+
+/// Publishes an image spec as `<name>:<tag>`.
+pub async fn put_image(
+    State(state): State<AppState>,
+    Path(reference): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+    source: Option<Extension<Arc<dyn PathSource>>>,
+    Json(spec): Json<ImageSpec>,
+) -> Result<Json<Published>, StatusCode> {
+    let (name, tag) = super::parse_reference(&reference).map_err(|e| {
+        warn!(error = %e, "Rejecting image");
+        StatusCode::BAD_REQUEST
+    })?;
+    let paths = paths(&state, source);
+    match state.oci.put_image(name, tag, spec, paths.as_ref()).await {
+        Ok(image) => {
+            info!(
+                target: "audit",
+                token = %auth::uploader(&token),
+                image = %reference,
+                digest = %image.manifest_digest,
+                "Published image"
+            );
+            Ok(Json(Published {
+                image: reference,
+                digest: image.manifest_digest,
+                layers: image.layers.len(),
+            }))
+        }
+        Err(e) => {
+            let status = spec_status(&e);
+            if status.is_server_error() {
+                error!(image = %reference, error = %e, "Failed to publish image");
+            } else {
+                warn!(image = %reference, error = %e, "Rejecting image");
+            }
+            Err(status)
+        }
+    }
+}
+
+/// The image published as `<name>:<tag>`.
+pub async fn get_image(
+    State(state): State<AppState>,
+    Path(reference): Path<String>,
+) -> Result<Json<Image>, StatusCode> {
+    let (name, tag) = super::parse_reference(&reference).map_err(|_| StatusCode::BAD_REQUEST)?;
+    match state.oci.image(name, tag).await {
+        Ok(Some(image)) => Ok(Json(image)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!(image = %reference, error = %e, "Failed to load image");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub async fn delete_image(
+    State(state): State<AppState>,
+    Path(reference): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+) -> StatusCode {
+    let Ok((name, tag)) = super::parse_reference(&reference) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    match state.oci.delete_image(name, tag).await {
+        Ok(true) => {
+            info!(
+                target: "audit",
+                token = %auth::uploader(&token),
+                image = %reference,
+                "Deleted image"
+            );
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!(image = %reference, error = %e, "Failed to delete image");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// An error in the format of the distribution spec.
+fn registry_error(status: StatusCode, code: &str, message: &str) -> Response {
+    let body = serde_json::json!({ "errors": [{ "code": code, "message": message }] });
+    (status, [(API_VERSION, "registry/2.0")], Json(body)).into_response()
+}
+
+/// `GET /v2/`, which clients use to check that this is a registry and whether they
+/// need to log in.
+pub async fn get_base() -> Response {
+    ([(API_VERSION, "registry/2.0")], Json(serde_json::json!({}))).into_response()
+}
+
+/// What a path below `/v2/` asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum Endpoint<'a> {
+    Manifest { name: &'a str, reference: &'a str },
+    Blob { name: &'a str, digest: &'a str },
+    Tags { name: &'a str },
+}
+
+impl<'a> Endpoint<'a> {
+    fn parse(path: &'a str) -> Option<Self> {
+        if let Some(name) = path.strip_suffix("/tags/list") {
+            return Some(Endpoint::Tags { name });
+        }
+        if let Some((name, reference)) = path.rsplit_once("/manifests/") {
+            return Some(Endpoint::Manifest { name, reference });
+        }
+        if let Some((name, digest)) = path.rsplit_once("/blobs/") {
+            return Some(Endpoint::Blob { name, digest });
+        }
+        None
+    }
+}
+
+/// Manifests, blobs and tag lists of the published images.
+pub async fn get_v2(
+    State(state): State<AppState>,
+    method: Method,
+    Path(path): Path<String>,
+    source: Option<Extension<Arc<dyn PathSource>>>,
+) -> Response {
+    let Some(endpoint) = Endpoint::parse(&path) else {
+        return registry_error(StatusCode::NOT_FOUND, "UNSUPPORTED", "unknown endpoint");
+    };
+    let head = method == Method::HEAD;
+    let result = match endpoint {
+        Endpoint::Manifest { name, reference } => {
+            state.oci.image(name, reference).await.map(|image| {
+                let Some(image) = image else {
+                    return registry_error(
+                        StatusCode::NOT_FOUND,
+                        "MANIFEST_UNKNOWN",
+                        "manifest unknown",
+                    );
+                };
+                let headers = [
+                    (header::CONTENT_TYPE, MANIFEST_TYPE.to_owned()),
+                    (header::CONTENT_LENGTH, image.manifest.len().to_string()),
+                    (CONTENT_DIGEST, image.manifest_digest),
+                    (API_VERSION, "registry/2.0".to_owned()),
+                ];
+                let body = if head {
+                    Body::empty()
+                } else {
+                    image.manifest.into()
+                };
+                (headers, body).into_response()
+            })
+        }
+        Endpoint::Blob { name, digest } => state.oci.blob(name, digest).await.map(|blob| {
+            let (size, body) = match blob {
+                None => {
+                    return registry_error(StatusCode::NOT_FOUND, "BLOB_UNKNOWN", "blob unknown")
+                }
+                Some(Blob::Config(config)) => (config.len() as u64, Body::from(config)),
+                Some(Blob::Layer(layer)) if head => (layer.size, Body::empty()),
+                Some(Blob::Layer(layer)) => {
+                    let paths = paths(&state, source);
+                    (
+                        layer.size,
+                        Body::from_stream(state.oci.layer_stream(layer, paths)),
+                    )
+                }
+            };
+            let headers = [
+                (header::CONTENT_TYPE, "application/octet-stream".to_owned()),
+                (header::CONTENT_LENGTH, size.to_string()),
+                (CONTENT_DIGEST, digest.to_owned()),
+                (API_VERSION, "registry/2.0".to_owned()),
+            ];
+            (headers, if head { Body::empty() } else { body }).into_response()
+        }),
+        Endpoint::Tags { name } => state.oci.tags(name).await.map(|tags| match tags {
+            Some(tags) => (
+                [(API_VERSION, "registry/2.0")],
+                Json(serde_json::json!({ "name": name, "tags": tags })),
+            )
+                .into_response(),
+            None => registry_error(StatusCode::NOT_FOUND, "NAME_UNKNOWN", "repository unknown"),
+        }),
+    };
+    result.unwrap_or_else(|e| {
+        error!(path = %path, error = %e, "Failed to serve registry request");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oci::test::{add_path, spec, APP, LIB};
+    use crate::storage::{DiskStorage, NixCacheStorage};
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use sha2::{Digest, Sha256};
+    use tower::ServiceExt;
+
+    #[test]
+    fn parses_endpoints() {
+        assert_eq!(
+            Endpoint::parse("team/app/manifests/latest"),
+            Some(Endpoint::Manifest {
+                name: "team/app",
+                reference: "latest"
+            })
+        );
+        assert_eq!(
+            Endpoint::parse("app/blobs/sha256:abc"),
+            Some(Endpoint::Blob {
+                name: "app",
+                digest: "sha256:abc"
+            })
+        );
+        assert_eq!(
+            Endpoint::parse("a/tags/list"),
+            Some(Endpoint::Tags { name: "a" })
+        );
+        assert_eq!(Endpoint::parse("app/uploads/"), None);
+    }
+
+    #[tokio::test]
+    async fn serves_published_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn NixCacheStorage> =
+            Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        add_path(storage.as_ref(), LIB, &[]).await;
+        add_path(storage.as_ref(), APP, &[LIB]).await;
+        let app = crate::router(crate::test::test_state(storage));
+
+        let send = |method: Method, uri: String, body: Body| {
+            let app = app.clone();
+            async move {
+                let request = Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(body)
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let headers = response.headers().clone();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, headers, body)
+            }
+        };
+
+        let (status, headers, _) = send(Method::GET, "/v2/".into(), Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[API_VERSION], "registry/2.0");
+
+        let body = serde_json::to_vec(&spec(&[APP])).unwrap();
+        let (status, _, published) =
+            send(Method::PUT, "/images/team/app:1".into(), body.into()).await;
+        assert_eq!(status, StatusCode::OK);
+        let published: serde_json::Value = serde_json::from_slice(&published).unwrap();
+        assert_eq!(published["layers"], 2);
+
+        let (status, headers, manifest) = send(
+            Method::GET,
+            "/v2/team/app/manifests/1".into(),
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], MANIFEST_TYPE);
+        let digest = format!("sha256:{:x}", Sha256::digest(&manifest));
+        assert_eq!(headers[CONTENT_DIGEST], digest.as_str());
+        assert_eq!(published["digest"], digest);
+
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+        for blob in [&manifest["config"], &manifest["layers"][1]] {
+            let uri = format!("/v2/team/app/blobs/{}", blob["digest"].as_str().unwrap());
+            let (status, headers, _) = send(Method::HEAD, uri.clone(), Body::empty()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(headers[header::CONTENT_LENGTH], blob["size"].to_string());
+            let (status, _, content) = send(Method::GET, uri, Body::empty()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(content.len() as u64, blob["size"].as_u64().unwrap());
+            assert_eq!(
+                format!("sha256:{:x}", Sha256::digest(&content)),
+                blob["digest"]
+            );
+        }
+
+        let (status, _, tags) =
+            send(Method::GET, "/v2/team/app/tags/list".into(), Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        let tags: serde_json::Value = serde_json::from_slice(&tags).unwrap();
+        assert_eq!(tags["tags"], serde_json::json!(["1"]));
+
+        let (status, _, error) = send(
+            Method::GET,
+            "/v2/team/app/manifests/2".into(),
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let error: serde_json::Value = serde_json::from_slice(&error).unwrap();
+        assert_eq!(error["errors"][0]["code"], "MANIFEST_UNKNOWN");
+
+        let missing =
+            serde_json::to_vec(&spec(&["/nix/store/0yzhigwjl6bws649vcs2asa4lbs8hg93-x"])).unwrap();
+        let (status, _, _) = send(Method::PUT, "/images/app:1".into(), missing.into()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _, _) = send(Method::DELETE, "/images/team/app:1".into(), Body::empty()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _, _) = send(
+            Method::GET,
+            "/v2/team/app/manifests/1".into(),
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,604 @@
+//! Uploading from a builder: store paths handed over by the post-build hook are queued,
+//! checked against the cache, and their closures uploaded by a few workers while the
+//! build goes on. Only the end of the job waits for the queue to drain.
+
+use async_trait::async_trait;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use futures::TryStreamExt;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio_util::io::ReaderStream;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+use common::hash::Hash;
+use common::narinfo::{Compression, NarInfo, StorePath};
+
+use crate::config::PushConfig;
+use crate::signing::{SecretKey, BUILDER_SIG};
+use crate::storage::ByteStream;
+
+/// Wait before the first retry; doubled for every further one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum PushError {
+    #[error("failed to run {0}: {1}")]
+    Spawn(&'static str, std::io::Error),
+    #[error("{0} failed: {1}")]
+    Command(&'static str, String),
+    #[error("invalid path info: {0}")]
+    PathInfo(String),
+    #[error("HTTP client error: {0}")]
+    Client(#[from] reqwest::Error),
+    #[error("{0} answered {1}")]
+    Status(String, StatusCode),
+    #[error("reference {0} could not be uploaded")]
+    Reference(String),
+}
+
+impl PushError {
+    /// Whether trying again could help: network trouble and server errors, not
+    /// rejected uploads.
+    fn is_transient(&self) -> bool {
+        match self {
+            PushError::Client(_) => true,
+            PushError::Status(_, status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What the local store knows about a path, as `nix path-info --json` prints it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathInfo {
+    pub path: StorePath,
+    pub nar_hash: Hash,
+    pub nar_size: u64,
+    pub references: Vec<StorePath>,
+    pub deriver: Option<StorePath>,
+    pub ca: Option<String>,
+}
+
+impl PathInfo {
+    /// Parses the output of `nix path-info --json`, either the list older Nix prints or
+    /// the object keyed by path newer Nix prints.
+    pub fn parse_json(json: &str) -> Result<Vec<Self>, PushError> {
+        let invalid = |what: &str| PushError::PathInfo(what.to_owned());
+        let value: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| invalid(&e.to_string()))?;
+        let entries: Vec<(&str, &serde_json::Value)> = match &value {
+            serde_json::Value::Array(entries) => entries
+                .iter()
+                .map(|entry| {
+                    Ok((
+                        entry["path"].as_str().ok_or_else(|| invalid("path"))?,
+                        entry,
+                    ))
+                })
+                .collect::<Result<_, PushError>>()?,
+            serde_json::Value::Object(entries) => entries
+                .iter()
+                .map(|(path, entry)| (path.as_str(), entry))
+                .collect(),
+            _ => return Err(invalid("expected a list or an object")),
+        };
+
+        let store_path =
+            |path: &str| StorePath::from_absolute(path).map_err(|e| invalid(&e.to_string()));
+        entries
+            .into_iter()
+            .map(|(path, entry)| {
+                if entry.is_null() {
+                    return Err(invalid(&format!("{path} is not valid")));
+                }
+                let references = entry["references"]
+                    .as_array()
+                    .ok_or_else(|| invalid("references"))?
+                    .iter()
+                    .map(|reference| store_path(reference.as_str().unwrap_or_default()))
+                    .collect::<Result<_, _>>()?;
+                Ok(PathInfo {
+                    path: store_path(path)?,
+                    nar_hash: entry["narHash"]
+                        .as_str()
+                        .ok_or_else(|| invalid("narHash"))?
+                        .parse()
+                        .map_err(|e: common::hash::HashError| invalid(&e.to_string()))?,
+                    nar_size: entry["narSize"]
+                        .as_u64()
+                        .ok_or_else(|| invalid("narSize"))?,
+                    references,
+                    deriver: entry["deriver"].as_str().map(store_path).transpose()?,
+                    ca: entry["ca"].as_str().map(str::to_owned),
+                })
+            })
+            .collect()
+    }
+
+    /// The narinfo for an uncompressed upload, signed with `key` in a `Builder-Sig:`
+    /// line. The cache compresses the NAR itself.
+    pub fn narinfo(&self, key: &SecretKey) -> NarInfo {
+        let mut info = NarInfo {
+            store_path: self.path.clone(),
+            url: format!("nar/{}.nar", self.nar_hash.to_nix32()),
+            compression: Compression::None,
+            file_hash: Some(self.nar_hash.clone()),
+            file_size: Some(self.nar_size),
+            nar_hash: self.nar_hash.clone(),
+            nar_size: self.nar_size,
+            references: self.references.clone(),
+            deriver: self.deriver.clone(),
+            sigs: Vec::new(),
+            ca: self.ca.clone(),
+            extra: Vec::new(),
+        };
+        let sig = key.sign(&info.fingerprint());
+        info.extra.push((BUILDER_SIG.to_owned(), sig));
+        info
+    }
+}
+
+/// Where the paths to upload come from.
+#[async_trait]
+pub trait LocalStore: Send + Sync {
+    /// Info on `paths` and everything they reference.
+    async fn closure(&self, paths: &[String]) -> Result<Vec<PathInfo>, PushError>;
+    /// The uncompressed NAR of `path`.
+    async fn dump(&self, path: &StorePath) -> Result<ByteStream, PushError>;
+}
+
+/// The Nix store of the builder, read through the `nix` commands.
+pub struct NixStore;
+
+#[async_trait]
+impl LocalStore for NixStore {
+    async fn closure(&self, paths: &[String]) -> Result<Vec<PathInfo>, PushError> {
+        let output = Command::new("nix")
+            .args([
+                "--extra-experimental-features",
+                "nix-command",
+                "path-info",
+                "--json",
+                "--recursive",
+                "--",
+            ])
+            .args(paths)
+            .output()
+            .await
+            .map_err(|e| PushError::Spawn("nix path-info", e))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+            return Err(PushError::Command("nix path-info", stderr));
+        }
+        PathInfo::parse_json(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    async fn dump(&self, path: &StorePath) -> Result<ByteStream, PushError> {
+        let mut child = Command::new("nix-store")
+            .arg("--dump")
+            .arg(path.to_absolute())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| PushError::Spawn("nix-store --dump", e))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let path = path.clone();
+        // A dump that stops early is caught by the cache, which checks the NAR hash.
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) if status.success() => {}
+                Ok(status) => {
+                    error!(store_path = %path, status = %status, "nix-store --dump failed")
+                }
+                Err(e) => error!(store_path = %path, error = %e, "nix-store --dump failed"),
+            }
+        });
+        Ok(Box::pin(ReaderStream::new(stdout)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Uploaded,
+    /// The cache already had it.
+    Present,
+}
+
+type Upload = Shared<BoxFuture<'static, Result<Outcome, Arc<PushError>>>>;
+
+/// What [`Pusher::drain`] reports once everything queued is done.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Summary {
+    pub uploaded: usize,
+    pub present: usize,
+    /// Store paths that could not be uploaded, with why.
+    pub failed: Vec<(String, String)>,
+    /// Batches whose closure could not even be queried.
+    pub failed_batches: usize,
+}
+
+impl Summary {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty() && self.failed_batches == 0
+    }
+}
+
+struct Inner {
+    client: reqwest::Client,
+    cache_url: String,
+    token: Option<String>,
+    key: SecretKey,
+    store: Arc<dyn LocalStore>,
+    retries: u32,
+    /// One permit per worker, held while talking to the cache about one path.
+    workers: Semaphore,
+    /// Every path seen so far, so a path in several closures is only uploaded once.
+    uploads: Mutex<HashMap<String, Upload>>,
+    failed_batches: AtomicUsize,
+    tasks: TaskTracker,
+}
+
+/// The upload queue of one build.
+#[derive(Clone)]
+pub struct Pusher {
+    inner: Arc<Inner>,
+}
+
+impl Pusher {
+    pub fn new(
+        config: &PushConfig,
+        key: SecretKey,
+        store: Arc<dyn LocalStore>,
+    ) -> Result<Self, PushError> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .build()?;
+        info!(cache = %config.cache_url, workers = config.workers, key = %key.name(), "Uploading to cache");
+        Ok(Pusher {
+            inner: Arc::new(Inner {
+                client,
+                cache_url: config.cache_url.trim_end_matches('/').to_owned(),
+                token: config.token.clone(),
+                key,
+                store,
+                retries: config.retries,
+                workers: Semaphore::new(config.workers.max(1)),
+                uploads: Mutex::new(HashMap::new()),
+                failed_batches: AtomicUsize::new(0),
+                tasks: TaskTracker::new(),
+            }),
+        })
+    }
+
+    /// Queues `paths` and their closure without waiting for the upload.
+    pub fn push(&self, paths: Vec<String>) {
+        let inner = self.inner.clone();
+        self.inner.tasks.spawn(async move {
+            match inner.store.closure(&paths).await {
+                Ok(closure) => inner.schedule(closure),
+                Err(e) => {
+                    error!(paths = ?paths, error = %e, "Failed to query paths to upload");
+                    inner.failed_batches.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    /// Waits for everything queued so far. Nothing can be pushed afterwards.
+    pub async fn drain(&self) -> Summary {
+        self.inner.tasks.close();
+        self.inner.tasks.wait().await;
+
+        let mut summary = Summary {
+            failed_batches: self.inner.failed_batches.load(Ordering::Relaxed),
+            ..Summary::default()
+        };
+        let uploads = self.inner.uploads.lock().unwrap();
+        for (path, upload) in uploads.iter() {
+            match upload.peek() {
+                Some(Ok(Outcome::Uploaded)) => summary.uploaded += 1,
+                Some(Ok(Outcome::Present)) => summary.present += 1,
+                Some(Err(e)) => summary.failed.push((path.clone(), e.to_string())),
+                None => unreachable!("drained uploads are finished"),
+            }
+        }
+        summary.failed.sort();
+        summary
+    }
+}
+
+impl Inner {
+    fn schedule(self: &Arc<Self>, closure: Vec<PathInfo>) {
+        let mut uploads = self.uploads.lock().unwrap();
+        for info in closure {
+            let path = info.path.to_absolute();
+            if uploads.contains_key(&path) {
+                continue;
+            }
+            let upload = self.clone().upload(info).boxed().shared();
+            uploads.insert(path, upload.clone());
+            self.tasks.spawn(upload);
+        }
+    }
+
+    /// Uploads the NAR of one path, then its narinfo once all its references are in the
+    /// cache, so the cache never serves a path whose closure is incomplete.
+    async fn upload(self: Arc<Self>, info: PathInfo) -> Result<Outcome, Arc<PushError>> {
+        let path = info.path.to_absolute();
+        let result = async {
+            let permit = self
+                .workers
+                .acquire()
+                .await
+                .expect("semaphore is never closed");
+            if self.retry(&path, || self.has_narinfo(&info)).await? {
+                return Ok(Outcome::Present);
+            }
+            self.retry(&path, || self.put_nar(&info)).await?;
+            drop(permit);
+
+            let references: Vec<(String, Upload)> = {
+                let uploads = self.uploads.lock().unwrap();
+                info.references
+                    .iter()
+                    .filter(|reference| **reference != info.path)
+                    .filter_map(|reference| {
+                        let reference = reference.to_absolute();
+                        let upload = uploads.get(&reference)?.clone();
+                        Some((reference, upload))
+                    })
+                    .collect()
+            };
+            for (reference, upload) in references {
+                if upload.await.is_err() {
+                    return Err(PushError::Reference(reference));
+                }
+            }
+
+            let _permit = self
+                .workers
+                .acquire()
+                .await
+                .expect("semaphore is never closed");
+            self.retry(&path, || self.put_narinfo(&info)).await?;
+            Ok(Outcome::Uploaded)
+        }
+        .await;
+
+        match &result {
+            Ok(Outcome::Uploaded) => {
+                info!(store_path = %path, size = info.nar_size, "Uploaded path")
+            }
+            Ok(Outcome::Present) => info!(store_path = %path, "Path is already in the cache"),
+            Err(e) => error!(store_path = %path, error = %e, "Failed to upload path"),
+        }
+        result.map_err(Arc::new)
+    }
+
+    async fn retry<T, F, Fut>(&self, path: &str, mut attempt: F) -> Result<T, PushError>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<T, PushError>>,
+    {
+        let mut delay = RETRY_DELAY;
+        let mut tries = 0;
+        loop {
+            match attempt().await {
+                Err(e) if e.is_transient() && tries < self.retries => {
+                    tries += 1;
+                    warn!(store_path = %path, error = %e, attempt = tries, "Upload failed, retrying");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, file: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}/{file}", self.cache_url));
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn has_narinfo(&self, info: &PathInfo) -> Result<bool, PushError> {
+        let file = format!("{}.narinfo", info.path.hash_part());
+        let response = self.request(reqwest::Method::HEAD, &file).send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => Err(PushError::Status(file, status)),
+        }
+    }
+
+    async fn put_nar(&self, info: &PathInfo) -> Result<(), PushError> {
+        let file = format!("nar/{}.nar", info.nar_hash.to_nix32());
+        let nar = self.store.dump(&info.path).await?;
+        let response = self
+            .request(reqwest::Method::PUT, &file)
+            .header(reqwest::header::CONTENT_LENGTH, info.nar_size)
+            .body(reqwest::Body::wrap_stream(
+                nar.map_err(std::io::Error::other),
+            ))
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(PushError::Status(file, status)),
+        }
+    }
+
+    async fn put_narinfo(&self, info: &PathInfo) -> Result<(), PushError> {
+        let file = format!("{}.narinfo", info.path.hash_part());
+        let narinfo = info.narinfo(&self.key).to_string();
+        let response = self
+            .request(reqwest::Method::PUT, &file)
+            .body(narinfo)
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(PushError::Status(file, status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::integrity::sha256;
+    use crate::signing::test_key;
+    use crate::storage::{DiskStorage, NixCacheStorage};
+    use bytes::Bytes;
+
+    const LIB: &str = "/nix/store/yaz7pyf0ah88g2v505l38n0f3wg2vzdj-lib";
+    const APP: &str = "/nix/store/7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-app";
+    const BROKEN: &str = "/nix/store/0yzhigwjl6bws649vcs2asa4lbs8hg93-broken";
+
+    /// Has [`LIB`], [`APP`] depending on it, and [`BROKEN`], whose dump doesn't match
+    /// its NAR hash.
+    struct FakeStore {
+        paths: HashMap<String, (PathInfo, Vec<u8>)>,
+    }
+
+    impl FakeStore {
+        fn new() -> Self {
+            let mut paths = HashMap::new();
+            let mut add = |path: &str, references: &[&str], nar: Vec<u8>| {
+                let info = PathInfo {
+                    path: StorePath::from_absolute(path).unwrap(),
+                    nar_hash: sha256(&nar),
+                    nar_size: nar.len() as u64,
+                    references: references
+                        .iter()
+                        .map(|r| StorePath::from_absolute(r).unwrap())
+                        .collect(),
+                    deriver: None,
+                    ca: None,
+                };
+                paths.insert(path.to_owned(), (info, nar));
+            };
+            add(LIB, &[LIB], crate::nar::test::sample_nar());
+            add(APP, &[LIB], vec![7; 70_000]);
+            add(BROKEN, &[LIB], vec![1; 100]);
+            FakeStore { paths }
+        }
+    }
+
+    #[async_trait]
+    impl LocalStore for FakeStore {
+        async fn closure(&self, paths: &[String]) -> Result<Vec<PathInfo>, PushError> {
+            let mut closure: HashMap<String, PathInfo> = HashMap::new();
+            let mut todo = paths.to_vec();
+            while let Some(path) = todo.pop() {
+                if closure.contains_key(&path) {
+                    continue;
+                }
+                let (info, _) = self
+                    .paths
+                    .get(&path)
+                    .ok_or_else(|| PushError::Command("nix path-info", path.clone()))?;
+                todo.extend(info.references.iter().map(StorePath::to_absolute));
+                closure.insert(path, info.clone());
+            }
+            Ok(closure.into_values().collect())
+        }
+
+        async fn dump(&self, path: &StorePath) -> Result<ByteStream, PushError> {
+            let (_, nar) = &self.paths[&path.to_absolute()];
+            let mut nar = nar.clone();
+            if path.to_absolute() == BROKEN {
+                nar[0] ^= 1;
+            }
+            Ok(Box::pin(futures::stream::once(async {
+                Ok(Bytes::from(nar))
+            })))
+        }
+    }
+
+    #[test]
+    fn parses_both_path_info_formats() {
+        let old = format!(
+            r#"[{{"path":"{APP}","narHash":"sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73","narSize":8,"references":["{LIB}"],"deriver":null}}]"#
+        );
+        let new = format!(
+            r#"{{"{APP}":{{"narHash":"sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=","narSize":8,"references":["{LIB}"],"deriver":null,"ca":null}}}}"#
+        );
+        let old = PathInfo::parse_json(&old).unwrap();
+        assert_eq!(old, PathInfo::parse_json(&new).unwrap());
+        assert_eq!(old[0].references[0].to_absolute(), LIB);
+
+        let missing = format!(r#"{{"{APP}":null}}"#);
+        assert!(matches!(
+            PathInfo::parse_json(&missing),
+            Err(PushError::PathInfo(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn uploads_closures_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn NixCacheStorage> =
+            Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let app = crate::router(crate::test::test_state(storage.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = PushConfig {
+            cache_url: format!("http://{addr}/"),
+            token: None,
+            secret_key: String::new(),
+            socket: String::new(),
+            workers: 2,
+            retries: 0,
+        };
+        let lib = StorePath::from_absolute(LIB).unwrap();
+        let push = |store: Arc<FakeStore>| Pusher::new(&config, test_key("builder-1", 2), store);
+
+        let store = Arc::new(FakeStore::new());
+        let pusher = push(store.clone()).unwrap();
+        pusher.push(vec![APP.to_owned()]);
+        pusher.push(vec![LIB.to_owned(), APP.to_owned()]);
+        pusher.push(vec![BROKEN.to_owned()]);
+        pusher.push(vec![
+            "/nix/store/00000000000000000000000000000000-gone".to_owned()
+        ]);
+        let summary = pusher.drain().await;
+        assert_eq!(summary.uploaded, 2);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, BROKEN);
+        assert_eq!(summary.failed_batches, 1);
+        assert!(!summary.is_success());
+
+        let info: NarInfo = storage
+            .get_narinfo(lib.hash_part())
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(info.extra_values(BUILDER_SIG).next().is_none());
+        assert!(info.sigs[0].starts_with("cache-1:"));
+
+        // A second build finds what the first one uploaded.
+        let pusher = push(Arc::new(FakeStore::new())).unwrap();
+        pusher.push(vec![APP.to_owned()]);
+        let summary = pusher.drain().await;
+        assert_eq!((summary.uploaded, summary.present), (0, 2));
+        assert!(summary.is_success());
+    }
+}
@@ -0,0 +1,131 @@
+//! Realisations of content-addressed derivation outputs: which store path the output
+//! `<drv-hash>!<output>` was built into. Stored as the JSON Nix puts in
+//! `realisations/<id>.doi`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use common::narinfo::{NarInfoError, StorePath};
+
+#[derive(Debug, Error)]
+pub enum RealisationError {
+    #[error("malformed realisation: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0:?} is not a derivation output id")]
+    InvalidId(String),
+    #[error(transparent)]
+    OutPath(#[from] NarInfoError),
+}
+
+/// Whether `id` is a derivation output id, `<algo>:<base16 hash>!<output name>`.
+pub fn is_valid_id(id: &str) -> bool {
+    let Some((hash, output)) = id.split_once('!') else {
+        return false;
+    };
+    let Some((algo, digest)) = hash.split_once(':') else {
+        return false;
+    };
+    matches!(algo, "md5" | "sha1" | "sha256" | "sha512")
+        && !digest.is_empty()
+        && digest.bytes().all(|b| b.is_ascii_hexdigit())
+        && !output.is_empty()
+        && !output.starts_with('.')
+        && output
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"+-._?=".contains(&b))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Realisation {
+    pub id: String,
+    /// Base name of the output path.
+    pub out_path: String,
+    #[serde(default)]
+    pub signatures: Vec<String>,
+    /// Realisations of the inputs the output was built with, id to output base name.
+    #[serde(default)]
+    pub dependent_realisations: BTreeMap<String, String>,
+}
+
+impl Realisation {
+    pub fn parse(content: &str) -> Result<Self, RealisationError> {
+        let realisation: Realisation = serde_json::from_str(content)?;
+        if !is_valid_id(&realisation.id) {
+            return Err(RealisationError::InvalidId(realisation.id));
+        }
+        realisation.out_path()?;
+        Ok(realisation)
+    }
+
+    pub fn out_path(&self) -> Result<StorePath, NarInfoError> {
+        StorePath::from_base_name(&self.out_path)
+    }
+
+    /// What signatures cover: the JSON without `signatures`, compact and with sorted
+    /// keys, which is how Nix serialises it.
+    pub fn fingerprint(&self) -> String {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Unsigned<'a> {
+            dependent_realisations: &'a BTreeMap<String, String>,
+            id: &'a str,
+            out_path: &'a str,
+        }
+        serde_json::to_string(&Unsigned {
+            dependent_realisations: &self.dependent_realisations,
+            id: &self.id,
+            out_path: &self.out_path,
+        })
+        .expect("serialising strings can't fail")
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("serialising strings can't fail")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ID: &str = "sha256:ba1ec0bde44b16bbab7a3e6e3ea5bb0df0e0a2d1bc1fc2bcd2a4ab5b2ba70ac4!out";
+
+    #[test]
+    fn parses_and_fingerprints() {
+        let json = format!(
+            r#"{{"id":"{ID}","outPath":"7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello","signatures":["cache-1:abc"],"dependentRealisations":{{}}}}"#
+        );
+        let realisation = Realisation::parse(&json).unwrap();
+        assert_eq!(
+            realisation.out_path().unwrap().hash_part(),
+            "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1"
+        );
+        assert_eq!(
+            realisation.fingerprint(),
+            format!(
+                r#"{{"dependentRealisations":{{}},"id":"{ID}","outPath":"7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello"}}"#
+            )
+        );
+        assert_eq!(
+            Realisation::parse(&realisation.to_json()).unwrap(),
+            realisation
+        );
+
+        assert!(is_valid_id(ID));
+        for id in [
+            "sha256:abc",
+            "sha256:xyz!out",
+            "blake:abc!out",
+            "sha256:abc!../x",
+        ] {
+            assert!(!is_valid_id(id), "{id}");
+        }
+        let bad_path = json.replace("7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello", "hello");
+        assert!(matches!(
+            Realisation::parse(&bad_path),
+            Err(RealisationError::OutPath(_))
+        ));
+    }
+}
@@ -0,0 +1,838 @@
+use axum::{
+    body::Body,
+    extract::{Extension, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
+
+use common::narinfo::{self, Compression, NarInfo};
+
+use crate::auth::{self, Token};
+use crate::compression;
+use crate::integrity::{self, IntegrityError};
+use crate::nar::{self, DebugInfoLink, Listing};
+use crate::realisation::{self, Realisation};
+use crate::signing::{self, PublicKey, SigError};
+use crate::storage::{self, ByteRange, StorageError};
+use crate::usage::QuotaError;
+use crate::AppState;
+
+/// The `nix-cache-info` of a cache with the given priority.
+pub fn cache_info(priority: u32) -> String {
+    format!("StoreDir: /nix/store\nWantMassQuery: 1\nPriority: {priority}")
+}
+
+pub async fn get_cache_info(State(state): State<AppState>) -> String {
+    info!("Serving nix-cache-info");
+    cache_info(state.priority)
+}
+
+/// Pulls the store path hash out of `<hash>.narinfo`, refusing anything that isn't a
+/// real hash part so it can't be used to walk the storage backend.
+fn narinfo_hash(file: &str) -> Result<&str, StatusCode> {
+    let hash = file.strip_suffix(".narinfo").ok_or(StatusCode::NOT_FOUND)?;
+    if !narinfo::is_valid_hash_part(hash) {
+        warn!(file = %file, "Rejecting invalid narinfo hash");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(hash)
+}
+
+fn nar_file(file: &str) -> Result<&str, StatusCode> {
+    if !narinfo::is_valid_nar_file(file) {
+        warn!(file = %file, "Rejecting invalid NAR file name");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(file)
+}
+
+fn storage_status(e: StorageError) -> StatusCode {
+    match e {
+        StorageError::NotFound => StatusCode::NOT_FOUND,
+        StorageError::Invalid(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Points the narinfo at an xz transcoding of its NAR for clients that can't decompress
+/// what is stored.
+fn for_client(state: &AppState, headers: &HeaderMap, content: String) -> String {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok());
+    if !state.compression.wants_xz(user_agent) {
+        return content;
+    }
+    compression::xz_narinfo(&content).unwrap_or(content)
+}
+
+/// `<hash>.narinfo` and `<hash>.ls` share a route.
+pub async fn get_file(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    match file.strip_suffix(".ls") {
+        Some(hash) => get_listing(&state, hash).await,
+        None => get_narinfo(State(state), Path(file), headers)
+            .await
+            .map(IntoResponse::into_response),
+    }
+}
+
+async fn get_listing(state: &AppState, hash: &str) -> Result<Response, StatusCode> {
+    if !narinfo::is_valid_hash_part(hash) {
+        warn!(hash = %hash, "Rejecting invalid listing hash");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    info!(hash = %hash, "Fetching NAR listing");
+    match state.storage.get_listing(hash).await {
+        Ok(content) => Ok(([(header::CONTENT_TYPE, "application/json")], content).into_response()),
+        Err(StorageError::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!(hash = %hash, error = %e, "Failed to read NAR listing");
+            Err(storage_status(e))
+        }
+    }
+}
+
+/// Where the debug info for a build id is, for dwarffs and the like: a JSON pointer to
+/// a NAR and the file in it, as Nix's `index-debug-info` writes them.
+pub async fn get_debuginfo(
+    State(state): State<AppState>,
+    Path(build_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !nar::is_valid_build_id(&build_id) {
+        warn!(build_id = %build_id, "Rejecting invalid build id");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    match state.storage.get_debuginfo(&build_id).await {
+        Ok(content) => Ok(([(header::CONTENT_TYPE, "application/json")], content)),
+        Err(StorageError::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!(build_id = %build_id, error = %e, "Failed to read debug info link");
+            Err(storage_status(e))
+        }
+    }
+}
+
+pub async fn get_narinfo(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    let hash = narinfo_hash(&file)?;
+    info!(hash = %hash, "Fetching narinfo");
+    match state.storage.get_narinfo(hash).await {
+        Ok(content) => {
+            state.gc.touch(hash);
+            let content = for_client(&state, &headers, content);
+            Ok(([(header::CONTENT_TYPE, "text/x-nix-narinfo")], content))
+        }
+        Err(StorageError::NotFound) => {
+            if let Some(content) = match &state.proxy {
+                Some(proxy) => proxy.narinfo(hash).await,
+                None => None,
+            } {
+                let content = for_client(&state, &headers, content);
+                return Ok(([(header::CONTENT_TYPE, "text/x-nix-narinfo")], content));
+            }
+            info!(hash = %hash, "narinfo not found");
+            Err(StatusCode::NOT_FOUND)
+        }
+        Err(e) => {
+            error!(hash = %hash, error = %e, "Failed to read narinfo");
+            Err(storage_status(e))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No range, or one we don't handle (several ranges, other units); serve a plain 200.
+    Full,
+    Partial(ByteRange),
+    Unsatisfiable,
+}
+
+/// Parses a `Range:` header against a NAR of `size` bytes.
+pub fn parse_range(header: &str, size: u64) -> RangeRequest {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return RangeRequest::Unsatisfiable;
+    };
+    let last = size.checked_sub(1);
+    let bounds = match (start.trim(), end.trim()) {
+        ("", suffix) => suffix
+            .parse::<u64>()
+            .ok()
+            .filter(|&suffix| suffix > 0)
+            .zip(last)
+            .map(|(suffix, last)| (size.saturating_sub(suffix), last)),
+        (start, "") => start.parse().ok().zip(last),
+        (start, end) => start
+            .parse()
+            .ok()
+            .zip(end.parse::<u64>().ok())
+            .zip(last)
+            .map(|((start, end), last)| (start, end.min(last))),
+    };
+    match bounds {
+        Some((start, end)) if start <= end && start < size => {
+            RangeRequest::Partial(ByteRange { start, end })
+        }
+        _ => RangeRequest::Unsatisfiable,
+    }
+}
+
+fn nar_error(file: &str, e: StorageError) -> StatusCode {
+    match e {
+        StorageError::NotFound => {
+            info!(file = %file, "NAR not found");
+            StatusCode::NOT_FOUND
+        }
+        e => {
+            error!(file = %file, error = %e, "Failed to read NAR");
+            storage_status(e)
+        }
+    }
+}
+
+/// The NAR's size, fetching it from upstream first if it is missing and the cache pulls
+/// through.
+async fn nar_size(state: &AppState, file: &str) -> Result<u64, StatusCode> {
+    match state.storage.nar_size(file).await {
+        Err(StorageError::NotFound) => match &state.proxy {
+            Some(proxy) if proxy.nar(file).await => state.storage.nar_size(file).await,
+            _ => Err(StorageError::NotFound),
+        },
+        result => result,
+    }
+    .map_err(|e| nar_error(file, e))
+}
+
+/// For a missing `<file>.xz` that old clients were sent to by [`for_client`], the
+/// stored NAR it is a transcoding of.
+async fn xz_source<'a>(state: &AppState, file: &'a str) -> Option<&'a str> {
+    if !state.compression.transcodes() {
+        return None;
+    }
+    let source = file.strip_suffix(".xz")?;
+    state.storage.nar_size(source).await.ok()?;
+    Some(source)
+}
+
+/// The size of a stored NAR, or `None` if it will be transcoded and so isn't known.
+async fn nar_or_xz_size(state: &AppState, file: &str) -> Result<Option<u64>, StatusCode> {
+    match nar_size(state, file).await {
+        Err(StatusCode::NOT_FOUND) if xz_source(state, file).await.is_some() => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+pub async fn head_nar(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+) -> Result<Response, StatusCode> {
+    let file = nar_file(&file)?;
+    let Some(size) = nar_or_xz_size(&state, file).await? else {
+        return Ok(([(header::CONTENT_TYPE, "application/x-nix-nar")], ()).into_response());
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-nix-nar".to_owned()),
+            (header::CONTENT_LENGTH, size.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_owned()),
+        ],
+        (),
+    )
+        .into_response())
+}
+
+/// Streams `<source>` recompressed as xz. The length isn't known up front, so ranges
+/// aren't supported.
+async fn get_transcoded_nar(state: &AppState, file: &str) -> Result<Response, StatusCode> {
+    let source = xz_source(state, file).await.ok_or(StatusCode::NOT_FOUND)?;
+    info!(file = %file, source = %source, "Streaming NAR transcoded to xz");
+    let stream = compression::transcode_to_xz(state.storage.as_ref(), source)
+        .await
+        .map_err(|e| {
+            error!(file = %file, error = %e, "Failed to transcode NAR");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/x-nix-nar")
+        .body(Body::from_stream(stream))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Streams a NAR from the storage backend, honouring a single `Range:` so interrupted
+/// substitutions can resume.
+pub async fn get_nar(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let file = nar_file(&file)?;
+    info!(file = %file, "Fetching NAR");
+    let Some(size) = nar_or_xz_size(&state, file).await? else {
+        return get_transcoded_nar(&state, file).await;
+    };
+
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(value) => match parse_range(value, size) {
+            RangeRequest::Full => None,
+            RangeRequest::Partial(range) => Some(range),
+            RangeRequest::Unsatisfiable => {
+                info!(file = %file, range = %value, "Unsatisfiable range");
+                return Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+                )
+                    .into_response());
+            }
+        },
+        None => None,
+    };
+
+    let stream = state
+        .storage
+        .get_nar(file, range)
+        .await
+        .map_err(|e| nar_error(file, e))?;
+    let body = Body::from_stream(stream);
+
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-nix-nar")
+        .header(header::ACCEPT_RANGES, "bytes");
+    let response = match range {
+        Some(range) => {
+            info!(file = %file, start = range.start, end = range.end, "Streaming NAR range");
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_LENGTH, range.length())
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{size}", range.start, range.end),
+                )
+        }
+        None => {
+            info!(file = %file, size = size, "Streaming NAR");
+            response
+                .status(StatusCode::OK)
+                .header(header::CONTENT_LENGTH, size)
+        }
+    };
+    response
+        .body(body)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// The keys an upload may be signed by: the builder keys, and the cache's own, so
+/// what the cache signed can be uploaded again, as replicas do among themselves.
+fn upload_keys(state: &AppState) -> Vec<PublicKey> {
+    let mut keys = state.builder_keys.keys();
+    keys.extend(state.cache_key.as_ref().map(|key| key.public_key()));
+    keys
+}
+
+/// Accepts a narinfo only if it parses, belongs to the store path it is uploaded as, a
+/// trusted builder or the cache itself signed it and its NAR has been uploaded with
+/// matching hashes and sizes. It is stored signed with the cache key instead.
+pub async fn put_narinfo(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+    body: String,
+) -> StatusCode {
+    let hash = match narinfo_hash(&file) {
+        Ok(hash) => hash,
+        Err(status) => return status,
+    };
+    info!(hash = %hash, size = body.len(), "Uploading narinfo");
+
+    let mut info: NarInfo = match body.parse() {
+        Ok(info) => info,
+        Err(e) => {
+            warn!(hash = %hash, error = %e, "Rejecting malformed narinfo");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    if info.store_path.hash_part() != hash {
+        warn!(hash = %hash, store_path = %info.store_path, "narinfo uploaded under the wrong hash");
+        return StatusCode::BAD_REQUEST;
+    }
+
+    if let Err(e) =
+        signing::resign_narinfo(&mut info, &upload_keys(&state), state.cache_key.as_deref())
+    {
+        warn!(hash = %hash, error = %e, "Rejecting narinfo");
+        return match e {
+            SigError::Unsigned => StatusCode::UNAUTHORIZED,
+            SigError::BadSignature => StatusCode::FORBIDDEN,
+        };
+    }
+
+    let verified = state
+        .uploaded
+        .verify_narinfo(state.storage.as_ref(), &info)
+        .await;
+    let listing = match verified {
+        Ok(listing) => listing,
+        Err(e) => {
+            warn!(hash = %hash, url = %info.url, error = %e, "Rejecting narinfo that doesn't match its NAR");
+            return match e {
+                IntegrityError::Storage(e) => storage_status(e),
+                _ => StatusCode::BAD_REQUEST,
+            };
+        }
+    };
+
+    let limits_paths = state.usage.quota().max_paths.is_some();
+    if limits_paths
+        && matches!(
+            state.storage.get_narinfo(hash).await,
+            Err(StorageError::NotFound)
+        )
+    {
+        if let Err(e) = state.usage.check_paths() {
+            warn!(hash = %hash, error = %e, "Rejecting narinfo over quota");
+            return e.status();
+        }
+    }
+
+    if state.compression.store != Compression::None && info.compression == Compression::None {
+        match compression::compress_stored(state.storage.as_ref(), &state.compression, &info).await
+        {
+            Ok(compressed) => info = compressed,
+            Err(e) => {
+                warn!(hash = %hash, error = %e, "Failed to compress NAR, storing it as uploaded")
+            }
+        }
+    }
+
+    match state.storage.put_narinfo(hash, info.to_string()).await {
+        Ok(_) => {
+            info!(hash = %hash, "Successfully wrote narinfo");
+            let size = info.file_size.unwrap_or(info.nar_size);
+            state
+                .usage
+                .record_upload(hash, auth::uploader(&token), size);
+            info!(
+                target: "audit",
+                token = %auth::uploader(&token),
+                store_path = %info.store_path,
+                nar = %info.url,
+                "Uploaded store path"
+            );
+            state.events.path_added(&info, auth::uploader(&token));
+            if let Some(listing) = listing {
+                store_listing(&state, hash, &info, listing).await;
+            }
+            StatusCode::OK
+        }
+        Err(e) => {
+            error!(hash = %hash, error = %e, "Failed to write narinfo");
+            storage_status(e)
+        }
+    }
+}
+
+/// Stores the `.ls` listing of a path's NAR and, if enabled, indexes its debug info.
+/// Failures are only logged; the path is in the cache either way.
+pub(crate) async fn store_listing(state: &AppState, hash: &str, info: &NarInfo, listing: Listing) {
+    if let Err(e) = state.storage.put_listing(hash, listing.to_json()).await {
+        error!(hash = %hash, error = %e, "Failed to write NAR listing");
+    }
+    if !state.index_debug_info {
+        return;
+    }
+    for debug_info in listing.debug_info() {
+        let link = DebugInfoLink::new(&info.url, debug_info.member);
+        let content = serde_json::to_string(&link).expect("serialising strings can't fail");
+        match state
+            .storage
+            .put_debuginfo(&debug_info.build_id, content)
+            .await
+        {
+            Ok(()) => info!(hash = %hash, build_id = %debug_info.build_id, "Indexed debug info"),
+            Err(e) => {
+                error!(build_id = %debug_info.build_id, error = %e, "Failed to index debug info")
+            }
+        }
+    }
+}
+
+/// Stores a NAR. When it is named after its file hash, as Nix does, the upload is only
+/// committed if the body actually hashes to that, and its digests are kept for checking
+/// the narinfo that follows.
+pub async fn put_nar(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, StatusCode> {
+    let file = nar_file(&file)?;
+    warn!(file = %file, "Starting NAR upload");
+    let size = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse().ok());
+
+    let stream = body
+        .into_data_stream()
+        .map_err(std::io::Error::other)
+        .boxed();
+    let (stream, hashing) = match integrity::file_hash_from_name(file) {
+        Some(expected) => {
+            let stream = integrity::verify_upload(stream, expected);
+            let (stream, hashing) =
+                integrity::hash_upload(stream, Compression::from_file_name(file));
+            (stream, Some(hashing))
+        }
+        None => (stream, None),
+    };
+    // Holds the upload's bytes against the quotas until it is stored and counted.
+    let (stream, _reservation) = state
+        .usage
+        .limit(stream, auth::uploader(&token), size)
+        .map_err(|e| {
+            warn!(file = %file, error = %e, "Rejecting NAR upload over quota");
+            e.status()
+        })?;
+
+    if let Err(e) = state.storage.put_nar(file, stream).await {
+        if let Some(e) = IntegrityError::from_storage(&e) {
+            warn!(file = %file, error = %e, "Rejecting corrupt NAR upload");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        if let Some(e) = QuotaError::from_storage(&e) {
+            warn!(file = %file, error = %e, "Rejecting NAR upload over quota");
+            return Err(e.status());
+        }
+        error!(file = %file, error = %e, "Failed to write NAR");
+        return Err(storage_status(e));
+    }
+    if let Some(hashing) = hashing {
+        match hashing.await {
+            Ok(Ok(uploaded)) => state.uploaded.record(file, uploaded),
+            Ok(Err(e)) => debug!(file = %file, error = %e, "Uploaded NAR didn't read back"),
+            Err(e) => debug!(file = %file, error = %e, "Hashing uploaded NAR failed"),
+        }
+    }
+
+    info!(file = %file, "Successfully wrote NAR");
+    info!(target: "audit", token = %auth::uploader(&token), file = %file, "Uploaded NAR");
+    Ok(StatusCode::OK)
+}
+
+/// The id out of `<drv-hash>!<output>.doi`.
+fn realisation_id(file: &str) -> Result<&str, StatusCode> {
+    let id = file.strip_suffix(".doi").ok_or(StatusCode::NOT_FOUND)?;
+    if !realisation::is_valid_id(id) {
+        warn!(file = %file, "Rejecting invalid realisation id");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(id)
+}
+
+pub async fn get_realisation(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let id = realisation_id(&file)?;
+    info!(id = %id, "Fetching realisation");
+    match state.storage.get_realisation(id).await {
+        Ok(content) => Ok(([(header::CONTENT_TYPE, "application/json")], content)),
+        Err(StorageError::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!(id = %id, error = %e, "Failed to read realisation");
+            Err(storage_status(e))
+        }
+    }
+}
+
+/// Stores a realisation of a content-addressed derivation output, as `nix copy` uploads
+/// them after the output path. Like narinfo, it must be signed by a trusted builder and
+/// is stored signed by the cache instead. The output path must already be in the cache.
+pub async fn put_realisation(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+    body: String,
+) -> StatusCode {
+    let id = match realisation_id(&file) {
+        Ok(id) => id,
+        Err(status) => return status,
+    };
+    info!(id = %id, "Uploading realisation");
+
+    let mut realisation = match Realisation::parse(&body) {
+        Ok(realisation) => realisation,
+        Err(e) => {
+            warn!(id = %id, error = %e, "Rejecting malformed realisation");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    if realisation.id != id {
+        warn!(id = %id, uploaded = %realisation.id, "Realisation uploaded under the wrong id");
+        return StatusCode::BAD_REQUEST;
+    }
+
+    if let Err(e) = signing::resign_realisation(
+        &mut realisation,
+        &upload_keys(&state),
+        state.cache_key.as_deref(),
+    ) {
+        warn!(id = %id, error = %e, "Rejecting realisation");
+        return match e {
+            SigError::Unsigned => StatusCode::UNAUTHORIZED,
+            SigError::BadSignature => StatusCode::FORBIDDEN,
+        };
+    }
+
+    let out_path = match realisation.out_path() {
+        Ok(out_path) => out_path,
+        Err(e) => {
+            warn!(id = %id, error = %e, "Rejecting realisation");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    match state.storage.get_narinfo(out_path.hash_part()).await {
+        Ok(_) => {}
+        Err(StorageError::NotFound) => {
+            warn!(id = %id, out_path = %out_path, "Rejecting realisation of a path not in the cache");
+            return StatusCode::BAD_REQUEST;
+        }
+        Err(e) => {
+            error!(id = %id, error = %e, "Failed to look up realised path");
+            return storage_status(e);
+        }
+    }
+
+    match state
+        .storage
+        .put_realisation(id, realisation.to_json())
+        .await
+    {
+        Ok(_) => {
+            info!(id = %id, "Successfully wrote realisation");
+            info!(
+                target: "audit",
+                token = %auth::uploader(&token),
+                id = %id,
+                out_path = %out_path,
+                "Uploaded realisation"
+            );
+            StatusCode::OK
+        }
+        Err(e) => {
+            error!(id = %id, error = %e, "Failed to write realisation");
+            storage_status(e)
+        }
+    }
+}
+
+fn log_name(drv: &str) -> Result<&str, StatusCode> {
+    if !storage::is_valid_log_name(drv) {
+        warn!(drv = %drv, "Rejecting invalid derivation name");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(drv)
+}
+
+/// Whether the client takes `Content-Encoding: br`.
+fn accepts_brotli(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| coding.split(';').next().unwrap_or_default().trim() == "br")
+}
+
+/// Serves a build log for `nix log`. It is sent brotli-encoded as stored if the
+/// client takes that, decompressed otherwise.
+pub async fn get_log(
+    State(state): State<AppState>,
+    Path(drv): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let drv = log_name(&drv)?;
+    info!(drv = %drv, "Fetching build log");
+    let stream = state.storage.get_log(drv).await.map_err(|e| match e {
+        StorageError::NotFound => StatusCode::NOT_FOUND,
+        e => {
+            error!(drv = %drv, error = %e, "Failed to read build log");
+            storage_status(e)
+        }
+    })?;
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8");
+    let (response, stream) = if accepts_brotli(&headers) {
+        (response.header(header::CONTENT_ENCODING, "br"), stream)
+    } else {
+        let plain = compression::decompress_log(stream).map_err(|e| {
+            error!(drv = %drv, error = %e, "Failed to decompress build log");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        (response, plain)
+    };
+    response
+        .body(Body::from_stream(stream))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Stores a build log, as uploaded by `nix store copy-log`, compressed. Nix may send it
+/// already compressed with a `Content-Encoding:`.
+pub async fn put_log(
+    State(state): State<AppState>,
+    Path(drv): Path<String>,
+    token: Option<Extension<Arc<Token>>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, StatusCode> {
+    let drv = log_name(&drv)?;
+    let encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let Some(encoding) = compression::from_content_encoding(encoding) else {
+        warn!(drv = %drv, encoding = %encoding, "Rejecting log with unknown encoding");
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    };
+    let stream = body
+        .into_data_stream()
+        .map_err(std::io::Error::other)
+        .boxed();
+    let stream = compression::compress_log(stream, &encoding)
+        .map_err(|_| StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+    if let Err(e) = state.storage.put_log(drv, stream).await {
+        error!(drv = %drv, error = %e, "Failed to write build log");
+        return Err(storage_status(e));
+    }
+    info!(drv = %drv, "Successfully wrote build log");
+    info!(target: "audit", token = %auth::uploader(&token), drv = %drv, "Uploaded build log");
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signing::test_key;
+    use crate::storage::DiskStorage;
+    use crate::test::{request, send, test_state, upload_hello, HELLO};
+    use axum::http::{header, Method, Request};
+    use axum::Router;
+
+    async fn app() -> (tempfile::TempDir, AppState, Router) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let state = test_state(storage);
+        let app = crate::router(state.clone());
+        (dir, state, app)
+    }
+
+    #[test]
+    fn ranges() {
+        let range = |start, end| RangeRequest::Partial(ByteRange { start, end });
+        assert_eq!(parse_range("bytes=0-99", 1000), range(0, 99));
+        assert_eq!(parse_range("bytes=500-", 1000), range(500, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), range(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), range(0, 999));
+        assert_eq!(parse_range("bytes=990-2000", 1000), range(990, 999));
+        assert_eq!(parse_range("bytes=7-7", 1000), range(7, 7));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=5-2", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[tokio::test]
+    async fn signs_realisations_of_stored_paths() {
+        let (_dir, state, app) = app().await;
+        let cache_key = state.cache_key.as_ref().unwrap().public_key();
+        upload_hello(&app).await;
+
+        let id = "sha256:ba1ec0bde44b16bbab7a3e6e3ea5bb0df0e0a2d1bc1fc2bcd2a4ab5b2ba70ac4!out";
+        let realisation_uri = format!("/realisations/{id}.doi");
+        let mut realisation = Realisation {
+            id: id.to_owned(),
+            out_path: format!("{HELLO}-hello"),
+            signatures: Vec::new(),
+            dependent_realisations: Default::default(),
+        };
+        let (status, _) = request(
+            &app,
+            Method::PUT,
+            &realisation_uri,
+            realisation.to_json().into(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let fingerprint = realisation.fingerprint();
+        realisation
+            .signatures
+            .push(test_key("builder-1", 2).sign(&fingerprint));
+        let other_uri = realisation_uri.replace("!out", "!dev");
+        let (status, _) =
+            request(&app, Method::PUT, &other_uri, realisation.to_json().into()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request(
+            &app,
+            Method::PUT,
+            &realisation_uri,
+            realisation.to_json().into(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = request(&app, Method::GET, &realisation_uri, vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        let served = Realisation::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+        assert_eq!(served.signatures.len(), 1);
+        assert!(cache_key.verify(&fingerprint, &served.signatures[0]));
+        let (status, _) = request(&app, Method::GET, &other_uri, vec![]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn serves_logs_plain_or_brotli() {
+        let (_dir, _, app) = app().await;
+
+        // `nix store copy-log` uploads plain, `nix log` takes brotli.
+        let log_uri = format!("/log/{HELLO}-hello.drv");
+        let log = b"building hello\nhello> ok\n".repeat(100);
+        let (status, _) = request(&app, Method::GET, &log_uri, vec![]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = request(&app, Method::PUT, "/log/hello.txt", log.clone()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request(&app, Method::PUT, &log_uri, log.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = request(&app, Method::GET, &log_uri, vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, log);
+        let brotli = Request::get(&log_uri)
+            .header(header::ACCEPT_ENCODING, "gzip, br")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, body) = send(&app, brotli).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_ENCODING], "br");
+        assert!(body.len() < log.len());
+    }
+}
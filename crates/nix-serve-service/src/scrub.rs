@@ -0,0 +1,539 @@
+//! Background integrity scrubbing. Disk rot or a partial write would otherwise go
+//! unnoticed until a client fails to substitute, so every stored NAR is re-hashed
+//! against its narinfo now and then. Paths whose NAR is gone or doesn't match are
+//! quarantined: their narinfo is moved aside so the path is no longer served, and the
+//! next upload of it replaces the broken copy.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use common::narinfo::{NarInfo, StorePath};
+
+use crate::config::ScrubConfig;
+use crate::events::{DeletionReason, Events};
+use crate::integrity::{self, IntegrityError};
+use crate::storage::{NixCacheStorage, StorageError};
+
+/// Name of the scrubber's document in the storage backend's meta area.
+const STATE_FILE: &str = "scrub.json";
+
+#[derive(Debug, Error)]
+pub enum ScrubError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("corrupt scrub state: {0}")]
+    State(#[from] serde_json::Error),
+}
+
+/// What is wrong with a stored path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum Problem {
+    Unparsable {
+        error: String,
+    },
+    /// The NAR the narinfo points at isn't there.
+    MissingNar {
+        nar: String,
+    },
+    /// The NAR doesn't match the narinfo's hashes or sizes, or can't be decompressed.
+    Corrupt {
+        error: String,
+    },
+    /// The NAR can't be checked, e.g. for an unsupported hash algorithm.
+    Unverifiable {
+        error: String,
+    },
+    /// A path in `References:` isn't in the cache, so the closure can't be substituted.
+    MissingReference {
+        reference: String,
+    },
+}
+
+impl Problem {
+    /// Every [`Problem::kind`].
+    pub const KINDS: [&'static str; 5] = [
+        "unparsable",
+        "missing_nar",
+        "corrupt",
+        "unverifiable",
+        "missing_reference",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Problem::Unparsable { .. } => "unparsable",
+            Problem::MissingNar { .. } => "missing_nar",
+            Problem::Corrupt { .. } => "corrupt",
+            Problem::Unverifiable { .. } => "unverifiable",
+            Problem::MissingReference { .. } => "missing_reference",
+        }
+    }
+
+    /// Whether the path can't be substituted as it is, so it shouldn't be served.
+    fn is_broken(&self) -> bool {
+        matches!(self, Problem::MissingNar { .. } | Problem::Corrupt { .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finding {
+    pub hash: String,
+    pub store_path: Option<String>,
+    #[serde(flatten)]
+    pub problem: Problem,
+    pub quarantined: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubReport {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub paths_checked: usize,
+    /// Bytes of NARs read, as stored.
+    pub bytes_checked: u64,
+    pub findings: Vec<Finding>,
+    /// Quarantined paths that have since been uploaded again intact.
+    pub healed: Vec<String>,
+    /// Paths that couldn't be checked because the storage backend failed.
+    pub errors: usize,
+}
+
+/// A path taken out of the cache by the scrubber.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quarantined {
+    pub store_path: Option<String>,
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub problem: Problem,
+    /// The narinfo as it was served.
+    pub narinfo: String,
+}
+
+/// What the scrubber remembers between runs and restarts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScrubStatus {
+    #[serde(default)]
+    pub last_run: Option<ScrubReport>,
+    /// By hash part.
+    #[serde(default)]
+    pub quarantined: BTreeMap<String, Quarantined>,
+}
+
+/// Counters for `/metrics`.
+#[derive(Debug, Clone, Default)]
+pub struct ScrubStats {
+    pub runs: u64,
+    pub quarantined_total: u64,
+    pub last_finished: Option<DateTime<Utc>>,
+    pub paths_checked: u64,
+    pub bytes_checked: u64,
+    /// Findings of the last run by [`Problem::kind`].
+    pub findings: BTreeMap<&'static str, u64>,
+    pub quarantined: u64,
+}
+
+pub struct Scrubber {
+    storage: Arc<dyn NixCacheStorage>,
+    config: ScrubConfig,
+    stats: Mutex<ScrubStats>,
+    runs: AtomicU64,
+    quarantined_total: AtomicU64,
+    /// Serialises runs and state updates.
+    lock: tokio::sync::Mutex<()>,
+    events: Arc<Events>,
+    /// Set on cluster replicas, which don't check references.
+    clustered: bool,
+}
+
+impl Scrubber {
+    pub fn new(storage: Arc<dyn NixCacheStorage>, config: ScrubConfig) -> Self {
+        Scrubber {
+            storage,
+            config,
+            stats: Mutex::new(ScrubStats::default()),
+            runs: AtomicU64::new(0),
+            quarantined_total: AtomicU64::new(0),
+            lock: tokio::sync::Mutex::new(()),
+            events: Arc::default(),
+            clustered: false,
+        }
+    }
+
+    /// Reports quarantined paths to `events`.
+    pub fn with_events(mut self, events: Arc<Events>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Skips looking for missing references, which on a cluster replica are usually
+    /// just held by another one.
+    pub fn in_cluster(mut self) -> Self {
+        self.clustered = true;
+        self
+    }
+
+    pub fn stats(&self) -> ScrubStats {
+        ScrubStats {
+            runs: self.runs.load(Ordering::Relaxed),
+            quarantined_total: self.quarantined_total.load(Ordering::Relaxed),
+            ..self.stats.lock().unwrap().clone()
+        }
+    }
+
+    fn update_stats(&self, status: &ScrubStatus) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.quarantined = status.quarantined.len() as u64;
+        if let Some(report) = &status.last_run {
+            stats.last_finished = Some(report.finished);
+            stats.paths_checked = report.paths_checked as u64;
+            stats.bytes_checked = report.bytes_checked;
+            stats.findings.clear();
+            for finding in &report.findings {
+                *stats.findings.entry(finding.problem.kind()).or_default() += 1;
+            }
+        }
+    }
+
+    async fn load_status(&self) -> Result<ScrubStatus, ScrubError> {
+        match self.storage.get_meta(STATE_FILE).await {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(StorageError::NotFound) => Ok(ScrubStatus::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save_status(&self, status: &ScrubStatus) -> Result<(), ScrubError> {
+        let content = serde_json::to_vec(status)?;
+        self.storage.put_meta(STATE_FILE, content).await?;
+        self.update_stats(status);
+        Ok(())
+    }
+
+    /// The last run and what is in quarantine.
+    pub async fn status(&self) -> Result<ScrubStatus, ScrubError> {
+        let _guard = self.lock.lock().await;
+        let status = self.load_status().await?;
+        self.update_stats(&status);
+        Ok(status)
+    }
+
+    /// Re-hashes the NAR of `info`. A failure to read is retried once, since it may as
+    /// well have been the network as a damaged file.
+    async fn verify(&self, info: &NarInfo) -> Result<(), Option<Problem>> {
+        let mut retried = false;
+        loop {
+            return match integrity::verify_narinfo(self.storage.as_ref(), info).await {
+                Ok(_) => Ok(()),
+                Err(IntegrityError::Read(_)) if !retried => {
+                    retried = true;
+                    continue;
+                }
+                Err(IntegrityError::MissingNar(nar)) => Err(Some(Problem::MissingNar { nar })),
+                Err(e @ (IntegrityError::Mismatch { .. } | IntegrityError::Read(_))) => {
+                    Err(Some(Problem::Corrupt {
+                        error: e.to_string(),
+                    }))
+                }
+                Err(
+                    e @ (IntegrityError::BadUrl(_)
+                    | IntegrityError::UnsupportedCompression(_)
+                    | IntegrityError::UnsupportedHash(_)),
+                ) => Err(Some(Problem::Unverifiable {
+                    error: e.to_string(),
+                })),
+                Err(IntegrityError::Storage(e)) => {
+                    error!(hash = %info.store_path.hash_part(), error = %e, "Failed to read NAR for scrubbing");
+                    Err(None)
+                }
+            };
+        }
+    }
+
+    /// Takes a path out of the cache, unless its narinfo changed since it was checked.
+    /// Returns whether it was quarantined.
+    async fn quarantine(
+        &self,
+        status: &mut ScrubStatus,
+        hash: &str,
+        checked: &str,
+        store_path: Option<String>,
+        problem: &Problem,
+    ) -> Result<bool, ScrubError> {
+        match self.storage.get_narinfo(hash).await {
+            Ok(current) if current == checked => {}
+            Ok(_) | Err(StorageError::NotFound) => {
+                info!(hash = %hash, "Not quarantining path that changed while it was checked");
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        status.quarantined.insert(
+            hash.to_owned(),
+            Quarantined {
+                store_path: store_path.clone(),
+                time: Utc::now(),
+                problem: problem.clone(),
+                narinfo: checked.to_owned(),
+            },
+        );
+        // Saved before the narinfo goes, so it is never lost.
+        self.save_status(status).await?;
+        self.storage.delete_narinfo(hash).await?;
+        match self.storage.delete_listing(hash).await {
+            Ok(()) | Err(StorageError::NotFound) => {}
+            Err(e) => warn!(hash = %hash, error = %e, "Failed to delete NAR listing"),
+        }
+        self.quarantined_total.fetch_add(1, Ordering::Relaxed);
+        warn!(hash = %hash, problem = problem.kind(), "Quarantined path");
+        if let Some(store_path) = &store_path {
+            self.events
+                .path_deleted(store_path, DeletionReason::Quarantine);
+        }
+        Ok(true)
+    }
+
+    /// Checks every path in the cache once.
+    pub async fn run(&self) -> Result<ScrubReport, ScrubError> {
+        let _guard = self.lock.lock().await;
+        let mut status = self.load_status().await?;
+        let started = Utc::now();
+        let mut report = ScrubReport {
+            started,
+            finished: started,
+            paths_checked: 0,
+            bytes_checked: 0,
+            findings: Vec::new(),
+            healed: Vec::new(),
+            errors: 0,
+        };
+
+        let mut present = HashSet::new();
+        let mut references: HashMap<String, Vec<StorePath>> = HashMap::new();
+        for object in self.storage.list_narinfos().await? {
+            let hash = object.name;
+            let content = match self.storage.get_narinfo(&hash).await {
+                Ok(content) => content,
+                Err(StorageError::NotFound) => continue,
+                Err(e) => {
+                    error!(hash = %hash, error = %e, "Failed to read narinfo for scrubbing");
+                    report.errors += 1;
+                    present.insert(hash);
+                    continue;
+                }
+            };
+            report.paths_checked += 1;
+            present.insert(hash.clone());
+
+            let info = match content.parse::<NarInfo>() {
+                Ok(info) => info,
+                Err(e) => {
+                    warn!(hash = %hash, error = %e, "Scrubbed unparsable narinfo");
+                    report.findings.push(Finding {
+                        hash,
+                        store_path: None,
+                        problem: Problem::Unparsable {
+                            error: e.to_string(),
+                        },
+                        quarantined: false,
+                    });
+                    continue;
+                }
+            };
+            let store_path = Some(info.store_path.to_absolute());
+            match self.verify(&info).await {
+                Ok(()) => {
+                    report.bytes_checked += info.file_size.unwrap_or(info.nar_size);
+                    if status.quarantined.remove(&hash).is_some() {
+                        info!(hash = %hash, "Quarantined path was uploaded again");
+                        report.healed.push(hash.clone());
+                    }
+                }
+                Err(None) => report.errors += 1,
+                Err(Some(problem)) => {
+                    warn!(hash = %hash, problem = problem.kind(), details = ?problem, "Scrub found a damaged path");
+                    let quarantined = self.config.quarantine
+                        && problem.is_broken()
+                        && self
+                            .quarantine(&mut status, &hash, &content, store_path.clone(), &problem)
+                            .await?;
+                    if quarantined {
+                        present.remove(&hash);
+                    }
+                    report.findings.push(Finding {
+                        hash: hash.clone(),
+                        store_path: store_path.clone(),
+                        problem,
+                        quarantined,
+                    });
+                }
+            }
+            references.insert(hash, info.references);
+        }
+
+        // Checked last, so references to paths quarantined above count as missing.
+        let mut missing: Vec<Finding> = Vec::new();
+        for (hash, references) in &references {
+            if self.clustered || !present.contains(hash) {
+                continue;
+            }
+            for reference in references {
+                if present.contains(reference.hash_part()) {
+                    continue;
+                }
+                missing.push(Finding {
+                    hash: hash.clone(),
+                    store_path: None,
+                    problem: Problem::MissingReference {
+                        reference: reference.to_absolute(),
+                    },
+                    quarantined: false,
+                });
+            }
+        }
+        missing.sort_by(|a, b| a.hash.cmp(&b.hash));
+        report.findings.extend(missing);
+
+        report.finished = Utc::now();
+        status.last_run = Some(report.clone());
+        self.save_status(&status).await?;
+        self.runs.fetch_add(1, Ordering::Relaxed);
+
+        info!(
+            paths_checked = report.paths_checked,
+            bytes_checked = report.bytes_checked,
+            findings = report.findings.len(),
+            quarantined = report.findings.iter().filter(|f| f.quarantined).count(),
+            healed = report.healed.len(),
+            errors = report.errors,
+            "Scrub finished"
+        );
+        Ok(report)
+    }
+
+    /// Runs a scrub on the configured interval.
+    pub async fn background(self: Arc<Self>) {
+        if let Err(e) = self.status().await {
+            error!(error = %e, "Failed to load scrub state");
+        }
+        let Some(secs) = self.config.interval_secs else {
+            return;
+        };
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(secs));
+        // Fires immediately; skip that so startup doesn't read the whole cache.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.run().await {
+                error!(error = %e, "Scheduled scrub failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::integrity::sha256;
+    use crate::storage::DiskStorage;
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    const A: &str = "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1";
+    const B: &str = "0mdqa9w1p6cmli6976v4wi0sw9r4p5pr";
+    const C: &str = "kj7lzfd1877wk11c9c730yzhigwjl6bw";
+    const D: &str = "s649vcs2asa4lbs8hg93hyix187gc7s7";
+
+    async fn put_nar(storage: &dyn NixCacheStorage, file: &str, content: &[u8]) {
+        let content = Bytes::copy_from_slice(content);
+        storage
+            .put_nar(file, futures::stream::once(async { Ok(content) }).boxed())
+            .await
+            .unwrap();
+    }
+
+    /// Stores a path whose NAR is `content`, referring to `references`.
+    async fn add_path(
+        storage: &dyn NixCacheStorage,
+        hash: &str,
+        content: &[u8],
+        references: &[&str],
+    ) -> String {
+        let file = format!("{}.nar", sha256(content).to_nix32());
+        put_nar(storage, &file, content).await;
+        let references: Vec<String> = references.iter().map(|r| format!("{r}-dep")).collect();
+        let narinfo = format!(
+            "StorePath: /nix/store/{hash}-path\n\
+             URL: nar/{file}\n\
+             Compression: none\n\
+             NarHash: {}\n\
+             NarSize: {}\n\
+             References: {}\n",
+            sha256(content),
+            content.len(),
+            references.join(" ")
+        );
+        storage.put_narinfo(hash, narinfo).await.unwrap();
+        file
+    }
+
+    fn kinds(report: &ScrubReport) -> Vec<(&str, &'static str, bool)> {
+        let mut kinds: Vec<_> = report
+            .findings
+            .iter()
+            .map(|f| (f.hash.as_str(), f.problem.kind(), f.quarantined))
+            .collect();
+        kinds.sort();
+        kinds
+    }
+
+    #[tokio::test]
+    async fn quarantines_damaged_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn NixCacheStorage> =
+            Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        // A -> B, B -> C, D on its own.
+        add_path(storage.as_ref(), A, b"a", &[B]).await;
+        let b_file = add_path(storage.as_ref(), B, b"b", &[C]).await;
+        let c_file = add_path(storage.as_ref(), C, b"c", &[]).await;
+        add_path(storage.as_ref(), D, b"d", &[]).await;
+        put_nar(storage.as_ref(), &b_file, b"rotten").await;
+        storage.delete_nar(&c_file).await.unwrap();
+
+        let scrubber = Scrubber::new(storage.clone(), ScrubConfig::default());
+        let report = scrubber.run().await.unwrap();
+        assert_eq!(report.paths_checked, 4);
+        assert_eq!(
+            kinds(&report),
+            [
+                (B, "corrupt", true),
+                (A, "missing_reference", false),
+                (C, "missing_nar", true),
+            ]
+        );
+        assert!(matches!(
+            storage.get_narinfo(B).await,
+            Err(StorageError::NotFound)
+        ));
+        storage.get_narinfo(D).await.unwrap();
+
+        let status = scrubber.status().await.unwrap();
+        assert_eq!(status.quarantined.len(), 2);
+        assert!(status.quarantined[B].narinfo.contains("/nix/store/"));
+        let stats = scrubber.stats();
+        assert_eq!(stats.quarantined, 2);
+        assert_eq!(stats.findings["corrupt"], 1);
+
+        // Uploading the path again brings it back.
+        add_path(storage.as_ref(), B, b"b", &[C]).await;
+        let report = scrubber.run().await.unwrap();
+        assert_eq!(report.healed, [B]);
+        assert_eq!(kinds(&report), [(B, "missing_reference", false)]);
+        assert_eq!(scrubber.stats().quarantined, 1);
+    }
+}
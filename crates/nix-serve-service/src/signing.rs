@@ -0,0 +1,339 @@
+use base64::engine::general_purpose::STANDARD as b64;
+use base64::Engine;
+use common::narinfo::NarInfo;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use thiserror::Error;
+
+use crate::realisation::Realisation;
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("failed to read key file {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("key is not of the form <name>:<base64>")]
+    Format,
+    #[error("key has the wrong length")]
+    Length,
+    #[error("invalid key: {0}")]
+    Invalid(#[from] ed25519_dalek::SignatureError),
+}
+
+fn split_key(s: &str) -> Result<(&str, Vec<u8>), KeyError> {
+    let (name, data) = s.trim().split_once(':').ok_or(KeyError::Format)?;
+    if name.is_empty() {
+        return Err(KeyError::Format);
+    }
+    let bytes = b64.decode(data).map_err(|_| KeyError::Format)?;
+    Ok((name, bytes))
+}
+
+/// A secret key in the format written by `nix key generate-secret`.
+pub struct SecretKey {
+    name: String,
+    key: SigningKey,
+}
+
+impl SecretKey {
+    pub fn parse(s: &str) -> Result<Self, KeyError> {
+        let (name, bytes) = split_key(s)?;
+        let bytes: [u8; 64] = bytes.try_into().map_err(|_| KeyError::Length)?;
+        Ok(SecretKey {
+            name: name.to_owned(),
+            key: SigningKey::from_keypair_bytes(&bytes)?,
+        })
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, KeyError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| KeyError::Read(path.to_owned(), e))?;
+        Self::parse(&content)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.key.verifying_key(),
+        }
+    }
+
+    /// Signs `fingerprint`, giving a `<name>:<base64>` string as used in `Sig:` lines.
+    pub fn sign(&self, fingerprint: &str) -> String {
+        let signature = self.key.sign(fingerprint.as_bytes());
+        format!("{}:{}", self.name, b64.encode(signature.to_bytes()))
+    }
+}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretKey")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// A public key in the format of `trusted-public-keys`.
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    name: String,
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    pub fn parse(s: &str) -> Result<Self, KeyError> {
+        let (name, bytes) = split_key(s)?;
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| KeyError::Length)?;
+        Ok(PublicKey {
+            name: name.to_owned(),
+            key: VerifyingKey::from_bytes(&bytes)?,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks a `<name>:<base64>` signature. Signatures made by a key with a different
+    /// name never verify.
+    pub fn verify(&self, fingerprint: &str, sig: &str) -> bool {
+        let Ok((name, bytes)) = split_key(sig) else {
+            return false;
+        };
+        if name != self.name {
+            return false;
+        }
+        let Ok(bytes) = <[u8; 64]>::try_from(bytes) else {
+            return false;
+        };
+        self.key
+            .verify(fingerprint.as_bytes(), &Signature::from_bytes(&bytes))
+            .is_ok()
+    }
+}
+
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.name, b64.encode(self.key.as_bytes()))
+    }
+}
+
+/// True if any of `keys` produced `sig` over `fingerprint`.
+pub fn verify_any(keys: &[PublicKey], fingerprint: &str, sig: &str) -> bool {
+    keys.iter().any(|key| key.verify(fingerprint, sig))
+}
+
+#[derive(Debug, Error)]
+pub enum SigError {
+    #[error("upload carries no builder signature")]
+    Unsigned,
+    #[error("no builder signature verifies against the trusted builder keys")]
+    BadSignature,
+}
+
+/// The field builders put their ephemeral signatures in.
+pub const BUILDER_SIG: &str = "Builder-Sig";
+
+/// Checks that one of `builder_sigs`, or of the `sigs` made by a trusted builder key,
+/// is a valid builder signature over `fingerprint`. Then drops the builder signatures
+/// from `sigs` and replaces any old signature by the cache with a fresh one.
+fn resign(
+    fingerprint: &str,
+    sigs: &mut Vec<String>,
+    builder_sigs: &[String],
+    builder_keys: &[PublicKey],
+    cache_key: Option<&SecretKey>,
+) -> Result<(), SigError> {
+    let is_builder_sig = |sig: &str| {
+        let name = sig.split_once(':').map_or(sig, |(name, _)| name);
+        builder_keys.iter().any(|key| key.name() == name)
+    };
+
+    let candidates: Vec<&str> = builder_sigs
+        .iter()
+        .chain(sigs.iter().filter(|sig| is_builder_sig(sig)))
+        .map(String::as_str)
+        .collect();
+    if candidates.is_empty() {
+        return Err(SigError::Unsigned);
+    }
+    if !candidates
+        .iter()
+        .any(|sig| verify_any(builder_keys, fingerprint, sig))
+    {
+        return Err(SigError::BadSignature);
+    }
+
+    sigs.retain(|sig| {
+        let own = cache_key.is_some_and(|key| sig.starts_with(&format!("{}:", key.name())));
+        !own && !is_builder_sig(sig)
+    });
+    if let Some(key) = cache_key {
+        sigs.push(key.sign(fingerprint));
+    }
+    Ok(())
+}
+
+/// Checks that a trusted builder signed the narinfo and swaps its signatures for the
+/// cache's own.
+///
+/// Builders sign with `Builder-Sig:` lines. `Sig:` lines made by a trusted builder key
+/// count too, which is what `nix store sign` followed by `nix copy` produces. Builder
+/// signatures are dropped from the stored narinfo since clients can't do anything with
+/// them; if the cache has a key, a `Sig:` line made with it is appended.
+pub fn resign_narinfo(
+    info: &mut NarInfo,
+    builder_keys: &[PublicKey],
+    cache_key: Option<&SecretKey>,
+) -> Result<(), SigError> {
+    let fingerprint = info.fingerprint();
+    let builder_sigs: Vec<String> = info.extra_values(BUILDER_SIG).map(str::to_owned).collect();
+    resign(
+        &fingerprint,
+        &mut info.sigs,
+        &builder_sigs,
+        builder_keys,
+        cache_key,
+    )?;
+    info.extra.retain(|(key, _)| key != BUILDER_SIG);
+    Ok(())
+}
+
+/// Like [`resign_narinfo`], for a realisation. There is nowhere to put a separate
+/// builder signature in its JSON, so the builder signs it with `nix store sign`.
+pub fn resign_realisation(
+    realisation: &mut Realisation,
+    builder_keys: &[PublicKey],
+    cache_key: Option<&SecretKey>,
+) -> Result<(), SigError> {
+    let fingerprint = realisation.fingerprint();
+    resign(
+        &fingerprint,
+        &mut realisation.signatures,
+        &[],
+        builder_keys,
+        cache_key,
+    )
+}
+
+/// A deterministic key for tests.
+#[cfg(test)]
+pub(crate) fn test_key(name: &str, seed: u8) -> SecretKey {
+    SecretKey {
+        name: name.to_owned(),
+        key: SigningKey::from_bytes(&[seed; 32]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = test_key("test-cache-1", 7);
+        let roundtrip = format!("test-cache-1:{}", b64.encode(key.key.to_keypair_bytes()));
+        assert_eq!(
+            SecretKey::parse(&roundtrip)
+                .unwrap()
+                .public_key()
+                .to_string(),
+            key.public_key().to_string()
+        );
+        let public = PublicKey::parse(&key.public_key().to_string()).unwrap();
+
+        let sig = key.sign("1;/nix/store/abc-hello;sha256:xyz;123;");
+        assert!(sig.starts_with("test-cache-1:"));
+        assert!(public.verify("1;/nix/store/abc-hello;sha256:xyz;123;", &sig));
+        assert!(!public.verify("1;/nix/store/abc-hello;sha256:xyz;124;", &sig));
+
+        let renamed = sig.replacen("test-cache-1", "other", 1);
+        assert!(!public.verify("1;/nix/store/abc-hello;sha256:xyz;123;", &renamed));
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert!(matches!(PublicKey::parse("nocolon"), Err(KeyError::Format)));
+        assert!(matches!(
+            PublicKey::parse("name:aGVsbG8="),
+            Err(KeyError::Length)
+        ));
+        assert!(SecretKey::parse(":AAAA").is_err());
+    }
+
+    #[test]
+    fn resign_replaces_builder_sig() {
+        let builder = test_key("builder", 7);
+        let cache = test_key("cache.fyfaen.as-1", 9);
+        let builder_keys = vec![builder.public_key()];
+
+        let unsigned: NarInfo = "StorePath: /nix/store/7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello
+URL: nar/x.nar
+NarHash: sha256:0yzhigwjl6bws649vcs2asa4lbs8hg93hyix187gc7s7a74w5h80
+NarSize: 4242
+References:
+"
+        .parse()
+        .unwrap();
+        let fingerprint = unsigned.fingerprint();
+
+        let mut info = unsigned.clone();
+        assert!(matches!(
+            resign_narinfo(&mut info, &builder_keys, Some(&cache)),
+            Err(SigError::Unsigned)
+        ));
+
+        let mut forged = unsigned.clone();
+        forged
+            .extra
+            .push((BUILDER_SIG.to_owned(), cache.sign(&fingerprint)));
+        assert!(matches!(
+            resign_narinfo(&mut forged, &builder_keys, Some(&cache)),
+            Err(SigError::BadSignature)
+        ));
+
+        let mut signed = unsigned.clone();
+        signed
+            .extra
+            .push((BUILDER_SIG.to_owned(), builder.sign(&fingerprint)));
+        resign_narinfo(&mut signed, &builder_keys, Some(&cache)).unwrap();
+        assert!(signed.extra.is_empty());
+        assert_eq!(signed.sigs.len(), 1);
+        assert!(cache.public_key().verify(&fingerprint, &signed.sigs[0]));
+
+        // A `Sig:` from `nix store sign` with the builder key is accepted and replaced.
+        let mut nix_signed = unsigned.clone();
+        nix_signed.sigs.push(builder.sign(&fingerprint));
+        resign_narinfo(&mut nix_signed, &builder_keys, Some(&cache)).unwrap();
+        assert_eq!(nix_signed.sigs.len(), 1);
+        assert!(nix_signed.sigs[0].starts_with("cache.fyfaen.as-1:"));
+    }
+
+    #[test]
+    fn resign_realisation_needs_builder_sig() {
+        let builder = test_key("builder", 7);
+        let cache = test_key("cache.fyfaen.as-1", 9);
+        let builder_keys = vec![builder.public_key()];
+        let mut realisation = Realisation {
+            id: "sha256:ba1ec0bde44b16bbab7a3e6e3ea5bb0df0e0a2d1bc1fc2bcd2a4ab5b2ba70ac4!out"
+                .to_owned(),
+            out_path: "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello".to_owned(),
+            signatures: vec!["other:c2ln".to_owned()],
+            dependent_realisations: Default::default(),
+        };
+        assert!(matches!(
+            resign_realisation(&mut realisation.clone(), &builder_keys, Some(&cache)),
+            Err(SigError::Unsigned)
+        ));
+
+        let fingerprint = realisation.fingerprint();
+        realisation.signatures.push(builder.sign(&fingerprint));
+        resign_realisation(&mut realisation, &builder_keys, Some(&cache)).unwrap();
+        assert_eq!(realisation.signatures.len(), 2);
+        assert!(cache
+            .public_key()
+            .verify(&fingerprint, &realisation.signatures[1]));
+    }
+}
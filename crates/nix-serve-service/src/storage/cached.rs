@@ -0,0 +1,432 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use super::{ByteRange, ByteStream, NixCacheStorage, ObjectInfo, StorageError};
+use crate::config::NarInfoCacheConfig;
+
+enum Cached {
+    Present(String),
+    /// A lookup that came back empty, remembered until the deadline.
+    Missing(Instant),
+}
+
+/// A least recently used map. `order` maps each entry's last use to its key.
+struct Lru {
+    entries: HashMap<String, (Cached, u64)>,
+    order: BTreeMap<u64, String>,
+    clock: u64,
+    capacity: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &str) -> Option<&Cached> {
+        self.clock += 1;
+        let (_, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.clock;
+        self.order.insert(self.clock, key.to_owned());
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    fn insert(&mut self, key: String, value: Cached) {
+        self.remove(&key);
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(key, (value, self.clock));
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Lookups answered from the negative cache.
+    pub negative_hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+    pub entries: u64,
+}
+
+/// Recently served narinfo, and recent misses, so the flood of parallel lookups Nix
+/// makes doesn't all go to the storage backend.
+pub struct NarInfoCache {
+    lru: Mutex<Lru>,
+    negative_ttl: Duration,
+    /// Bumped on every invalidation, so a lookup that raced with a write doesn't put
+    /// what it read back into the cache.
+    generation: AtomicU64,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl NarInfoCache {
+    pub fn new(config: &NarInfoCacheConfig) -> Self {
+        NarInfoCache {
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                clock: 0,
+                capacity: config.capacity,
+            }),
+            negative_ttl: Duration::from_secs(config.negative_ttl_secs),
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    /// `Some(Ok)` for a hit, `Some(Err(NotFound))` for a remembered miss.
+    fn lookup(&self, hash: &str) -> Option<Result<String, StorageError>> {
+        let mut lru = self.lru.lock().unwrap();
+        let result = match lru.get(hash) {
+            Some(Cached::Present(content)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(Ok(content.clone()))
+            }
+            Some(Cached::Missing(until)) if *until > Instant::now() => {
+                self.negative_hits.fetch_add(1, Ordering::Relaxed);
+                Some(Err(StorageError::NotFound))
+            }
+            Some(Cached::Missing(_)) => {
+                lru.remove(hash);
+                None
+            }
+            None => None,
+        };
+        if result.is_none() {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn store(&self, hash: &str, generation: u64, value: Cached) {
+        let mut lru = self.lru.lock().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            lru.insert(hash.to_owned(), value);
+        }
+    }
+
+    pub fn invalidate(&self, hash: &str) {
+        let mut lru = self.lru.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+        lru.remove(hash);
+    }
+
+    pub fn clear(&self) {
+        let mut lru = self.lru.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+        lru.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            negative_hits: self.negative_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            entries: self.lru.lock().unwrap().entries.len() as u64,
+        }
+    }
+}
+
+/// Puts a [`NarInfoCache`] in front of another backend. Writes and deletes through it
+/// invalidate the cache; for a disk backend, so do changes other processes make to the
+/// directory.
+pub struct CachedStorage {
+    inner: Arc<dyn NixCacheStorage>,
+    cache: Arc<NarInfoCache>,
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl CachedStorage {
+    pub fn new(inner: Arc<dyn NixCacheStorage>, cache: Arc<NarInfoCache>) -> Self {
+        CachedStorage {
+            inner,
+            cache,
+            _watcher: None,
+        }
+    }
+
+    /// Also drops narinfo from the cache when their files in `dir` change.
+    pub fn watch(mut self, dir: &Path) -> Result<Self, notify::Error> {
+        let cache = self.cache.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) if !event.need_rescan() => event,
+                    Ok(_) => return cache.clear(),
+                    Err(e) => {
+                        warn!(error = %e, "narinfo watcher failed, dropping the narinfo cache");
+                        return cache.clear();
+                    }
+                };
+                let hashes = event.paths.iter().filter_map(|path| {
+                    path.file_name()?
+                        .to_str()?
+                        .strip_suffix(".narinfo")
+                        .map(str::to_owned)
+                });
+                for hash in hashes {
+                    debug!(hash = %hash, "narinfo changed on disk");
+                    cache.invalidate(&hash);
+                }
+            })?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        self._watcher = Some(watcher);
+        Ok(self)
+    }
+}
+
+#[async_trait]
+impl NixCacheStorage for CachedStorage {
+    async fn get_narinfo(&self, hash: &str) -> Result<String, StorageError> {
+        if let Some(result) = self.cache.lookup(hash) {
+            return result;
+        }
+        let generation = self.cache.generation.load(Ordering::SeqCst);
+        match self.inner.get_narinfo(hash).await {
+            Ok(content) => {
+                self.cache
+                    .store(hash, generation, Cached::Present(content.clone()));
+                Ok(content)
+            }
+            Err(StorageError::NotFound) => {
+                let until = Instant::now() + self.cache.negative_ttl;
+                self.cache.store(hash, generation, Cached::Missing(until));
+                Err(StorageError::NotFound)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        let result = self.inner.put_narinfo(hash, content).await;
+        self.cache.invalidate(hash);
+        result
+    }
+
+    async fn nar_size(&self, file: &str) -> Result<u64, StorageError> {
+        self.inner.nar_size(file).await
+    }
+
+    async fn get_nar(
+        &self,
+        file: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream, StorageError> {
+        self.inner.get_nar(file, range).await
+    }
+
+    async fn put_nar(&self, file: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.inner.put_nar(file, content).await
+    }
+
+    async fn list_narinfos(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_narinfos().await
+    }
+
+    async fn list_nars(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_nars().await
+    }
+
+    async fn delete_narinfo(&self, hash: &str) -> Result<(), StorageError> {
+        let result = self.inner.delete_narinfo(hash).await;
+        self.cache.invalidate(hash);
+        result
+    }
+
+    async fn delete_nar(&self, file: &str) -> Result<(), StorageError> {
+        self.inner.delete_nar(file).await
+    }
+
+    async fn rename_nar(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        self.inner.rename_nar(from, to).await
+    }
+
+    async fn get_log(&self, drv: &str) -> Result<ByteStream, StorageError> {
+        self.inner.get_log(drv).await
+    }
+
+    async fn put_log(&self, drv: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.inner.put_log(drv, content).await
+    }
+
+    async fn list_logs(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_logs().await
+    }
+
+    async fn delete_log(&self, drv: &str) -> Result<(), StorageError> {
+        self.inner.delete_log(drv).await
+    }
+
+    async fn get_realisation(&self, id: &str) -> Result<String, StorageError> {
+        self.inner.get_realisation(id).await
+    }
+
+    async fn put_realisation(&self, id: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_realisation(id, content).await
+    }
+
+    async fn list_realisations(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_realisations().await
+    }
+
+    async fn delete_realisation(&self, id: &str) -> Result<(), StorageError> {
+        self.inner.delete_realisation(id).await
+    }
+
+    async fn get_listing(&self, hash: &str) -> Result<String, StorageError> {
+        self.inner.get_listing(hash).await
+    }
+
+    async fn put_listing(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_listing(hash, content).await
+    }
+
+    async fn delete_listing(&self, hash: &str) -> Result<(), StorageError> {
+        self.inner.delete_listing(hash).await
+    }
+
+    async fn get_debuginfo(&self, build_id: &str) -> Result<String, StorageError> {
+        self.inner.get_debuginfo(build_id).await
+    }
+
+    async fn put_debuginfo(&self, build_id: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_debuginfo(build_id, content).await
+    }
+
+    async fn list_debuginfo(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_debuginfo().await
+    }
+
+    async fn delete_debuginfo(&self, build_id: &str) -> Result<(), StorageError> {
+        self.inner.delete_debuginfo(build_id).await
+    }
+
+    async fn get_chunk(&self, hash: &str) -> Result<Bytes, StorageError> {
+        self.inner.get_chunk(hash).await
+    }
+
+    async fn put_chunk(&self, hash: &str, content: Bytes) -> Result<(), StorageError> {
+        self.inner.put_chunk(hash, content).await
+    }
+
+    async fn list_chunks(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_chunks().await
+    }
+
+    async fn delete_chunk(&self, hash: &str) -> Result<(), StorageError> {
+        self.inner.delete_chunk(hash).await
+    }
+
+    async fn get_meta(&self, name: &str) -> Result<Vec<u8>, StorageError> {
+        self.inner.get_meta(name).await
+    }
+
+    async fn put_meta(&self, name: &str, content: Vec<u8>) -> Result<(), StorageError> {
+        self.inner.put_meta(name, content).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::DiskStorage;
+
+    const HASH: &str = "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1";
+
+    fn config(capacity: usize) -> NarInfoCacheConfig {
+        NarInfoCacheConfig {
+            capacity,
+            negative_ttl_secs: 60,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = NarInfoCache::new(&config(2));
+        cache.store("a", 0, Cached::Present("a".to_owned()));
+        cache.store("b", 0, Cached::Present("b".to_owned()));
+        assert!(cache.lookup("a").is_some());
+        cache.store("c", 0, Cached::Present("c".to_owned()));
+        assert!(cache.lookup("b").is_none());
+        assert!(cache.lookup("a").is_some());
+        assert!(cache.lookup("c").is_some());
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[tokio::test]
+    async fn caches_hits_and_misses() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let cache = Arc::new(NarInfoCache::new(&config(16)));
+        let storage = CachedStorage::new(disk.clone(), cache.clone());
+
+        assert!(storage.get_narinfo(HASH).await.is_err());
+        assert!(storage.get_narinfo(HASH).await.is_err());
+        // Written behind the cache's back and not watched, so still a miss.
+        disk.put_narinfo(HASH, "one".to_owned()).await.unwrap();
+        assert!(storage.get_narinfo(HASH).await.is_err());
+
+        storage.put_narinfo(HASH, "two".to_owned()).await.unwrap();
+        assert_eq!(storage.get_narinfo(HASH).await.unwrap(), "two");
+        assert_eq!(storage.get_narinfo(HASH).await.unwrap(), "two");
+
+        let stats = cache.stats();
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.negative_hits, 2);
+        assert_eq!(stats.hits, 1);
+    }
+
+    #[tokio::test]
+    async fn watches_the_cache_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let cache = Arc::new(NarInfoCache::new(&config(16)));
+        let storage = CachedStorage::new(disk.clone(), cache)
+            .watch(dir.path())
+            .unwrap();
+
+        disk.put_narinfo(HASH, "one".to_owned()).await.unwrap();
+        assert_eq!(storage.get_narinfo(HASH).await.unwrap(), "one");
+        disk.put_narinfo(HASH, "two".to_owned()).await.unwrap();
+
+        for _ in 0..100 {
+            if storage.get_narinfo(HASH).await.unwrap() == "two" {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("narinfo change on disk was not picked up");
+    }
+}
@@ -0,0 +1,675 @@
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chrono::{Duration, Utc};
+use fastcdc::v2020::{
+    FastCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+};
+use futures::stream::FuturesOrdered;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use common::hash::{Hash, HashAlgo};
+
+use super::{ByteRange, ByteStream, NixCacheStorage, ObjectInfo, StorageError};
+use crate::config::DedupConfig;
+
+/// Starts every manifest, so NARs stored before deduplication was turned on can be
+/// told apart and are served as they are.
+const MAGIC: &[u8] = b"nix-serve-chunks\n";
+
+/// Chunks written or fetched at once for a single NAR.
+const CONCURRENCY: usize = 8;
+
+/// Unreferenced chunks younger than this may belong to an upload in progress.
+fn orphan_min_age() -> Duration {
+    Duration::hours(1)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChunkRef {
+    hash: String,
+    size: u64,
+}
+
+/// What the inner backend stores in place of a NAR.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    size: u64,
+    chunks: Vec<ChunkRef>,
+}
+
+impl Manifest {
+    fn encode(&self) -> Bytes {
+        let mut content = MAGIC.to_vec();
+        serde_json::to_writer(&mut content, self).expect("serialising a manifest can't fail");
+        content.into()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Chunk {
+    /// Manifests and uploads in progress using it.
+    refs: u64,
+    /// Whether it is known to be in the inner backend.
+    stored: bool,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    chunks: HashMap<String, Chunk>,
+    /// NAR sizes from the manifests.
+    nars: HashMap<String, u64>,
+}
+
+/// Stores NARs in content-defined chunks, each chunk once under the hash of its
+/// contents, so NARs that differ in a few files share everything else. In place of the
+/// NAR the inner backend keeps a manifest listing its chunks; reads fetch them back in
+/// order. Chunks are reference counted over the manifests, which are read on startup,
+/// and deleted with the last NAR using them.
+pub struct ChunkedStorage {
+    inner: Arc<dyn NixCacheStorage>,
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+    index: Mutex<Index>,
+    /// Held for reading while writing a chunk that wasn't stored, and for writing while
+    /// deleting chunks, so a chunk is never deleted from under an upload that is
+    /// writing it again.
+    deleting: RwLock<()>,
+}
+
+fn sha256(content: &[u8]) -> Hash {
+    Hash::new(HashAlgo::Sha256, Sha256::digest(content).to_vec())
+        .expect("sha256 digest has the right length")
+}
+
+async fn compress(content: &[u8]) -> io::Result<Bytes> {
+    let mut compressed = Vec::new();
+    ZstdEncoder::new(content)
+        .read_to_end(&mut compressed)
+        .await?;
+    Ok(compressed.into())
+}
+
+async fn decompress(content: &[u8]) -> io::Result<Bytes> {
+    let mut plain = Vec::new();
+    ZstdDecoder::new(content).read_to_end(&mut plain).await?;
+    Ok(plain.into())
+}
+
+fn into_io(e: StorageError) -> io::Error {
+    match e {
+        StorageError::Io(e) => e,
+        StorageError::NotFound => io::Error::new(io::ErrorKind::NotFound, "chunk not found"),
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+impl ChunkedStorage {
+    pub async fn new(
+        inner: Arc<dyn NixCacheStorage>,
+        config: &DedupConfig,
+    ) -> Result<Self, StorageError> {
+        let DedupConfig {
+            min_chunk_size: min_size,
+            avg_chunk_size: avg_size,
+            max_chunk_size: max_size,
+        } = *config;
+        let valid = (MINIMUM_MIN..=MINIMUM_MAX).contains(&min_size)
+            && (AVERAGE_MIN..=AVERAGE_MAX).contains(&avg_size)
+            && (MAXIMUM_MIN..=MAXIMUM_MAX).contains(&max_size)
+            && min_size <= avg_size
+            && avg_size <= max_size;
+        if !valid {
+            return Err(StorageError::Invalid(format!(
+                "chunk sizes {min_size}/{avg_size}/{max_size} are out of range"
+            )));
+        }
+        let storage = ChunkedStorage {
+            inner,
+            min_size,
+            avg_size,
+            max_size,
+            index: Mutex::new(Index::default()),
+            deleting: RwLock::new(()),
+        };
+        storage.load_index().await?;
+        Ok(storage)
+    }
+
+    /// Counts chunk references over all manifests, and deletes chunks nothing uses.
+    async fn load_index(&self) -> Result<(), StorageError> {
+        let mut index = Index::default();
+        for nar in self.inner.list_nars().await? {
+            let manifest = match self.load_manifest(&nar.name).await {
+                Ok(Some(manifest)) => manifest,
+                Ok(None) | Err(StorageError::NotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            for chunk in &manifest.chunks {
+                let entry = index.chunks.entry(chunk.hash.clone()).or_default();
+                entry.refs += 1;
+                entry.stored = true;
+            }
+            index.nars.insert(nar.name, manifest.size);
+        }
+
+        let cutoff = Utc::now() - orphan_min_age();
+        let mut orphans = 0;
+        let mut chunk_bytes = 0;
+        for chunk in self.inner.list_chunks().await? {
+            if index.chunks.contains_key(&chunk.name) {
+                chunk_bytes += chunk.size;
+            } else if chunk.modified <= cutoff {
+                match self.inner.delete_chunk(&chunk.name).await {
+                    Ok(()) | Err(StorageError::NotFound) => orphans += 1,
+                    Err(e) => {
+                        warn!(chunk = %chunk.name, error = %e, "Failed to delete orphaned chunk")
+                    }
+                }
+            }
+        }
+        info!(
+            nars = index.nars.len(),
+            nar_bytes = index.nars.values().sum::<u64>(),
+            chunks = index.chunks.len(),
+            chunk_bytes,
+            orphans_deleted = orphans,
+            "Loaded chunk index"
+        );
+        *self.index.lock().unwrap() = index;
+        Ok(())
+    }
+
+    /// The manifest stored for `file`, or `None` if it is a NAR stored whole.
+    async fn load_manifest(&self, file: &str) -> Result<Option<Manifest>, StorageError> {
+        let mut content = self.inner.get_nar(file, None).await?;
+        let mut buffer = BytesMut::new();
+        while buffer.len() < MAGIC.len() {
+            match content.next().await {
+                Some(bytes) => buffer.extend_from_slice(&bytes?),
+                None => break,
+            }
+        }
+        if !buffer.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        while let Some(bytes) = content.next().await {
+            buffer.extend_from_slice(&bytes?);
+        }
+        serde_json::from_slice(&buffer[MAGIC.len()..])
+            .map(Some)
+            .map_err(|e| StorageError::Invalid(format!("chunk manifest of {file}: {e}")))
+    }
+
+    /// Takes a reference to the chunk with `content`, storing it unless it already is.
+    async fn acquire(&self, content: Bytes) -> Result<ChunkRef, StorageError> {
+        let chunk = ChunkRef {
+            hash: sha256(&content).to_nix32(),
+            size: content.len() as u64,
+        };
+        let stored = {
+            let mut index = self.index.lock().unwrap();
+            let entry = index.chunks.entry(chunk.hash.clone()).or_default();
+            entry.refs += 1;
+            entry.stored
+        };
+        if !stored {
+            let written = {
+                let _guard = self.deleting.read().await;
+                match compress(&content).await {
+                    Ok(compressed) => self.inner.put_chunk(&chunk.hash, compressed).await,
+                    Err(e) => Err(e.into()),
+                }
+            };
+            if let Err(e) = written {
+                self.release(std::slice::from_ref(&chunk)).await;
+                return Err(e);
+            }
+            if let Some(entry) = self.index.lock().unwrap().chunks.get_mut(&chunk.hash) {
+                entry.stored = true;
+            }
+        }
+        Ok(chunk)
+    }
+
+    /// Drops a reference to each of `chunks`, deleting those no longer used.
+    async fn release(&self, chunks: &[ChunkRef]) {
+        let _guard = self.deleting.write().await;
+        let unused: Vec<String> = {
+            let mut index = self.index.lock().unwrap();
+            chunks
+                .iter()
+                .filter(|chunk| {
+                    let Some(entry) = index.chunks.get_mut(&chunk.hash) else {
+                        return false;
+                    };
+                    entry.refs = entry.refs.saturating_sub(1);
+                    entry.refs == 0 && index.chunks.remove(&chunk.hash).is_some()
+                })
+                .map(|chunk| chunk.hash.clone())
+                .collect()
+        };
+        let inner = &self.inner;
+        futures::stream::iter(unused)
+            .for_each_concurrent(CONCURRENCY, |hash| async move {
+                match inner.delete_chunk(&hash).await {
+                    Ok(()) | Err(StorageError::NotFound) => {}
+                    Err(e) => warn!(chunk = %hash, error = %e, "Failed to delete chunk"),
+                }
+            })
+            .await;
+    }
+
+    /// Cuts `content` into chunks and stores them, adding each to `chunks` as it is
+    /// stored. Returns the total size.
+    async fn store_chunks(
+        &self,
+        mut content: ByteStream,
+        chunks: &mut Vec<ChunkRef>,
+    ) -> Result<u64, StorageError> {
+        let mut buffer = BytesMut::new();
+        let mut pending = FuturesOrdered::new();
+        let mut size = 0;
+        let mut result = Ok(());
+        let mut done = false;
+        'read: while !done {
+            match content.next().await {
+                Some(Ok(bytes)) => buffer.extend_from_slice(&bytes),
+                Some(Err(e)) => {
+                    result = Err(e.into());
+                    break;
+                }
+                None => done = true,
+            }
+            while buffer.len() >= self.max_size as usize || (done && !buffer.is_empty()) {
+                let (_, cut) = FastCDC::new(&buffer, self.min_size, self.avg_size, self.max_size)
+                    .cut(0, buffer.len());
+                let chunk = buffer.split_to(cut).freeze();
+                size += chunk.len() as u64;
+                pending.push_back(self.acquire(chunk));
+                if pending.len() >= CONCURRENCY {
+                    match pending.next().await.expect("chunks are pending") {
+                        Ok(chunk) => chunks.push(chunk),
+                        Err(e) => {
+                            result = Err(e);
+                            break 'read;
+                        }
+                    }
+                }
+            }
+        }
+        while let Some(stored) = pending.next().await {
+            match stored {
+                Ok(chunk) => chunks.push(chunk),
+                Err(e) => result = result.and(Err(e)),
+            }
+        }
+        result.map(|()| size)
+    }
+}
+
+#[async_trait]
+impl NixCacheStorage for ChunkedStorage {
+    async fn get_narinfo(&self, hash: &str) -> Result<String, StorageError> {
+        self.inner.get_narinfo(hash).await
+    }
+
+    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_narinfo(hash, content).await
+    }
+
+    async fn nar_size(&self, file: &str) -> Result<u64, StorageError> {
+        if let Some(size) = self.index.lock().unwrap().nars.get(file) {
+            return Ok(*size);
+        }
+        match self.load_manifest(file).await? {
+            Some(manifest) => Ok(manifest.size),
+            None => self.inner.nar_size(file).await,
+        }
+    }
+
+    async fn get_nar(
+        &self,
+        file: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream, StorageError> {
+        let Some(manifest) = self.load_manifest(file).await? else {
+            return self.inner.get_nar(file, range).await;
+        };
+        let (start, end) = range.map_or((0, manifest.size), |range| (range.start, range.end + 1));
+        let mut pieces = Vec::new();
+        let mut offset = 0;
+        for chunk in manifest.chunks {
+            let chunk_end = offset + chunk.size;
+            if chunk_end > start && offset < end {
+                let from = start.saturating_sub(offset) as usize;
+                let to = (end.min(chunk_end) - offset) as usize;
+                pieces.push((chunk, from, to));
+            }
+            offset = chunk_end;
+        }
+
+        let inner = self.inner.clone();
+        let stream = futures::stream::iter(pieces)
+            .map(move |(chunk, from, to)| {
+                let inner = inner.clone();
+                async move {
+                    let compressed = inner.get_chunk(&chunk.hash).await.map_err(into_io)?;
+                    let content = decompress(&compressed).await?;
+                    if content.len() as u64 != chunk.size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("chunk {} has the wrong size", chunk.hash),
+                        ));
+                    }
+                    Ok(content.slice(from..to))
+                }
+            })
+            .buffered(CONCURRENCY);
+        Ok(stream.boxed())
+    }
+
+    async fn put_nar(&self, file: &str, content: ByteStream) -> Result<(), StorageError> {
+        let mut chunks = Vec::new();
+        let size = match self.store_chunks(content, &mut chunks).await {
+            Ok(size) => size,
+            Err(e) => {
+                self.release(&chunks).await;
+                return Err(e);
+            }
+        };
+        let replaced = match self.load_manifest(file).await {
+            Ok(manifest) => manifest,
+            Err(StorageError::NotFound) => None,
+            Err(e) => {
+                self.release(&chunks).await;
+                return Err(e);
+            }
+        };
+
+        let manifest = Manifest { size, chunks };
+        let body = manifest.encode();
+        let body = futures::stream::once(async move { Ok(body) }).boxed();
+        if let Err(e) = self.inner.put_nar(file, body).await {
+            self.release(&manifest.chunks).await;
+            return Err(e);
+        }
+        self.index
+            .lock()
+            .unwrap()
+            .nars
+            .insert(file.to_owned(), size);
+        if let Some(replaced) = replaced {
+            self.release(&replaced.chunks).await;
+        }
+        Ok(())
+    }
+
+    async fn list_narinfos(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_narinfos().await
+    }
+
+    async fn list_nars(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        let mut nars = self.inner.list_nars().await?;
+        let index = self.index.lock().unwrap();
+        for nar in &mut nars {
+            if let Some(size) = index.nars.get(&nar.name) {
+                nar.size = *size;
+            }
+        }
+        Ok(nars)
+    }
+
+    async fn delete_narinfo(&self, hash: &str) -> Result<(), StorageError> {
+        self.inner.delete_narinfo(hash).await
+    }
+
+    async fn delete_nar(&self, file: &str) -> Result<(), StorageError> {
+        let manifest = self.load_manifest(file).await?;
+        self.inner.delete_nar(file).await?;
+        self.index.lock().unwrap().nars.remove(file);
+        if let Some(manifest) = manifest {
+            self.release(&manifest.chunks).await;
+        }
+        Ok(())
+    }
+
+    /// Moves the manifest; the chunks stay where they are.
+    async fn rename_nar(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let replaced = match self.load_manifest(to).await {
+            Ok(manifest) => manifest,
+            Err(StorageError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        self.inner.rename_nar(from, to).await?;
+        {
+            let mut index = self.index.lock().unwrap();
+            index.nars.remove(to);
+            if let Some(size) = index.nars.remove(from) {
+                index.nars.insert(to.to_owned(), size);
+            }
+        }
+        if let Some(replaced) = replaced {
+            self.release(&replaced.chunks).await;
+        }
+        Ok(())
+    }
+
+    async fn get_log(&self, drv: &str) -> Result<ByteStream, StorageError> {
+        self.inner.get_log(drv).await
+    }
+
+    async fn put_log(&self, drv: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.inner.put_log(drv, content).await
+    }
+
+    async fn list_logs(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_logs().await
+    }
+
+    async fn delete_log(&self, drv: &str) -> Result<(), StorageError> {
+        self.inner.delete_log(drv).await
+    }
+
+    async fn get_realisation(&self, id: &str) -> Result<String, StorageError> {
+        self.inner.get_realisation(id).await
+    }
+
+    async fn put_realisation(&self, id: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_realisation(id, content).await
+    }
+
+    async fn list_realisations(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_realisations().await
+    }
+
+    async fn delete_realisation(&self, id: &str) -> Result<(), StorageError> {
+        self.inner.delete_realisation(id).await
+    }
+
+    async fn get_listing(&self, hash: &str) -> Result<String, StorageError> {
+        self.inner.get_listing(hash).await
+    }
+
+    async fn put_listing(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_listing(hash, content).await
+    }
+
+    async fn delete_listing(&self, hash: &str) -> Result<(), StorageError> {
+        self.inner.delete_listing(hash).await
+    }
+
+    async fn get_debuginfo(&self, build_id: &str) -> Result<String, StorageError> {
+        self.inner.get_debuginfo(build_id).await
+    }
+
+    async fn put_debuginfo(&self, build_id: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_debuginfo(build_id, content).await
+    }
+
+    async fn list_debuginfo(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_debuginfo().await
+    }
+
+    async fn delete_debuginfo(&self, build_id: &str) -> Result<(), StorageError> {
+        self.inner.delete_debuginfo(build_id).await
+    }
+
+    async fn get_chunk(&self, hash: &str) -> Result<Bytes, StorageError> {
+        self.inner.get_chunk(hash).await
+    }
+
+    async fn put_chunk(&self, hash: &str, content: Bytes) -> Result<(), StorageError> {
+        self.inner.put_chunk(hash, content).await
+    }
+
+    async fn list_chunks(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_chunks().await
+    }
+
+    async fn delete_chunk(&self, hash: &str) -> Result<(), StorageError> {
+        self.inner.delete_chunk(hash).await
+    }
+
+    async fn get_meta(&self, name: &str) -> Result<Vec<u8>, StorageError> {
+        self.inner.get_meta(name).await
+    }
+
+    async fn put_meta(&self, name: &str, content: Vec<u8>) -> Result<(), StorageError> {
+        self.inner.put_meta(name, content).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::DiskStorage;
+    use futures::TryStreamExt;
+
+    /// Incompressible bytes, the same for the same seed.
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn stream_of(content: Vec<u8>) -> ByteStream {
+        futures::stream::iter(
+            content
+                .chunks(10_000)
+                .map(|c| Ok(Bytes::copy_from_slice(c)))
+                .collect::<Vec<_>>(),
+        )
+        .boxed()
+    }
+
+    async fn read(storage: &dyn NixCacheStorage, file: &str, range: Option<ByteRange>) -> Vec<u8> {
+        let chunks: Vec<Bytes> = storage
+            .get_nar(file, range)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        chunks.concat()
+    }
+
+    async fn chunk_count(storage: &dyn NixCacheStorage) -> usize {
+        storage.list_chunks().await.unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn deduplicates_nars() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk: Arc<dyn NixCacheStorage> = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        disk.put_nar("plain.nar", stream_of(b"stored whole".to_vec()))
+            .await
+            .unwrap();
+        let storage = ChunkedStorage::new(disk.clone(), &DedupConfig::default())
+            .await
+            .unwrap();
+
+        // Two NARs that differ in a few bytes in the middle.
+        let a = noise(1, 2_000_000);
+        let mut b = a.clone();
+        b[1_000_000..1_000_100].copy_from_slice(&noise(2, 100));
+        storage
+            .put_nar("a.nar", stream_of(a.clone()))
+            .await
+            .unwrap();
+        let after_a = chunk_count(disk.as_ref()).await;
+        storage
+            .put_nar("b.nar", stream_of(b.clone()))
+            .await
+            .unwrap();
+        let after_b = chunk_count(disk.as_ref()).await;
+        assert!(after_a > 10);
+        assert!(after_b - after_a <= 3, "{after_a} -> {after_b}");
+
+        assert_eq!(read(&storage, "a.nar", None).await, a);
+        assert_eq!(read(&storage, "b.nar", None).await, b);
+        let range = ByteRange {
+            start: 999_990,
+            end: 1_200_000,
+        };
+        assert_eq!(
+            read(&storage, "b.nar", Some(range)).await,
+            &b[999_990..=1_200_000]
+        );
+        assert_eq!(storage.nar_size("a.nar").await.unwrap(), 2_000_000);
+        assert_eq!(read(&storage, "plain.nar", None).await, b"stored whole");
+        let sizes: HashMap<String, u64> = storage
+            .list_nars()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|nar| (nar.name, nar.size))
+            .collect();
+        assert_eq!(sizes["b.nar"], 2_000_000);
+
+        // An upload that fails leaves nothing behind.
+        let failing = stream_of(noise(3, 500_000))
+            .chain(futures::stream::once(async {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "bad hash"))
+            }))
+            .boxed();
+        let e = storage.put_nar("c.nar", failing).await.unwrap_err();
+        assert!(matches!(e, StorageError::Io(e) if e.kind() == io::ErrorKind::InvalidData));
+        assert_eq!(chunk_count(disk.as_ref()).await, after_b);
+
+        // Renaming moves the manifest; the chunks stay.
+        storage.rename_nar("b.nar", "d.nar").await.unwrap();
+        assert_eq!(storage.nar_size("d.nar").await.unwrap(), 2_000_000);
+        assert_eq!(chunk_count(disk.as_ref()).await, after_b);
+
+        // The references are counted again on startup.
+        drop(storage);
+        let storage = ChunkedStorage::new(disk.clone(), &DedupConfig::default())
+            .await
+            .unwrap();
+        storage.delete_nar("a.nar").await.unwrap();
+        assert!(chunk_count(disk.as_ref()).await >= after_a);
+        assert_eq!(read(&storage, "d.nar", None).await, b);
+        storage.delete_nar("d.nar").await.unwrap();
+        assert_eq!(chunk_count(disk.as_ref()).await, 0);
+        assert!(matches!(
+            storage.get_nar("d.nar", None).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+}
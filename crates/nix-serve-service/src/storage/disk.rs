@@ -0,0 +1,504 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use common::narinfo;
+use futures::StreamExt;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{nar, realisation};
+
+use super::{ByteRange, ByteStream, NixCacheStorage, ObjectInfo, StorageError};
+
+/// Read buffer for streaming NARs off disk.
+const READ_BUFFER: usize = 256 * 1024;
+
+/// Subdirectories of the cache directory.
+const DIRS: [&str; 6] = ["nar", "log", "realisations", "debuginfo", "chunks", "meta"];
+
+/// Stores everything below `base_dir` in the same layout as a `file://` binary cache:
+/// `<hash>.narinfo` and `<hash>.ls` at the top, the NARs in `nar/`, build logs in
+/// `log/`, realisations in `realisations/` and debug info pointers in `debuginfo/`.
+/// Deduplicated NARs keep their chunks in `chunks/`. Service bookkeeping lives in
+/// `meta/`. Directories are created on the first write into them, so opening a cache
+/// only to read from it leaves it as it was.
+pub struct DiskStorage {
+    base_dir: PathBuf,
+}
+
+impl DiskStorage {
+    pub async fn new(base_dir: impl Into<PathBuf>) -> Result<Self, StorageError> {
+        let base_dir = base_dir.into();
+        let storage = DiskStorage { base_dir };
+        storage.move_flat_nars().await?;
+        Ok(storage)
+    }
+
+    /// Moves NARs left at the top of the directory by versions that kept everything in
+    /// one flat directory into `nar/`, where they are served from now.
+    async fn move_flat_nars(&self) -> Result<(), StorageError> {
+        let mut moved = 0;
+        let mut entries = match fs::read_dir(&self.base_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let Some(name) = file_name.to_str().filter(|name| is_flat_nar(name)) else {
+                continue;
+            };
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+            fs::create_dir_all(self.base_dir.join("nar")).await?;
+            fs::rename(entry.path(), self.nar_path(name)).await?;
+            moved += 1;
+        }
+        if moved > 0 {
+            info!(moved, dir = %self.base_dir.display(), "moved NARs into nar/");
+        }
+        Ok(())
+    }
+
+    /// Deletes the temporary files of writes that never finished, such as uploads cut
+    /// off by a crash, and returns how many there were. Only files not modified for
+    /// `older_than` count as abandoned.
+    pub async fn remove_temp_files(&self, older_than: Duration) -> Result<u64, StorageError> {
+        let cutoff = SystemTime::now() - older_than;
+        let mut removed = 0;
+        let dirs = std::iter::once(self.base_dir.clone())
+            .chain(DIRS.iter().map(|dir| self.base_dir.join(dir)));
+        for dir in dirs {
+            let mut entries = match fs::read_dir(dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let is_temp = entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| name.ends_with(".temp"));
+                if !is_temp {
+                    continue;
+                }
+                let metadata = match entry.metadata().await {
+                    Ok(metadata) => metadata,
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                if !metadata.is_file() || metadata.modified()? > cutoff {
+                    continue;
+                }
+                match fs::remove_file(entry.path()).await {
+                    Ok(()) => removed += 1,
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    fn narinfo_path(&self, hash: &str) -> PathBuf {
+        self.base_dir.join(format!("{hash}.narinfo"))
+    }
+
+    fn nar_path(&self, file: &str) -> PathBuf {
+        self.base_dir.join("nar").join(file)
+    }
+
+    fn log_path(&self, drv: &str) -> PathBuf {
+        self.base_dir.join("log").join(drv)
+    }
+
+    fn realisation_path(&self, id: &str) -> PathBuf {
+        self.base_dir.join("realisations").join(format!("{id}.doi"))
+    }
+
+    fn listing_path(&self, hash: &str) -> PathBuf {
+        self.base_dir.join(format!("{hash}.ls"))
+    }
+
+    fn debuginfo_path(&self, build_id: &str) -> PathBuf {
+        self.base_dir.join("debuginfo").join(build_id)
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.base_dir.join("chunks").join(hash)
+    }
+
+    fn meta_path(&self, name: &str) -> PathBuf {
+        self.base_dir.join("meta").join(name)
+    }
+
+    /// Writes via a temporary file in the same directory and renames it into place,
+    /// so readers never observe a half-written file. Both are synced before returning,
+    /// so a crash right after can't lose a write that was reported as done. Creates the
+    /// directory if need be.
+    async fn write_atomic(
+        &self,
+        path: PathBuf,
+        mut content: ByteStream,
+    ) -> Result<(), StorageError> {
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(format!(".{}.temp", Uuid::new_v4()));
+        let temp_path = path.with_file_name(temp_name);
+        let mut file = match fs::File::create(&temp_path).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).await?;
+                }
+                fs::File::create(&temp_path).await?
+            }
+            file => file?,
+        };
+
+        let written = async {
+            while let Some(chunk) = content.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await?;
+            file.sync_all().await
+        }
+        .await;
+
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+
+        fs::rename(&temp_path, &path).await?;
+        if let Some(dir) = path.parent() {
+            fs::File::open(dir).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` is a NAR file name as the flat layout stored them, such as
+/// `<hash>.nar` or `<hash>.nar.xz`.
+fn is_flat_nar(name: &str) -> bool {
+    !name.ends_with(".temp") && (name.ends_with(".nar") || name.contains(".nar."))
+}
+
+/// Lists the files in `dir` whose names `accept` maps to an object name. Nothing if
+/// `dir` hasn't been written to yet.
+async fn list_dir(
+    dir: PathBuf,
+    accept: impl Fn(&str) -> Option<&str>,
+) -> Result<Vec<ObjectInfo>, StorageError> {
+    let mut objects = Vec::new();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(objects),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some(name) = file_name.to_str().and_then(&accept) else {
+            continue;
+        };
+        let metadata = match entry.metadata().await {
+            Ok(metadata) => metadata,
+            // Deleted since we read the directory.
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        objects.push(ObjectInfo {
+            name: name.to_owned(),
+            size: metadata.len(),
+            modified: metadata.modified()?.into(),
+        });
+    }
+    Ok(objects)
+}
+
+fn not_found(e: std::io::Error) -> StorageError {
+    match e.kind() {
+        ErrorKind::NotFound => StorageError::NotFound,
+        _ => e.into(),
+    }
+}
+
+#[async_trait]
+impl NixCacheStorage for DiskStorage {
+    async fn get_narinfo(&self, hash: &str) -> Result<String, StorageError> {
+        fs::read_to_string(self.narinfo_path(hash))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        let body = futures::stream::once(async move { Ok(Bytes::from(content)) }).boxed();
+        self.write_atomic(self.narinfo_path(hash), body).await
+    }
+
+    async fn nar_size(&self, file: &str) -> Result<u64, StorageError> {
+        let metadata = fs::metadata(self.nar_path(file)).await.map_err(not_found)?;
+        Ok(metadata.len())
+    }
+
+    async fn get_nar(
+        &self,
+        file: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream, StorageError> {
+        let mut file = fs::File::open(self.nar_path(file))
+            .await
+            .map_err(not_found)?;
+        let stream = match range {
+            None => ReaderStream::with_capacity(file, READ_BUFFER).boxed(),
+            Some(range) => {
+                file.seek(std::io::SeekFrom::Start(range.start)).await?;
+                ReaderStream::with_capacity(file.take(range.length()), READ_BUFFER).boxed()
+            }
+        };
+        Ok(stream)
+    }
+
+    async fn put_nar(&self, file: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.write_atomic(self.nar_path(file), content).await
+    }
+
+    async fn list_narinfos(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        list_dir(self.base_dir.clone(), |name| {
+            name.strip_suffix(".narinfo")
+                .filter(|hash| narinfo::is_valid_hash_part(hash))
+        })
+        .await
+    }
+
+    async fn list_nars(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        list_dir(self.base_dir.join("nar"), |name| {
+            (!name.ends_with(".temp") && narinfo::is_valid_nar_file(name)).then_some(name)
+        })
+        .await
+    }
+
+    async fn delete_narinfo(&self, hash: &str) -> Result<(), StorageError> {
+        fs::remove_file(self.narinfo_path(hash))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn delete_nar(&self, file: &str) -> Result<(), StorageError> {
+        fs::remove_file(self.nar_path(file))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn rename_nar(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        fs::rename(self.nar_path(from), self.nar_path(to))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn get_log(&self, drv: &str) -> Result<ByteStream, StorageError> {
+        let file = fs::File::open(self.log_path(drv))
+            .await
+            .map_err(not_found)?;
+        Ok(ReaderStream::with_capacity(file, READ_BUFFER).boxed())
+    }
+
+    async fn put_log(&self, drv: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.write_atomic(self.log_path(drv), content).await
+    }
+
+    async fn list_logs(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        list_dir(self.base_dir.join("log"), |name| {
+            (!name.ends_with(".temp") && super::is_valid_log_name(name)).then_some(name)
+        })
+        .await
+    }
+
+    async fn delete_log(&self, drv: &str) -> Result<(), StorageError> {
+        fs::remove_file(self.log_path(drv)).await.map_err(not_found)
+    }
+
+    async fn get_realisation(&self, id: &str) -> Result<String, StorageError> {
+        fs::read_to_string(self.realisation_path(id))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn put_realisation(&self, id: &str, content: String) -> Result<(), StorageError> {
+        let body = futures::stream::once(async move { Ok(Bytes::from(content)) }).boxed();
+        self.write_atomic(self.realisation_path(id), body).await
+    }
+
+    async fn list_realisations(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        list_dir(self.base_dir.join("realisations"), |name| {
+            name.strip_suffix(".doi")
+                .filter(|id| realisation::is_valid_id(id))
+        })
+        .await
+    }
+
+    async fn delete_realisation(&self, id: &str) -> Result<(), StorageError> {
+        fs::remove_file(self.realisation_path(id))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn get_listing(&self, hash: &str) -> Result<String, StorageError> {
+        fs::read_to_string(self.listing_path(hash))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn put_listing(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        let body = futures::stream::once(async move { Ok(Bytes::from(content)) }).boxed();
+        self.write_atomic(self.listing_path(hash), body).await
+    }
+
+    async fn delete_listing(&self, hash: &str) -> Result<(), StorageError> {
+        fs::remove_file(self.listing_path(hash))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn get_debuginfo(&self, build_id: &str) -> Result<String, StorageError> {
+        fs::read_to_string(self.debuginfo_path(build_id))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn put_debuginfo(&self, build_id: &str, content: String) -> Result<(), StorageError> {
+        let body = futures::stream::once(async move { Ok(Bytes::from(content)) }).boxed();
+        self.write_atomic(self.debuginfo_path(build_id), body).await
+    }
+
+    async fn list_debuginfo(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        list_dir(self.base_dir.join("debuginfo"), |name| {
+            nar::is_valid_build_id(name).then_some(name)
+        })
+        .await
+    }
+
+    async fn delete_debuginfo(&self, build_id: &str) -> Result<(), StorageError> {
+        fs::remove_file(self.debuginfo_path(build_id))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn get_chunk(&self, hash: &str) -> Result<Bytes, StorageError> {
+        let content = fs::read(self.chunk_path(hash)).await.map_err(not_found)?;
+        Ok(content.into())
+    }
+
+    async fn put_chunk(&self, hash: &str, content: Bytes) -> Result<(), StorageError> {
+        let body = futures::stream::once(async move { Ok(content) }).boxed();
+        self.write_atomic(self.chunk_path(hash), body).await
+    }
+
+    async fn list_chunks(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        list_dir(self.base_dir.join("chunks"), |name| {
+            super::is_valid_chunk_name(name).then_some(name)
+        })
+        .await
+    }
+
+    async fn delete_chunk(&self, hash: &str) -> Result<(), StorageError> {
+        fs::remove_file(self.chunk_path(hash))
+            .await
+            .map_err(not_found)
+    }
+
+    async fn get_meta(&self, name: &str) -> Result<Vec<u8>, StorageError> {
+        fs::read(self.meta_path(name)).await.map_err(not_found)
+    }
+
+    async fn put_meta(&self, name: &str, content: Vec<u8>) -> Result<(), StorageError> {
+        let body = futures::stream::once(async move { Ok(Bytes::from(content)) }).boxed();
+        self.write_atomic(self.meta_path(name), body).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn removes_temp_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        storage
+            .put_nar(
+                "abc.nar",
+                futures::stream::once(async { Ok(Bytes::from("nar")) }).boxed(),
+            )
+            .await
+            .unwrap();
+        std::fs::create_dir(dir.path().join("log")).unwrap();
+        std::fs::create_dir(dir.path().join("meta")).unwrap();
+        for temp in [
+            "nar/def.nar.1234.temp",
+            "log/x.drv.5678.temp",
+            "meta/gc.json.9.temp",
+        ] {
+            std::fs::write(dir.path().join(temp), "partial").unwrap();
+        }
+        // Not ours to touch.
+        std::fs::create_dir(dir.path().join("export.tar.1.temp")).unwrap();
+
+        // Too fresh to be abandoned.
+        let hour = Duration::from_secs(3600);
+        assert_eq!(storage.remove_temp_files(hour).await.unwrap(), 0);
+        assert_eq!(storage.remove_temp_files(Duration::ZERO).await.unwrap(), 3);
+        let left: Vec<_> = std::fs::read_dir(dir.path().join("nar"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left, ["abc.nar"]);
+        assert!(dir.path().join("export.tar.1.temp").is_dir());
+        assert_eq!(storage.remove_temp_files(Duration::ZERO).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn moves_nars_from_the_flat_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["abc.nar.xz", "def.nar", "abc.narinfo", "def.nar.1.temp"] {
+            std::fs::write(dir.path().join(file), file).unwrap();
+        }
+
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        let mut nars: Vec<_> = storage
+            .list_nars()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|nar| nar.name)
+            .collect();
+        nars.sort();
+        assert_eq!(nars, ["abc.nar.xz", "def.nar"]);
+        assert!(dir.path().join("abc.narinfo").is_file());
+        assert!(dir.path().join("def.nar.1.temp").is_file());
+    }
+
+    #[tokio::test]
+    async fn creates_directories_on_first_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        assert!(storage.list_logs().await.unwrap().is_empty());
+        assert!(matches!(
+            storage.get_meta("gc.json").await,
+            Err(StorageError::NotFound)
+        ));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        storage.put_meta("gc.json", b"{}".to_vec()).await.unwrap();
+        assert_eq!(storage.get_meta("gc.json").await.unwrap(), b"{}");
+        let dirs: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(dirs, ["meta"]);
+    }
+}
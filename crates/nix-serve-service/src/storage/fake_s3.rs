@@ -0,0 +1,204 @@
+//! Just enough of the S3 API to run `S3Storage` against in tests.
+
+use axum::{
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::config::S3Config;
+
+struct Object {
+    data: Bytes,
+    modified: DateTime<Utc>,
+}
+
+impl Object {
+    fn new(data: Bytes) -> Self {
+        Object {
+            data,
+            modified: Utc::now(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Objects {
+    objects: BTreeMap<String, Object>,
+    uploads: HashMap<String, BTreeMap<u32, Bytes>>,
+}
+
+type Shared = Arc<Mutex<Objects>>;
+
+/// Serves a fake bucket on a random local port and returns a config pointing at it.
+pub async fn spawn() -> S3Config {
+    let app = Router::new()
+        .route("/:bucket/", get(list_objects))
+        .route(
+            "/:bucket/*key",
+            get(get_object)
+                .head(head_object)
+                .put(put_object)
+                .post(post_object)
+                .delete(delete_object),
+        )
+        .layer(DefaultBodyLimit::disable())
+        .with_state(Shared::default());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    S3Config {
+        bucket: "nix-cache".to_owned(),
+        endpoint: format!("http://{addr}"),
+        region: "fsn1".to_owned(),
+        prefix: "cache".to_owned(),
+        access_key: Some("access".to_owned()),
+        secret_key: Some("secret".to_owned()),
+        path_style: true,
+        hot_tier: None,
+    }
+}
+
+async fn get_object(
+    State(store): State<Shared>,
+    Path((_, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(data) = store
+        .lock()
+        .unwrap()
+        .objects
+        .get(&key)
+        .map(|o| o.data.clone())
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let range = headers
+        .get("range")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes="))
+        .and_then(|v| v.split_once('-'));
+    match range {
+        Some((start, end)) => {
+            let start: usize = start.parse().unwrap();
+            let end = end.parse::<usize>().map_or(data.len(), |end| end + 1);
+            (
+                StatusCode::PARTIAL_CONTENT,
+                data.slice(start..end.min(data.len())),
+            )
+                .into_response()
+        }
+        None => data.into_response(),
+    }
+}
+
+async fn head_object(
+    State(store): State<Shared>,
+    Path((_, key)): Path<(String, String)>,
+) -> Response {
+    match store.lock().unwrap().objects.get(&key) {
+        Some(object) => ([("content-length", object.data.len().to_string())], ()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn put_object(
+    State(store): State<Shared>,
+    Path((_, key)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> Response {
+    let mut store = store.lock().unwrap();
+    match (query.get("uploadId"), query.get("partNumber")) {
+        (Some(upload_id), Some(part)) => {
+            let Some(upload) = store.uploads.get_mut(upload_id) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let part: u32 = part.parse().unwrap();
+            upload.insert(part, body);
+            let mut headers = HeaderMap::new();
+            headers.insert("etag", format!("\"part-{part}\"").parse().unwrap());
+            (headers, ()).into_response()
+        }
+        _ => {
+            store.objects.insert(key, Object::new(body));
+            StatusCode::OK.into_response()
+        }
+    }
+}
+
+async fn post_object(
+    State(store): State<Shared>,
+    Path((bucket, key)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let mut store = store.lock().unwrap();
+    if query.contains_key("uploads") {
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        store.uploads.insert(upload_id.clone(), BTreeMap::new());
+        return format!(
+            "<InitiateMultipartUploadResult><Bucket>{bucket}</Bucket><Key>{key}</Key>\
+             <UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>"
+        )
+        .into_response();
+    }
+
+    let Some(parts) = query
+        .get("uploadId")
+        .and_then(|id| store.uploads.remove(id))
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let data: Vec<u8> = parts.into_values().flat_map(|part| part.to_vec()).collect();
+    store
+        .objects
+        .insert(key.clone(), Object::new(Bytes::from(data)));
+    format!("<CompleteMultipartUploadResult><Key>{key}</Key></CompleteMultipartUploadResult>")
+        .into_response()
+}
+
+async fn delete_object(
+    State(store): State<Shared>,
+    Path((_, key)): Path<(String, String)>,
+) -> StatusCode {
+    store.lock().unwrap().objects.remove(&key);
+    StatusCode::NO_CONTENT
+}
+
+/// ListObjectsV2, without paging.
+async fn list_objects(
+    State(store): State<Shared>,
+    Path(bucket): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+    let delimiter = query.get("delimiter").cloned();
+    let store = store.lock().unwrap();
+    let mut contents = String::new();
+    for (key, object) in store.objects.range(prefix.clone()..) {
+        let Some(rest) = key.strip_prefix(&prefix) else {
+            break;
+        };
+        if delimiter.as_deref().is_some_and(|d| rest.contains(d)) {
+            continue;
+        }
+        contents.push_str(&format!(
+            "<Contents><Key>{key}</Key><LastModified>{}</LastModified><Size>{}</Size></Contents>",
+            object.modified.to_rfc3339(),
+            object.data.len()
+        ));
+    }
+    format!(
+        "<ListBucketResult><Name>{bucket}</Name><Prefix>{prefix}</Prefix>\
+         <IsTruncated>false</IsTruncated>{contents}</ListBucketResult>"
+    )
+    .into_response()
+}
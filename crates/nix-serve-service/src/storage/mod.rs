@@ -0,0 +1,176 @@
+mod cached;
+mod chunked;
+mod disk;
+mod s3;
+mod tiered;
+
+#[cfg(test)]
+pub(crate) mod fake_s3;
+
+pub use cached::{CacheStats, CachedStorage, NarInfoCache};
+pub use chunked::ChunkedStorage;
+pub use disk::DiskStorage;
+pub use s3::S3Storage;
+pub use tiered::TieredStorage;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+use common::hash::HashAlgo;
+use common::narinfo::StorePath;
+use common::nixbase32;
+
+use crate::config::StorageConfig;
+
+/// Whether `name` is a derivation base name, as logs are stored under.
+pub fn is_valid_log_name(name: &str) -> bool {
+    name.ends_with(".drv") && StorePath::from_base_name(name).is_ok()
+}
+
+/// Whether `name` is a chunk hash, the nix32 sha256 of its contents.
+pub fn is_valid_chunk_name(name: &str) -> bool {
+    nixbase32::decode(name, HashAlgo::Sha256.digest_len()).is_some()
+}
+
+/// A stream of body chunks, independent of where the bytes come from.
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("not found")]
+    NotFound,
+    #[error("IO Error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("S3 Error: {0}")]
+    S3(#[from] ::s3::error::S3Error),
+    #[error("Invalid content: {0}")]
+    Invalid(String),
+}
+
+/// An inclusive byte range, as in an HTTP `Range:` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// An object found by listing the storage backend.
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    /// The narinfo hash or NAR file name, as passed to the other methods.
+    pub name: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+/// Where the cache keeps its narinfo files and NARs.
+///
+/// `hash` is the hash part of a store path, `file` is the NAR file name as it
+/// appears in the narinfo `URL:` field without the `nar/` prefix.
+#[async_trait]
+pub trait NixCacheStorage: Send + Sync {
+    async fn get_narinfo(&self, hash: &str) -> Result<String, StorageError>;
+    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StorageError>;
+    async fn nar_size(&self, file: &str) -> Result<u64, StorageError>;
+    /// Streams the NAR, or only `range` of it. Implementations must not buffer the
+    /// whole NAR in memory.
+    async fn get_nar(
+        &self,
+        file: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream, StorageError>;
+    async fn put_nar(&self, file: &str, content: ByteStream) -> Result<(), StorageError>;
+
+    async fn list_narinfos(&self) -> Result<Vec<ObjectInfo>, StorageError>;
+    async fn list_nars(&self) -> Result<Vec<ObjectInfo>, StorageError>;
+    async fn delete_narinfo(&self, hash: &str) -> Result<(), StorageError>;
+    async fn delete_nar(&self, file: &str) -> Result<(), StorageError>;
+    /// Moves a NAR to another name, replacing whatever is there.
+    async fn rename_nar(&self, from: &str, to: &str) -> Result<(), StorageError>;
+
+    /// Build logs, by derivation base name (`<hash>-<name>.drv`). Stored as given; the
+    /// routes compress them.
+    async fn get_log(&self, drv: &str) -> Result<ByteStream, StorageError>;
+    async fn put_log(&self, drv: &str, content: ByteStream) -> Result<(), StorageError>;
+    async fn list_logs(&self) -> Result<Vec<ObjectInfo>, StorageError>;
+    async fn delete_log(&self, drv: &str) -> Result<(), StorageError>;
+
+    /// Realisations of content-addressed derivation outputs, by id
+    /// (`<drv-hash>!<output>`), as JSON.
+    async fn get_realisation(&self, id: &str) -> Result<String, StorageError>;
+    async fn put_realisation(&self, id: &str, content: String) -> Result<(), StorageError>;
+    async fn list_realisations(&self) -> Result<Vec<ObjectInfo>, StorageError>;
+    async fn delete_realisation(&self, id: &str) -> Result<(), StorageError>;
+
+    /// `.ls` listings of NARs, by the hash part of the narinfo they belong to.
+    async fn get_listing(&self, hash: &str) -> Result<String, StorageError>;
+    async fn put_listing(&self, hash: &str, content: String) -> Result<(), StorageError>;
+    async fn delete_listing(&self, hash: &str) -> Result<(), StorageError>;
+
+    /// Where to find the debug info file of an ELF build id, as JSON.
+    async fn get_debuginfo(&self, build_id: &str) -> Result<String, StorageError>;
+    async fn put_debuginfo(&self, build_id: &str, content: String) -> Result<(), StorageError>;
+    async fn list_debuginfo(&self) -> Result<Vec<ObjectInfo>, StorageError>;
+    async fn delete_debuginfo(&self, build_id: &str) -> Result<(), StorageError>;
+
+    /// Pieces of NARs stored once and shared between them by [`ChunkedStorage`], by
+    /// hash. Stored as given.
+    async fn get_chunk(&self, hash: &str) -> Result<Bytes, StorageError>;
+    async fn put_chunk(&self, hash: &str, content: Bytes) -> Result<(), StorageError>;
+    async fn list_chunks(&self) -> Result<Vec<ObjectInfo>, StorageError>;
+    async fn delete_chunk(&self, hash: &str) -> Result<(), StorageError>;
+
+    /// Small bookkeeping documents the service keeps next to the cache, such as GC state.
+    async fn get_meta(&self, name: &str) -> Result<Vec<u8>, StorageError>;
+    async fn put_meta(&self, name: &str, content: Vec<u8>) -> Result<(), StorageError>;
+}
+
+pub async fn from_config(config: &StorageConfig) -> Result<Arc<dyn NixCacheStorage>, StorageError> {
+    match config {
+        StorageConfig::Disk { cache_dir } => Ok(Arc::new(DiskStorage::new(cache_dir).await?)),
+        StorageConfig::S3(s3) => {
+            let bucket = Arc::new(S3Storage::new(s3)?);
+            match &s3.hot_tier {
+                Some(hot) => {
+                    let disk = DiskStorage::new(&hot.cache_dir).await?;
+                    Ok(Arc::new(
+                        TieredStorage::new(disk, bucket, hot.max_bytes).await?,
+                    ))
+                }
+                None => Ok(bucket),
+            }
+        }
+    }
+}
+
+/// Temporary files untouched for this long belong to writes that were interrupted. Ones
+/// in progress are written to all the time, also those of another process sharing the
+/// directory, such as an import.
+const ABANDONED_TEMP_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Clears what interrupted writes left in the local directories of `config`: the cache
+/// directory, or the hot tier of an S3 bucket.
+pub async fn remove_temp_files(config: &StorageConfig) -> Result<u64, StorageError> {
+    let dir = match config {
+        StorageConfig::Disk { cache_dir } => cache_dir,
+        StorageConfig::S3(s3) => match &s3.hot_tier {
+            Some(hot) => &hot.cache_dir,
+            None => return Ok(0),
+        },
+    };
+    DiskStorage::new(dir)
+        .await?
+        .remove_temp_files(ABANDONED_TEMP_FILE_AGE)
+        .await
+}
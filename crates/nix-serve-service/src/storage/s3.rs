@@ -0,0 +1,411 @@
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use common::narinfo;
+use futures::{StreamExt, TryStreamExt};
+use s3::{
+    bucket::CHUNK_SIZE, creds::Credentials, error::S3Error, serde_types::Part, Bucket, Region,
+};
+
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+
+use super::{ByteRange, ByteStream, NixCacheStorage, ObjectInfo, StorageError};
+use crate::config::S3Config;
+use crate::{nar, realisation};
+
+/// Buffer between the S3 response and the client when streaming ranges.
+const READ_BUFFER: usize = 256 * 1024;
+
+/// For hetzner buckets, or anything else that speaks S3.
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Result<Self, StorageError> {
+        let region = Region::Custom {
+            region: config.region.clone(),
+            endpoint: config.endpoint.clone(),
+        };
+
+        let credentials = match (&config.access_key, &config.secret_key) {
+            (Some(access_key), Some(secret_key)) => {
+                Credentials::new(Some(access_key), Some(secret_key), None, None, None)
+            }
+            _ => Credentials::default(),
+        }
+        .map_err(S3Error::from)?;
+
+        let mut bucket = Bucket::new(&config.bucket, region, credentials)?;
+        if config.path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        Ok(S3Storage {
+            bucket,
+            prefix: config.prefix.trim_matches('/').to_owned(),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}/{}", self.prefix, name)
+        }
+    }
+
+    /// Lists the objects directly below `dir` whose names `accept` maps to an object name.
+    async fn list_dir(
+        &self,
+        dir: &str,
+        accept: impl Fn(&str) -> Option<&str>,
+    ) -> Result<Vec<ObjectInfo>, StorageError> {
+        let mut prefix = self.key(dir);
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        let pages = self
+            .bucket
+            .list(prefix.clone(), Some("/".to_owned()))
+            .await?;
+        let mut objects = Vec::new();
+        for object in pages.into_iter().flat_map(|page| page.contents) {
+            let Some(name) = object.key.strip_prefix(&prefix).and_then(&accept) else {
+                continue;
+            };
+            let modified = DateTime::parse_from_rfc3339(&object.last_modified)
+                .map_err(|e| StorageError::Invalid(format!("{}: {e}", object.key)))?;
+            objects.push(ObjectInfo {
+                name: name.to_owned(),
+                size: object.size,
+                modified: modified.with_timezone(&Utc),
+            });
+        }
+        Ok(objects)
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), StorageError> {
+        self.bucket.delete_object(key).await.map_err(not_found)?;
+        Ok(())
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Bytes, StorageError> {
+        let response = self.bucket.get_object(key).await.map_err(not_found)?;
+        Ok(response.bytes().clone())
+    }
+
+    /// Small objects go up in a single request. Anything larger than one chunk is sent
+    /// as a multipart upload, one part at a time, so at most one chunk is held in memory.
+    async fn put_stream(&self, key: &str, mut content: ByteStream) -> Result<(), StorageError> {
+        let mut buffer = BytesMut::with_capacity(CHUNK_SIZE);
+        let mut done = fill_chunk(&mut buffer, &mut content).await?;
+        if done {
+            self.bucket.put_object(key, &buffer).await?;
+            return Ok(());
+        }
+
+        let upload = self
+            .bucket
+            .initiate_multipart_upload(key, "application/octet-stream")
+            .await?;
+        let mut parts: Vec<Part> = Vec::new();
+
+        let uploaded: Result<(), StorageError> = async {
+            loop {
+                let part = self
+                    .bucket
+                    .put_multipart_chunk(
+                        buffer.split().to_vec(),
+                        key,
+                        parts.len() as u32 + 1,
+                        &upload.upload_id,
+                        "application/octet-stream",
+                    )
+                    .await?;
+                parts.push(part);
+
+                if done {
+                    return Ok(());
+                }
+                done = fill_chunk(&mut buffer, &mut content).await?;
+                if done && buffer.is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+        .await;
+
+        if let Err(e) = uploaded {
+            let _ = self.bucket.abort_upload(key, &upload.upload_id).await;
+            return Err(e);
+        }
+
+        self.bucket
+            .complete_multipart_upload(key, &upload.upload_id, parts)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Reads from `content` until `buffer` holds a full chunk. Returns true once the stream is exhausted.
+async fn fill_chunk(buffer: &mut BytesMut, content: &mut ByteStream) -> Result<bool, StorageError> {
+    while buffer.len() < CHUNK_SIZE {
+        match content.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk?),
+            None => return Ok(true),
+        }
+    }
+    Ok(false)
+}
+
+fn not_found(e: S3Error) -> StorageError {
+    match e {
+        S3Error::HttpFailWithBody(404, _) => StorageError::NotFound,
+        e => e.into(),
+    }
+}
+
+#[async_trait]
+impl NixCacheStorage for S3Storage {
+    async fn get_narinfo(&self, hash: &str) -> Result<String, StorageError> {
+        let data = self
+            .get_object(&self.key(&format!("{hash}.narinfo")))
+            .await?;
+        String::from_utf8(data.to_vec()).map_err(|e| StorageError::Invalid(e.to_string()))
+    }
+
+    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        self.bucket
+            .put_object_with_content_type(
+                self.key(&format!("{hash}.narinfo")),
+                content.as_bytes(),
+                "text/x-nix-narinfo",
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn nar_size(&self, file: &str) -> Result<u64, StorageError> {
+        let (head, _) = self
+            .bucket
+            .head_object(self.key(&format!("nar/{file}")))
+            .await
+            .map_err(not_found)?;
+        head.content_length
+            .and_then(|len| u64::try_from(len).ok())
+            .ok_or_else(|| StorageError::Invalid(format!("no content length for {file}")))
+    }
+
+    async fn get_nar(
+        &self,
+        file: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream, StorageError> {
+        let key = self.key(&format!("nar/{file}"));
+        let Some(range) = range else {
+            let response = self
+                .bucket
+                .get_object_stream(key)
+                .await
+                .map_err(not_found)?;
+            return Ok(response.bytes.map_err(std::io::Error::other).boxed());
+        };
+
+        // rust-s3 only streams ranges into a writer, so pipe that through a small buffer.
+        let (reader, mut writer) = tokio::io::duplex(READ_BUFFER);
+        let bucket = self.bucket.clone();
+        tokio::spawn(async move {
+            // The crate refuses single byte ranges, so ask for the rest of the object
+            // and let the reader stop after `range.length()` bytes.
+            let end = (range.end > range.start).then_some(range.end);
+            if let Err(e) = bucket
+                .get_object_range_to_writer(key, range.start, end, &mut writer)
+                .await
+            {
+                tracing::error!(error = %e, "Failed to stream NAR range from S3");
+            }
+        });
+        Ok(ReaderStream::with_capacity(reader.take(range.length()), READ_BUFFER).boxed())
+    }
+
+    async fn put_nar(&self, file: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.put_stream(&self.key(&format!("nar/{file}")), content)
+            .await
+    }
+
+    async fn list_narinfos(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.list_dir("", |name| {
+            name.strip_suffix(".narinfo")
+                .filter(|hash| narinfo::is_valid_hash_part(hash))
+        })
+        .await
+    }
+
+    async fn list_nars(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.list_dir("nar", |name| {
+            narinfo::is_valid_nar_file(name).then_some(name)
+        })
+        .await
+    }
+
+    async fn delete_narinfo(&self, hash: &str) -> Result<(), StorageError> {
+        self.delete_object(&self.key(&format!("{hash}.narinfo")))
+            .await
+    }
+
+    async fn delete_nar(&self, file: &str) -> Result<(), StorageError> {
+        self.delete_object(&self.key(&format!("nar/{file}"))).await
+    }
+
+    /// S3 has no rename: copies within the bucket, then deletes the original.
+    async fn rename_nar(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let from = self.key(&format!("nar/{from}"));
+        self.bucket
+            .copy_object_internal(&from, self.key(&format!("nar/{to}")))
+            .await
+            .map_err(not_found)?;
+        self.delete_object(&from).await
+    }
+
+    async fn get_log(&self, drv: &str) -> Result<ByteStream, StorageError> {
+        let response = self
+            .bucket
+            .get_object_stream(self.key(&format!("log/{drv}")))
+            .await
+            .map_err(not_found)?;
+        Ok(response.bytes.map_err(std::io::Error::other).boxed())
+    }
+
+    async fn put_log(&self, drv: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.put_stream(&self.key(&format!("log/{drv}")), content)
+            .await
+    }
+
+    async fn list_logs(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.list_dir("log", |name| super::is_valid_log_name(name).then_some(name))
+            .await
+    }
+
+    async fn delete_log(&self, drv: &str) -> Result<(), StorageError> {
+        self.delete_object(&self.key(&format!("log/{drv}"))).await
+    }
+
+    async fn get_realisation(&self, id: &str) -> Result<String, StorageError> {
+        let data = self
+            .get_object(&self.key(&format!("realisations/{id}.doi")))
+            .await?;
+        String::from_utf8(data.to_vec()).map_err(|e| StorageError::Invalid(e.to_string()))
+    }
+
+    async fn put_realisation(&self, id: &str, content: String) -> Result<(), StorageError> {
+        self.bucket
+            .put_object_with_content_type(
+                self.key(&format!("realisations/{id}.doi")),
+                content.as_bytes(),
+                "application/json",
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_realisations(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.list_dir("realisations", |name| {
+            name.strip_suffix(".doi")
+                .filter(|id| realisation::is_valid_id(id))
+        })
+        .await
+    }
+
+    async fn delete_realisation(&self, id: &str) -> Result<(), StorageError> {
+        self.delete_object(&self.key(&format!("realisations/{id}.doi")))
+            .await
+    }
+
+    async fn get_listing(&self, hash: &str) -> Result<String, StorageError> {
+        let data = self.get_object(&self.key(&format!("{hash}.ls"))).await?;
+        String::from_utf8(data.to_vec()).map_err(|e| StorageError::Invalid(e.to_string()))
+    }
+
+    async fn put_listing(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        self.bucket
+            .put_object_with_content_type(
+                self.key(&format!("{hash}.ls")),
+                content.as_bytes(),
+                "application/json",
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_listing(&self, hash: &str) -> Result<(), StorageError> {
+        self.delete_object(&self.key(&format!("{hash}.ls"))).await
+    }
+
+    async fn get_debuginfo(&self, build_id: &str) -> Result<String, StorageError> {
+        let data = self
+            .get_object(&self.key(&format!("debuginfo/{build_id}")))
+            .await?;
+        String::from_utf8(data.to_vec()).map_err(|e| StorageError::Invalid(e.to_string()))
+    }
+
+    async fn put_debuginfo(&self, build_id: &str, content: String) -> Result<(), StorageError> {
+        self.bucket
+            .put_object_with_content_type(
+                self.key(&format!("debuginfo/{build_id}")),
+                content.as_bytes(),
+                "application/json",
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_debuginfo(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.list_dir("debuginfo", |name| {
+            nar::is_valid_build_id(name).then_some(name)
+        })
+        .await
+    }
+
+    async fn delete_debuginfo(&self, build_id: &str) -> Result<(), StorageError> {
+        self.delete_object(&self.key(&format!("debuginfo/{build_id}")))
+            .await
+    }
+
+    async fn get_chunk(&self, hash: &str) -> Result<Bytes, StorageError> {
+        self.get_object(&self.key(&format!("chunks/{hash}"))).await
+    }
+
+    async fn put_chunk(&self, hash: &str, content: Bytes) -> Result<(), StorageError> {
+        self.bucket
+            .put_object(self.key(&format!("chunks/{hash}")), &content)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_chunks(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.list_dir("chunks", |name| {
+            super::is_valid_chunk_name(name).then_some(name)
+        })
+        .await
+    }
+
+    async fn delete_chunk(&self, hash: &str) -> Result<(), StorageError> {
+        self.delete_object(&self.key(&format!("chunks/{hash}")))
+            .await
+    }
+
+    async fn get_meta(&self, name: &str) -> Result<Vec<u8>, StorageError> {
+        let data = self.get_object(&self.key(&format!("meta/{name}"))).await?;
+        Ok(data.to_vec())
+    }
+
+    async fn put_meta(&self, name: &str, content: Vec<u8>) -> Result<(), StorageError> {
+        self.bucket
+            .put_object(self.key(&format!("meta/{name}")), &content)
+            .await?;
+        Ok(())
+    }
+}
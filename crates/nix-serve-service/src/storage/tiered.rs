@@ -0,0 +1,488 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc;
+use futures::{ready, Stream, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tracing::{debug, error, info, warn};
+
+use super::{ByteRange, ByteStream, DiskStorage, NixCacheStorage, ObjectInfo, StorageError};
+
+/// A NAR in the hot tier.
+struct HotNar {
+    size: u64,
+    last_access: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct HotIndex {
+    nars: HashMap<String, HotNar>,
+    bytes: u64,
+    /// NARs being copied into the hot tier.
+    rehydrating: HashSet<String>,
+}
+
+impl HotIndex {
+    fn insert(&mut self, file: String, size: u64, last_access: DateTime<Utc>) {
+        let old = self.nars.insert(file, HotNar { size, last_access });
+        self.bytes = self.bytes + size - old.map_or(0, |old| old.size);
+    }
+
+    fn remove(&mut self, file: &str) {
+        if let Some(old) = self.nars.remove(file) {
+            self.bytes -= old.size;
+        }
+    }
+}
+
+struct Tiers {
+    hot: DiskStorage,
+    cold: Arc<dyn NixCacheStorage>,
+    max_bytes: u64,
+    index: Mutex<HotIndex>,
+}
+
+impl Tiers {
+    /// Whether `file` is in the hot tier, marking it as used if so.
+    fn touch(&self, file: &str) -> bool {
+        let mut index = self.index.lock().unwrap();
+        match index.nars.get_mut(file) {
+            Some(nar) => {
+                nar.last_access = Utc::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records a NAR that was just written to the hot tier and makes room for it.
+    async fn admit(&self, file: &str) {
+        match self.hot.nar_size(file).await {
+            Ok(size) => self
+                .index
+                .lock()
+                .unwrap()
+                .insert(file.to_owned(), size, Utc::now()),
+            Err(e) => warn!(file = %file, error = %e, "NAR vanished from the hot tier"),
+        }
+        self.evict().await;
+    }
+
+    /// Drops the least recently used NARs from the hot tier until it fits. They stay in
+    /// the bucket.
+    async fn evict(&self) {
+        let victims = {
+            let mut index = self.index.lock().unwrap();
+            let mut victims = Vec::new();
+            while index.bytes > self.max_bytes {
+                let Some(oldest) = index
+                    .nars
+                    .iter()
+                    .min_by_key(|(_, nar)| nar.last_access)
+                    .map(|(file, _)| file.clone())
+                else {
+                    break;
+                };
+                index.remove(&oldest);
+                victims.push(oldest);
+            }
+            victims
+        };
+        for file in victims {
+            match self.hot.delete_nar(&file).await {
+                Ok(()) | Err(StorageError::NotFound) => {
+                    debug!(file = %file, "Evicted NAR from the hot tier")
+                }
+                Err(e) => error!(file = %file, error = %e, "Failed to evict NAR"),
+            }
+        }
+    }
+
+    /// Claims the rehydration of `file`, unless it is already under way.
+    fn start_rehydrating(&self, file: &str) -> bool {
+        self.index
+            .lock()
+            .unwrap()
+            .rehydrating
+            .insert(file.to_owned())
+    }
+
+    async fn rehydrate(&self, file: &str, content: ByteStream) {
+        match self.hot.put_nar(file, content).await {
+            Ok(()) => {
+                info!(file = %file, "Rehydrated NAR into the hot tier");
+                self.admit(file).await;
+            }
+            Err(e) => debug!(file = %file, error = %e, "Gave up rehydrating NAR"),
+        }
+        self.index.lock().unwrap().rehydrating.remove(file);
+    }
+}
+
+/// An S3 bucket with a bounded local disk in front of it for NARs.
+///
+/// Everything is written through to the bucket, which stays the source of truth; only
+/// NARs are kept on disk as well. Reads are served from disk when the NAR is there.
+/// Otherwise they come from the bucket and the NAR is copied to disk on the way, and the
+/// least recently used NARs are evicted from disk to stay within `max_bytes`. Everything
+/// but NARs is only in the bucket.
+pub struct TieredStorage {
+    tiers: Arc<Tiers>,
+}
+
+impl TieredStorage {
+    pub async fn new(
+        hot: DiskStorage,
+        cold: Arc<dyn NixCacheStorage>,
+        max_bytes: u64,
+    ) -> Result<Self, StorageError> {
+        let mut index = HotIndex::default();
+        for nar in hot.list_nars().await? {
+            index.insert(nar.name, nar.size, nar.modified);
+        }
+        info!(
+            nars = index.nars.len(),
+            bytes = index.bytes,
+            max_bytes,
+            "Loaded hot tier"
+        );
+        let tiers = Arc::new(Tiers {
+            hot,
+            cold,
+            max_bytes,
+            index: Mutex::new(index),
+        });
+        tiers.evict().await;
+        Ok(TieredStorage { tiers })
+    }
+
+    /// Bytes of NARs in the hot tier.
+    pub fn hot_bytes(&self) -> u64 {
+        self.tiers.index.lock().unwrap().bytes
+    }
+}
+
+/// Passes a NAR from the bucket through to the client while copying it into the hot
+/// tier. If the client stops reading early the copy fails, so no partial NAR is kept.
+struct Tee {
+    inner: ByteStream,
+    copy: Option<mpsc::UnboundedSender<io::Result<Bytes>>>,
+}
+
+impl Stream for Tee {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = ready!(self.inner.poll_next_unpin(cx));
+        match &next {
+            Some(Ok(chunk)) => {
+                if let Some(copy) = &self.copy {
+                    let _ = copy.unbounded_send(Ok(chunk.clone()));
+                }
+            }
+            Some(Err(e)) => {
+                if let Some(copy) = self.copy.take() {
+                    let _ = copy.unbounded_send(Err(io::Error::new(e.kind(), e.to_string())));
+                }
+            }
+            None => self.copy = None,
+        }
+        Poll::Ready(next)
+    }
+}
+
+impl Drop for Tee {
+    fn drop(&mut self) {
+        if let Some(copy) = self.copy.take() {
+            let _ = copy.unbounded_send(Err(io::Error::other("NAR read was cut short")));
+        }
+    }
+}
+
+#[async_trait]
+impl NixCacheStorage for TieredStorage {
+    async fn get_narinfo(&self, hash: &str) -> Result<String, StorageError> {
+        self.tiers.cold.get_narinfo(hash).await
+    }
+
+    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        self.tiers.cold.put_narinfo(hash, content).await
+    }
+
+    async fn nar_size(&self, file: &str) -> Result<u64, StorageError> {
+        if self.tiers.touch(file) {
+            match self.tiers.hot.nar_size(file).await {
+                Err(StorageError::NotFound) => {}
+                result => return result,
+            }
+        }
+        self.tiers.cold.nar_size(file).await
+    }
+
+    async fn get_nar(
+        &self,
+        file: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream, StorageError> {
+        if self.tiers.touch(file) {
+            match self.tiers.hot.get_nar(file, range).await {
+                Err(StorageError::NotFound) => self.tiers.index.lock().unwrap().remove(file),
+                result => return result,
+            }
+        }
+
+        let content = self.tiers.cold.get_nar(file, range).await?;
+        if !self.tiers.start_rehydrating(file) {
+            return Ok(content);
+        }
+        let tiers = self.tiers.clone();
+        let file = file.to_owned();
+        match range {
+            // Copy the whole NAR separately; the client only wants part of it.
+            Some(_) => {
+                tokio::spawn(async move {
+                    match tiers.cold.get_nar(&file, None).await {
+                        Ok(whole) => tiers.rehydrate(&file, whole).await,
+                        Err(e) => {
+                            warn!(file = %file, error = %e, "Failed to fetch NAR to rehydrate");
+                            tiers.index.lock().unwrap().rehydrating.remove(&file);
+                        }
+                    }
+                });
+                Ok(content)
+            }
+            None => {
+                let (copy, copied) = mpsc::unbounded();
+                tokio::spawn(async move { tiers.rehydrate(&file, copied.boxed()).await });
+                Ok(Tee {
+                    inner: content,
+                    copy: Some(copy),
+                }
+                .boxed())
+            }
+        }
+    }
+
+    /// Lands the upload on disk first, then copies it to the bucket. It only counts as
+    /// stored once it is in the bucket.
+    async fn put_nar(&self, file: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.tiers.hot.put_nar(file, content).await?;
+        let upload = self.tiers.hot.get_nar(file, None).await?;
+        if let Err(e) = self.tiers.cold.put_nar(file, upload).await {
+            self.tiers.index.lock().unwrap().remove(file);
+            let _ = self.tiers.hot.delete_nar(file).await;
+            return Err(e);
+        }
+        self.tiers.admit(file).await;
+        Ok(())
+    }
+
+    async fn list_narinfos(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.tiers.cold.list_narinfos().await
+    }
+
+    async fn list_nars(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.tiers.cold.list_nars().await
+    }
+
+    async fn delete_narinfo(&self, hash: &str) -> Result<(), StorageError> {
+        self.tiers.cold.delete_narinfo(hash).await
+    }
+
+    async fn delete_nar(&self, file: &str) -> Result<(), StorageError> {
+        self.tiers.index.lock().unwrap().remove(file);
+        match self.tiers.hot.delete_nar(file).await {
+            Ok(()) | Err(StorageError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        self.tiers.cold.delete_nar(file).await
+    }
+
+    /// Renames the NAR in the bucket, and in the hot tier if it is there.
+    async fn rename_nar(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        self.tiers.cold.rename_nar(from, to).await?;
+        let hot = {
+            let mut index = self.tiers.index.lock().unwrap();
+            index.remove(to);
+            let hot = index.nars.contains_key(from);
+            index.remove(from);
+            hot
+        };
+        let renamed = if hot {
+            self.tiers.hot.rename_nar(from, to).await
+        } else {
+            self.tiers.hot.delete_nar(to).await
+        };
+        match renamed {
+            Ok(()) if hot => self.tiers.admit(to).await,
+            Ok(()) | Err(StorageError::NotFound) => {}
+            Err(e) => warn!(file = %to, error = %e, "Failed to rename NAR in the hot tier"),
+        }
+        Ok(())
+    }
+
+    async fn get_log(&self, drv: &str) -> Result<ByteStream, StorageError> {
+        self.tiers.cold.get_log(drv).await
+    }
+
+    async fn put_log(&self, drv: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.tiers.cold.put_log(drv, content).await
+    }
+
+    async fn list_logs(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.tiers.cold.list_logs().await
+    }
+
+    async fn delete_log(&self, drv: &str) -> Result<(), StorageError> {
+        self.tiers.cold.delete_log(drv).await
+    }
+
+    async fn get_realisation(&self, id: &str) -> Result<String, StorageError> {
+        self.tiers.cold.get_realisation(id).await
+    }
+
+    async fn put_realisation(&self, id: &str, content: String) -> Result<(), StorageError> {
+        self.tiers.cold.put_realisation(id, content).await
+    }
+
+    async fn list_realisations(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.tiers.cold.list_realisations().await
+    }
+
+    async fn delete_realisation(&self, id: &str) -> Result<(), StorageError> {
+        self.tiers.cold.delete_realisation(id).await
+    }
+
+    async fn get_listing(&self, hash: &str) -> Result<String, StorageError> {
+        self.tiers.cold.get_listing(hash).await
+    }
+
+    async fn put_listing(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        self.tiers.cold.put_listing(hash, content).await
+    }
+
+    async fn delete_listing(&self, hash: &str) -> Result<(), StorageError> {
+        self.tiers.cold.delete_listing(hash).await
+    }
+
+    async fn get_debuginfo(&self, build_id: &str) -> Result<String, StorageError> {
+        self.tiers.cold.get_debuginfo(build_id).await
+    }
+
+    async fn put_debuginfo(&self, build_id: &str, content: String) -> Result<(), StorageError> {
+        self.tiers.cold.put_debuginfo(build_id, content).await
+    }
+
+    async fn list_debuginfo(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.tiers.cold.list_debuginfo().await
+    }
+
+    async fn delete_debuginfo(&self, build_id: &str) -> Result<(), StorageError> {
+        self.tiers.cold.delete_debuginfo(build_id).await
+    }
+
+    async fn get_chunk(&self, hash: &str) -> Result<Bytes, StorageError> {
+        self.tiers.cold.get_chunk(hash).await
+    }
+
+    async fn put_chunk(&self, hash: &str, content: Bytes) -> Result<(), StorageError> {
+        self.tiers.cold.put_chunk(hash, content).await
+    }
+
+    async fn list_chunks(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.tiers.cold.list_chunks().await
+    }
+
+    async fn delete_chunk(&self, hash: &str) -> Result<(), StorageError> {
+        self.tiers.cold.delete_chunk(hash).await
+    }
+
+    async fn get_meta(&self, name: &str) -> Result<Vec<u8>, StorageError> {
+        self.tiers.cold.get_meta(name).await
+    }
+
+    async fn put_meta(&self, name: &str, content: Vec<u8>) -> Result<(), StorageError> {
+        self.tiers.cold.put_meta(name, content).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::TryStreamExt;
+
+    fn stream_of(bytes: &'static [u8]) -> ByteStream {
+        futures::stream::once(async move { Ok(Bytes::from_static(bytes)) }).boxed()
+    }
+
+    async fn read(storage: &dyn NixCacheStorage, file: &str) -> Vec<u8> {
+        let chunks: Vec<Bytes> = storage
+            .get_nar(file, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        chunks.concat()
+    }
+
+    /// Waits for a background rehydration to land.
+    async fn rehydrated(storage: &TieredStorage, file: &str) {
+        for _ in 0..100 {
+            if storage.tiers.index.lock().unwrap().nars.contains_key(file) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("{file} was not rehydrated");
+    }
+
+    #[tokio::test]
+    async fn evicts_and_rehydrates() {
+        let dir = tempfile::tempdir().unwrap();
+        let cold: Arc<dyn NixCacheStorage> =
+            Arc::new(DiskStorage::new(dir.path().join("cold")).await.unwrap());
+        let hot = DiskStorage::new(dir.path().join("hot")).await.unwrap();
+        let storage = TieredStorage::new(hot, cold.clone(), 10).await.unwrap();
+        let hot_nar = |file: &str| dir.path().join("hot/nar").join(file);
+
+        storage
+            .put_nar("a.nar", stream_of(b"aaaaaa"))
+            .await
+            .unwrap();
+        assert!(hot_nar("a.nar").exists());
+        storage
+            .put_nar("b.nar", stream_of(b"bbbbbb"))
+            .await
+            .unwrap();
+        // Both are in the bucket, but only the newer fits on disk.
+        assert!(!hot_nar("a.nar").exists());
+        assert!(hot_nar("b.nar").exists());
+        assert_eq!(storage.hot_bytes(), 6);
+        assert_eq!(read(cold.as_ref(), "a.nar").await, b"aaaaaa");
+
+        assert_eq!(read(&storage, "a.nar").await, b"aaaaaa");
+        rehydrated(&storage, "a.nar").await;
+        assert!(hot_nar("a.nar").exists());
+        assert!(!hot_nar("b.nar").exists());
+        assert_eq!(storage.nar_size("b.nar").await.unwrap(), 6);
+
+        // A read cut short doesn't leave a partial NAR behind.
+        drop(storage.get_nar("b.nar", None).await.unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!hot_nar("b.nar").exists());
+        assert!(storage.tiers.index.lock().unwrap().rehydrating.is_empty());
+
+        storage.delete_nar("a.nar").await.unwrap();
+        assert!(!hot_nar("a.nar").exists());
+        assert!(matches!(
+            cold.nar_size("a.nar").await,
+            Err(StorageError::NotFound)
+        ));
+        assert_eq!(storage.hot_bytes(), 0);
+    }
+}
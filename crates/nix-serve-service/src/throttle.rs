@@ -0,0 +1,335 @@
+//! Backpressure on uploads. When many builds finish at once their NARs would all be
+//! streamed to storage at the same time; past the concurrency limits uploads are turned
+//! away with 429 so Nix and `nix-serve-push` retry later, and the bandwidth caps pace
+//! the ones that are let in.
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::auth::Token;
+use crate::config::UploadLimitsConfig;
+use crate::AppState;
+
+#[derive(Debug, Clone, Error)]
+pub enum ThrottleError {
+    #[error("{0} uploads are already in flight")]
+    Busy(usize),
+    #[error("client {client} already has {limit} uploads in flight")]
+    ClientBusy { client: String, limit: usize },
+}
+
+/// A token bucket holding up to a second's worth of bytes.
+struct Bucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Bucket {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, going into debt if need be, and returns how long
+    /// to wait until the debt is paid off.
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (available, last) = &mut *state;
+        let now = Instant::now();
+        *available = (*available + now.duration_since(*last).as_secs_f64() * self.rate)
+            .min(self.rate)
+            - bytes as f64;
+        *last = now;
+        Duration::from_secs_f64((-*available / self.rate).max(0.0))
+    }
+}
+
+/// What uploads of one client share.
+struct Client {
+    uploads: Option<Arc<Semaphore>>,
+    bandwidth: Option<Bucket>,
+}
+
+/// Holds an upload's place until it is dropped.
+pub struct Admission {
+    _permits: [Option<OwnedSemaphorePermit>; 2],
+    bandwidth: Option<Arc<Bucket>>,
+    client: Option<Arc<Client>>,
+    _in_flight: InFlight,
+}
+
+impl Admission {
+    /// Paces `body` to the bandwidth caps. The admission is released with the body.
+    pub fn pace(self, body: Body) -> Body {
+        let stream = body.into_data_stream().then(move |chunk| {
+            let len = chunk.as_ref().map_or(0, |chunk| chunk.len());
+            let buckets = [
+                self.bandwidth.as_deref(),
+                self.client
+                    .as_ref()
+                    .and_then(|client| client.bandwidth.as_ref()),
+            ];
+            let wait = buckets
+                .into_iter()
+                .flatten()
+                .map(|bucket| bucket.take(len))
+                .max()
+                .unwrap_or_default();
+            async move {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                chunk
+            }
+        });
+        Body::from_stream(stream)
+    }
+}
+
+/// Counts an upload as in flight while it lives.
+struct InFlight(Arc<AtomicU64>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Admits uploads within the configured limits. One is shared by all caches.
+pub struct Throttle {
+    config: UploadLimitsConfig,
+    uploads: Option<Arc<Semaphore>>,
+    bandwidth: Option<Arc<Bucket>>,
+    /// Clients with uploads in flight.
+    clients: Mutex<HashMap<String, Arc<Client>>>,
+    in_flight: Arc<AtomicU64>,
+    rejections: AtomicU64,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(Default::default())
+    }
+}
+
+impl Throttle {
+    pub fn new(config: UploadLimitsConfig) -> Self {
+        Throttle {
+            uploads: config
+                .max_concurrent
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            bandwidth: config
+                .max_bytes_per_sec
+                .map(|rate| Arc::new(Bucket::new(rate))),
+            config,
+            clients: Mutex::new(HashMap::new()),
+            in_flight: Arc::new(AtomicU64::new(0)),
+            rejections: AtomicU64::new(0),
+        }
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn rejections(&self) -> u64 {
+        self.rejections.load(Ordering::Relaxed)
+    }
+
+    fn client(&self, name: &str) -> Option<Arc<Client>> {
+        let config = &self.config;
+        if config.max_concurrent_per_client.is_none()
+            && config.max_bytes_per_sec_per_client.is_none()
+        {
+            return None;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        // Only admissions hold on to a client besides the map.
+        clients.retain(|_, client| Arc::strong_count(client) > 1);
+        let client = clients.entry(name.to_owned()).or_insert_with(|| {
+            Arc::new(Client {
+                uploads: config
+                    .max_concurrent_per_client
+                    .map(|limit| Arc::new(Semaphore::new(limit))),
+                bandwidth: config.max_bytes_per_sec_per_client.map(Bucket::new),
+            })
+        });
+        Some(client.clone())
+    }
+
+    /// Lets an upload by `client` in, unless that would go over a concurrency limit.
+    pub fn admit(&self, client: &str) -> Result<Admission, ThrottleError> {
+        let entry = self.client(client);
+        let acquire = |semaphore: Option<&Arc<Semaphore>>, e: ThrottleError| {
+            semaphore
+                .map(|semaphore| semaphore.clone().try_acquire_owned())
+                .transpose()
+                .map_err(|_| {
+                    self.rejections.fetch_add(1, Ordering::Relaxed);
+                    e
+                })
+        };
+        let client_permit = acquire(
+            entry.as_ref().and_then(|entry| entry.uploads.as_ref()),
+            ThrottleError::ClientBusy {
+                client: client.to_owned(),
+                limit: self.config.max_concurrent_per_client.unwrap_or_default(),
+            },
+        )?;
+        let permit = acquire(
+            self.uploads.as_ref(),
+            ThrottleError::Busy(self.config.max_concurrent.unwrap_or_default()),
+        )?;
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Ok(Admission {
+            _permits: [permit, client_permit],
+            bandwidth: self.bandwidth.clone(),
+            client: entry,
+            _in_flight: InFlight(self.in_flight.clone()),
+        })
+    }
+
+    fn busy(&self) -> Response {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(
+                header::RETRY_AFTER,
+                self.config.retry_after_secs.to_string(),
+            )],
+        )
+            .into_response()
+    }
+}
+
+/// Who an upload counts against: its token, or the address it came from.
+fn client_of(request: &Request) -> String {
+    if let Some(token) = request.extensions().get::<Arc<Token>>() {
+        return token.name.clone();
+    }
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.ip().to_string(),
+        None => "anonymous".to_owned(),
+    }
+}
+
+/// Middleware on upload routes: turns uploads over a concurrency limit away and paces
+/// the rest.
+pub async fn limit_uploads(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = client_of(&request);
+    let admission = match state.uploads.admit(&client) {
+        Ok(admission) => admission,
+        Err(e) => {
+            warn!(client = %client, path = %request.uri().path(), error = %e, "Turning upload away");
+            return state.uploads.busy();
+        }
+    };
+    next.run(request.map(|body| admission.pace(body))).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> UploadLimitsConfig {
+        UploadLimitsConfig {
+            max_concurrent: Some(3),
+            max_concurrent_per_client: Some(2),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn limits_concurrent_uploads() {
+        let throttle = Throttle::new(limits());
+        let first = throttle.admit("ci").unwrap();
+        let _second = throttle.admit("ci").unwrap();
+        assert!(matches!(
+            throttle.admit("ci"),
+            Err(ThrottleError::ClientBusy { limit: 2, .. })
+        ));
+        let _third = throttle.admit("laptop").unwrap();
+        assert!(matches!(
+            throttle.admit("laptop"),
+            Err(ThrottleError::Busy(3))
+        ));
+        assert_eq!(throttle.in_flight(), 3);
+        assert_eq!(throttle.rejections(), 2);
+
+        drop(first);
+        assert_eq!(throttle.in_flight(), 2);
+        let _fourth = throttle.admit("ci").unwrap();
+    }
+
+    #[tokio::test]
+    async fn turns_uploads_away_with_retry_after() {
+        use crate::storage::DiskStorage;
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let throttle = Arc::new(Throttle::new(UploadLimitsConfig {
+            max_concurrent: Some(1),
+            retry_after_secs: 7,
+            ..Default::default()
+        }));
+        let app = crate::router(AppState {
+            uploads: throttle.clone(),
+            ..crate::test::test_state(storage)
+        });
+        let upload = || {
+            Request::builder()
+                .method("PUT")
+                .uri("/nar/abc.nar")
+                .body(Body::from("nar"))
+                .unwrap()
+        };
+
+        let in_flight = throttle.admit("ci").unwrap();
+        let response = app.clone().oneshot(upload()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "7");
+
+        drop(in_flight);
+        let response = app.oneshot(upload()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(throttle.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn paces_uploads() {
+        let throttle = Throttle::new(UploadLimitsConfig {
+            max_bytes_per_sec_per_client: Some(100_000),
+            ..Default::default()
+        });
+        let body = Body::from(vec![0u8; 150_000]);
+        let started = std::time::Instant::now();
+        let paced = throttle.admit("ci").unwrap().pace(body);
+        let bytes = axum::body::to_bytes(paced, usize::MAX).await.unwrap();
+        assert_eq!(bytes.len(), 150_000);
+        // A second's worth goes through at once, the rest at the capped rate.
+        assert!(started.elapsed() >= Duration::from_millis(450));
+        assert_eq!(throttle.in_flight(), 0);
+    }
+}
@@ -0,0 +1,730 @@
+//! Copying cache contents between storage backends. Exports write into a
+//! [`DiskStorage`](crate::storage::DiskStorage), which has the layout of a `file://`
+//! binary cache; imports read one back and check every path before taking it, then
+//! store it the way an upload would be.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use common::narinfo::{Compression, NarInfo, NarInfoError};
+
+use crate::compression::{self, CompressionError};
+use crate::gc::hash_part;
+use crate::integrity::{self, IntegrityError};
+use crate::realisation::{Realisation, RealisationError};
+use crate::routes::store_listing;
+use crate::signing::{verify_any, PublicKey, SecretKey};
+use crate::storage::{NixCacheStorage, StorageError};
+use crate::AppState;
+
+/// What imported paths are attributed to, in place of a token.
+const IMPORTER: &str = "import";
+
+#[derive(Debug, Error)]
+pub enum TransferError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("invalid narinfo: {0}")]
+    NarInfo(#[from] NarInfoError),
+    #[error(transparent)]
+    Realisation(#[from] RealisationError),
+    #[error(transparent)]
+    Integrity(#[from] IntegrityError),
+    #[error(transparent)]
+    Compression(#[from] CompressionError),
+    #[error("{0} is not a store path")]
+    InvalidPath(String),
+    #[error("not in the cache")]
+    Missing,
+    #[error("reference {0} is in neither cache")]
+    MissingReference(String),
+    #[error("reference {0} could not be imported")]
+    FailedReference(String),
+    #[error("narinfo is for {0}")]
+    WrongPath(String),
+    #[error("no signature by a trusted key")]
+    Untrusted,
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// What to export.
+#[derive(Debug, Clone)]
+pub enum Selection {
+    /// Every store path, realisation and build log.
+    Everything,
+    /// Just these store paths, given as paths or hash parts.
+    Paths(Vec<String>),
+    /// These store paths and everything they reference.
+    Closures(Vec<String>),
+}
+
+/// What an import checks besides hashes.
+pub struct Trust {
+    /// Keys whose signatures are accepted.
+    pub keys: Vec<PublicKey>,
+    /// Without this, unsigned narinfo and realisations are taken too.
+    pub check_signatures: bool,
+    /// Signs imported narinfo and realisations, as uploads are.
+    pub cache_key: Option<SecretKey>,
+}
+
+impl Trust {
+    /// Checks the signatures over `fingerprint` and adds the cache's own.
+    fn resign(&self, fingerprint: &str, sigs: &mut Vec<String>) -> Result<(), TransferError> {
+        if self.check_signatures
+            && !sigs
+                .iter()
+                .any(|sig| verify_any(&self.keys, fingerprint, sig))
+        {
+            return Err(TransferError::Untrusted);
+        }
+        if let Some(key) = &self.cache_key {
+            let own = format!("{}:", key.name());
+            sigs.retain(|sig| !sig.starts_with(&own));
+            sigs.push(key.sign(fingerprint));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TransferReport {
+    /// Objects copied.
+    pub copied: usize,
+    /// Objects the destination already had.
+    pub present: usize,
+    /// Objects that could not be copied, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+impl TransferReport {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    fn record(&mut self, name: &str, result: Result<bool, TransferError>) {
+        match result {
+            Ok(true) => self.copied += 1,
+            Ok(false) => self.present += 1,
+            Err(e) => {
+                warn!(object = %name, error = %e, "Failed to copy");
+                self.failed.push((name.to_owned(), e.to_string()));
+            }
+        }
+    }
+}
+
+/// Copies what `selection` picks from `from` to `to`, trusting `from` to be intact
+/// apart from checking each NAR against its `FileHash`.
+pub async fn export(
+    from: &dyn NixCacheStorage,
+    to: &dyn NixCacheStorage,
+    selection: &Selection,
+) -> Result<TransferReport, TransferError> {
+    let mut report = TransferReport::default();
+    let hashes: BTreeSet<String> = match selection {
+        Selection::Everything => from
+            .list_narinfos()
+            .await?
+            .into_iter()
+            .map(|object| object.name)
+            .collect(),
+        Selection::Paths(paths) => {
+            let mut hashes = BTreeSet::new();
+            for path in paths {
+                match hash_part(path) {
+                    Some(hash) => {
+                        hashes.insert(hash.to_owned());
+                    }
+                    None => report.record(path, Err(TransferError::InvalidPath(path.clone()))),
+                }
+            }
+            hashes
+        }
+        Selection::Closures(paths) => closure(from, paths, &mut report).await?,
+    };
+    info!(paths = hashes.len(), "Exporting store paths");
+    for hash in &hashes {
+        report.record(hash, copy_path(from, to, hash).await);
+    }
+
+    if let Selection::Everything = selection {
+        for object in from.list_realisations().await? {
+            let result = copy_realisation(from, to, &object.name, None).await;
+            report.record(&object.name, result);
+        }
+        for object in from.list_logs().await? {
+            let result = copy_log(from, to, &object.name, Direction::Export).await;
+            report.record(&object.name, result);
+        }
+    }
+    Ok(report)
+}
+
+/// Copies everything in `from` into the cache of `state`, through its storage stack
+/// like an upload: compressed as its policy says, counted, listed and announced. Each
+/// NAR is re-hashed against its narinfo and the narinfo and realisations must carry a
+/// signature `trust` accepts. Paths go in after their references, and a path whose
+/// references are in neither cache is left out.
+pub async fn import(
+    from: &dyn NixCacheStorage,
+    state: &AppState,
+    trust: &Trust,
+) -> Result<TransferReport, TransferError> {
+    let mut report = TransferReport::default();
+    let mut narinfos = BTreeMap::new();
+    for object in from.list_narinfos().await? {
+        let result = async {
+            let info: NarInfo = from.get_narinfo(&object.name).await?.parse()?;
+            if info.store_path.hash_part() != object.name {
+                return Err(TransferError::WrongPath(info.store_path.to_absolute()));
+            }
+            Ok(info)
+        }
+        .await;
+        match result {
+            Ok(info) => {
+                narinfos.insert(object.name, info);
+            }
+            Err(e) => report.record(&object.name, Err(e)),
+        }
+    }
+    info!(paths = narinfos.len(), "Importing store paths");
+    let mut imported = HashSet::new();
+    for hash in dependency_order(&narinfos) {
+        let info = &narinfos[hash];
+        let result = match missing_reference(state, &narinfos, &imported, info).await {
+            Ok(Some(e)) => Err(e),
+            Ok(None) => import_path(from, state, info.clone(), trust).await,
+            Err(e) => Err(e),
+        };
+        if result.is_ok() {
+            imported.insert(hash.as_str());
+        }
+        report.record(hash, result);
+    }
+    for object in from.list_realisations().await? {
+        let result =
+            copy_realisation(from, state.storage.as_ref(), &object.name, Some(trust)).await;
+        report.record(&object.name, result);
+    }
+    for object in from.list_logs().await? {
+        let result = copy_log(
+            from,
+            state.storage.as_ref(),
+            &object.name,
+            Direction::Import,
+        )
+        .await;
+        report.record(&object.name, result);
+    }
+    Ok(report)
+}
+
+/// The hash parts of `narinfos`, each after the ones it references.
+fn dependency_order(narinfos: &BTreeMap<String, NarInfo>) -> Vec<&String> {
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    for root in narinfos.keys() {
+        // Depth first, without recursion: a path is pushed once its references are.
+        let mut stack = vec![(root, false)];
+        while let Some((hash, expanded)) = stack.pop() {
+            if expanded {
+                order.push(hash);
+                continue;
+            }
+            if !visited.insert(hash) {
+                continue;
+            }
+            stack.push((hash, true));
+            for reference in &narinfos[hash].references {
+                if let Some((reference, _)) = narinfos.get_key_value(reference.hash_part()) {
+                    if !visited.contains(reference) {
+                        stack.push((reference, false));
+                    }
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Why `info` can't be imported yet: a reference that failed to import, or that is
+/// in neither cache.
+async fn missing_reference(
+    state: &AppState,
+    narinfos: &BTreeMap<String, NarInfo>,
+    imported: &HashSet<&str>,
+    info: &NarInfo,
+) -> Result<Option<TransferError>, TransferError> {
+    for reference in &info.references {
+        let hash = reference.hash_part();
+        if hash == info.store_path.hash_part() || imported.contains(hash) {
+            continue;
+        }
+        match state.storage.get_narinfo(hash).await {
+            Ok(_) => continue,
+            Err(StorageError::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let path = reference.to_absolute();
+        return Ok(Some(if narinfos.contains_key(hash) {
+            TransferError::FailedReference(path)
+        } else {
+            TransferError::MissingReference(path)
+        }));
+    }
+    Ok(None)
+}
+
+/// Imports a store path like an upload of its NAR and narinfo. Returns false if the
+/// cache already has it.
+async fn import_path(
+    from: &dyn NixCacheStorage,
+    state: &AppState,
+    mut info: NarInfo,
+    trust: &Trust,
+) -> Result<bool, TransferError> {
+    let hash = info.store_path.hash_part().to_owned();
+    let to = state.storage.as_ref();
+    match to.get_narinfo(&hash).await {
+        Ok(_) => return Ok(false),
+        Err(StorageError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    trust.resign(&info.fingerprint(), &mut info.sigs)?;
+    let listing = integrity::verify_narinfo(from, &info).await?;
+    copy_nar(from, to, &info).await?;
+
+    if state.compression.store != Compression::None && info.compression == Compression::None {
+        match compression::compress_stored(to, &state.compression, &info).await {
+            Ok(compressed) => info = compressed,
+            Err(e) => {
+                warn!(hash = %hash, error = %e, "Failed to compress NAR, storing it as imported")
+            }
+        }
+    }
+    to.put_narinfo(&hash, info.to_string()).await?;
+    let size = info.file_size.unwrap_or(info.nar_size);
+    state.usage.record_upload(&hash, IMPORTER, size);
+    info!(
+        target: "audit",
+        token = IMPORTER,
+        store_path = %info.store_path,
+        nar = %info.url,
+        "Imported store path"
+    );
+    state.events.path_added(&info, IMPORTER);
+    if let Some(listing) = listing {
+        store_listing(state, &hash, &info, listing).await;
+    }
+    Ok(true)
+}
+
+/// The hash parts of `paths` and everything they reference. Paths missing from the
+/// cache are recorded as failures.
+async fn closure(
+    storage: &dyn NixCacheStorage,
+    paths: &[String],
+    report: &mut TransferReport,
+) -> Result<BTreeSet<String>, TransferError> {
+    let mut closure = BTreeSet::new();
+    let mut queue = Vec::new();
+    for path in paths {
+        match hash_part(path) {
+            Some(hash) => queue.push(hash.to_owned()),
+            None => report.record(path, Err(TransferError::InvalidPath(path.clone()))),
+        }
+    }
+    let mut seen: HashSet<String> = queue.iter().cloned().collect();
+    while let Some(hash) = queue.pop() {
+        let info: NarInfo = match storage.get_narinfo(&hash).await {
+            Ok(content) => content.parse()?,
+            Err(StorageError::NotFound) => {
+                report.record(&hash, Err(TransferError::Missing));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for reference in &info.references {
+            if seen.insert(reference.hash_part().to_owned()) {
+                queue.push(reference.hash_part().to_owned());
+            }
+        }
+        closure.insert(hash);
+    }
+    Ok(closure)
+}
+
+/// Copies a narinfo with its NAR and listing, the narinfo last so the path only shows
+/// up once it is complete. Returns false if `to` already has it.
+async fn copy_path(
+    from: &dyn NixCacheStorage,
+    to: &dyn NixCacheStorage,
+    hash: &str,
+) -> Result<bool, TransferError> {
+    match to.get_narinfo(hash).await {
+        Ok(_) => return Ok(false),
+        Err(StorageError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let content = match from.get_narinfo(hash).await {
+        Ok(content) => content,
+        Err(StorageError::NotFound) => return Err(TransferError::Missing),
+        Err(e) => return Err(e.into()),
+    };
+    let info: NarInfo = content.parse()?;
+    if info.store_path.hash_part() != hash {
+        return Err(TransferError::WrongPath(info.store_path.to_absolute()));
+    }
+    copy_nar(from, to, &info).await?;
+
+    match from.get_listing(hash).await {
+        Ok(listing) => to.put_listing(hash, listing).await?,
+        Err(StorageError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    to.put_narinfo(hash, content).await?;
+    Ok(true)
+}
+
+/// Copies the NAR of `info` unless `to` already has it, checking it against its
+/// `FileHash` on the way.
+async fn copy_nar(
+    from: &dyn NixCacheStorage,
+    to: &dyn NixCacheStorage,
+    info: &NarInfo,
+) -> Result<(), TransferError> {
+    let file = info
+        .nar_file()
+        .ok_or_else(|| IntegrityError::BadUrl(info.url.clone()))?;
+    match to.nar_size(file).await {
+        Ok(_) => return Ok(()),
+        Err(StorageError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let nar = match from.get_nar(file, None).await {
+        Ok(nar) => nar,
+        Err(StorageError::NotFound) => {
+            return Err(IntegrityError::MissingNar(file.to_owned()).into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let nar = match &info.file_hash {
+        Some(file_hash) => integrity::verify_upload(nar, file_hash.clone()),
+        None => nar,
+    };
+    to.put_nar(file, nar).await?;
+    Ok(())
+}
+
+async fn copy_realisation(
+    from: &dyn NixCacheStorage,
+    to: &dyn NixCacheStorage,
+    id: &str,
+    trust: Option<&Trust>,
+) -> Result<bool, TransferError> {
+    match to.get_realisation(id).await {
+        Ok(_) => return Ok(false),
+        Err(StorageError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let mut content = from.get_realisation(id).await?;
+    if let Some(trust) = trust {
+        let mut realisation = Realisation::parse(&content)?;
+        if realisation.id != id {
+            return Err(TransferError::WrongPath(realisation.id));
+        }
+        trust.resign(&realisation.fingerprint(), &mut realisation.signatures)?;
+        content = realisation.to_json();
+    }
+    to.put_realisation(id, content).await?;
+    Ok(true)
+}
+
+/// Which way a copy goes. The cache stores build logs compressed, a `file://` binary
+/// cache plain.
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Export,
+    Import,
+}
+
+/// Build logs are copied without checks; there is nothing to check them against.
+async fn copy_log(
+    from: &dyn NixCacheStorage,
+    to: &dyn NixCacheStorage,
+    drv: &str,
+    direction: Direction,
+) -> Result<bool, TransferError> {
+    match to.get_log(drv).await {
+        Ok(_) => return Ok(false),
+        Err(StorageError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let log = from.get_log(drv).await?;
+    let log = match direction {
+        Direction::Export => compression::decompress_log(log)?,
+        Direction::Import => compression::compress_log(log, &Compression::None)?,
+    };
+    to.put_log(drv, log).await?;
+    Ok(true)
+}
+
+/// Packs the directory `dir` into the tarball `tarball`.
+pub async fn pack(dir: PathBuf, tarball: PathBuf) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut builder = tar::Builder::new(std::fs::File::create(tarball)?);
+        builder.follow_symlinks(false);
+        builder.append_dir_all(".", dir)?;
+        builder.into_inner()?.sync_all()
+    })
+    .await?
+}
+
+/// Unpacks the tarball `tarball` into the directory `dir`. Entries that would land
+/// outside of `dir` are skipped.
+pub async fn unpack(tarball: PathBuf, dir: PathBuf) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        tar::Archive::new(std::fs::File::open(tarball)?).unpack(dir)
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oci::test::{add_path, APP, LIB};
+    use crate::signing::test_key;
+    use crate::storage::{ByteStream, DiskStorage};
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    const MISSING: &str = "/nix/store/0yzhigwjl6bws649vcs2asa4lbs8hg93-missing";
+
+    async fn sign_all(storage: &dyn NixCacheStorage, key: &SecretKey) {
+        for object in storage.list_narinfos().await.unwrap() {
+            let content = storage.get_narinfo(&object.name).await.unwrap();
+            let mut info: NarInfo = content.parse().unwrap();
+            info.sigs.push(key.sign(&info.fingerprint()));
+            storage
+                .put_narinfo(&object.name, info.to_string())
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn disk() -> (tempfile::TempDir, DiskStorage) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path()).await.unwrap();
+        (dir, storage)
+    }
+
+    /// A cache to import into.
+    async fn target_cache() -> (tempfile::TempDir, AppState) {
+        let (dir, storage) = disk().await;
+        (dir, crate::test::test_state(std::sync::Arc::new(storage)))
+    }
+
+    #[tokio::test]
+    async fn exports_and_imports_closures() {
+        let builder = test_key("builder-1", 1);
+        let cache = test_key("cache-1", 2);
+        let (_source_dir, source) = disk().await;
+        add_path(&source, LIB, &[]).await;
+        add_path(&source, APP, &[LIB]).await;
+        sign_all(&source, &builder).await;
+
+        let (_export_dir, export) = disk().await;
+        let selection = Selection::Paths(vec![MISSING.to_owned()]);
+        let report = super::export(&source, &export, &selection).await.unwrap();
+        assert_eq!((report.copied, report.failed.len()), (0, 1));
+        let selection = Selection::Closures(vec![APP.to_owned()]);
+        let report = super::export(&source, &export, &selection).await.unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.copied, 2);
+
+        let trust = Trust {
+            keys: vec![builder.public_key()],
+            check_signatures: true,
+            cache_key: Some(cache),
+        };
+        let (_target_dir, target) = target_cache().await;
+        let report = import(&export, &target, &trust).await.unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.copied, 2);
+        let hash = hash_part(APP).unwrap();
+        let info: NarInfo = target
+            .storage
+            .get_narinfo(hash)
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(info.sigs.len(), 2);
+        assert!(info.sigs[1].starts_with("cache-1:"));
+        assert!(integrity::verify_narinfo(target.storage.as_ref(), &info)
+            .await
+            .is_ok());
+
+        let report = import(&export, &target, &trust).await.unwrap();
+        assert_eq!((report.copied, report.present), (0, 2));
+
+        let untrusted = Trust {
+            keys: vec![test_key("other", 3).public_key()],
+            check_signatures: true,
+            cache_key: None,
+        };
+        let (_dir, target) = target_cache().await;
+        let report = import(&export, &target, &untrusted).await.unwrap();
+        assert_eq!((report.copied, report.failed.len()), (0, 2));
+        assert_eq!(report.failed[0].1, "no signature by a trusted key");
+    }
+
+    #[tokio::test]
+    async fn imports_like_uploads_in_dependency_order() {
+        use crate::compression::CompressionPolicy;
+        use crate::config::CompressionConfig;
+        use std::sync::Arc;
+
+        const TOOL: &str = "/nix/store/1b9p07z77phvv2hf6gm9f28syp39f1ag-tool";
+        let (_export_dir, export) = disk().await;
+        add_path(&export, APP, &[LIB]).await;
+        add_path(&export, LIB, &[]).await;
+        add_path(&export, TOOL, &[MISSING]).await;
+
+        let (events, mut sent_events) = crate::events::test_events(None);
+        let policy = CompressionPolicy::from_config(&CompressionConfig {
+            store: "zstd".to_owned(),
+            level: None,
+            xz_user_agents: Vec::new(),
+        })
+        .unwrap();
+        let (_target_dir, target) = target_cache().await;
+        let target = AppState {
+            compression: Arc::new(policy),
+            events: Arc::new(events),
+            ..target
+        };
+        let trust = Trust {
+            keys: Vec::new(),
+            check_signatures: false,
+            cache_key: None,
+        };
+        let report = import(&export, &target, &trust).await.unwrap();
+        assert_eq!(report.copied, 2);
+        assert_eq!(
+            report.failed,
+            [(
+                hash_part(TOOL).unwrap().to_owned(),
+                format!("reference {MISSING} is in neither cache")
+            )]
+        );
+
+        // Added as uploads are, references first.
+        let added: Vec<String> = sent_events()
+            .into_iter()
+            .map(|(_, event)| event["store_path"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(added, [LIB, APP]);
+        let hash = hash_part(APP).unwrap();
+        let info: NarInfo = target
+            .storage
+            .get_narinfo(hash)
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(info.compression, Compression::Zstd);
+        assert_eq!(target.usage.upload(hash).unwrap().token, IMPORTER);
+        assert!(target.storage.get_listing(hash).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn refuses_damaged_nars() {
+        let (_export_dir, export) = disk().await;
+        add_path(&export, LIB, &[]).await;
+        let info: NarInfo = export
+            .get_narinfo(hash_part(LIB).unwrap())
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut nar = crate::nar::test::sample_nar();
+        *nar.last_mut().unwrap() ^= 1;
+        let body = futures::stream::once(async { Ok(Bytes::from(nar)) }).boxed();
+        export
+            .put_nar(info.nar_file().unwrap(), body)
+            .await
+            .unwrap();
+
+        let trust = Trust {
+            keys: Vec::new(),
+            check_signatures: false,
+            cache_key: None,
+        };
+        let (_target_dir, target) = target_cache().await;
+        let report = import(&export, &target, &trust).await.unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.failed[0].1.contains("NarHash mismatch"));
+        assert!(target.storage.list_narinfos().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stores_logs_compressed_and_exports_them_plain() {
+        use futures::TryStreamExt;
+
+        let read = |log: ByteStream| async move {
+            let chunks: Vec<Bytes> = log.try_collect().await.unwrap();
+            chunks.concat()
+        };
+        let drv = "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1-hello.drv";
+        let plain = b"building '/nix/store/...-hello.drv'...\n".to_vec();
+        let (_export_dir, export) = disk().await;
+        let body = Bytes::from(plain.clone());
+        let body = futures::stream::once(async { Ok(body) }).boxed();
+        export.put_log(drv, body).await.unwrap();
+
+        let trust = Trust {
+            keys: Vec::new(),
+            check_signatures: false,
+            cache_key: None,
+        };
+        let (_cache_dir, cache) = target_cache().await;
+        let report = import(&export, &cache, &trust).await.unwrap();
+        assert_eq!(report.copied, 1);
+        let stored = cache.storage.get_log(drv).await.unwrap();
+        let stored = compression::decompress_log(stored).unwrap();
+        assert_eq!(read(stored).await, plain);
+
+        let (_dir, exported) = disk().await;
+        let report = super::export(cache.storage.as_ref(), &exported, &Selection::Everything)
+            .await
+            .unwrap();
+        assert_eq!(report.copied, 1);
+        assert_eq!(read(exported.get_log(drv).await.unwrap()).await, plain);
+    }
+
+    #[tokio::test]
+    async fn round_trips_tarballs() {
+        let (source_dir, source) = disk().await;
+        add_path(&source, LIB, &[]).await;
+        let scratch = tempfile::tempdir().unwrap();
+        let tarball = scratch.path().join("cache.tar");
+        pack(source_dir.path().to_owned(), tarball.clone())
+            .await
+            .unwrap();
+        let unpacked = scratch.path().join("unpacked");
+        unpack(tarball, unpacked.clone()).await.unwrap();
+        let unpacked = DiskStorage::new(unpacked).await.unwrap();
+        assert_eq!(
+            unpacked.get_narinfo(hash_part(LIB).unwrap()).await.unwrap(),
+            source.get_narinfo(hash_part(LIB).unwrap()).await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,460 @@
+//! Pull-through mode: narinfo and NARs missing locally are fetched from upstream
+//! caches, checked against their keys and hashes, and kept so each path is only
+//! downloaded once.
+
+use futures::{StreamExt, TryStreamExt};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use common::narinfo::NarInfo;
+
+use crate::config::UpstreamConfig;
+use crate::integrity;
+use crate::signing::{self, KeyError, PublicKey, SecretKey};
+use crate::storage::{ByteStream, NixCacheStorage, StorageError};
+
+/// How long a path none of the upstreams have is not asked for again.
+const MISS_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Misses remembered before the list is thrown away.
+const MAX_MISSES: usize = 100_000;
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("invalid key for upstream {0}: {1}")]
+    Key(String, KeyError),
+    #[error("HTTP client error: {0}")]
+    Client(#[from] reqwest::Error),
+}
+
+struct Upstream {
+    url: String,
+    keys: Vec<PublicKey>,
+}
+
+pub struct Proxy {
+    upstreams: Vec<Upstream>,
+    client: reqwest::Client,
+    storage: Arc<dyn NixCacheStorage>,
+    cache_key: Option<Arc<SecretKey>>,
+    misses: Mutex<HashMap<String, Instant>>,
+    /// narinfo already handed out while their NAR is still being mirrored.
+    pending: Mutex<HashMap<String, String>>,
+    /// One download per NAR at a time.
+    downloads: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl Proxy {
+    pub fn new(
+        config: &[UpstreamConfig],
+        storage: Arc<dyn NixCacheStorage>,
+        cache_key: Option<Arc<SecretKey>>,
+    ) -> Result<Self, ProxyError> {
+        let upstreams = config
+            .iter()
+            .map(|upstream| {
+                let keys = upstream
+                    .public_keys
+                    .iter()
+                    .map(|key| PublicKey::parse(key))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| ProxyError::Key(upstream.url.clone(), e))?;
+                Ok(Upstream {
+                    url: upstream.url.trim_end_matches('/').to_owned(),
+                    keys,
+                })
+            })
+            .collect::<Result<Vec<_>, ProxyError>>()?;
+        for upstream in &upstreams {
+            if upstream.keys.is_empty() {
+                warn!(upstream = %upstream.url, "Upstream has no public keys, nothing will be taken from it");
+            }
+        }
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Proxy {
+            upstreams,
+            client,
+            storage,
+            cache_key,
+            misses: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            downloads: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// GETs `url`; `None` if the upstream doesn't have it.
+    async fn fetch(&self, url: &str) -> Option<reqwest::Response> {
+        match self.client.get(url).send().await {
+            Ok(response) if response.status().is_success() => Some(response),
+            Ok(response)
+                if matches!(response.status(), StatusCode::NOT_FOUND | StatusCode::GONE) =>
+            {
+                None
+            }
+            Ok(response) => {
+                warn!(url = %url, status = %response.status(), "Upstream request failed");
+                None
+            }
+            Err(e) => {
+                warn!(url = %url, error = %e, "Upstream request failed");
+                None
+            }
+        }
+    }
+
+    fn recently_missed(&self, hash: &str) -> bool {
+        let mut misses = self.misses.lock().unwrap();
+        match misses.get(hash) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                misses.remove(hash);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn record_miss(&self, hash: &str) {
+        let mut misses = self.misses.lock().unwrap();
+        if misses.len() >= MAX_MISSES {
+            misses.clear();
+        }
+        misses.insert(hash.to_owned(), Instant::now() + MISS_TTL);
+    }
+
+    /// Looks for `hash` upstream. A narinfo signed by one of the upstream's keys is
+    /// returned right away, signed by the cache as well, while its NAR is mirrored in the
+    /// background; it is only stored once the NAR checks out.
+    pub async fn narinfo(self: &Arc<Self>, hash: &str) -> Option<String> {
+        if let Some(content) = self.pending.lock().unwrap().get(hash) {
+            return Some(content.clone());
+        }
+        if self.recently_missed(hash) {
+            return None;
+        }
+
+        for (index, upstream) in self.upstreams.iter().enumerate() {
+            let url = format!("{}/{hash}.narinfo", upstream.url);
+            let Some(response) = self.fetch(&url).await else {
+                continue;
+            };
+            let mut info: NarInfo = match response.text().await.map(|body| body.parse()) {
+                Ok(Ok(info)) => info,
+                Ok(Err(e)) => {
+                    warn!(url = %url, error = %e, "Upstream sent a malformed narinfo");
+                    continue;
+                }
+                Err(e) => {
+                    warn!(url = %url, error = %e, "Failed to read upstream narinfo");
+                    continue;
+                }
+            };
+            let fingerprint = info.fingerprint();
+            if info.store_path.hash_part() != hash
+                || !info
+                    .sigs
+                    .iter()
+                    .any(|sig| signing::verify_any(&upstream.keys, &fingerprint, sig))
+            {
+                warn!(url = %url, "Upstream narinfo is not signed by a trusted key");
+                continue;
+            }
+
+            if let Some(key) = &self.cache_key {
+                info.sigs
+                    .retain(|sig| !sig.starts_with(&format!("{}:", key.name())));
+                info.sigs.push(key.sign(&fingerprint));
+            }
+            let content = info.to_string();
+            info!(hash = %hash, upstream = %upstream.url, "Pulling path from upstream");
+            self.pending
+                .lock()
+                .unwrap()
+                .insert(hash.to_owned(), content.clone());
+
+            let proxy = self.clone();
+            let hash = hash.to_owned();
+            tokio::spawn(async move {
+                proxy.mirror(index, &hash, info).await;
+                proxy.pending.lock().unwrap().remove(&hash);
+            });
+            return Some(content);
+        }
+
+        self.record_miss(hash);
+        None
+    }
+
+    /// Stores the NAR of an upstream narinfo and then the narinfo itself.
+    async fn mirror(&self, index: usize, hash: &str, info: NarInfo) {
+        let Some(file) = info.nar_file() else {
+            warn!(hash = %hash, url = %info.url, "Upstream narinfo has an unusable URL");
+            return;
+        };
+        let upstream = &self.upstreams[index];
+        let downloaded = self
+            .with_download_lock(file, || async {
+                match self.storage.nar_size(file).await {
+                    Ok(_) => return true,
+                    Err(StorageError::NotFound) => {}
+                    Err(e) => {
+                        error!(file = %file, error = %e, "Failed to check for NAR");
+                        return false;
+                    }
+                }
+                self.download(upstream, file, &info.url).await
+            })
+            .await;
+        if !downloaded {
+            return;
+        }
+
+        if let Err(e) = integrity::verify_narinfo(self.storage.as_ref(), &info).await {
+            error!(hash = %hash, error = %e, "Upstream NAR doesn't match its narinfo");
+            let _ = self.storage.delete_nar(file).await;
+            return;
+        }
+        match self.storage.put_narinfo(hash, info.to_string()).await {
+            Ok(()) => info!(hash = %hash, upstream = %upstream.url, "Mirrored path from upstream"),
+            Err(e) => error!(hash = %hash, error = %e, "Failed to store upstream narinfo"),
+        }
+    }
+
+    /// Fetches a NAR that isn't in storage by its file name. Only NARs named after their
+    /// file hash are fetched, as that is the only way to check them without a narinfo.
+    /// Returns true once the NAR is in storage.
+    pub async fn nar(&self, file: &str) -> bool {
+        // Waits for a mirror of the same NAR that is already under way.
+        self.with_download_lock(file, || async {
+            if self.storage.nar_size(file).await.is_ok() {
+                return true;
+            }
+            if integrity::file_hash_from_name(file).is_none() {
+                return false;
+            }
+            for upstream in &self.upstreams {
+                if self.download(upstream, file, &format!("nar/{file}")).await {
+                    return true;
+                }
+            }
+            false
+        })
+        .await
+    }
+
+    async fn download(&self, upstream: &Upstream, file: &str, path: &str) -> bool {
+        let url = format!("{}/{path}", upstream.url);
+        let Some(response) = self.fetch(&url).await else {
+            return false;
+        };
+        let stream: ByteStream = response
+            .bytes_stream()
+            .map_err(std::io::Error::other)
+            .boxed();
+        let stream = match integrity::file_hash_from_name(file) {
+            Some(expected) => integrity::verify_upload(stream, expected),
+            None => stream,
+        };
+        match self.storage.put_nar(file, stream).await {
+            Ok(()) => {
+                info!(file = %file, upstream = %upstream.url, "Fetched NAR from upstream");
+                true
+            }
+            Err(e) => {
+                error!(file = %file, upstream = %upstream.url, error = %e, "Failed to fetch NAR from upstream");
+                false
+            }
+        }
+    }
+
+    async fn with_download_lock<F, Fut>(&self, file: &str, f: F) -> bool
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        let lock = self
+            .downloads
+            .lock()
+            .unwrap()
+            .entry(file.to_owned())
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = lock.lock().await;
+            f().await
+        };
+        drop(lock);
+        self.downloads
+            .lock()
+            .unwrap()
+            .retain(|_, lock| Arc::strong_count(lock) > 1);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::integrity::sha256;
+    use crate::signing::test_key;
+    use crate::storage::DiskStorage;
+    use async_compression::tokio::bufread::XzEncoder;
+    use axum::{extract::Path, http::StatusCode as Status, routing::get, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+
+    const HASH: &str = "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1";
+    const UNSIGNED: &str = "0mdqa9w1p6cmli6976v4wi0sw9r4p5pr";
+
+    struct Upstream {
+        url: String,
+        requests: Arc<AtomicUsize>,
+        nar: Vec<u8>,
+        file: String,
+    }
+
+    /// Serves one xz-compressed path signed by `upstream-1`, and one unsigned one.
+    async fn spawn_upstream() -> Upstream {
+        let nar: Vec<u8> = (0..10_000u32).map(|i| (i % 13) as u8).collect();
+        let mut xz = Vec::new();
+        XzEncoder::new(&nar[..]).read_to_end(&mut xz).await.unwrap();
+        let file = format!("{}.nar.xz", sha256(&xz).to_nix32());
+        let narinfo = |hash: &str| {
+            format!(
+                "StorePath: /nix/store/{hash}-upstream\n\
+                 URL: nar/{file}\n\
+                 Compression: xz\n\
+                 FileHash: {}\n\
+                 FileSize: {}\n\
+                 NarHash: {}\n\
+                 NarSize: {}\n\
+                 References: \n",
+                sha256(&xz),
+                xz.len(),
+                sha256(&nar),
+                nar.len()
+            )
+        };
+        let signed: NarInfo = narinfo(HASH).parse().unwrap();
+        let signed = format!(
+            "{}Sig: {}\n",
+            narinfo(HASH),
+            test_key("upstream-1", 7).sign(&signed.fingerprint())
+        );
+        let unsigned = narinfo(UNSIGNED);
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let served_file = file.clone();
+        let served_nar = xz.clone();
+        let app = Router::new()
+            .route(
+                "/:file",
+                get(move |Path(name): Path<String>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let response = if name == format!("{HASH}.narinfo") {
+                        Ok(signed.clone())
+                    } else if name == format!("{UNSIGNED}.narinfo") {
+                        Ok(unsigned.clone())
+                    } else {
+                        Err(Status::NOT_FOUND)
+                    };
+                    async move { response }
+                }),
+            )
+            .route(
+                "/nar/:file",
+                get(move |Path(name): Path<String>| {
+                    let response = if name == served_file {
+                        Ok(served_nar.clone())
+                    } else {
+                        Err(Status::NOT_FOUND)
+                    };
+                    async move { response }
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        Upstream {
+            url: format!("http://{addr}/"),
+            requests,
+            nar: xz,
+            file,
+        }
+    }
+
+    async fn proxy(
+        upstream: &Upstream,
+    ) -> (tempfile::TempDir, Arc<dyn NixCacheStorage>, Arc<Proxy>) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn NixCacheStorage> =
+            Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let config = UpstreamConfig {
+            url: upstream.url.clone(),
+            public_keys: vec![test_key("upstream-1", 7).public_key().to_string()],
+        };
+        let proxy = Proxy::new(
+            &[config],
+            storage.clone(),
+            Some(Arc::new(test_key("cache-1", 1))),
+        )
+        .unwrap();
+        (dir, storage, Arc::new(proxy))
+    }
+
+    #[tokio::test]
+    async fn pulls_signed_paths_through() {
+        let upstream = spawn_upstream().await;
+        let (_dir, storage, proxy) = proxy(&upstream).await;
+
+        let content = proxy.narinfo(HASH).await.unwrap();
+        let info: NarInfo = content.parse().unwrap();
+        let cache_key = test_key("cache-1", 1).public_key();
+        assert!(info
+            .sigs
+            .iter()
+            .any(|sig| cache_key.verify(&info.fingerprint(), sig)));
+        assert_eq!(info.sigs.len(), 2);
+
+        for _ in 0..100 {
+            if storage.get_narinfo(HASH).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(storage.get_narinfo(HASH).await.unwrap(), content);
+        assert_eq!(
+            storage.nar_size(&upstream.file).await.unwrap(),
+            upstream.nar.len() as u64
+        );
+
+        assert!(proxy.narinfo(UNSIGNED).await.is_none());
+        let requests = upstream.requests.load(Ordering::SeqCst);
+        assert!(proxy.narinfo(UNSIGNED).await.is_none());
+        assert_eq!(upstream.requests.load(Ordering::SeqCst), requests);
+        assert!(storage.get_narinfo(UNSIGNED).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fetches_nars_by_file_hash() {
+        let upstream = spawn_upstream().await;
+        let (_dir, storage, proxy) = proxy(&upstream).await;
+
+        assert!(proxy.nar(&upstream.file).await);
+        assert_eq!(
+            storage.nar_size(&upstream.file).await.unwrap(),
+            upstream.nar.len() as u64
+        );
+        let missing = format!("{}.nar", sha256(b"nope").to_nix32());
+        assert!(!proxy.nar(&missing).await);
+        assert!(!proxy.nar("unverifiable.nar").await);
+    }
+}
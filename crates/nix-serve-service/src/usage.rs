@@ -0,0 +1,777 @@
+//! Space accounting and quotas. [`AccountedStorage`] sees every write and delete and
+//! keeps the totals current; a periodic re-count from a listing catches whatever
+//! changed behind the service's back.
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::config::QuotaConfig;
+use crate::storage::{ByteRange, ByteStream, NixCacheStorage, ObjectInfo, StorageError};
+
+/// Name of the document recording who uploaded which path.
+const UPLOADS_FILE: &str = "uploads.json";
+
+/// How often the totals are re-counted and uploads written back.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How far ahead of what it has sent an unannounced upload holds bytes, so the quotas
+/// are checked every few MiB rather than on every chunk.
+const HOLD_AHEAD: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Error)]
+pub enum QuotaError {
+    #[error("upload exceeds the limit of {limit} bytes")]
+    TooLarge { limit: u64 },
+    #[error("cache is full: {used} of {limit} bytes used")]
+    CacheFull { used: u64, limit: u64 },
+    #[error("cache already holds its limit of {limit} paths")]
+    TooManyPaths { limit: u64 },
+    #[error("token {token} is over its quota: {used} of {limit} bytes used")]
+    TokenFull {
+        token: String,
+        used: u64,
+        limit: u64,
+    },
+}
+
+impl QuotaError {
+    /// 413 for an upload that could never fit, 507 for one that doesn't fit right now.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            QuotaError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::INSUFFICIENT_STORAGE,
+        }
+    }
+
+    /// Finds a quota violation that a limited upload stream passed through the storage
+    /// backend as an IO error.
+    pub fn from_storage(e: &StorageError) -> Option<&QuotaError> {
+        match e {
+            StorageError::Io(e) => e.get_ref()?.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum UsageError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("corrupt upload records: {0}")]
+    State(#[from] serde_json::Error),
+}
+
+/// Who uploaded a store path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Upload {
+    pub token: String,
+    /// Size of the NAR file as stored.
+    pub size: u64,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Totals {
+    /// Bytes of stored NARs.
+    pub bytes: u64,
+    /// Stored narinfo.
+    pub paths: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    #[serde(flatten)]
+    pub totals: Totals,
+    pub quota: QuotaConfig,
+    /// Paths still in the cache, by the token that uploaded them.
+    pub tokens: BTreeMap<String, Totals>,
+    pub rejected_uploads: u64,
+}
+
+struct UsageState {
+    totals: Totals,
+    uploads: HashMap<String, Upload>,
+    /// `uploads` summed up by token.
+    used_by: HashMap<String, Totals>,
+    /// Bytes held for uploads in flight, overall and by token.
+    reserved: u64,
+    reserved_by: HashMap<String, u64>,
+    /// Whether `uploads` has been read back from storage yet.
+    loaded: bool,
+    dirty: bool,
+}
+
+impl UsageState {
+    fn insert_upload(&mut self, hash: String, upload: Upload) {
+        let totals = self.used_by.entry(upload.token.clone()).or_default();
+        totals.bytes += upload.size;
+        totals.paths += 1;
+        if let Some(old) = self.uploads.insert(hash, upload) {
+            self.forget(&old);
+        }
+    }
+
+    /// Whether there was a record for `hash`.
+    fn remove_upload(&mut self, hash: &str) -> bool {
+        match self.uploads.remove(hash) {
+            Some(old) => {
+                self.forget(&old);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn forget(&mut self, upload: &Upload) {
+        if let Some(totals) = self.used_by.get_mut(&upload.token) {
+            totals.bytes = totals.bytes.saturating_sub(upload.size);
+            totals.paths = totals.paths.saturating_sub(1);
+            if totals.paths == 0 {
+                self.used_by.remove(&upload.token);
+            }
+        }
+    }
+}
+
+pub struct Usage {
+    storage: Arc<dyn NixCacheStorage>,
+    quota: QuotaConfig,
+    state: Mutex<UsageState>,
+    rejections: AtomicU64,
+}
+
+impl Usage {
+    /// Starts from zero; [`Usage::refresh`] counts what is already stored.
+    pub fn new(storage: Arc<dyn NixCacheStorage>, quota: QuotaConfig) -> Self {
+        Usage {
+            storage,
+            quota,
+            state: Mutex::new(UsageState {
+                totals: Totals::default(),
+                uploads: HashMap::new(),
+                used_by: HashMap::new(),
+                reserved: 0,
+                reserved_by: HashMap::new(),
+                loaded: false,
+                dirty: false,
+            }),
+            rejections: AtomicU64::new(0),
+        }
+    }
+
+    pub fn totals(&self) -> Totals {
+        self.state.lock().unwrap().totals
+    }
+
+    /// Totals of the paths each token uploaded that are still in the cache.
+    pub fn token_totals(&self) -> BTreeMap<String, Totals> {
+        let state = self.state.lock().unwrap();
+        state
+            .used_by
+            .iter()
+            .map(|(token, totals)| (token.clone(), *totals))
+            .collect()
+    }
+
+    pub fn upload(&self, hash: &str) -> Option<Upload> {
+        self.state.lock().unwrap().uploads.get(hash).cloned()
+    }
+
+    pub fn rejections(&self) -> u64 {
+        self.rejections.load(Ordering::Relaxed)
+    }
+
+    pub fn report(&self) -> UsageReport {
+        UsageReport {
+            totals: self.totals(),
+            quota: self.quota.clone(),
+            tokens: self.token_totals(),
+            rejected_uploads: self.rejections(),
+        }
+    }
+
+    pub fn quota(&self) -> &QuotaConfig {
+        &self.quota
+    }
+
+    /// Attributes a stored path to the token that uploaded it.
+    pub fn record_upload(&self, hash: &str, token: &str, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.insert_upload(
+            hash.to_owned(),
+            Upload {
+                token: token.to_owned(),
+                size,
+                time: Utc::now(),
+            },
+        );
+        state.dirty = true;
+    }
+
+    fn reject(&self, e: QuotaError) -> QuotaError {
+        self.rejections.fetch_add(1, Ordering::Relaxed);
+        e
+    }
+
+    /// Fails if another path can't be added.
+    pub fn check_paths(&self) -> Result<(), QuotaError> {
+        match self.quota.max_paths {
+            Some(limit) if self.totals().paths >= limit => {
+                Err(self.reject(QuotaError::TooManyPaths { limit }))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// How many bytes an upload by `token` that already holds `held` may come to, and
+    /// the error for going over. What other uploads in flight hold counts as used.
+    fn allowance(&self, state: &UsageState, token: &str, held: u64) -> Option<(u64, QuotaError)> {
+        let used = state.totals.bytes + state.reserved - held;
+        let token_used = state.used_by.get(token).map_or(0, |totals| totals.bytes)
+            + state.reserved_by.get(token).copied().unwrap_or(0)
+            - held;
+        let limits = [
+            self.quota
+                .max_upload_bytes
+                .map(|limit| (limit, QuotaError::TooLarge { limit })),
+            self.quota.max_bytes.map(|limit| {
+                (
+                    limit.saturating_sub(used),
+                    QuotaError::CacheFull { used, limit },
+                )
+            }),
+            self.quota.tokens.get(token).map(|&limit| {
+                (
+                    limit.saturating_sub(token_used),
+                    QuotaError::TokenFull {
+                        token: token.to_owned(),
+                        used: token_used,
+                        limit,
+                    },
+                )
+            }),
+        ];
+        limits
+            .into_iter()
+            .flatten()
+            .min_by_key(|(allowed, _)| *allowed)
+    }
+
+    /// Cuts an upload by `token` off with a [`QuotaError`] once it goes over a quota.
+    /// Fails right away if the announced `size` already does. The bytes let through are
+    /// held against the quotas until both the stream and the returned [`Reservation`]
+    /// are dropped, so keep that until the upload is stored and counted.
+    pub fn limit(
+        self: &Arc<Self>,
+        content: ByteStream,
+        token: &str,
+        size: Option<u64>,
+    ) -> Result<(ByteStream, Reservation), QuotaError> {
+        let quota = &self.quota;
+        if quota.max_upload_bytes.is_none()
+            && quota.max_bytes.is_none()
+            && !quota.tokens.contains_key(token)
+        {
+            return Ok((content, Reservation { _held: None }));
+        }
+        let held = Arc::new(Held {
+            usage: self.clone(),
+            token: token.to_owned(),
+            bytes: AtomicU64::new(0),
+        });
+        let size = size.unwrap_or(0);
+        held.hold(size, size)?;
+        let holding = held.clone();
+        let mut seen = 0u64;
+        let content = content
+            .and_then(move |chunk| {
+                seen += chunk.len() as u64;
+                let result = holding
+                    .hold(seen, seen + HOLD_AHEAD)
+                    .map(|()| chunk)
+                    .map_err(io::Error::other);
+                futures::future::ready(result)
+            })
+            .boxed();
+        Ok((content, Reservation { _held: Some(held) }))
+    }
+
+    fn nar_replaced(&self, removed: u64, added: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.totals.bytes = state.totals.bytes.saturating_sub(removed) + added;
+    }
+
+    fn narinfo_added(&self) {
+        self.state.lock().unwrap().totals.paths += 1;
+    }
+
+    fn narinfo_removed(&self, hash: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.totals.paths = state.totals.paths.saturating_sub(1);
+        if state.remove_upload(hash) {
+            state.dirty = true;
+        }
+    }
+
+    /// Re-counts the totals from a listing of the storage backend.
+    pub async fn refresh(&self) -> Result<(), UsageError> {
+        let loaded = if self.state.lock().unwrap().loaded {
+            None
+        } else {
+            match self.storage.get_meta(UPLOADS_FILE).await {
+                Ok(content) => Some(serde_json::from_slice::<HashMap<String, Upload>>(&content)?),
+                Err(StorageError::NotFound) => Some(HashMap::new()),
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let narinfos = self.storage.list_narinfos().await?;
+        let bytes = self.storage.list_nars().await?.iter().map(|n| n.size).sum();
+        let present: HashSet<&str> = narinfos.iter().map(|n| n.name.as_str()).collect();
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(loaded) = loaded {
+            for (hash, upload) in loaded {
+                if !state.uploads.contains_key(&hash) {
+                    state.insert_upload(hash, upload);
+                }
+            }
+            state.loaded = true;
+        }
+        let gone: Vec<String> = state
+            .uploads
+            .keys()
+            .filter(|hash| !present.contains(hash.as_str()))
+            .cloned()
+            .collect();
+        for hash in &gone {
+            state.remove_upload(hash);
+        }
+        state.dirty |= !gone.is_empty();
+        state.totals = Totals {
+            bytes,
+            paths: narinfos.len() as u64,
+        };
+        Ok(())
+    }
+
+    /// Writes the upload records back if they changed.
+    pub async fn save(&self) -> Result<(), UsageError> {
+        let content = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty || !state.loaded {
+                return Ok(());
+            }
+            state.dirty = false;
+            serde_json::to_vec(&state.uploads)?
+        };
+        if let Err(e) = self.storage.put_meta(UPLOADS_FILE, content).await {
+            self.state.lock().unwrap().dirty = true;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Counts what is stored, then keeps re-counting and saving upload records.
+    pub async fn background(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.refresh().await {
+                Ok(()) => {
+                    let totals = self.totals();
+                    info!(
+                        bytes = totals.bytes,
+                        paths = totals.paths,
+                        "Counted cache usage"
+                    );
+                }
+                Err(e) => error!(error = %e, "Failed to count cache usage"),
+            }
+            if let Err(e) = self.save().await {
+                error!(error = %e, "Failed to write upload records");
+            }
+        }
+    }
+}
+
+/// Bytes held for an upload in flight, so parallel uploads can't each pass a quota
+/// check that only one of them fits. See [`Usage::limit`].
+pub struct Reservation {
+    _held: Option<Arc<Held>>,
+}
+
+struct Held {
+    usage: Arc<Usage>,
+    token: String,
+    bytes: AtomicU64,
+}
+
+impl Held {
+    /// Makes sure at least `needed` bytes are held for the upload, if the quotas leave
+    /// room for them, taking up to `wanted` while at it.
+    fn hold(&self, needed: u64, wanted: u64) -> Result<(), QuotaError> {
+        // Only grows, and only under the lock, so this saves taking it for most chunks.
+        if needed <= self.bytes.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut state = self.usage.state.lock().unwrap();
+        let held = self.bytes.load(Ordering::Relaxed);
+        if needed <= held {
+            return Ok(());
+        }
+        let mut total = wanted.max(needed);
+        if let Some((allowed, e)) = self.usage.allowance(&state, &self.token, held) {
+            if needed > allowed {
+                return Err(self.usage.reject(e));
+            }
+            total = total.min(allowed);
+        }
+        state.reserved += total - held;
+        *state.reserved_by.entry(self.token.clone()).or_default() += total - held;
+        self.bytes.store(total, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        let held = *self.bytes.get_mut();
+        let mut state = self.usage.state.lock().unwrap();
+        state.reserved -= held;
+        if let Some(reserved) = state.reserved_by.get_mut(&self.token) {
+            *reserved -= held;
+            if *reserved == 0 {
+                state.reserved_by.remove(&self.token);
+            }
+        }
+    }
+}
+
+/// Keeps a [`Usage`] current with the writes and deletes made through it.
+pub struct AccountedStorage {
+    inner: Arc<dyn NixCacheStorage>,
+    usage: Arc<Usage>,
+}
+
+impl AccountedStorage {
+    pub fn new(inner: Arc<dyn NixCacheStorage>, usage: Arc<Usage>) -> Self {
+        AccountedStorage { inner, usage }
+    }
+}
+
+#[async_trait]
+impl NixCacheStorage for AccountedStorage {
+    async fn get_narinfo(&self, hash: &str) -> Result<String, StorageError> {
+        self.inner.get_narinfo(hash).await
+    }
+
+    async fn put_narinfo(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        let new = matches!(
+            self.inner.get_narinfo(hash).await,
+            Err(StorageError::NotFound)
+        );
+        self.inner.put_narinfo(hash, content).await?;
+        if new {
+            self.usage.narinfo_added();
+        }
+        Ok(())
+    }
+
+    async fn nar_size(&self, file: &str) -> Result<u64, StorageError> {
+        self.inner.nar_size(file).await
+    }
+
+    async fn get_nar(
+        &self,
+        file: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ByteStream, StorageError> {
+        self.inner.get_nar(file, range).await
+    }
+
+    async fn put_nar(&self, file: &str, content: ByteStream) -> Result<(), StorageError> {
+        let old = self.inner.nar_size(file).await.unwrap_or(0);
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = written.clone();
+        let content = content
+            .inspect_ok(move |chunk| {
+                counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            })
+            .boxed();
+        self.inner.put_nar(file, content).await?;
+        self.usage
+            .nar_replaced(old, written.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    async fn list_narinfos(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_narinfos().await
+    }
+
+    async fn list_nars(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_nars().await
+    }
+
+    async fn delete_narinfo(&self, hash: &str) -> Result<(), StorageError> {
+        let existed = self.inner.get_narinfo(hash).await.is_ok();
+        self.inner.delete_narinfo(hash).await?;
+        if existed {
+            self.usage.narinfo_removed(hash);
+        }
+        Ok(())
+    }
+
+    async fn delete_nar(&self, file: &str) -> Result<(), StorageError> {
+        let size = self.inner.nar_size(file).await.unwrap_or(0);
+        self.inner.delete_nar(file).await?;
+        self.usage.nar_replaced(size, 0);
+        Ok(())
+    }
+
+    async fn rename_nar(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let replaced = self.inner.nar_size(to).await.unwrap_or(0);
+        self.inner.rename_nar(from, to).await?;
+        self.usage.nar_replaced(replaced, 0);
+        Ok(())
+    }
+
+    async fn get_log(&self, drv: &str) -> Result<ByteStream, StorageError> {
+        self.inner.get_log(drv).await
+    }
+
+    async fn put_log(&self, drv: &str, content: ByteStream) -> Result<(), StorageError> {
+        self.inner.put_log(drv, content).await
+    }
+
+    async fn list_logs(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_logs().await
+    }
+
+    async fn delete_log(&self, drv: &str) -> Result<(), StorageError> {
+        self.inner.delete_log(drv).await
+    }
+
+    async fn get_realisation(&self, id: &str) -> Result<String, StorageError> {
+        self.inner.get_realisation(id).await
+    }
+
+    async fn put_realisation(&self, id: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_realisation(id, content).await
+    }
+
+    async fn list_realisations(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_realisations().await
+    }
+
+    async fn delete_realisation(&self, id: &str) -> Result<(), StorageError> {
+        self.inner.delete_realisation(id).await
+    }
+
+    async fn get_listing(&self, hash: &str) -> Result<String, StorageError> {
+        self.inner.get_listing(hash).await
+    }
+
+    async fn put_listing(&self, hash: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_listing(hash, content).await
+    }
+
+    async fn delete_listing(&self, hash: &str) -> Result<(), StorageError> {
+        self.inner.delete_listing(hash).await
+    }
+
+    async fn get_debuginfo(&self, build_id: &str) -> Result<String, StorageError> {
+        self.inner.get_debuginfo(build_id).await
+    }
+
+    async fn put_debuginfo(&self, build_id: &str, content: String) -> Result<(), StorageError> {
+        self.inner.put_debuginfo(build_id, content).await
+    }
+
+    async fn list_debuginfo(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_debuginfo().await
+    }
+
+    async fn delete_debuginfo(&self, build_id: &str) -> Result<(), StorageError> {
+        self.inner.delete_debuginfo(build_id).await
+    }
+
+    async fn get_chunk(&self, hash: &str) -> Result<Bytes, StorageError> {
+        self.inner.get_chunk(hash).await
+    }
+
+    async fn put_chunk(&self, hash: &str, content: Bytes) -> Result<(), StorageError> {
+        self.inner.put_chunk(hash, content).await
+    }
+
+    async fn list_chunks(&self) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list_chunks().await
+    }
+
+    async fn delete_chunk(&self, hash: &str) -> Result<(), StorageError> {
+        self.inner.delete_chunk(hash).await
+    }
+
+    async fn get_meta(&self, name: &str) -> Result<Vec<u8>, StorageError> {
+        self.inner.get_meta(name).await
+    }
+
+    async fn put_meta(&self, name: &str, content: Vec<u8>) -> Result<(), StorageError> {
+        self.inner.put_meta(name, content).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::DiskStorage;
+    use bytes::Bytes;
+
+    const HASH: &str = "7h1ydl0sxmdc8aihhwbdbx0ybvxqmfd1";
+
+    fn body(len: usize) -> ByteStream {
+        futures::stream::once(async move { Ok(Bytes::from(vec![1; len])) }).boxed()
+    }
+
+    #[tokio::test]
+    async fn tracks_writes_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk: Arc<dyn NixCacheStorage> = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        disk.put_nar("old.nar", body(10)).await.unwrap();
+        disk.put_meta(
+            UPLOADS_FILE,
+            br#"{"0mdqa9w1p6cmli6976v4wi0sw9r4p000": {"token": "ci", "size": 10, "time": "2024-01-01T00:00:00Z"}}"#.to_vec(),
+        )
+        .await
+        .unwrap();
+
+        let usage = Arc::new(Usage::new(disk.clone(), QuotaConfig::default()));
+        let storage = AccountedStorage::new(disk.clone(), usage.clone());
+        usage.refresh().await.unwrap();
+        // The recorded path is gone, so its record is dropped.
+        assert!(usage.token_totals().is_empty());
+        assert_eq!(
+            usage.totals(),
+            Totals {
+                bytes: 10,
+                paths: 0
+            }
+        );
+
+        storage.put_nar("a.nar", body(100)).await.unwrap();
+        storage.put_nar("a.nar", body(50)).await.unwrap();
+        storage.put_narinfo(HASH, "a".to_owned()).await.unwrap();
+        storage.put_narinfo(HASH, "b".to_owned()).await.unwrap();
+        usage.record_upload(HASH, "ci", 50);
+        assert_eq!(
+            usage.totals(),
+            Totals {
+                bytes: 60,
+                paths: 1
+            }
+        );
+        assert_eq!(
+            usage.token_totals()["ci"],
+            Totals {
+                bytes: 50,
+                paths: 1
+            }
+        );
+
+        storage.delete_narinfo(HASH).await.unwrap();
+        storage.delete_nar("old.nar").await.unwrap();
+        assert_eq!(
+            usage.totals(),
+            Totals {
+                bytes: 50,
+                paths: 0
+            }
+        );
+        assert!(usage.token_totals().is_empty());
+    }
+
+    #[tokio::test]
+    async fn enforces_quotas() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk: Arc<dyn NixCacheStorage> = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let quota = QuotaConfig {
+            max_bytes: Some(1000),
+            max_paths: Some(1),
+            max_upload_bytes: Some(600),
+            tokens: [("dev".to_owned(), 200)].into(),
+        };
+        let usage = Arc::new(Usage::new(disk.clone(), quota));
+        let storage = AccountedStorage::new(disk, usage.clone());
+
+        let e = usage.limit(body(700), "ci", Some(700)).err().unwrap();
+        assert_eq!(e.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let e = usage.limit(body(300), "dev", Some(300)).err().unwrap();
+        assert!(matches!(e, QuotaError::TokenFull { .. }));
+
+        let (limited, reservation) = usage.limit(body(500), "ci", None).unwrap();
+        storage.put_nar("a.nar", limited).await.unwrap();
+        drop(reservation);
+        // Only 500 bytes left now, and the size isn't announced.
+        let (limited, _reservation) = usage.limit(body(550), "ci", None).unwrap();
+        let e = storage.put_nar("b.nar", limited).await.unwrap_err();
+        assert!(matches!(
+            QuotaError::from_storage(&e),
+            Some(QuotaError::CacheFull { used: 500, .. })
+        ));
+        assert_eq!(usage.totals().bytes, 500);
+
+        storage.put_narinfo(HASH, "a".to_owned()).await.unwrap();
+        assert_eq!(
+            usage.check_paths().unwrap_err().status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(usage.rejections(), 4);
+    }
+
+    #[tokio::test]
+    async fn reserves_bytes_for_uploads_in_flight() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk: Arc<dyn NixCacheStorage> = Arc::new(DiskStorage::new(dir.path()).await.unwrap());
+        let quota = QuotaConfig {
+            max_bytes: Some(1000),
+            tokens: [("dev".to_owned(), 700)].into(),
+            ..Default::default()
+        };
+        let usage = Arc::new(Usage::new(disk.clone(), quota));
+        let storage = AccountedStorage::new(disk, usage.clone());
+
+        // Each fits on its own, but not both at once.
+        let (first, reservation) = usage.limit(body(600), "ci", Some(600)).unwrap();
+        let e = usage.limit(body(600), "dev", Some(600)).err().unwrap();
+        assert!(matches!(e, QuotaError::CacheFull { used: 600, .. }));
+        // Unannounced uploads are held as they go.
+        let (second, _second_reservation) = usage.limit(body(500), "dev", None).unwrap();
+        let e = storage.put_nar("b.nar", second).await.unwrap_err();
+        assert!(matches!(
+            QuotaError::from_storage(&e),
+            Some(QuotaError::CacheFull { used: 600, .. })
+        ));
+
+        // A failed upload gives its bytes back.
+        drop((first, reservation));
+        let (third, reservation) = usage.limit(body(400), "dev", Some(400)).unwrap();
+        let e = usage.limit(body(400), "dev", Some(400)).err().unwrap();
+        assert!(matches!(e, QuotaError::TokenFull { used: 400, .. }));
+        // A stored one is counted instead.
+        storage.put_nar("c.nar", third).await.unwrap();
+        drop(reservation);
+        assert_eq!(usage.totals().bytes, 400);
+        let (_, _reservation) = usage.limit(body(600), "ci", Some(600)).unwrap();
+        assert!(usage.limit(body(1), "ci", Some(1)).is_err());
+    }
+}